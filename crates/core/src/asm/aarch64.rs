@@ -1,11 +1,25 @@
 //! Assembler for ARM AArch64
 
 use std::collections::HashMap;
-use std::fmt;
+use std::error::Error;
+use std::fmt::{self, Write};
 
-// This is used for debug prints, but I deleted them :3
+use super::CodeBuffer;
+
+pub mod disasm;
+#[cfg(test)]
+mod encodings;
+
+/// Adds the instruction to the listing, if there is one. Goes before the instruction is emitted.
 macro_rules! asm {
-    ($($fmt: expr),+) => {{}};
+    ($self: ident, $($fmt: expr),+) => {{
+        // Formatted first, since formatting a label looks up its name.
+        let text = $self.listing.as_ref().map(|_| format!($($fmt),+));
+        if let (Some(text), Some(listing)) = (text, &mut $self.listing) {
+            let offset = $self.instr.len();
+            listing.push(ListingLine::Instruction { offset, text });
+        }
+    }};
 }
 
 /// Reference to 64-bit register
@@ -16,6 +30,10 @@ pub struct X(pub u8);
 #[derive(Clone, Copy)]
 pub struct W(pub u8);
 
+/// Reference to a 128-bit vector register, as sixteen bytes.
+#[derive(Clone, Copy)]
+pub struct V(pub u8);
+
 /// An immediate value in the instruction.
 #[derive(Clone, Copy)]
 pub struct Imm(pub u8, pub i32);
@@ -24,26 +42,99 @@ pub struct Imm(pub u8, pub i32);
 #[derive(Clone, Copy)]
 pub struct Umm(pub u8, pub u32);
 
-/// A branch label in the assembly
+/// A branch label in the assembly. Either numbered by the caller, or declared with a name by
+/// [AArch64Assembly::declare_label].
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
 pub struct Label(pub usize);
 
+/// Somewhere in the machine code that refers to a symbol by its address, which isn't known until
+/// the code is linked (e.g., `putchar()`, when it's not passed in as a pointer). Until then, the
+/// instructions are emitted as if the address were zero.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relocation {
+    /// Where the first instruction to fix up is, in bytes from the start of the machine code.
+    pub offset: usize,
+    pub kind: RelocationKind,
+    pub symbol: String,
+}
+
+/// Which instructions a [Relocation] fixes up, and how. Each corresponds to an ELF relocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocationKind {
+    /// A `movz` and three `movk`s, which load the symbol's absolute address, 16 bits at a time
+    /// (`R_AARCH64_MOVW_UABS_G0_NC` through `R_AARCH64_MOVW_UABS_G3`).
+    AbsoluteMovWide,
+    /// A `bl` to the symbol, at most 128 MiB away (`R_AARCH64_CALL26`).
+    Call26,
+}
+
+/// Labels that branches refer to, but that were never bound, each with the offsets (in bytes) of
+/// the branches that refer to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnresolvedLabels(pub Vec<(String, Vec<usize>)>);
+
 #[derive(Debug, Clone, Copy)]
 pub struct WordOffset(i32);
 
+/// A condition on the flags, as set by e.g. [AArch64Assembly::cmp]. The unsigned comparisons are
+/// `hs`, `lo`, `hi`, and `ls`; the signed ones are `ge`, `lt`, `gt`, and `le`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cond {
+    Eq = 0b0000,
+    Ne = 0b0001,
+    Hs = 0b0010,
+    Lo = 0b0011,
+    Hi = 0b1000,
+    Ls = 0b1001,
+    Ge = 0b1010,
+    Lt = 0b1011,
+    Gt = 0b1100,
+    Le = 0b1101,
+}
+
+/// A line of the listing.
+enum ListingLine {
+    Label(Label),
+    Comment(String),
+    /// The instruction at `offset` bytes into the machine code.
+    Instruction {
+        offset: usize,
+        text: String,
+    },
+}
+
 #[derive(Clone, Copy)]
 enum IncompleteInstruction {
     Cbz,
+    Cbnz,
+    BCond,
     B,
+    LdrLiteral,
+    Adr,
+    Adrp,
+    AddLo12,
 }
 
 /// Generates ARM AArch64 machine code.
 pub struct AArch64Assembly {
-    instr: Vec<u8>,
+    instr: CodeBuffer,
     // Maps labels to the offset in the instruction vector
     label_targets: HashMap<Label, WordOffset>,
     //
     unresolved_branch_targets: Vec<(WordOffset, IncompleteInstruction, Label)>,
+    // Human-readable assembly of everything emitted so far, if it was asked for
+    listing: Option<Vec<ListingLine>>,
+    // Places that refer to symbols, for whatever links the code to fix up
+    relocations: Vec<Relocation>,
+    // Labels from declare_label(), which count down from the top so as not to clash with the
+    // caller's numbers
+    label_names: HashMap<Label, String>,
+    // Literals that loads refer to, which haven't been laid out in a pool yet
+    literals: Vec<(u64, Label)>,
+    // Whether to leave out instructions that are obviously redundant, as they're emitted
+    peephole: bool,
+    // Where the last label was bound, which nothing can be left out across
+    last_bound: Option<usize>,
 }
 
 impl AArch64Assembly {
@@ -52,40 +143,229 @@ impl AArch64Assembly {
 
     pub fn new() -> Self {
         AArch64Assembly {
-            instr: Vec::new(),
+            instr: CodeBuffer::new(),
             label_targets: HashMap::new(),
             unresolved_branch_targets: Vec::new(),
+            listing: None,
+            relocations: Vec::new(),
+            label_names: HashMap::new(),
+            literals: Vec::new(),
+            peephole: false,
+            last_bound: None,
+        }
+    }
+
+    /// Also keeps a listing of the assembly, as it would be written in a `.s` file.
+    pub fn with_listing(self) -> Self {
+        AArch64Assembly {
+            listing: Some(Vec::new()),
+            ..self
+        }
+    }
+
+    /// Also leaves out instructions that obviously do nothing, as they're emitted: a load of the
+    /// byte that the last instruction stored (which becomes a `uxtb` of what was stored), an `add`
+    /// or `sub` of zero to a 64-bit register, and a `b` to the instruction right after it. These
+    /// come up where one piece of generated code meets the next.
+    ///
+    /// Nothing is left out across a label, since code that branches there didn't come through
+    /// the instruction before it.
+    pub fn with_peephole(self) -> Self {
+        AArch64Assembly {
+            peephole: true,
+            ..self
+        }
+    }
+
+    /// The listing so far, if it was asked for with [AArch64Assembly::with_listing].
+    pub fn listing(&self) -> Option<String> {
+        self.write_listing(|_, text| format!("    {}", text))
+    }
+
+    /// The listing so far, with the offset and encoding of each instruction in front of it, like
+    /// a disassembler would write it. Branches only have their encoding once
+    /// [AArch64Assembly::patch_branch_targets] has been called, and nothing does once the machine
+    /// code has been taken.
+    pub fn annotated_listing(&self) -> Option<String> {
+        self.write_listing(|offset, text| match self.instr.get(offset..offset + 4) {
+            Some(word) => {
+                let word = u32::from_le_bytes(word.try_into().unwrap());
+                format!("    {:04x}:  {:08x}    {}", offset, word, text)
+            }
+            None => format!("    {:04x}:  {:8}    {}", offset, "", text),
+        })
+    }
+
+    /// Writes each line of the listing, with `instruction` writing the instructions.
+    fn write_listing(&self, instruction: impl Fn(usize, &str) -> String) -> Option<String> {
+        let listing = self.listing.as_ref()?;
+        let mut text = String::new();
+        for line in listing {
+            match line {
+                ListingLine::Label(label) => writeln!(text, "{}:", self.label_name(*label)),
+                ListingLine::Comment(comment) => writeln!(text, "    // {}", comment),
+                ListingLine::Instruction { offset, text: asm } => {
+                    writeln!(text, "{}", instruction(*offset, asm))
+                }
+            }
+            .unwrap();
         }
+        Some(text)
     }
 
-    /// Call this before the first instruction of the desired label
-    pub fn set_label_target(&mut self, label: Label) {
+    /// Adds a comment to the listing, if there is one. Does not change the machine code.
+    pub fn comment(&mut self, text: impl fmt::Display) {
+        if let Some(listing) = &mut self.listing {
+            listing.push(ListingLine::Comment(text.to_string()));
+        }
+    }
+
+    /// A new label, which is called `name` in the listing and in [UnresolvedLabels].
+    ///
+    /// # Panics
+    ///
+    /// When a label was already declared with the same name.
+    pub fn declare_label(&mut self, name: &str) -> Label {
+        assert!(
+            !self.label_names.values().any(|other| other == name),
+            "there is already a label called {}",
+            name
+        );
+        let label = Label(usize::MAX - self.label_names.len());
+        self.label_names.insert(label, name.to_owned());
+        label
+    }
+
+    /// Binds the label to where the next instruction goes. Branches to it can come before or after.
+    ///
+    /// # Panics
+    ///
+    /// When the label was already bound.
+    pub fn bind(&mut self, label: Label) {
+        if self.peephole {
+            self.drop_branch_to(label);
+        }
+        self.last_bound = Some(self.instr.len());
         let offset = WordOffset::from_byte_offset(self.instr.len());
-        self.label_targets.insert(label, offset);
+        if self.label_targets.insert(label, offset).is_some() {
+            panic!("label {} is bound twice", self.label_name(label));
+        }
+        if let Some(listing) = &mut self.listing {
+            listing.push(ListingLine::Label(label));
+        }
     }
 
+    /// Takes back the last instruction, if it's a `b` to the label, since the label is about to
+    /// be bound right after it. Labels that were bound after the branch move back with it.
+    fn drop_branch_to(&mut self, label: Label) {
+        let Some(&(source, IncompleteInstruction::B, target)) =
+            self.unresolved_branch_targets.last()
+        else {
+            return;
+        };
+        let start = source.to_usize();
+        if target != label || start + 4 != self.instr.len() {
+            return;
+        }
+
+        self.unresolved_branch_targets.pop();
+        self.instr.truncate(start);
+        for offset in self.label_targets.values_mut() {
+            if offset.to_usize() > start {
+                *offset = source;
+            }
+        }
+        if let Some(listing) = &mut self.listing {
+            let branch = listing.iter().rposition(
+                |line| matches!(line, ListingLine::Instruction { offset, .. } if *offset == start),
+            );
+            listing.remove(branch.expect("every instruction is listed"));
+        }
+    }
+
+    /// The last instruction emitted, if the peephole can look at it: nothing was bound since.
+    fn previous_instruction(&self) -> Option<u32> {
+        let end = self.instr.len();
+        if !self.peephole || end == 0 || self.last_bound == Some(end) {
+            return None;
+        }
+        Some(self.get_instruction(WordOffset::from_byte_offset(end - 4)))
+    }
+
+    /// What register the last instruction stored to the byte that `load` loads, if it did.
+    fn stored_by_previous_instruction(&self, load: u32) -> Option<W> {
+        // A strb is the same as the ldrb with the same address, without the load bit.
+        let store = load & !(1 << 22);
+        let previous = self.previous_instruction()?;
+        (previous & !0x1F == store & !0x1F).then_some(W((previous & 0x1F) as u8))
+    }
+
+    /// Whether adding or subtracting `imm` to xn to get xd does nothing at all.
+    fn is_no_op(&self, xd: X, xn: X, imm: u16) -> bool {
+        self.peephole && imm == 0 && xd.0 == xn.0
+    }
+
+    /// What the label is called in the listing.
+    fn label_name(&self, label: Label) -> String {
+        match self.label_names.get(&label) {
+            Some(name) => name.clone(),
+            None => label.to_string(),
+        }
+    }
+
+    /// Fills in the offset of every branch to a label that's been bound. Branches to labels that
+    /// haven't been bound yet are left for next time.
+    ///
+    /// Any literals that loads refer to are laid out first, right here, so only call this once
+    /// the code is done when using [AArch64Assembly::ldr_literal].
     pub fn patch_branch_targets(&mut self) {
-        let patch_list = self.unresolved_branch_targets.clone();
+        self.emit_literal_pool();
+        let patch_list = std::mem::take(&mut self.unresolved_branch_targets);
         for (source, instr, label) in patch_list {
-            let target = self
-                .label_targets
-                .get(&label)
-                .expect("should have seen label");
+            let Some(target) = self.label_targets.get(&label) else {
+                self.unresolved_branch_targets.push((source, instr, label));
+                continue;
+            };
             let incomplete = self.get_instruction(source);
 
             let offset = *target - source;
 
             let missing_bits = match instr {
-                IncompleteInstruction::Cbz => Self::patch_cbz(offset),
+                IncompleteInstruction::Cbz
+                | IncompleteInstruction::Cbnz
+                | IncompleteInstruction::BCond
+                | IncompleteInstruction::LdrLiteral => Self::patch_imm19(offset),
                 IncompleteInstruction::B => Self::patch_b(offset),
+                IncompleteInstruction::Adr => Self::patch_adr(offset),
+                IncompleteInstruction::Adrp => Self::patch_adrp(source, *target),
+                IncompleteInstruction::AddLo12 => Self::patch_lo12(*target),
             };
 
             let complete = incomplete | missing_bits;
 
             self.set_instruction(source, complete);
         }
+    }
+
+    /// Whether every branch has been patched, and if not, which labels are missing.
+    pub fn check_labels(&self) -> Result<(), UnresolvedLabels> {
+        let mut missing: Vec<(Label, Vec<usize>)> = Vec::new();
+        for &(source, _, label) in &self.unresolved_branch_targets {
+            match missing.iter_mut().find(|(l, _)| *l == label) {
+                Some((_, sites)) => sites.push(source.to_usize()),
+                None => missing.push((label, vec![source.to_usize()])),
+            }
+        }
 
-        self.unresolved_branch_targets.clear();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        Err(UnresolvedLabels(
+            missing
+                .into_iter()
+                .map(|(label, sites)| (self.label_name(label), sites))
+                .collect(),
+        ))
     }
 
     fn get_instruction(&self, offset: WordOffset) -> u32 {
@@ -103,20 +383,38 @@ impl AArch64Assembly {
         self.instr[n_bytes..(n_bytes + 4)].copy_from_slice(&bytes);
     }
 
+    /// Where the next instruction goes, in bytes from the start of the machine code.
+    pub fn offset(&self) -> usize {
+        self.instr.len()
+    }
+
     /// Returns machine code.
-    /// Panics if there are unresolved branch targets.
+    /// Panics if there are unresolved branch targets, saying which labels they refer to.
     pub fn machine_code(&self) -> &[u8] {
-        let incomplete = self.unresolved_branch_targets.len();
-        if incomplete > 0 {
-            panic!(
-                "tried to generate binary, but there are still {} unresolved branch targets!",
-                incomplete
-            );
-        }
-
+        self.assert_complete();
         &self.instr[..]
     }
 
+    /// Everything in the machine code that refers to a symbol, in the order it was emitted.
+    pub fn relocations(&self) -> &[Relocation] {
+        &self.relocations
+    }
+
+    /// Takes the machine code, leaving nothing behind (including its [relocations](Self::relocations)).
+    /// Panics if there are unresolved branch targets.
+    pub fn take_machine_code(&mut self) -> CodeBuffer {
+        self.assert_complete();
+        self.label_targets.clear();
+        self.relocations.clear();
+        std::mem::take(&mut self.instr)
+    }
+
+    fn assert_complete(&self) {
+        if let Err(unresolved) = self.check_labels() {
+            panic!("tried to generate binary, but {}", unresolved);
+        }
+    }
+
     // Instructions
     //
     // The following instructions are in the order given by
@@ -127,14 +425,35 @@ impl AArch64Assembly {
     /// Compare register and Branch if Zero
     pub fn cbz(&mut self, rt: W, label: Label) {
         use IncompleteInstruction::Cbz;
-        asm!("cbz {}, {}", rt, label);
+        asm!(self, "cbz {}, {}", rt, self.label_name(label));
         //          sf ______ op              imm19    rt
         //                      23                5 4   0
         let base = 0b0_011010_0_0000000000000000000_00000;
         self.emit_incomplete_branch(label, Cbz, base | rt.at(0..=4));
     }
 
-    fn patch_cbz(offset: WordOffset) -> u32 {
+    /// Compare register and Branch if Not Zero
+    pub fn cbnz(&mut self, rt: W, label: Label) {
+        use IncompleteInstruction::Cbnz;
+        asm!(self, "cbnz {}, {}", rt, self.label_name(label));
+        //          sf ______ op              imm19    rt
+        //                      23                5 4   0
+        let base = 0b0_011010_1_0000000000000000000_00000;
+        self.emit_incomplete_branch(label, Cbnz, base | rt.at(0..=4));
+    }
+
+    /// Branch conditionally, on the flags
+    pub fn b_cond(&mut self, cond: Cond, label: Label) {
+        use IncompleteInstruction::BCond;
+        asm!(self, "b.{} {}", cond, self.label_name(label));
+        //                   o1              imm19 o0 cond
+        let base = 0b0101010_0_0000000000000000000_0_0000;
+        self.emit_incomplete_branch(label, BCond, base | Umm(4, cond as u32).at(0..=3));
+    }
+
+    /// `cbz`, `cbnz`, `b.cond`, and `ldr` (literal) all have a 19-bit word offset in the same
+    /// place.
+    fn patch_imm19(offset: WordOffset) -> u32 {
         let WordOffset(imm) = offset;
         Imm(19, imm).at(5..=23)
     }
@@ -142,7 +461,7 @@ impl AArch64Assembly {
     /// Unconditional branch
     pub fn b(&mut self, label: Label) {
         use IncompleteInstruction::B;
-        asm!("b {}", label);
+        asm!(self, "b {}", self.label_name(label));
         //          op                            imm26
         let base = 0b0_00101_00000000000000000000000000;
         self.emit_incomplete_branch(label, B, base);
@@ -155,15 +474,24 @@ impl AArch64Assembly {
 
     /// Branch and Link to Register
     pub fn blr(&mut self, rn: X) {
-        asm!("blr {}", rn);
+        asm!(self, "blr {}", rn);
         //                   opc    op2    op3    rn   op4;
         let base = 0b1101011_0001_11111_000000_00000_00000;
         self.emit(base | rn.at(5..=9));
     }
 
+    /// Branch with Link to a symbol, which is filled in by a [RelocationKind::Call26].
+    pub fn bl_symbol(&mut self, symbol: &str) {
+        asm!(self, "bl {}", symbol);
+        self.relocate(RelocationKind::Call26, symbol);
+        //          op                            imm26
+        let base = 0b1_00101_00000000000000000000000000;
+        self.emit(base);
+    }
+
     /// ret (return from subroutine)
     pub fn ret(&mut self) {
-        asm!("ret x30");
+        asm!(self, "ret x30");
         let base = 0b1101011_0010_11111_000000_00000_00000;
         self.emit(base | X(30).at(5..=9));
     }
@@ -176,44 +504,157 @@ impl AArch64Assembly {
     /// https://developer.arm.com/documentation/100076/0100/a64-instruction-set-reference/a64-data-transfer-instructions/strb--immediate-?lang=en
     pub fn strb(&mut self, wt: W, xn: X, offset: u16) {
         // https://developer.arm.com/documentation/102374/0101/Loads-and-stores---addressing
-        asm!("strb {}, [{}, #{}]", wt, xn, offset);
-        // Offset is described in bytes, but must be 8-byte aligned (lower 3 bits are implied 0)
-        let dword_aligned_offset = (offset >> 3) as i32;
+        asm!(self, "strb {}, [{}, #{}]", wt, xn, offset);
+        // Offsets are scaled by the size of what's stored, which for a byte is not at all.
         //         size     V   opc        imm12    rn    rt
         let base = 0b00_111_0_01_00_000000000000_00000_00000;
-        self.emit(base | wt.at(0..=4) | xn.at(5..=9) | Imm(12, dword_aligned_offset).at(10..=21));
+        self.emit(base | wt.at(0..=4) | xn.at(5..=9) | Umm(12, offset as u32).at(10..=21));
     }
 
     /// Load Register Byte (immediate)
+    ///
+    /// With [AArch64Assembly::with_peephole], a load of the byte that the last instruction stored
+    /// is a `uxtb` of the register it stored instead, since that's all the byte is.
     pub fn ldrb(&mut self, wt: W, xn: X, offset: u16) {
-        // https://developer.arm.com/documentation/102374/0101/Loads-and-stores---addressing
-        asm!("ldrb {}, [{}, #{}]", wt, xn, offset);
-        // Offset is described in bytes, but must be 8-byte aligned (lower 3 bits are implied 0)
-        let dword_aligned_offset = (offset >> 3) as i32;
+        // Offsets are scaled by the size of what's loaded, which for a byte is not at all.
         //         size     V   opc        imm12    rn    rt
         let base = 0b00_111_0_01_01_000000000000_00000_00000;
-        self.emit(base | wt.at(0..=4) | xn.at(5..=9) | Imm(12, dword_aligned_offset).at(10..=21));
+        let load = base | wt.at(0..=4) | xn.at(5..=9) | Umm(12, offset as u32).at(10..=21);
+        if let Some(stored) = self.stored_by_previous_instruction(load) {
+            self.uxtb(wt, stored);
+            return;
+        }
+        // https://developer.arm.com/documentation/102374/0101/Loads-and-stores---addressing
+        asm!(self, "ldrb {}, [{}, #{}]", wt, xn, offset);
+        self.emit(load);
+    }
+
+    /// Store Register Byte (unscaled), for offsets from -256 to 255: [xn + offset] <- wt
+    pub fn sturb(&mut self, wt: W, xn: X, offset: i16) {
+        asm!(self, "sturb {}, [{}, #{}]", wt, xn, offset);
+        //         size     V   opc   imm9         rn    rt
+        let base = 0b00_111_0_00_00_0_000000000_00_00000_00000;
+        self.emit(base | wt.at(0..=4) | xn.at(5..=9) | Imm(9, offset as i32).at(12..=20));
+    }
+
+    /// Load Register Byte (unscaled), for offsets from -256 to 255: wt <- [xn + offset]
+    pub fn ldurb(&mut self, wt: W, xn: X, offset: i16) {
+        asm!(self, "ldurb {}, [{}, #{}]", wt, xn, offset);
+        //         size     V   opc   imm9         rn    rt
+        let base = 0b00_111_0_00_01_0_000000000_00_00000_00000;
+        self.emit(base | wt.at(0..=4) | xn.at(5..=9) | Imm(9, offset as i32).at(12..=20));
     }
 
     /// Store dword register with immediate offset
     /// https://developer.arm.com/documentation/dui0802/a/CIHGJHED
     pub fn str_imm(&mut self, rt: X, rn: X, offset: u16) {
-        asm!("str {}, [{}, #{}]", rt, rn, offset);
+        asm!(self, "str {}, [{}, #{}]", rt, rn, offset);
         // Offset is described in bytes, but must be 8-byte aligned (lower 3 bits are implied 0)
-        let dword_aligned_offset = (offset >> 3) as i32;
+        let dword_aligned_offset = scale(offset as i32, 8);
         //         size     V   opc        imm12    rn    rt
         let base = 0b11_111_0_01_00_000000000000_00000_00000;
-        self.emit(base | rt.at(0..=4) | rn.at(5..=9) | Imm(12, dword_aligned_offset).at(10..=21));
+        self.emit(
+            base | rt.at(0..=4) | rn.at(5..=9) | Umm(12, dword_aligned_offset as u32).at(10..=21),
+        );
     }
 
     /// Load dword register with unsigned immediate offset
     pub fn ldr_imm(&mut self, rt: X, rn: X, offset: i16) {
-        asm!("ldr {}, [{}, #{}]", rt, rn, offset);
+        asm!(self, "ldr {}, [{}, #{}]", rt, rn, offset);
         // Offset is described in bytes, but must be 8-byte aligned (lower 3 bits are implied 0)
-        let dword_aligned_offset = (offset >> 3) as i32;
+        let dword_aligned_offset = scale(offset as i32, 8);
         //         size     V   opc        imm12    rn    rt
         let base = 0b11_111_0_01_01_000000000000_00000_00000;
-        self.emit(base | rt.at(0..=4) | rn.at(5..=9) | Imm(12, dword_aligned_offset).at(10..=21));
+        self.emit(
+            base | rt.at(0..=4) | rn.at(5..=9) | Umm(12, dword_aligned_offset as u32).at(10..=21),
+        );
+    }
+
+    // Load/store register (register offset)
+
+    /// The label of a 64-bit literal in the pool after the code (e.g., for
+    /// [AArch64Assembly::adr]). The literal is only emitted once, no matter how many times it's
+    /// asked for.
+    pub fn literal(&mut self, value: u64) -> Label {
+        if let Some(&(_, label)) = self.literals.iter().find(|&&(v, _)| v == value) {
+            return label;
+        }
+        let label = self.declare_label(&format!("lit{}", self.label_names.len()));
+        self.literals.push((value, label));
+        label
+    }
+
+    /// Load a 64-bit literal, from the pool after the code: xt <- value
+    pub fn ldr_literal(&mut self, xt: X, value: u64) {
+        use IncompleteInstruction::LdrLiteral;
+        let label = self.literal(value);
+        asm!(self, "ldr {}, {}", xt, self.label_name(label));
+        //         opc        V            imm19    rt
+        let base = 0b01_011_0_00_0000000000000000000_00000;
+        self.emit_incomplete_branch(label, LdrLiteral, base | xt.at(0..=4));
+    }
+
+    /// Lays out the literals that [AArch64Assembly::ldr_literal] loads, here, aligned to 8 bytes.
+    /// Called by [AArch64Assembly::patch_branch_targets], but can be called sooner, e.g., after an
+    /// unconditional branch, to keep the literals within reach of the loads (1 MiB).
+    pub fn emit_literal_pool(&mut self) {
+        if self.literals.is_empty() {
+            return;
+        }
+        if !self.instr.len().is_multiple_of(8) {
+            asm!(self, ".word 0");
+            self.emit(0);
+        }
+        for (value, label) in std::mem::take(&mut self.literals) {
+            self.bind(label);
+            asm!(self, ".quad {:#x}", value);
+            self.emit(value as u32);
+            self.emit((value >> 32) as u32);
+        }
+    }
+
+    /// Store Register Byte (register): [xn + xm] <- wt
+    pub fn strb_reg(&mut self, wt: W, xn: X, xm: X) {
+        asm!(self, "strb {}, [{}, {}]", wt, xn, xm);
+        //         size     V   opc   rm  opt S      rn    rt
+        let base = 0b00_111_0_00_00_1_00000_011_0_10_00000_00000;
+        self.emit(base | wt.at(0..=4) | xn.at(5..=9) | xm.at(16..=20));
+    }
+
+    /// Load Register Byte (register): wt <- [xn + xm]
+    pub fn ldrb_reg(&mut self, wt: W, xn: X, xm: X) {
+        asm!(self, "ldrb {}, [{}, {}]", wt, xn, xm);
+        //         size     V   opc   rm  opt S      rn    rt
+        let base = 0b00_111_0_00_01_1_00000_011_0_10_00000_00000;
+        self.emit(base | wt.at(0..=4) | xn.at(5..=9) | xm.at(16..=20));
+    }
+
+    /// Store dword register (register): [xn + (xm << 3)] <- xt when `scaled`, or else
+    /// [xn + xm] <- xt
+    pub fn str_reg(&mut self, xt: X, xn: X, xm: X, scaled: bool) {
+        if scaled {
+            asm!(self, "str {}, [{}, {}, lsl #3]", xt, xn, xm);
+        } else {
+            asm!(self, "str {}, [{}, {}]", xt, xn, xm);
+        }
+        //         size     V   opc   rm  opt S      rn    rt
+        let base = 0b11_111_0_00_00_1_00000_011_0_10_00000_00000;
+        let s = Umm(1, scaled as u32).at(12..=12);
+        self.emit(base | s | xt.at(0..=4) | xn.at(5..=9) | xm.at(16..=20));
+    }
+
+    /// Load dword register (register): xt <- [xn + (xm << 3)] when `scaled`, or else
+    /// xt <- [xn + xm]
+    pub fn ldr_reg(&mut self, xt: X, xn: X, xm: X, scaled: bool) {
+        if scaled {
+            asm!(self, "ldr {}, [{}, {}, lsl #3]", xt, xn, xm);
+        } else {
+            asm!(self, "ldr {}, [{}, {}]", xt, xn, xm);
+        }
+        //         size     V   opc   rm  opt S      rn    rt
+        let base = 0b11_111_0_00_01_1_00000_011_0_10_00000_00000;
+        let s = Umm(1, scaled as u32).at(12..=12);
+        self.emit(base | s | xt.at(0..=4) | xn.at(5..=9) | xm.at(16..=20));
     }
 
     // Load/store register pair (unsigned offset)
@@ -222,31 +663,31 @@ impl AArch64Assembly {
     /// https://developer.arm.com/documentation/dui0801/h/A64-Data-Transfer-Instructions/STP
     pub fn stp_offset(&mut self, rt: X, rt2: X, rn: X, imm: i16) {
         // https://developer.arm.com/documentation/102374/0101/Loads-and-stores---addressing
-        asm!("stp {}, {}, [{}, #{}]", rt, rt2, rn, imm);
+        asm!(self, "stp {}, {}, [{}, #{}]", rt, rt2, rn, imm);
         //          opc     V     L    imm7   rt2    rn    rt
         let base = 0b10_101_0_010_0_0000000_00000_00000_00000;
         // Offset is described in bytes, but must be 8-byte aligned (lower 3 bits are implied 0)
-        let dword_aligned_offset = (imm >> 3) as u32;
+        let dword_aligned_offset = scale(imm as i32, 8);
         self.emit(
             base | rt.at(0..=4)
                 | rn.at(5..=9)
                 | rt2.at(10..=14)
-                | Umm(7, dword_aligned_offset).at(15..=21),
+                | Imm(7, dword_aligned_offset).at(15..=21),
         );
     }
 
     /// Load pair of registers (unsigned offset)
     pub fn ldp_offset(&mut self, rt: X, rt2: X, rn: X, imm: i16) {
-        asm!("ldp {}, {}, [{}, #{}]", rt, rt2, rn, imm);
+        asm!(self, "ldp {}, {}, [{}, #{}]", rt, rt2, rn, imm);
         //          opc     V     L    imm7   rt2    rn    rt
         let base = 0b10_101_0_010_1_0000000_00000_00000_00000;
         // Offset is described in bytes, but must be 8-byte aligned (lower 3 bits are implied 0)
-        let dword_aligned_offset = (imm >> 3) as u32;
+        let dword_aligned_offset = scale(imm as i32, 8);
         self.emit(
             base | rt.at(0..=4)
                 | rn.at(5..=9)
                 | rt2.at(10..=14)
-                | Umm(7, dword_aligned_offset).at(15..=21),
+                | Imm(7, dword_aligned_offset).at(15..=21),
         );
     }
 
@@ -256,11 +697,11 @@ impl AArch64Assembly {
 
     /// Load pair of registers (post-index)
     pub fn ldp_postindex(&mut self, rt1: X, rt2: X, rn: X, imm: i16) {
-        asm!("ldp {}, {}, [{}], #{}", rt1, rt2, rn, imm);
+        asm!(self, "ldp {}, {}, [{}], #{}", rt1, rt2, rn, imm);
         //          opc     V     L    imm7   rt2    rn    rt
         let base = 0b10_101_0_001_1_0000000_00000_00000_00000;
         // Offset is described in bytes, but must be 8-byte aligned (lower 3 bits are implied 0)
-        let dword_aligned_offset = (imm >> 3) as i32;
+        let dword_aligned_offset = scale(imm as i32, 8);
         self.emit(
             base | rt1.at(0..=4)
                 | rn.at(5..=9)
@@ -275,11 +716,11 @@ impl AArch64Assembly {
     /// Store Pair of registers (pre-indexed)
     pub fn stp_preindex(&mut self, rt1: X, rt2: X, rn: X, imm: i16) {
         // https://developer.arm.com/documentation/102374/0101/Loads-and-stores---addressing
-        asm!("stp {}, {}, [{}, #{}]!", rt1, rt2, rn, imm);
+        asm!(self, "stp {}, {}, [{}, #{}]!", rt1, rt2, rn, imm);
         //          opc     V     L    imm7   rt2    rn    rt
         let base = 0b10_101_0_011_0_0000000_00000_00000_00000;
         // Offset is described in bytes, but must be 8-byte aligned (lower 3 bits are implied 0)
-        let dword_aligned_offset = (imm >> 3) as i32;
+        let dword_aligned_offset = scale(imm as i32, 8);
         self.emit(
             base | rt1.at(0..=4)
                 | rn.at(5..=9)
@@ -291,22 +732,42 @@ impl AArch64Assembly {
     // Data processing -- immediate ///////////////////////////////////////////////////////////////
 
     pub fn add(&mut self, wd: W, wn: W, imm: u16) {
-        asm!("add {}, {}, {}", wd, wn, imm);
+        asm!(self, "add {}, {}, #{}", wd, wn, imm);
         //          sfop S       <<        imm12 Rn    Rd
         let base = 0b0_0_0_10001_00_000000000000_00000_00000;
-        self.emit(base | Imm(12, imm as i32).at(10..=21) | wn.at(5..=9) | wd.at(0..=4));
+        self.emit(base | Umm(12, imm as u32).at(10..=21) | wn.at(5..=9) | wd.at(0..=4));
     }
 
     pub fn add64(&mut self, xd: X, xn: X, imm: u16) {
-        asm!("add {}, {}, {}", xd, xn, imm);
+        if self.is_no_op(xd, xn, imm) {
+            return;
+        }
+        asm!(self, "add {}, {}, #{}", xd, xn, imm);
         //          sfop S       <<        imm12 Rn    Rd
         let base = 0b1_0_0_10001_00_000000000000_00000_00000;
-        self.emit(base | Imm(12, imm as i32).at(10..=21) | xn.at(5..=9) | xd.at(0..=4));
+        self.emit(base | Umm(12, imm as u32).at(10..=21) | xn.at(5..=9) | xd.at(0..=4));
+    }
+
+    /// Add (immediate, shifted left by 12 bits): xd <- xn + (imm << 12)
+    pub fn add64_lsl12(&mut self, xd: X, xn: X, imm: u16) {
+        asm!(self, "add {}, {}, #{}, lsl #12", xd, xn, imm);
+        //          sfop S       <<        imm12 Rn    Rd
+        let base = 0b1_0_0_10001_01_000000000000_00000_00000;
+        self.emit(base | Umm(12, imm as u32).at(10..=21) | xn.at(5..=9) | xd.at(0..=4));
+    }
+
+    /// Compare (immediate): sets the flags on wn - imm (shh! this is secretly SUBS to wzr)
+    /// https://developer.arm.com/documentation/dui0802/a/A64-General-Instructions/CMP--immediate-
+    pub fn cmp(&mut self, wn: W, imm: u16) {
+        asm!(self, "cmp {}, #{}", wn, imm);
+        //          sfop S       <<        imm12 Rn    Rd
+        let base = 0b0_1_1_10001_00_000000000000_00000_00000;
+        self.emit(base | Umm(12, imm as u32).at(10..=21) | wn.at(5..=9) | W(31).at(0..=4));
     }
 
     /// Move register (shh! this is secretly ORR)
     pub fn mov(&mut self, rd: X, rm: X) {
-        asm!("mov {0}, {1} ; orr {0}, x31, {1}", rd, rm);
+        asm!(self, "mov {}, {}", rd, rm);
         //          sf op       << N    rm   imm6    rn    rd
         let base = 0b1_01_01010_00_0_00000_000000_00000_00000;
         self.emit(base | rm.at(16..=20) | X(31).at(5..=9) | rd.at(0..=4));
@@ -315,21 +776,280 @@ impl AArch64Assembly {
     /// Subract (immediate)
     /// https://developer.arm.com/documentation/100076/0100/a64-instruction-set-reference/a64-general-instructions/sub--immediate-?lang=en
     pub fn sub(&mut self, wd: W, wn: W, imm: u16) {
-        asm!("sub {}, {}, #{}", wd, wn, imm);
+        asm!(self, "sub {}, {}, #{}", wd, wn, imm);
         //          sfop S       <<        imm12 Rn    Rd
         let base = 0b0_1_0_10001_00_000000000000_00000_00000;
-        self.emit(base | Imm(12, imm as i32).at(10..=21) | wn.at(5..=9) | wd.at(0..=4));
+        self.emit(base | Umm(12, imm as u32).at(10..=21) | wn.at(5..=9) | wd.at(0..=4));
     }
 
     pub fn sub64(&mut self, xd: X, xn: X, imm: u16) {
-        asm!("sub {}, {}, #{}", xd, xn, imm);
+        if self.is_no_op(xd, xn, imm) {
+            return;
+        }
+        asm!(self, "sub {}, {}, #{}", xd, xn, imm);
         //          sfop S       <<        imm12 Rn    Rd
         let base = 0b1_1_0_10001_00_000000000000_00000_00000;
-        self.emit(base | Imm(12, imm as i32).at(10..=21) | xn.at(5..=9) | xd.at(0..=4));
+        self.emit(base | Umm(12, imm as u32).at(10..=21) | xn.at(5..=9) | xd.at(0..=4));
+    }
+
+    /// Subtract (immediate, shifted left by 12 bits): xd <- xn - (imm << 12)
+    pub fn sub64_lsl12(&mut self, xd: X, xn: X, imm: u16) {
+        asm!(self, "sub {}, {}, #{}, lsl #12", xd, xn, imm);
+        //          sfop S       <<        imm12 Rn    Rd
+        let base = 0b1_1_0_10001_01_000000000000_00000_00000;
+        self.emit(base | Umm(12, imm as u32).at(10..=21) | xn.at(5..=9) | xd.at(0..=4));
+    }
+
+    /// Move wide with zero (32-bit)
+    /// https://developer.arm.com/documentation/dui0802/a/A64-General-Instructions/MOVZ
+    pub fn movz(&mut self, wd: W, imm: u16) {
+        asm!(self, "movz {}, #{}", wd, imm);
+        //          sf opc        hw             imm16    rd
+        let base = 0b0_10_100101_00_0000000000000000_00000;
+        self.emit(base | Umm(16, imm as u32).at(5..=20) | wd.at(0..=4));
+    }
+
+    /// Move wide with zero (64-bit): xd <- imm << (16 * hw)
+    pub fn movz64(&mut self, xd: X, imm: u16, hw: u8) {
+        asm!(self, "movz {}, #{}, lsl #{}", xd, imm, 16 * hw);
+        //          sf opc        hw             imm16    rd
+        let base = 0b1_10_100101_00_0000000000000000_00000;
+        self.emit(
+            base | Umm(2, hw as u32).at(21..=22) | Umm(16, imm as u32).at(5..=20) | xd.at(0..=4),
+        );
+    }
+
+    /// Move wide with keep (64-bit): replaces bits `16 * hw` and up of xd with imm
+    /// https://developer.arm.com/documentation/dui0802/a/A64-General-Instructions/MOVK
+    pub fn movk64(&mut self, xd: X, imm: u16, hw: u8) {
+        asm!(self, "movk {}, #{}, lsl #{}", xd, imm, 16 * hw);
+        //          sf opc        hw             imm16    rd
+        let base = 0b1_11_100101_00_0000000000000000_00000;
+        self.emit(
+            base | Umm(2, hw as u32).at(21..=22) | Umm(16, imm as u32).at(5..=20) | xd.at(0..=4),
+        );
+    }
+
+    /// Move wide with NOT (64-bit): xd <- !(imm << (16 * hw))
+    /// https://developer.arm.com/documentation/dui0802/a/A64-General-Instructions/MOVN
+    pub fn movn64(&mut self, xd: X, imm: u16, hw: u8) {
+        asm!(self, "movn {}, #{}, lsl #{}", xd, imm, 16 * hw);
+        //          sf opc        hw             imm16    rd
+        let base = 0b1_00_100101_00_0000000000000000_00000;
+        self.emit(
+            base | Umm(2, hw as u32).at(21..=22) | Umm(16, imm as u32).at(5..=20) | xd.at(0..=4),
+        );
+    }
+
+    /// Loads any 64-bit value into xd, in as few instructions as it takes: a `movz` (or a `movn`,
+    /// when most of the value is ones), then a `movk` for each 16 bits that are left.
+    pub fn load_imm64(&mut self, xd: X, value: u64) {
+        let halves = (0..4u8).map(|hw| (hw, (value >> (16 * hw)) as u16));
+        let zeros = halves.clone().filter(|&(_, half)| half == 0).count();
+        let ones = halves.clone().filter(|&(_, half)| half == 0xFFFF).count();
+        // Whatever the first instruction sets every other half to doesn't need a movk.
+        let filler = if ones > zeros { 0xFFFF } else { 0 };
+
+        let mut rest = halves.filter(|&(_, half)| half != filler).peekable();
+        let (hw, half) = rest.peek().copied().unwrap_or((0, filler));
+        if filler == 0 {
+            self.movz64(xd, half, hw);
+        } else {
+            self.movn64(xd, !half, hw);
+        }
+        for (hw, half) in rest.skip(1) {
+            self.movk64(xd, half, hw);
+        }
+    }
+
+    /// Loads the symbol's address into xd, which is filled in by a
+    /// [RelocationKind::AbsoluteMovWide]. Always four instructions, since the address could be
+    /// anything.
+    pub fn load_symbol_address(&mut self, xd: X, symbol: &str) {
+        self.comment(format_args!("{} <- &{}", xd, symbol));
+        self.relocate(RelocationKind::AbsoluteMovWide, symbol);
+        self.movz64(xd, 0, 0);
+        for hw in 1..4 {
+            self.movk64(xd, 0, hw);
+        }
+    }
+
+    /// Form PC-relative address: xd <- the address of the label, at most 1 MiB away
+    pub fn adr(&mut self, xd: X, label: Label) {
+        use IncompleteInstruction::Adr;
+        asm!(self, "adr {}, {}", xd, self.label_name(label));
+        //          op immlo        immhi              rd
+        let base = 0b0_00_10000_0000000000000000000_00000;
+        self.emit_incomplete_branch(label, Adr, base | xd.at(0..=4));
+    }
+
+    /// `adr` has a 21-bit byte offset, split into its low 2 bits and the rest.
+    fn patch_adr(offset: WordOffset) -> u32 {
+        let WordOffset(words) = offset;
+        // Labels are word aligned, so immlo is always zero.
+        Imm(19, words).at(5..=23)
+    }
+
+    /// Form the address of a label at most 4 GiB away, with an `adrp` of its 4 KiB page, then an
+    /// `add` of where in the page it is: xd <- the address of the label
+    ///
+    /// This assumes that the code starts on a page boundary, which it does when it runs from its
+    /// own mapping.
+    pub fn adrp_add(&mut self, xd: X, label: Label) {
+        use IncompleteInstruction::{AddLo12, Adrp};
+        let name = self.label_name(label);
+        asm!(self, "adrp {}, {}", xd, name);
+        //          op immlo        immhi              rd
+        let base = 0b1_00_10000_0000000000000000000_00000;
+        self.emit_incomplete_branch(label, Adrp, base | xd.at(0..=4));
+        asm!(self, "add {}, {}, :lo12:{}", xd, xd, name);
+        //          sfop S       <<        imm12 Rn    Rd
+        let base = 0b1_0_0_10001_00_000000000000_00000_00000;
+        self.emit_incomplete_branch(label, AddLo12, base | xd.at(5..=9) | xd.at(0..=4));
+    }
+
+    /// `adrp` has a 21-bit offset in pages, split into its low 2 bits and the rest.
+    fn patch_adrp(source: WordOffset, target: WordOffset) -> u32 {
+        let page = |offset: WordOffset| (offset.to_usize() >> 12) as i32;
+        let pages = Imm(21, page(target) - page(source)).to_u32();
+        Umm(2, pages & 0b11).at(29..=30) | Umm(19, pages >> 2).at(5..=23)
+    }
+
+    /// The `add` after an `adrp` adds the label's offset into its page.
+    fn patch_lo12(target: WordOffset) -> u32 {
+        Umm(12, (target.to_usize() & 0xFFF) as u32).at(10..=21)
+    }
+
+    /// Unsigned Extend Byte: wd <- the low byte of wn (shh! this is secretly UBFM)
+    pub fn uxtb(&mut self, wd: W, wn: W) {
+        asm!(self, "uxtb {}, {}", wd, wn);
+        //          sfopc        N immr   imms   rn    rd
+        let base = 0b0_10_100110_0_000000_000111_00000_00000;
+        self.emit(base | wn.at(5..=9) | wd.at(0..=4));
+    }
+
+    /// And with a mask of the low `bits` bits (64-bit): xd <- xn & ((1 << bits) - 1)
+    pub fn and64_low_bits(&mut self, xd: X, xn: X, bits: u8) {
+        assert!(
+            (1..64).contains(&bits),
+            "cannot encode a mask of {} bits",
+            bits
+        );
+        asm!(self, "and {}, {}, #{:#x}", xd, xn, (1u64 << bits) - 1);
+        // A run of `bits` ones, not rotated, in a 64-bit element.
+        //          sfopc        N immr   imms   rn    rd
+        let base = 0b1_00_100100_1_000000_000000_00000_00000;
+        self.emit(base | Umm(6, bits as u32 - 1).at(10..=15) | xn.at(5..=9) | xd.at(0..=4));
+    }
+
+    // Data processing -- register ////////////////////////////////////////////////////////////////
+
+    /// Add (register, 64-bit): xd <- xn + xm
+    pub fn add64_reg(&mut self, xd: X, xn: X, xm: X) {
+        asm!(self, "add {}, {}, {}", xd, xn, xm);
+        //          sfop S       sh   rm   imm6    rn    rd
+        let base = 0b1_0_0_01011_00_0_00000_000000_00000_00000;
+        self.emit(base | xm.at(16..=20) | xn.at(5..=9) | xd.at(0..=4));
+    }
+
+    /// Subtract (register, 64-bit): xd <- xn - xm
+    pub fn sub64_reg(&mut self, xd: X, xn: X, xm: X) {
+        asm!(self, "sub {}, {}, {}", xd, xn, xm);
+        //          sfop S       sh   rm   imm6    rn    rd
+        let base = 0b1_1_0_01011_00_0_00000_000000_00000_00000;
+        self.emit(base | xm.at(16..=20) | xn.at(5..=9) | xd.at(0..=4));
+    }
+
+    /// Multiply-add (32-bit): wd <- wa + wn * wm
+    /// https://developer.arm.com/documentation/dui0802/a/A64-General-Instructions/MADD
+    pub fn madd(&mut self, wd: W, wn: W, wm: W, wa: W) {
+        asm!(self, "madd {}, {}, {}, {}", wd, wn, wm, wa);
+        //          sf       op31    rm o0   ra    rn    rd
+        let base = 0b0_00_11011_000_00000_0_00000_00000_00000;
+        self.emit(base | wm.at(16..=20) | wa.at(10..=14) | wn.at(5..=9) | wd.at(0..=4));
+    }
+
+    /// Multiply-subtract (32-bit): wd <- wa - wn * wm
+    /// https://developer.arm.com/documentation/dui0802/a/A64-General-Instructions/MSUB
+    pub fn msub(&mut self, wd: W, wn: W, wm: W, wa: W) {
+        asm!(self, "msub {}, {}, {}, {}", wd, wn, wm, wa);
+        //          sf       op31    rm o0   ra    rn    rd
+        let base = 0b0_00_11011_000_00000_1_00000_00000_00000;
+        self.emit(base | wm.at(16..=20) | wa.at(10..=14) | wn.at(5..=9) | wd.at(0..=4));
+    }
+
+    /// Multiply (32-bit): wd <- wn * wm (shh! this is secretly MADD, adding wzr)
+    /// https://developer.arm.com/documentation/dui0802/a/A64-General-Instructions/MUL
+    pub fn mul(&mut self, wd: W, wn: W, wm: W) {
+        asm!(self, "mul {}, {}, {}", wd, wn, wm);
+        //          sf       op31    rm o0   ra    rn    rd
+        let base = 0b0_00_11011_000_00000_0_11111_00000_00000;
+        self.emit(base | wm.at(16..=20) | wn.at(5..=9) | wd.at(0..=4));
+    }
+
+    // Advanced SIMD //////////////////////////////////////////////////////////////////////////////////
+    //
+    // Only the 16B arrangement: sixteen cells at a time.
+
+    /// Load one 16-byte vector (no offset): vt <- [xn .. xn + 16]
+    pub fn ld1(&mut self, vt: V, xn: X) {
+        asm!(self, "ld1 {{{}.16b}}, [{}]", vt, xn);
+        //           Q          L      opcodesize    rn    rt
+        let base = 0b0_1_0011000_1_000000_0111_00_00000_00000;
+        self.emit(base | xn.at(5..=9) | vt.at(0..=4));
+    }
+
+    /// Store one 16-byte vector (no offset): [xn .. xn + 16] <- vt
+    pub fn st1(&mut self, vt: V, xn: X) {
+        asm!(self, "st1 {{{}.16b}}, [{}]", vt, xn);
+        //           Q          L      opcodesize    rn    rt
+        let base = 0b0_1_0011000_0_000000_0111_00_00000_00000;
+        self.emit(base | xn.at(5..=9) | vt.at(0..=4));
+    }
+
+    /// Duplicate the low byte of wn into every byte of vd
+    pub fn dup(&mut self, vd: V, wn: W) {
+        asm!(self, "dup {}.16b, {}", vd, wn);
+        //           Q op          imm5    imm4     rn    rd
+        let base = 0b0_1_0_01110000_00001_0_0001_1_00000_00000;
+        self.emit(base | wn.at(5..=9) | vd.at(0..=4));
+    }
+
+    /// Add each byte (wrapping): vd <- vn + vm
+    pub fn add_16b(&mut self, vd: V, vn: V, vm: V) {
+        asm!(self, "add {}.16b, {}.16b, {}.16b", vd, vn, vm);
+        //           Q U       size   rm  opcode     rn    rd
+        let base = 0b0_1_0_01110_00_1_00000_10000_1_00000_00000;
+        self.emit(base | vm.at(16..=20) | vn.at(5..=9) | vd.at(0..=4));
+    }
+
+    /// Compare each byte for equality: each byte of vd <- 0xff if vn == vm, otherwise 0
+    pub fn cmeq(&mut self, vd: V, vn: V, vm: V) {
+        asm!(self, "cmeq {}.16b, {}.16b, {}.16b", vd, vn, vm);
+        //           Q U       size   rm  opcode     rn    rd
+        let base = 0b0_1_1_01110_00_1_00000_10001_1_00000_00000;
+        self.emit(base | vm.at(16..=20) | vn.at(5..=9) | vd.at(0..=4));
+    }
+
+    /// Compare each byte with zero: each byte of vd <- 0xff if vn == 0, otherwise 0
+    pub fn cmeq_zero(&mut self, vd: V, vn: V) {
+        asm!(self, "cmeq {}.16b, {}.16b, #0", vd, vn);
+        //           Q U       size          opcode       rn    rd
+        let base = 0b0_1_0_01110_00_10000_01001_10_00000_00000;
+        self.emit(base | vn.at(5..=9) | vd.at(0..=4));
     }
 
     // Private methods ////////////////////////////////////////////////////////////////////////////
 
+    /// Records a relocation for the next instruction.
+    fn relocate(&mut self, kind: RelocationKind, symbol: &str) {
+        self.relocations.push(Relocation {
+            offset: self.instr.len(),
+            kind,
+            symbol: symbol.to_owned(),
+        });
+    }
+
     fn emit(&mut self, instruction: u32) {
         let arr = instruction.to_le_bytes();
         self.instr.extend_from_slice(&arr);
@@ -388,8 +1108,22 @@ trait BitPack: Copy {
     }
 }
 
+/// Checks that a byte offset is a multiple of how many bytes the instruction scales it by, and
+/// returns the scaled offset.
+fn scale(offset: i32, bytes: i32) -> i32 {
+    assert_eq!(
+        0,
+        offset % bytes,
+        "offset {} is not a multiple of {}",
+        offset,
+        bytes
+    );
+    offset / bytes
+}
+
 impl BitPack for X {
     fn to_u32(self) -> u32 {
+        assert!(self.0 < 32, "there is no register {}", self);
         self.0 as u32
     }
     fn expected_size(self) -> u8 {
@@ -399,6 +1133,17 @@ impl BitPack for X {
 
 impl BitPack for W {
     fn to_u32(self) -> u32 {
+        assert!(self.0 < 32, "there is no register {}", self);
+        self.0 as u32
+    }
+    fn expected_size(self) -> u8 {
+        5
+    }
+}
+
+impl BitPack for V {
+    fn to_u32(self) -> u32 {
+        assert!(self.0 < 32, "there is no register {}", self);
         self.0 as u32
     }
     fn expected_size(self) -> u8 {
@@ -408,10 +1153,17 @@ impl BitPack for W {
 
 impl BitPack for Imm {
     fn to_u32(self) -> u32 {
-        let raw_bits = self.1 as u32;
+        let Imm(bits, value) = self;
+        let limit = 1i64 << (bits - 1);
+        assert!(
+            (-limit..limit).contains(&(value as i64)),
+            "{} does not fit in a {}-bit signed immediate",
+            value,
+            bits
+        );
         // Keep only the bits that contribute to the immediate value:
         let mask = 2u32.pow(self.expected_size() as u32) - 1;
-        mask & raw_bits
+        mask & value as u32
     }
     fn expected_size(self) -> u8 {
         self.0
@@ -420,7 +1172,14 @@ impl BitPack for Imm {
 
 impl BitPack for Umm {
     fn to_u32(self) -> u32 {
-        self.1 as u32
+        let Umm(bits, value) = self;
+        assert!(
+            (value as u64) < 1u64 << bits,
+            "{} does not fit in a {}-bit unsigned immediate",
+            value,
+            bits
+        );
+        value
     }
     fn expected_size(self) -> u8 {
         self.0
@@ -429,7 +1188,11 @@ impl BitPack for Umm {
 
 impl fmt::Display for W {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "w{}", self.0)
+        if self.0 == 31 {
+            write!(f, "wzr")
+        } else {
+            write!(f, "w{}", self.0)
+        }
     }
 }
 
@@ -443,8 +1206,454 @@ impl fmt::Display for X {
     }
 }
 
+impl fmt::Display for V {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "v{}", self.0)
+    }
+}
+
+impl fmt::Display for Cond {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Cond::Eq => "eq",
+            Cond::Ne => "ne",
+            Cond::Hs => "hs",
+            Cond::Lo => "lo",
+            Cond::Hi => "hi",
+            Cond::Ls => "ls",
+            Cond::Ge => "ge",
+            Cond::Lt => "lt",
+            Cond::Gt => "gt",
+            Cond::Le => "le",
+        };
+        write!(f, "{}", name)
+    }
+}
+
 impl fmt::Display for Label {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "L{}", self.0)
     }
 }
+
+impl fmt::Display for UnresolvedLabels {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "branches refer to labels that were never bound:")?;
+        for (name, sites) in &self.0 {
+            write!(f, "\n    {} (from", name)?;
+            for site in sites {
+                write!(f, " {:#06x}", site)?;
+            }
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for UnresolvedLabels {}
+
+impl Relocation {
+    /// Fixes up the instructions, now that the machine code is at `code_address`, and the symbol
+    /// is at `symbol_address`.
+    ///
+    /// # Panics
+    ///
+    /// When a [RelocationKind::Call26] can't reach the symbol.
+    pub fn apply(&self, code: &mut [u8], code_address: u64, symbol_address: u64) {
+        #![allow(clippy::unusual_byte_groupings)]
+        let mut fix_up = |index: usize, bits: u32| {
+            let at = self.offset + 4 * index;
+            let word = u32::from_le_bytes(code[at..at + 4].try_into().unwrap());
+            code[at..at + 4].copy_from_slice(&(word | bits).to_le_bytes());
+        };
+
+        match self.kind {
+            RelocationKind::AbsoluteMovWide => {
+                for hw in 0..4 {
+                    let half = (symbol_address >> (16 * hw)) as u32 & 0xFFFF;
+                    fix_up(hw, Umm(16, half).at(5..=20));
+                }
+            }
+            RelocationKind::Call26 => {
+                let from = code_address + self.offset as u64;
+                let offset = symbol_address.wrapping_sub(from) as i64;
+                assert!(
+                    offset % 4 == 0 && (-(1 << 27)..(1 << 27)).contains(&offset),
+                    "{} is out of reach of the bl at {:#x}",
+                    self.symbol,
+                    from
+                );
+                fix_up(0, Imm(26, (offset / 4) as i32).at(0..=25));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Assembles the instructions, and returns the machine code as 32-bit words.
+    fn assemble(f: impl FnOnce(&mut AArch64Assembly)) -> Vec<u32> {
+        let mut asm = AArch64Assembly::new();
+        f(&mut asm);
+        asm.patch_branch_targets();
+        asm.machine_code()
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn lists_the_offset_and_encoding_of_each_instruction() {
+        let mut asm = AArch64Assembly::new().with_listing();
+        asm.comment("loop");
+        asm.bind(Label(0));
+        asm.sub(W(0), W(0), 1);
+        asm.cbnz(W(0), Label(0));
+        asm.patch_branch_targets();
+
+        assert_eq!(
+            "    // loop\n\
+             L0:\n    \
+             0000:  51000400    sub w0, w0, #1\n    \
+             0004:  35ffffe0    cbnz w0, L0\n",
+            asm.annotated_listing().unwrap()
+        );
+        // Without the offsets and encodings, it's ready to assemble:
+        assert_eq!(
+            "    // loop\nL0:\n    sub w0, w0, #1\n    cbnz w0, L0\n",
+            asm.listing().unwrap()
+        );
+    }
+
+    #[test]
+    fn leaves_out_what_obviously_does_nothing() {
+        let mut asm = AArch64Assembly::new().with_peephole().with_listing();
+        let (top, next, done) = (Label(0), Label(1), Label(2));
+        asm.bind(top);
+        asm.add(W(0), W(0), 1);
+        asm.strb(W(0), X(19), 0);
+        asm.ldrb(W(0), X(19), 0);
+        asm.cbz(W(0), done);
+        asm.add64(X(19), X(19), 0);
+        asm.sub64(X(9), X(19), 0);
+        asm.b(next);
+        asm.bind(next);
+        // Code that branches here didn't store anything first:
+        asm.strb(W(31), X(19), 1);
+        asm.bind(done);
+        asm.ldrb(W(10), X(19), 1);
+        asm.b(top);
+        asm.patch_branch_targets();
+
+        assert_eq!(
+            "L0:\n    \
+             0000:  11000400    add w0, w0, #1\n    \
+             0004:  39000260    strb w0, [x19, #0]\n    \
+             0008:  53001c00    uxtb w0, w0\n    \
+             000c:  34000060    cbz w0, L2\n    \
+             0010:  d1000269    sub x9, x19, #0\n\
+             L1:\n    \
+             0014:  3900067f    strb wzr, [x19, #1]\n\
+             L2:\n    \
+             0018:  3940066a    ldrb w10, [x19, #1]\n    \
+             001c:  17fffff9    b L0\n",
+            asm.annotated_listing().unwrap()
+        );
+    }
+
+    #[test]
+    fn names_labels_in_the_listing() {
+        let mut asm = AArch64Assembly::new().with_listing();
+        let (top, done) = (asm.declare_label("top"), asm.declare_label("done"));
+        asm.bind(top);
+        asm.cbz(W(0), done);
+        asm.b(top);
+        asm.bind(done);
+        asm.patch_branch_targets();
+
+        assert_eq!(
+            "top:\n    cbz w0, done\n    b top\ndone:\n",
+            asm.listing().unwrap()
+        );
+        assert_eq!(Ok(()), asm.check_labels());
+    }
+
+    #[test]
+    fn says_which_labels_are_missing() {
+        let mut asm = AArch64Assembly::new();
+        let done = asm.declare_label("done");
+        asm.cbz(W(0), done);
+        asm.b(Label(3));
+        asm.b(done);
+        asm.patch_branch_targets();
+
+        let unresolved = asm.check_labels().unwrap_err();
+        assert_eq!(
+            UnresolvedLabels(vec![
+                ("done".to_owned(), vec![0, 8]),
+                ("L3".to_owned(), vec![4])
+            ]),
+            unresolved
+        );
+        assert_eq!(
+            "branches refer to labels that were never bound:\n    \
+             done (from 0x0000 0x0008)\n    \
+             L3 (from 0x0004)",
+            unresolved.to_string()
+        );
+
+        // Binding them later still works:
+        asm.bind(done);
+        asm.bind(Label(3));
+        asm.patch_branch_targets();
+        assert_eq!(Ok(()), asm.check_labels());
+    }
+
+    #[test]
+    #[should_panic(expected = "label top is bound twice")]
+    fn binds_each_label_once() {
+        let mut asm = AArch64Assembly::new();
+        let top = asm.declare_label("top");
+        asm.bind(top);
+        asm.bind(top);
+    }
+
+    #[test]
+    fn records_relocations_for_symbols() {
+        let mut asm = AArch64Assembly::new();
+        asm.mov(X(0), X(19));
+        asm.load_symbol_address(X(9), "putchar");
+        asm.bl_symbol("getchar");
+        assert_eq!(
+            vec![
+                Relocation {
+                    offset: 4,
+                    kind: RelocationKind::AbsoluteMovWide,
+                    symbol: "putchar".to_owned()
+                },
+                Relocation {
+                    offset: 20,
+                    kind: RelocationKind::Call26,
+                    symbol: "getchar".to_owned()
+                },
+            ],
+            asm.relocations()
+        );
+
+        let mut code = asm.machine_code().to_vec();
+        let relocations = asm.relocations();
+        relocations[0].apply(&mut code, 0x1000, 0x1234_5678_9abc_def0);
+        relocations[1].apply(&mut code, 0x1000, 0x1000 + 20 - 8);
+        let words: Vec<_> = code
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+        // movz x9, #0xdef0 ; movk x9, #0x9abc, lsl #16 ; movk x9, #0x5678, lsl #32 ;
+        // movk x9, #0x1234, lsl #48 ; bl .-8
+        assert_eq!(
+            [0xd29bde09, 0xf2b35789, 0xf2cacf09, 0xf2e24689, 0x97fffffe],
+            words[1..]
+        );
+    }
+
+    #[test]
+    fn operates_on_sixteen_bytes_at_a_time() {
+        let code = assemble(|a| {
+            a.ld1(V(0), X(19));
+            a.st1(V(1), X(9));
+            a.dup(V(2), W(0));
+            a.add_16b(V(3), V(1), V(2));
+            a.cmeq(V(4), V(5), V(6));
+            a.cmeq_zero(V(7), V(8));
+        });
+        assert_eq!(
+            vec![0x4c407260, 0x4c007121, 0x4e010c02, 0x4e228423, 0x6e268ca4, 0x4e209907],
+            code
+        );
+    }
+
+    #[test]
+    fn loads_literals_from_a_pool_after_the_code() {
+        let mut asm = AArch64Assembly::new().with_listing();
+        asm.ldr_literal(X(9), 0x1234_5678_9abc_def0);
+        asm.ldr_literal(X(10), 42);
+        asm.ldr_literal(X(11), 0x1234_5678_9abc_def0);
+        asm.ret();
+        asm.patch_branch_targets();
+
+        assert_eq!(
+            "    ldr x9, lit0\n    \
+             ldr x10, lit1\n    \
+             ldr x11, lit0\n    \
+             ret x30\n\
+             lit0:\n    \
+             .quad 0x123456789abcdef0\n\
+             lit1:\n    \
+             .quad 0x2a\n",
+            asm.listing().unwrap()
+        );
+        // ldr x9, #16 ; ldr x10, #20 ; ldr x11, #8 ; ret ; (the literals, aligned to 8 bytes)
+        let words: Vec<_> = asm
+            .machine_code()
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+        assert_eq!(
+            vec![0x58000089, 0x580000aa, 0x5800004b, 0xd65f03c0, 0x9abcdef0, 0x12345678, 42, 0],
+            words
+        );
+    }
+
+    #[test]
+    fn forms_the_addresses_of_labels() {
+        let mut asm = AArch64Assembly::new().with_listing();
+        let literal = asm.literal(42);
+        let here = asm.declare_label("here");
+        asm.bind(here);
+        asm.adr(X(9), literal);
+        asm.adr(X(0), here);
+        let far = asm.declare_label("far");
+        asm.adrp_add(X(10), far);
+        // Far enough away that it's on the next page:
+        for _ in 0..1024 {
+            asm.ret();
+        }
+        asm.bind(far);
+        asm.ret();
+        asm.patch_branch_targets();
+
+        let code = asm.machine_code();
+        let word = |i: usize| u32::from_le_bytes(code[4 * i..4 * i + 4].try_into().unwrap());
+        // adr x9, #4120 ; adr x0, #-4
+        assert_eq!(0x1000_80c9, word(0));
+        assert_eq!(0x10ff_ffe0, word(1));
+        // adrp x10, #4096 ; add x10, x10, #0x10 (far is 0x1010 bytes in)
+        assert_eq!(0xb000_000a, word(2));
+        assert_eq!(0x9100_414a, word(3));
+
+        let listing = asm.listing().unwrap();
+        assert!(listing.contains("    adr x9, lit0\n    adr x0, here\n"));
+        assert!(listing.contains("    adrp x10, far\n    add x10, x10, :lo12:far\n"));
+    }
+
+    #[test]
+    fn addresses_bytes_at_any_offset() {
+        let code = assemble(|a| {
+            a.strb(W(0), X(19), 5);
+            a.ldrb(W(10), X(9), 4095);
+            a.sturb(W(0), X(19), -1);
+            a.ldurb(W(10), X(9), -256);
+            a.ldurb(W(1), X(2), 255);
+        });
+        assert_eq!(
+            vec![0x39001660, 0x397ffd2a, 0x381ff260, 0x3850012a, 0x384ff041],
+            code
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "-257 does not fit in a 9-bit signed immediate")]
+    fn refuses_unscaled_offsets_that_do_not_fit() {
+        assemble(|a| a.ldurb(W(0), X(19), -257));
+    }
+
+    #[test]
+    fn branches_on_conditions() {
+        let code = assemble(|a| {
+            a.bind(Label(0));
+            a.cmp(W(0), 10);
+            a.b_cond(Cond::Eq, Label(1));
+            a.b_cond(Cond::Ne, Label(0));
+            a.bind(Label(1));
+        });
+        // cmp w0, #10 ; b.eq +8 ; b.ne -8
+        assert_eq!(vec![0x7100281f, 0x54000040, 0x54ffffc1], code);
+
+        // b.hs, b.lt, and b.le right here
+        let code = assemble(|a| {
+            a.bind(Label(0));
+            for cond in [Cond::Hs, Cond::Lt, Cond::Le] {
+                a.b_cond(cond, Label(0));
+            }
+        });
+        assert_eq!(vec![0x54000002, 0x54ffffeb, 0x54ffffcd], code);
+    }
+
+    #[test]
+    fn compares_and_branches_both_ways() {
+        let code = assemble(|a| {
+            a.bind(Label(0));
+            a.cbz(W(0), Label(1));
+            a.cbnz(W(10), Label(0));
+            a.bind(Label(1));
+        });
+        // cbz w0, +8 ; cbnz w10, -4
+        assert_eq!(vec![0x34000040, 0x35ffffea], code);
+    }
+
+    #[test]
+    fn loads_immediates_in_as_few_instructions_as_it_takes() {
+        let load = |value| assemble(|a| a.load_imm64(X(0), value));
+
+        // movz x0, #0
+        assert_eq!(vec![0xd2800000], load(0));
+        // movz x0, #0x4000, lsl #16
+        assert_eq!(vec![0xd2a80000], load(0x4000_0000));
+        // movn x0, #0
+        assert_eq!(vec![0x92800000], load(u64::MAX));
+        // movn x0, #0x1234, lsl #16
+        assert_eq!(vec![0x92a24680], load(!0x1234_0000));
+        // movz x0, #0x5678 ; movk x0, #0xbeef, lsl #48
+        assert_eq!(vec![0xd28acf00, 0xf2f7dde0], load(0xbeef_0000_0000_5678));
+        // movn x0, #0xedcb ; movk x0, #1, lsl #16
+        assert_eq!(vec![0x929db960, 0xf2a00020], load(0xffff_ffff_0001_1234));
+        assert_eq!(4, load(0x1122_3344_5566_7788).len());
+    }
+
+    #[test]
+    fn addresses_memory_with_a_register_offset() {
+        let code = assemble(|a| {
+            a.ldrb_reg(W(0), X(9), X(13));
+            a.strb_reg(W(0), X(9), X(13));
+            a.ldr_reg(X(10), X(9), X(12), false);
+            a.ldr_reg(X(10), X(9), X(12), true);
+            a.str_reg(X(10), X(9), X(12), true);
+            a.str_reg(X(10), X(9), X(12), false);
+        });
+        assert_eq!(
+            vec![0x386d6920, 0x382d6920, 0xf86c692a, 0xf86c792a, 0xf82c792a, 0xf82c692a],
+            code
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "4096 does not fit in a 12-bit unsigned immediate")]
+    fn refuses_immediates_that_do_not_fit() {
+        assemble(|a| a.add64(X(0), X(0), 4096));
+    }
+
+    #[test]
+    #[should_panic(expected = "offset 12 is not a multiple of 8")]
+    fn refuses_misaligned_offsets() {
+        assemble(|a| a.ldr_imm(X(0), X(1), 12));
+    }
+
+    #[test]
+    #[should_panic(expected = "there is no register x32")]
+    fn refuses_registers_that_do_not_exist() {
+        assemble(|a| a.blr(X(32)));
+    }
+
+    #[test]
+    fn multiplies() {
+        let code = assemble(|a| {
+            a.mul(W(0), W(1), W(2));
+            a.madd(W(10), W(0), W(11), W(10));
+            a.msub(W(10), W(0), W(11), W(10));
+        });
+        // mul w0, w1, w2 ; madd w10, w0, w11, w10 ; msub w10, w0, w11, w10
+        assert_eq!(vec![0x1b027c20, 0x1b0b280a, 0x1b0ba80a], code);
+    }
+}
@@ -0,0 +1,273 @@
+//! Decodes the instructions that [AArch64Assembly](super::AArch64Assembly) can emit back into
+//! assembly, written exactly the way its listing writes them. Branch targets are written as a
+//! byte offset from the branch, e.g., `b #-8`, since there are no labels to name them after.
+
+use super::{Cond, V, W, X};
+
+/// The instruction as assembly, or `None` if it's not one that the assembler can emit.
+pub fn disassemble(word: u32) -> Option<String> {
+    let rd = (word & 0x1F) as u8;
+    let rn = ((word >> 5) & 0x1F) as u8;
+    let rm = ((word >> 16) & 0x1F) as u8;
+    let ra = ((word >> 10) & 0x1F) as u8;
+    let imm12 = (word >> 10) & 0xFFF;
+    let imm16 = (word >> 5) & 0xFFFF;
+    let hw = (word >> 21) & 0b11;
+    let imm7 = sign_extend((word >> 15) & 0x7F, 7) * 8;
+    let imm19 = sign_extend((word >> 5) & 0x7FFFF, 19) * 4;
+
+    let matches = |mask: u32, value: u32| word & mask == value;
+
+    Some(match word {
+        // Branches
+        _ if matches(0xFF00_0000, 0x3400_0000) => format!("cbz {}, #{}", W(rd), imm19),
+        _ if matches(0xFF00_0000, 0x3500_0000) => format!("cbnz {}, #{}", W(rd), imm19),
+        _ if matches(0xFF00_0010, 0x5400_0000) => format!("b.{} #{}", cond(word & 0xF)?, imm19),
+        _ if matches(0xFC00_0000, 0x1400_0000) => {
+            format!("b #{}", sign_extend(word & 0x3FF_FFFF, 26) * 4)
+        }
+        _ if matches(0xFC00_0000, 0x9400_0000) => {
+            format!("bl #{}", sign_extend(word & 0x3FF_FFFF, 26) * 4)
+        }
+        _ if matches(0xFFFF_FC1F, 0xD63F_0000) => format!("blr {}", X(rn)),
+        _ if matches(0xFFFF_FC1F, 0xD65F_0000) => format!("ret {}", X(rn)),
+
+        _ if matches(0x9F00_0000, 0x1000_0000) => format!("adr {}, #{}", X(rd), imm21(word)),
+        _ if matches(0x9F00_0000, 0x9000_0000) => {
+            format!("adrp {}, #{}", X(rd), imm21(word) << 12)
+        }
+
+        // Loads and stores
+        _ if matches(0xFFC0_0000, 0x3900_0000) => {
+            format!("strb {}, [{}, #{}]", W(rd), X(rn), imm12)
+        }
+        _ if matches(0xFFC0_0000, 0x3940_0000) => {
+            format!("ldrb {}, [{}, #{}]", W(rd), X(rn), imm12)
+        }
+        _ if matches(0xFFE0_0C00, 0x3800_0000) => {
+            format!("sturb {}, [{}, #{}]", W(rd), X(rn), imm9(word))
+        }
+        _ if matches(0xFFE0_0C00, 0x3840_0000) => {
+            format!("ldurb {}, [{}, #{}]", W(rd), X(rn), imm9(word))
+        }
+        _ if matches(0xFFC0_0000, 0xF900_0000) => {
+            format!("str {}, [{}, #{}]", X(rd), X(rn), imm12 * 8)
+        }
+        _ if matches(0xFFC0_0000, 0xF940_0000) => {
+            format!("ldr {}, [{}, #{}]", X(rd), X(rn), imm12 * 8)
+        }
+        _ if matches(0xFF00_0000, 0x5800_0000) => format!("ldr {}, #{}", X(rd), imm19),
+        _ if matches(0xFFE0_FC00, 0x3820_6800) => format!("strb {}, [{}, {}]", W(rd), X(rn), X(rm)),
+        _ if matches(0xFFE0_FC00, 0x3860_6800) => format!("ldrb {}, [{}, {}]", W(rd), X(rn), X(rm)),
+        _ if matches(0xFFE0_EC00, 0xF820_6800) => {
+            format!("str {}, [{}, {}{}]", X(rd), X(rn), X(rm), shift(word))
+        }
+        _ if matches(0xFFE0_EC00, 0xF860_6800) => {
+            format!("ldr {}, [{}, {}{}]", X(rd), X(rn), X(rm), shift(word))
+        }
+        _ if matches(0xFFC0_0000, 0xA900_0000) => {
+            format!("stp {}, {}, [{}, #{}]", X(rd), X(ra), X(rn), imm7)
+        }
+        _ if matches(0xFFC0_0000, 0xA940_0000) => {
+            format!("ldp {}, {}, [{}, #{}]", X(rd), X(ra), X(rn), imm7)
+        }
+        _ if matches(0xFFC0_0000, 0xA8C0_0000) => {
+            format!("ldp {}, {}, [{}], #{}", X(rd), X(ra), X(rn), imm7)
+        }
+        _ if matches(0xFFC0_0000, 0xA980_0000) => {
+            format!("stp {}, {}, [{}, #{}]!", X(rd), X(ra), X(rn), imm7)
+        }
+
+        // Advanced SIMD
+        _ if matches(0xFFFF_FC00, 0x4C40_7000) => format!("ld1 {{{}.16b}}, [{}]", V(rd), X(rn)),
+        _ if matches(0xFFFF_FC00, 0x4C00_7000) => format!("st1 {{{}.16b}}, [{}]", V(rd), X(rn)),
+        _ if matches(0xFFFF_FC00, 0x4E01_0C00) => format!("dup {}.16b, {}", V(rd), W(rn)),
+        _ if matches(0xFFE0_FC00, 0x4E20_8400) => {
+            format!("add {}.16b, {}.16b, {}.16b", V(rd), V(rn), V(rm))
+        }
+        _ if matches(0xFFE0_FC00, 0x6E20_8C00) => {
+            format!("cmeq {}.16b, {}.16b, {}.16b", V(rd), V(rn), V(rm))
+        }
+        _ if matches(0xFFFF_FC00, 0x4E20_9800) => format!("cmeq {}.16b, {}.16b, #0", V(rd), V(rn)),
+
+        // Data processing -- immediate
+        _ if matches(0xFFC0_0000, 0x1100_0000) => format!("add {}, {}, #{}", W(rd), W(rn), imm12),
+        _ if matches(0xFFC0_0000, 0x9100_0000) => format!("add {}, {}, #{}", X(rd), X(rn), imm12),
+        _ if matches(0xFFC0_0000, 0x9140_0000) => {
+            format!("add {}, {}, #{}, lsl #12", X(rd), X(rn), imm12)
+        }
+        _ if matches(0xFFC0_001F, 0x7100_001F) => format!("cmp {}, #{}", W(rn), imm12),
+        _ if matches(0xFFC0_0000, 0x5100_0000) => format!("sub {}, {}, #{}", W(rd), W(rn), imm12),
+        _ if matches(0xFFC0_0000, 0xD100_0000) => format!("sub {}, {}, #{}", X(rd), X(rn), imm12),
+        _ if matches(0xFFC0_0000, 0xD140_0000) => {
+            format!("sub {}, {}, #{}, lsl #12", X(rd), X(rn), imm12)
+        }
+        _ if matches(0xFFE0_0000, 0x5280_0000) => format!("movz {}, #{}", W(rd), imm16),
+        _ if matches(0xFF80_0000, 0xD280_0000) => {
+            format!("movz {}, #{}, lsl #{}", X(rd), imm16, 16 * hw)
+        }
+        _ if matches(0xFF80_0000, 0xF280_0000) => {
+            format!("movk {}, #{}, lsl #{}", X(rd), imm16, 16 * hw)
+        }
+        _ if matches(0xFF80_0000, 0x9280_0000) => {
+            format!("movn {}, #{}, lsl #{}", X(rd), imm16, 16 * hw)
+        }
+        _ if matches(0xFFFF_FC00, 0x5300_1C00) => format!("uxtb {}, {}", W(rd), W(rn)),
+        _ if matches(0xFFFF_0000, 0x9240_0000) => {
+            let bits = ((word >> 10) & 0x3F) + 1;
+            if bits == 64 {
+                return None;
+            }
+            format!("and {}, {}, #{:#x}", X(rd), X(rn), (1u64 << bits) - 1)
+        }
+
+        // Data processing -- register
+        _ if matches(0xFFE0_FFE0, 0xAA00_03E0) => format!("mov {}, {}", X(rd), X(rm)),
+        _ if matches(0xFFE0_FC00, 0x8B00_0000) => {
+            format!("add {}, {}, {}", X(rd), X(rn), X(rm))
+        }
+        _ if matches(0xFFE0_FC00, 0xCB00_0000) => {
+            format!("sub {}, {}, {}", X(rd), X(rn), X(rm))
+        }
+        _ if matches(0xFFE0_FC00, 0x1B00_7C00) => {
+            format!("mul {}, {}, {}", W(rd), W(rn), W(rm))
+        }
+        _ if matches(0xFFE0_8000, 0x1B00_0000) => {
+            format!("madd {}, {}, {}, {}", W(rd), W(rn), W(rm), W(ra))
+        }
+        _ if matches(0xFFE0_8000, 0x1B00_8000) => {
+            format!("msub {}, {}, {}, {}", W(rd), W(rn), W(rm), W(ra))
+        }
+        _ => return None,
+    })
+}
+
+/// The signed byte offset of an unscaled load or store.
+fn imm9(word: u32) -> i32 {
+    sign_extend((word >> 12) & 0x1FF, 9)
+}
+
+/// The 21-bit immediate of `adr` and `adrp`, whose low 2 bits are in bits 29 and 30.
+fn imm21(word: u32) -> i32 {
+    sign_extend((((word >> 5) & 0x7FFFF) << 2) | ((word >> 29) & 0b11), 21)
+}
+
+fn sign_extend(value: u32, bits: u32) -> i32 {
+    let unused = 32 - bits;
+    ((value << unused) as i32) >> unused
+}
+
+/// How a dword load or store scales its register offset.
+fn shift(word: u32) -> &'static str {
+    if word & (1 << 12) != 0 {
+        ", lsl #3"
+    } else {
+        ""
+    }
+}
+
+fn cond(bits: u32) -> Option<Cond> {
+    use Cond::*;
+    [Eq, Ne, Hs, Lo, Hi, Ls, Ge, Lt, Gt, Le]
+        .into_iter()
+        .find(|&cond| cond as u32 == bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{AArch64Assembly, Label};
+    use super::*;
+
+    /// Assembles the instructions with a listing, and checks that each one disassembles to its
+    /// line of the listing.
+    fn round_trip(f: impl FnOnce(&mut AArch64Assembly)) {
+        let mut asm = AArch64Assembly::new().with_listing();
+        f(&mut asm);
+        asm.patch_branch_targets();
+
+        let listing = asm.listing().unwrap();
+        let listing: Vec<_> = listing.lines().map(str::trim).collect();
+        let disassembly: Vec<_> = asm
+            .machine_code()
+            .chunks(4)
+            .map(|word| disassemble(u32::from_le_bytes(word.try_into().unwrap())).unwrap())
+            .collect();
+        assert_eq!(listing, disassembly);
+    }
+
+    #[test]
+    fn disassembles_everything_the_assembler_emits() {
+        round_trip(|a| {
+            a.blr(X(9));
+            a.ret();
+            a.strb(W(0), X(19), 0);
+            a.ldrb(W(10), X(9), 0);
+            a.str_imm(X(13), X(21), 8);
+            a.ldr_imm(X(9), X(21), 16);
+            a.strb_reg(W(0), X(9), X(13));
+            a.ldrb_reg(W(0), X(9), X(13));
+            a.str_reg(X(10), X(9), X(12), true);
+            a.ldr_reg(X(10), X(9), X(12), false);
+            a.stp_offset(X(19), X(20), X(31), 16);
+            a.ldp_offset(X(21), X(22), X(31), 32);
+            a.stp_preindex(X(29), X(30), X(31), -48);
+            a.ldp_postindex(X(29), X(30), X(31), 48);
+            a.add(W(10), W(0), 1);
+            a.add64(X(19), X(19), 4095);
+            a.add64_lsl12(X(19), X(19), 1);
+            a.cmp(W(0), 10);
+            a.sub(W(10), W(0), 10);
+            a.sub64(X(19), X(19), 1);
+            a.sub64_lsl12(X(12), X(13), 1);
+            a.movz(W(11), 3);
+            a.movz64(X(0), 0x4000, 1);
+            a.movk64(X(9), 0xbeef, 3);
+            a.movn64(X(0), 0, 0);
+            a.uxtb(W(0), W(1));
+            a.and64_low_bits(X(19), X(19), 8);
+            a.mov(X(19), X(0));
+            a.add64_reg(X(19), X(22), X(19));
+            a.sub64_reg(X(19), X(19), X(22));
+            a.mul(W(0), W(1), W(2));
+            a.madd(W(10), W(0), W(11), W(10));
+            a.msub(W(10), W(0), W(11), W(10));
+            a.ld1(V(0), X(19));
+            a.st1(V(1), X(9));
+            a.dup(V(2), W(0));
+            a.add_16b(V(3), V(1), V(2));
+            a.cmeq(V(4), V(5), V(6));
+            a.cmeq_zero(V(7), V(8));
+        });
+    }
+
+    #[test]
+    fn disassembles_branches_as_offsets() {
+        let mut asm = AArch64Assembly::new();
+        asm.bind(Label(0));
+        asm.cbz(W(0), Label(1));
+        asm.cbnz(W(10), Label(0));
+        asm.b_cond(Cond::Hs, Label(1));
+        asm.b(Label(0));
+        asm.bind(Label(1));
+        asm.patch_branch_targets();
+
+        let disassembly: Vec<_> = asm
+            .machine_code()
+            .chunks(4)
+            .map(|word| disassemble(u32::from_le_bytes(word.try_into().unwrap())).unwrap())
+            .collect();
+        assert_eq!(
+            vec!["cbz w0, #16", "cbnz w10, #-4", "b.hs #8", "b #-12"],
+            disassembly
+        );
+        // Calls to symbols are only ever patched in by relocations:
+        assert_eq!(Some("bl #-8".to_owned()), disassemble(0x97ff_fffe));
+    }
+
+    #[test]
+    fn knows_what_it_does_not_know() {
+        // udf #0, and an add with a register shifted by 3:
+        assert_eq!(None, disassemble(0x0000_0000));
+        assert_eq!(None, disassemble(0x8b13_0e73));
+    }
+}
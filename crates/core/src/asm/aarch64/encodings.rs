@@ -0,0 +1,220 @@
+//! Checks every instruction the assembler can emit against a known-good encoding, from
+//! `llvm-mc -triple=aarch64 -mattr=+neon -show-encoding`. With `llvm-mc` installed, run the ignored
+//! test to check each encoding against it too, so that a new vector can be checked as it's added:
+//!
+//! ```text
+//! cargo test -p brainmuck_core agrees_with_llvm_mc -- --ignored
+//! ```
+//!
+//! Branches are checked with the label bound right next to them, and the instruction under test
+//! is always the last one emitted (before any literal pool).
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use super::disasm::disassemble;
+use super::{AArch64Assembly, Cond, Label, V, W, X};
+
+/// The assembly, its encoding, and how to get the assembler to emit it.
+type Vector = (&'static str, u32, fn(&mut AArch64Assembly));
+
+const VECTORS: &[Vector] = &[
+    ("cbz w0, #4", 0x3400_0020, |a| {
+        a.cbz(W(0), Label(0));
+        a.bind(Label(0));
+    }),
+    ("cbnz w10, #-4", 0x35ff_ffea, |a| {
+        a.bind(Label(0));
+        a.ret();
+        a.cbnz(W(10), Label(0));
+    }),
+    ("b.hs #4", 0x5400_0022, |a| {
+        a.b_cond(Cond::Hs, Label(0));
+        a.bind(Label(0));
+    }),
+    ("b #-12", 0x17ff_fffd, |a| {
+        a.bind(Label(0));
+        a.ret();
+        a.ret();
+        a.ret();
+        a.b(Label(0));
+    }),
+    // Until it's relocated:
+    ("bl #0", 0x9400_0000, |a| a.bl_symbol("putchar")),
+    ("adr x0, #-4", 0x10ff_ffe0, |a| {
+        a.bind(Label(0));
+        a.ret();
+        a.adr(X(0), Label(0));
+    }),
+    ("blr x9", 0xd63f_0120, |a| a.blr(X(9))),
+    ("ret x30", 0xd65f_03c0, |a| a.ret()),
+    // The literal goes after a word of padding:
+    ("ldr x9, #8", 0x5800_0049, |a| a.ldr_literal(X(9), 0x1234)),
+    ("strb w0, [x19, #0]", 0x3900_0260, |a| {
+        a.strb(W(0), X(19), 0)
+    }),
+    ("ldrb w10, [x9, #0]", 0x3940_012a, |a| {
+        a.ldrb(W(10), X(9), 0)
+    }),
+    ("strb w0, [x19, #5]", 0x3900_1660, |a| {
+        a.strb(W(0), X(19), 5)
+    }),
+    ("ldrb w10, [x9, #4095]", 0x397f_fd2a, |a| {
+        a.ldrb(W(10), X(9), 4095)
+    }),
+    ("sturb w0, [x19, #-1]", 0x381f_f260, |a| {
+        a.sturb(W(0), X(19), -1)
+    }),
+    ("ldurb w10, [x9, #-256]", 0x3850_012a, |a| {
+        a.ldurb(W(10), X(9), -256)
+    }),
+    ("str x13, [x21, #8]", 0xf900_06ad, |a| {
+        a.str_imm(X(13), X(21), 8)
+    }),
+    ("ldr x9, [x21, #16]", 0xf940_0aa9, |a| {
+        a.ldr_imm(X(9), X(21), 16)
+    }),
+    ("strb w0, [x9, x13]", 0x382d_6920, |a| {
+        a.strb_reg(W(0), X(9), X(13))
+    }),
+    ("ldrb w0, [x9, x13]", 0x386d_6920, |a| {
+        a.ldrb_reg(W(0), X(9), X(13))
+    }),
+    ("str x10, [x9, x12, lsl #3]", 0xf82c_792a, |a| {
+        a.str_reg(X(10), X(9), X(12), true)
+    }),
+    ("ldr x10, [x9, x12]", 0xf86c_692a, |a| {
+        a.ldr_reg(X(10), X(9), X(12), false)
+    }),
+    ("stp x19, x20, [sp, #16]", 0xa901_53f3, |a| {
+        a.stp_offset(X(19), X(20), X(31), 16)
+    }),
+    ("ldp x21, x22, [sp, #32]", 0xa942_5bf5, |a| {
+        a.ldp_offset(X(21), X(22), X(31), 32)
+    }),
+    ("stp x29, x30, [sp, #-48]!", 0xa9bd_7bfd, |a| {
+        a.stp_preindex(X(29), X(30), X(31), -48)
+    }),
+    ("ldp x29, x30, [sp], #48", 0xa8c3_7bfd, |a| {
+        a.ldp_postindex(X(29), X(30), X(31), 48)
+    }),
+    ("ld1 {v0.16b}, [x19]", 0x4c40_7260, |a| a.ld1(V(0), X(19))),
+    ("st1 {v1.16b}, [x9]", 0x4c00_7121, |a| a.st1(V(1), X(9))),
+    ("dup v2.16b, w0", 0x4e01_0c02, |a| a.dup(V(2), W(0))),
+    ("add v3.16b, v1.16b, v2.16b", 0x4e22_8423, |a| {
+        a.add_16b(V(3), V(1), V(2))
+    }),
+    ("cmeq v4.16b, v5.16b, v6.16b", 0x6e26_8ca4, |a| {
+        a.cmeq(V(4), V(5), V(6))
+    }),
+    ("cmeq v7.16b, v8.16b, #0", 0x4e20_9907, |a| {
+        a.cmeq_zero(V(7), V(8))
+    }),
+    ("add w10, w0, #1", 0x1100_040a, |a| a.add(W(10), W(0), 1)),
+    ("add x19, x19, #4095", 0x913f_fe73, |a| {
+        a.add64(X(19), X(19), 4095)
+    }),
+    ("add x19, x19, #1, lsl #12", 0x9140_0673, |a| {
+        a.add64_lsl12(X(19), X(19), 1)
+    }),
+    ("cmp w0, #10", 0x7100_281f, |a| a.cmp(W(0), 10)),
+    ("sub w10, w0, #10", 0x5100_280a, |a| a.sub(W(10), W(0), 10)),
+    ("sub x19, x19, #1", 0xd100_0673, |a| {
+        a.sub64(X(19), X(19), 1)
+    }),
+    ("sub x12, x13, #1, lsl #12", 0xd140_05ac, |a| {
+        a.sub64_lsl12(X(12), X(13), 1)
+    }),
+    ("movz w11, #3", 0x5280_006b, |a| a.movz(W(11), 3)),
+    ("movz x0, #16384, lsl #16", 0xd2a8_0000, |a| {
+        a.movz64(X(0), 0x4000, 1)
+    }),
+    ("movk x9, #48879, lsl #48", 0xf2f7_dde9, |a| {
+        a.movk64(X(9), 0xbeef, 3)
+    }),
+    ("movn x0, #0, lsl #0", 0x9280_0000, |a| a.movn64(X(0), 0, 0)),
+    ("uxtb w0, w1", 0x5300_1c20, |a| a.uxtb(W(0), W(1))),
+    ("and x19, x19, #0xff", 0x9240_1e73, |a| {
+        a.and64_low_bits(X(19), X(19), 8)
+    }),
+    ("mov x19, x0", 0xaa00_03f3, |a| a.mov(X(19), X(0))),
+    ("add x19, x22, x19", 0x8b13_02d3, |a| {
+        a.add64_reg(X(19), X(22), X(19))
+    }),
+    ("sub x19, x19, x22", 0xcb16_0273, |a| {
+        a.sub64_reg(X(19), X(19), X(22))
+    }),
+    ("mul w0, w1, w2", 0x1b02_7c20, |a| a.mul(W(0), W(1), W(2))),
+    ("madd w10, w0, w11, w10", 0x1b0b_280a, |a| {
+        a.madd(W(10), W(0), W(11), W(10))
+    }),
+    ("msub w10, w0, w11, w10", 0x1b0b_a80a, |a| {
+        a.msub(W(10), W(0), W(11), W(10))
+    }),
+];
+
+/// The last instruction that `emit` emitted.
+fn last_instruction(emit: fn(&mut AArch64Assembly)) -> u32 {
+    let mut asm = AArch64Assembly::new();
+    emit(&mut asm);
+    let last = asm.offset() - 4;
+    asm.patch_branch_targets();
+    let code = asm.machine_code();
+    u32::from_le_bytes(code[last..last + 4].try_into().unwrap())
+}
+
+#[test]
+fn encodes_every_instruction_like_the_reference() {
+    for &(assembly, expected, emit) in VECTORS {
+        let actual = last_instruction(emit);
+        assert_eq!(
+            expected, actual,
+            "{}: expected {:08x}, got {:08x}",
+            assembly, expected, actual
+        );
+        assert_eq!(Some(assembly), disassemble(actual).as_deref());
+    }
+}
+
+#[test]
+#[ignore = "needs llvm-mc"]
+fn agrees_with_llvm_mc() {
+    let mut child = Command::new("llvm-mc")
+        .args(["-triple=aarch64", "-mattr=+neon", "-show-encoding"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("llvm-mc is not installed");
+
+    let source: String = VECTORS
+        .iter()
+        .map(|(assembly, _, _)| format!("{}\n", assembly))
+        .collect();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(source.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "llvm-mc failed");
+
+    // e.g., "\tret  // encoding: [0xc0,0x03,0x5f,0xd6]"
+    let encodings: Vec<u32> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .filter_map(|line| line.split_once("encoding: [")?.1.strip_suffix(']'))
+        .map(|bytes| {
+            let bytes: Vec<u8> = bytes
+                .split(',')
+                .map(|byte| u8::from_str_radix(byte.trim_start_matches("0x"), 16).unwrap())
+                .collect();
+            u32::from_le_bytes(bytes.try_into().unwrap())
+        })
+        .collect();
+
+    assert_eq!(VECTORS.len(), encodings.len());
+    for (&(assembly, _, emit), expected) in VECTORS.iter().zip(encodings) {
+        assert_eq!(expected, last_instruction(emit), "{}", assembly);
+    }
+}
@@ -0,0 +1,98 @@
+//! Where assemblers put machine code: straight into memory that can be made executable, so that
+//! the finished code doesn't have to be copied there.
+
+use std::ops::{Deref, DerefMut};
+
+use mmap_jit::WritableRegion;
+
+/// How many bytes to map the first time anything is emitted.
+const INITIAL_CAPACITY: usize = 4096;
+
+/// Machine code, in a [WritableRegion] that grows as code is emitted.
+#[derive(Default)]
+pub struct CodeBuffer {
+    // Nothing is mapped until the first byte is emitted.
+    region: Option<WritableRegion>,
+    len: usize,
+}
+
+impl CodeBuffer {
+    pub fn new() -> Self {
+        CodeBuffer::default()
+    }
+
+    /// Appends the bytes, growing the region (which can move the code) when they don't fit.
+    pub fn extend_from_slice(&mut self, bytes: &[u8]) {
+        let end = self.len + bytes.len();
+        if end > self.capacity() {
+            self.grow(end);
+        }
+        let region = self.region.as_mut().expect("just grew");
+        region[self.len..end].copy_from_slice(bytes);
+        self.len = end;
+    }
+
+    /// Takes back everything after the first `len` bytes, so that it can be emitted again.
+    pub fn truncate(&mut self, len: usize) {
+        self.len = self.len.min(len);
+    }
+
+    /// The region the code is in, and how many bytes of it are code. Nothing is mapped when
+    /// nothing was emitted, so there's no region.
+    pub fn into_region(self) -> (Option<WritableRegion>, usize) {
+        (self.region, self.len)
+    }
+
+    fn capacity(&self) -> usize {
+        self.region.as_ref().map_or(0, |region| region.len())
+    }
+
+    fn grow(&mut self, needed: usize) {
+        let capacity = needed.max(2 * self.capacity()).max(INITIAL_CAPACITY);
+        match &mut self.region {
+            Some(region) => region.grow(capacity),
+            None => WritableRegion::allocate(capacity).map(|region| self.region = Some(region)),
+        }
+        .expect("could not map memory for code");
+    }
+}
+
+impl Deref for CodeBuffer {
+    type Target = [u8];
+    fn deref(&self) -> &Self::Target {
+        match &self.region {
+            Some(region) => &region[..self.len],
+            None => &[],
+        }
+    }
+}
+
+impl DerefMut for CodeBuffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        match &mut self.region {
+            Some(region) => &mut region[..self.len],
+            None => &mut [],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_code_when_it_outgrows_the_region() {
+        let mut code = CodeBuffer::new();
+        assert!(code.is_empty());
+
+        let bytes: Vec<u8> = (0..=255).cycle().take(3 * INITIAL_CAPACITY + 1).collect();
+        for chunk in bytes.chunks(100) {
+            code.extend_from_slice(chunk);
+        }
+        assert_eq!(&bytes[..], &code[..]);
+
+        let (region, len) = code.into_region();
+        assert_eq!(bytes.len(), len);
+        assert!(region.unwrap().len() >= len);
+    }
+}
@@ -0,0 +1,525 @@
+//! Assembler for x86_64 (a.k.a., AMD64)
+
+use std::collections::HashMap;
+
+use super::CodeBuffer;
+
+/// Reference to a general purpose register. Whether the instruction uses all 64 bits, the low 32
+/// bits, or the low byte is up to the instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct R(pub u8);
+
+pub const RAX: R = R(0);
+pub const RCX: R = R(1);
+pub const RDX: R = R(2);
+pub const RBX: R = R(3);
+pub const RSP: R = R(4);
+pub const RBP: R = R(5);
+pub const RSI: R = R(6);
+pub const RDI: R = R(7);
+pub const R12: R = R(12);
+pub const R13: R = R(13);
+pub const R14: R = R(14);
+
+/// A byte in memory, at a register plus a displacement: `[base + disp]`
+#[derive(Debug, Clone, Copy)]
+pub struct Mem {
+    pub base: R,
+    pub disp: i32,
+}
+
+/// A branch label in the assembly
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub struct Label(pub usize);
+
+/// Generates x86_64 machine code.
+///
+/// Unlike AArch64, instructions don't have a fixed size, so branches always use a 32-bit
+/// displacement, which is patched once every label has been seen.
+pub struct X86_64Assembly {
+    instr: CodeBuffer,
+    // Maps labels to the offset in the instruction vector
+    label_targets: HashMap<Label, usize>,
+    // Where the 32-bit displacement of each incomplete branch starts
+    unresolved_branch_targets: Vec<(usize, Label)>,
+}
+
+impl X86_64Assembly {
+    pub fn new() -> Self {
+        X86_64Assembly {
+            instr: CodeBuffer::new(),
+            label_targets: HashMap::new(),
+            unresolved_branch_targets: Vec::new(),
+        }
+    }
+
+    /// Call this before the first instruction of the desired label
+    pub fn set_label_target(&mut self, label: Label) {
+        self.label_targets.insert(label, self.instr.len());
+    }
+
+    pub fn patch_branch_targets(&mut self) {
+        for &(source, label) in self.unresolved_branch_targets.iter() {
+            let target = self
+                .label_targets
+                .get(&label)
+                .expect("should have seen label");
+
+            // Displacements are relative to the end of the instruction, which is right after the
+            // displacement itself.
+            let offset = *target as i64 - (source + 4) as i64;
+            let offset = i32::try_from(offset).expect("branch too far");
+            self.instr[source..(source + 4)].copy_from_slice(&offset.to_le_bytes());
+        }
+
+        self.unresolved_branch_targets.clear();
+    }
+
+    /// Where the next instruction goes, in bytes from the start of the machine code.
+    pub fn offset(&self) -> usize {
+        self.instr.len()
+    }
+
+    /// Returns machine code.
+    /// Panics if there are unresolved branch targets.
+    pub fn machine_code(&self) -> &[u8] {
+        self.assert_complete();
+        &self.instr[..]
+    }
+
+    /// Takes the machine code, leaving nothing behind.
+    /// Panics if there are unresolved branch targets.
+    pub fn take_machine_code(&mut self) -> CodeBuffer {
+        self.assert_complete();
+        self.label_targets.clear();
+        std::mem::take(&mut self.instr)
+    }
+
+    fn assert_complete(&self) {
+        let incomplete = self.unresolved_branch_targets.len();
+        if incomplete > 0 {
+            panic!(
+                "tried to generate binary, but there are still {} unresolved branch targets!",
+                incomplete
+            );
+        }
+    }
+
+    // Instructions
+    //
+    // Opcodes are from the Intel 64 and IA-32 Architectures Software Developer's Manual, Volume 2.
+
+    // Control flow ///////////////////////////////////////////////////////////////////////////////
+
+    /// Jump (with a 32-bit displacement)
+    pub fn jmp(&mut self, label: Label) {
+        self.emit(&[0xE9]);
+        self.emit_incomplete_branch(label);
+    }
+
+    /// Jump if equal, i.e., if the zero flag is set
+    pub fn je(&mut self, label: Label) {
+        self.emit(&[0x0F, 0x84]);
+        self.emit_incomplete_branch(label);
+    }
+
+    /// Jump if not equal, i.e., if the zero flag is clear
+    pub fn jne(&mut self, label: Label) {
+        self.emit(&[0x0F, 0x85]);
+        self.emit_incomplete_branch(label);
+    }
+
+    /// Call the function whose address is in the register
+    pub fn call(&mut self, r: R) {
+        self.emit_rex(false, R(0), r);
+        self.emit(&[0xFF]);
+        self.emit_modrm_reg(2, r);
+    }
+
+    pub fn ret(&mut self) {
+        self.emit(&[0xC3]);
+    }
+
+    // Stack //////////////////////////////////////////////////////////////////////////////////////
+
+    pub fn push(&mut self, r: R) {
+        self.emit_rex(false, R(0), r);
+        self.emit(&[0x50 + (r.0 & 7)]);
+    }
+
+    pub fn pop(&mut self, r: R) {
+        self.emit_rex(false, R(0), r);
+        self.emit(&[0x58 + (r.0 & 7)]);
+    }
+
+    // Registers //////////////////////////////////////////////////////////////////////////////////
+
+    /// Move (64-bit): dst <- src
+    pub fn mov64(&mut self, dst: R, src: R) {
+        self.emit_rex(true, src, dst);
+        self.emit(&[0x89]);
+        self.emit_modrm_reg(src.0, dst);
+    }
+
+    /// Move immediate (32-bit, zeroing the upper 32 bits): dst <- imm
+    pub fn mov32_imm(&mut self, dst: R, imm: u32) {
+        self.emit_rex(false, R(0), dst);
+        self.emit(&[0xB8 + (dst.0 & 7)]);
+        self.emit(&imm.to_le_bytes());
+    }
+
+    /// Move immediate (64-bit), a.k.a., movabs: dst <- imm
+    pub fn mov64_imm(&mut self, dst: R, imm: u64) {
+        self.emit_rex(true, R(0), dst);
+        self.emit(&[0xB8 + (dst.0 & 7)]);
+        self.emit(&imm.to_le_bytes());
+    }
+
+    /// Add immediate (64-bit): dst <- dst + imm
+    pub fn add64_imm(&mut self, dst: R, imm: i32) {
+        self.emit_rex(true, R(0), dst);
+        self.emit(&[0x81]);
+        self.emit_modrm_reg(0, dst);
+        self.emit(&imm.to_le_bytes());
+    }
+
+    /// Add (64-bit): dst <- dst + src
+    pub fn add64(&mut self, dst: R, src: R) {
+        self.emit_rex(true, src, dst);
+        self.emit(&[0x01]);
+        self.emit_modrm_reg(src.0, dst);
+    }
+
+    /// Subtract (64-bit): dst <- dst - src
+    pub fn sub64(&mut self, dst: R, src: R) {
+        self.emit_rex(true, src, dst);
+        self.emit(&[0x29]);
+        self.emit_modrm_reg(src.0, dst);
+    }
+
+    /// And with a sign-extended immediate (64-bit): dst <- dst & imm
+    pub fn and64_imm(&mut self, dst: R, imm: i32) {
+        self.emit_rex(true, R(0), dst);
+        self.emit(&[0x81]);
+        self.emit_modrm_reg(4, dst);
+        self.emit(&imm.to_le_bytes());
+    }
+
+    /// Compare with a sign-extended 8-bit immediate (32-bit)
+    pub fn cmp32_imm(&mut self, r: R, imm: i8) {
+        self.emit_rex(false, R(0), r);
+        self.emit(&[0x83]);
+        self.emit_modrm_reg(7, r);
+        self.emit(&[imm as u8]);
+    }
+
+    /// Compare with a 32-bit immediate (32-bit)
+    pub fn cmp32_imm32(&mut self, r: R, imm: i32) {
+        self.emit_rex(false, R(0), r);
+        self.emit(&[0x81]);
+        self.emit_modrm_reg(7, r);
+        self.emit(&imm.to_le_bytes());
+    }
+
+    /// Signed multiply by an immediate (32-bit): dst <- src * imm
+    pub fn imul32_imm(&mut self, dst: R, src: R, imm: i32) {
+        self.emit_rex(false, dst, src);
+        self.emit(&[0x69]);
+        self.emit_modrm_reg(dst.0, src);
+        self.emit(&imm.to_le_bytes());
+    }
+
+    // Memory /////////////////////////////////////////////////////////////////////////////////////
+
+    /// Load (64-bit): dst <- [mem]
+    pub fn mov64_load(&mut self, dst: R, mem: Mem) {
+        self.emit_rex(true, dst, mem.base);
+        self.emit(&[0x8B]);
+        self.emit_modrm_mem(dst.0, mem);
+    }
+
+    /// Store (64-bit): [mem] <- src
+    pub fn mov64_store(&mut self, mem: Mem, src: R) {
+        self.emit_rex(true, src, mem.base);
+        self.emit(&[0x89]);
+        self.emit_modrm_mem(src.0, mem);
+    }
+
+    /// Add a sign-extended immediate to memory (64-bit): [mem] <- [mem] + imm
+    pub fn add64_mem_imm(&mut self, mem: Mem, imm: i8) {
+        self.emit_rex(true, R(0), mem.base);
+        self.emit(&[0x83]);
+        self.emit_modrm_mem(0, mem);
+        self.emit(&imm.to_le_bytes());
+    }
+
+    /// Move with zero-extend a byte from memory (32-bit): dst <- [mem]
+    pub fn movzx32_byte(&mut self, dst: R, mem: Mem) {
+        self.emit_rex(false, dst, mem.base);
+        self.emit(&[0x0F, 0xB6]);
+        self.emit_modrm_mem(dst.0, mem);
+    }
+
+    /// Move the low byte of the register to memory: [mem] <- src
+    pub fn mov_byte(&mut self, mem: Mem, src: R) {
+        // Without a REX prefix, 4 to 7 mean ah, ch, dh, and bh instead of spl, bpl, sil, and dil.
+        self.emit_rex_always(false, src, mem.base, src.0 >= 4);
+        self.emit(&[0x88]);
+        self.emit_modrm_mem(src.0, mem);
+    }
+
+    /// Move an immediate byte to memory: [mem] <- imm
+    pub fn mov_byte_imm(&mut self, mem: Mem, imm: u8) {
+        self.emit_rex(false, R(0), mem.base);
+        self.emit(&[0xC6]);
+        self.emit_modrm_mem(0, mem);
+        self.emit(&[imm]);
+    }
+
+    /// Add an immediate byte to memory: [mem] <- [mem] + imm
+    pub fn add_byte_imm(&mut self, mem: Mem, imm: u8) {
+        self.emit_rex(false, R(0), mem.base);
+        self.emit(&[0x80]);
+        self.emit_modrm_mem(0, mem);
+        self.emit(&[imm]);
+    }
+
+    /// Add the low byte of the register to memory: [mem] <- [mem] + src
+    pub fn add_byte(&mut self, mem: Mem, src: R) {
+        self.emit_rex_always(false, src, mem.base, src.0 >= 4);
+        self.emit(&[0x00]);
+        self.emit_modrm_mem(src.0, mem);
+    }
+
+    /// Subtract an immediate byte from memory: [mem] <- [mem] - imm
+    pub fn sub_byte_imm(&mut self, mem: Mem, imm: u8) {
+        self.emit_rex(false, R(0), mem.base);
+        self.emit(&[0x80]);
+        self.emit_modrm_mem(5, mem);
+        self.emit(&[imm]);
+    }
+
+    /// Subtract the low byte of the register from memory: [mem] <- [mem] - src
+    pub fn sub_byte(&mut self, mem: Mem, src: R) {
+        self.emit_rex_always(false, src, mem.base, src.0 >= 4);
+        self.emit(&[0x28]);
+        self.emit_modrm_mem(src.0, mem);
+    }
+
+    /// Increment a byte in memory: [mem] <- [mem] + 1
+    pub fn inc_byte(&mut self, mem: Mem) {
+        self.emit_rex(false, R(0), mem.base);
+        self.emit(&[0xFE]);
+        self.emit_modrm_mem(0, mem);
+    }
+
+    /// Decrement a byte in memory: [mem] <- [mem] - 1
+    pub fn dec_byte(&mut self, mem: Mem) {
+        self.emit_rex(false, R(0), mem.base);
+        self.emit(&[0xFE]);
+        self.emit_modrm_mem(1, mem);
+    }
+
+    /// Compare a byte in memory with an immediate
+    pub fn cmp_byte_imm(&mut self, mem: Mem, imm: u8) {
+        self.emit_rex(false, R(0), mem.base);
+        self.emit(&[0x80]);
+        self.emit_modrm_mem(7, mem);
+        self.emit(&[imm]);
+    }
+
+    // Private methods ////////////////////////////////////////////////////////////////////////////
+
+    fn emit(&mut self, bytes: &[u8]) {
+        self.instr.extend_from_slice(bytes);
+    }
+
+    fn emit_incomplete_branch(&mut self, label: Label) {
+        self.unresolved_branch_targets
+            .push((self.instr.len(), label));
+        self.emit(&[0; 4]);
+    }
+
+    /// Emits a REX prefix, but only when it's needed: for 64-bit operands, or for any register
+    /// from r8 up.
+    fn emit_rex(&mut self, wide: bool, reg: R, rm: R) {
+        self.emit_rex_always(wide, reg, rm, false);
+    }
+
+    fn emit_rex_always(&mut self, wide: bool, reg: R, rm: R, always: bool) {
+        //       0100 W R X B
+        let rex = 0b0100_0000 | ((wide as u8) << 3) | (((reg.0 >> 3) & 1) << 2) | ((rm.0 >> 3) & 1);
+        if always || rex != 0b0100_0000 {
+            self.emit(&[rex]);
+        }
+    }
+
+    /// ModR/M byte where r/m is a register.
+    fn emit_modrm_reg(&mut self, reg: u8, rm: R) {
+        self.emit(&[0b1100_0000 | ((reg & 7) << 3) | (rm.0 & 7)]);
+    }
+
+    /// ModR/M byte (and SIB byte, and displacement, as needed) where r/m is memory.
+    fn emit_modrm_mem(&mut self, reg: u8, mem: Mem) {
+        let base = mem.base.0 & 7;
+        let disp = mem.disp.to_le_bytes();
+        // rbp and r13 with no displacement mean something else, so they always get one.
+        let (mode, disp) = match mem.disp {
+            0 if base != RBP.0 => (0b00, &disp[..0]),
+            d if i8::try_from(d).is_ok() => (0b01, &disp[..1]),
+            _ => (0b10, &disp[..]),
+        };
+
+        self.emit(&[(mode << 6) | ((reg & 7) << 3) | base]);
+        // rsp and r12 as a base need a SIB byte (with no index).
+        if base == RSP.0 {
+            self.emit(&[0x24]);
+        }
+        self.emit(disp);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Assembles the instructions, and returns the machine code.
+    fn assemble(f: impl FnOnce(&mut X86_64Assembly)) -> Vec<u8> {
+        let mut asm = X86_64Assembly::new();
+        f(&mut asm);
+        asm.patch_branch_targets();
+        asm.machine_code().to_vec()
+    }
+
+    #[test]
+    fn encodes_memory_operands() {
+        let cell = |base, disp| Mem { base, disp };
+
+        // add byte [rbx], 1
+        assert_eq!(
+            vec![0x80, 0x03, 0x01],
+            assemble(|a| a.add_byte_imm(cell(RBX, 0), 1))
+        );
+        // add byte [rbx - 2], 1
+        assert_eq!(
+            vec![0x80, 0x43, 0xFE, 0x01],
+            assemble(|a| a.add_byte_imm(cell(RBX, -2), 1))
+        );
+        // mov byte [rbx + 0x1000], 0
+        assert_eq!(
+            vec![0xC6, 0x83, 0x00, 0x10, 0x00, 0x00, 0x00],
+            assemble(|a| a.mov_byte_imm(cell(RBX, 0x1000), 0))
+        );
+        // mov byte [r12], sil
+        assert_eq!(
+            vec![0x41, 0x88, 0x34, 0x24],
+            assemble(|a| a.mov_byte(cell(R12, 0), RSI))
+        );
+        // movzx eax, byte [r13]
+        assert_eq!(
+            vec![0x41, 0x0F, 0xB6, 0x45, 0x00],
+            assemble(|a| a.movzx32_byte(RAX, cell(R13, 0)))
+        );
+        // mov rcx, [r12] ; mov [r12], rcx ; mov rax, [r12 + 8] ; add qword [rax], 1
+        assert_eq!(
+            vec![
+                0x49, 0x8B, 0x0C, 0x24, 0x49, 0x89, 0x0C, 0x24, 0x49, 0x8B, 0x44, 0x24, 0x08, 0x48,
+                0x83, 0x00, 0x01
+            ],
+            assemble(|a| {
+                a.mov64_load(RCX, cell(R12, 0));
+                a.mov64_store(cell(R12, 0), RCX);
+                a.mov64_load(RAX, cell(R12, 8));
+                a.add64_mem_imm(cell(RAX, 0), 1);
+            })
+        );
+    }
+
+    #[test]
+    fn subtracts_increments_and_decrements_bytes() {
+        let cell = |base, disp| Mem { base, disp };
+
+        // sub byte [rbx], 3 ; sub byte [r12 + 8], sil ; inc byte [rbx - 1] ; dec byte [r13]
+        assert_eq!(
+            vec![
+                0x80, 0x2B, 0x03, 0x41, 0x28, 0x74, 0x24, 0x08, 0xFE, 0x43, 0xFF, 0x41, 0xFE, 0x4D,
+                0x00
+            ],
+            assemble(|a| {
+                a.sub_byte_imm(cell(RBX, 0), 3);
+                a.sub_byte(cell(R12, 8), RSI);
+                a.inc_byte(cell(RBX, -1));
+                a.dec_byte(cell(R13, 0));
+            })
+        );
+    }
+
+    #[test]
+    fn encodes_registers() {
+        // push r12 ; pop rbx ; mov rbx, rdi ; call r13
+        assert_eq!(
+            vec![0x41, 0x54, 0x5B, 0x48, 0x89, 0xFB, 0x41, 0xFF, 0xD5],
+            assemble(|a| {
+                a.push(R12);
+                a.pop(RBX);
+                a.mov64(RBX, RDI);
+                a.call(R13);
+            })
+        );
+        // add rbx, -5 ; imul eax, eax, 3 ; cmp eax, -1 ; cmp ecx, 0x1000
+        assert_eq!(
+            vec![
+                0x48, 0x81, 0xC3, 0xFB, 0xFF, 0xFF, 0xFF, 0x69, 0xC0, 0x03, 0x00, 0x00, 0x00, 0x83,
+                0xF8, 0xFF, 0x81, 0xF9, 0x00, 0x10, 0x00, 0x00
+            ],
+            assemble(|a| {
+                a.add64_imm(RBX, -5);
+                a.imul32_imm(RAX, RAX, 3);
+                a.cmp32_imm(RAX, -1);
+                a.cmp32_imm32(RCX, 0x1000);
+            })
+        );
+        // mov rax, 0x1122334455667788 ; mov r14, 1
+        assert_eq!(
+            vec![
+                0x48, 0xB8, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, 0x49, 0xBE, 0x01, 0x00,
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00
+            ],
+            assemble(|a| {
+                a.mov64_imm(RAX, 0x1122334455667788);
+                a.mov64_imm(R14, 1);
+            })
+        );
+        // sub rdx, r14 ; and rdx, 0xFF ; add rbx, r14
+        assert_eq!(
+            vec![0x4C, 0x29, 0xF2, 0x48, 0x81, 0xE2, 0xFF, 0x00, 0x00, 0x00, 0x4C, 0x01, 0xF3],
+            assemble(|a| {
+                a.sub64(RDX, R14);
+                a.and64_imm(RDX, 0xFF);
+                a.add64(RBX, R14);
+            })
+        );
+    }
+
+    #[test]
+    fn patches_branches_both_ways() {
+        let code = assemble(|a| {
+            a.set_label_target(Label(0));
+            a.je(Label(1));
+            a.jmp(Label(0));
+            a.set_label_target(Label(1));
+        });
+        // je +5 ; jmp -11
+        assert_eq!(
+            vec![0x0F, 0x84, 0x05, 0x00, 0x00, 0x00, 0xE9, 0xF5, 0xFF, 0xFF, 0xFF],
+            code
+        );
+
+        let code = assemble(|a| {
+            a.set_label_target(Label(0));
+            a.jne(Label(0));
+        });
+        // jne -6
+        assert_eq!(vec![0x0F, 0x85, 0xFA, 0xFF, 0xFF, 0xFF], code);
+    }
+}
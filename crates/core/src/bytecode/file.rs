@@ -0,0 +1,339 @@
+//! Saves an [InterpretedProgram] to disk, and loads it back, so that a program only has to be
+//! compiled once.
+//!
+//! The format is little-endian throughout:
+//!
+//! ```text
+//! magic    "BMBC"
+//! version  u16
+//! flags    u8       bit 0: the tape wraps around
+//!                   bits 1-2: at the end of input, 0: set the cell to 255, 1: set it to 0, 2: leave it
+//! tape     u32      how many cells the tape should have
+//! code     u32 count, then each instruction: an opcode byte, followed by its operands
+//! data     u32 length, then the bytes that EmitBytes refers to
+//! blocks   u32 count, then each block: u32 label, u32 index of its first instruction
+//! ```
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use super::{BranchTarget, Bytecode, InterpretedProgram};
+use crate::config::{EofBehavior, ProgramConfig};
+use crate::ir::BlockLabel;
+use crate::parsing::ExtendedOp;
+
+const MAGIC: &[u8; 4] = b"BMBC";
+/// Bump this whenever the format changes, so that old files are rejected instead of misread.
+const VERSION: u16 = 3;
+
+const WRAP_TAPE: u8 = 0b1;
+const EOF_MASK: u8 = 0b110;
+const EOF_ZERO: u8 = 0b010;
+const EOF_UNCHANGED: u8 = 0b100;
+
+impl InterpretedProgram {
+    /// Saves the program to a file. See [InterpretedProgram::load].
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        self.write_to(&mut out)?;
+        out.flush()
+    }
+
+    /// Loads a program saved by [InterpretedProgram::save].
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        InterpretedProgram::read_from(&mut BufReader::new(File::open(path)?))
+    }
+
+    /// Writes the program in the binary format described in this module.
+    pub fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        out.write_all(MAGIC)?;
+        out.write_all(&VERSION.to_le_bytes())?;
+        let wrap = if self.wrap_tape { WRAP_TAPE } else { 0 };
+        let eof = match self.eof {
+            EofBehavior::MinusOne => 0,
+            EofBehavior::Zero => EOF_ZERO,
+            EofBehavior::Unchanged => EOF_UNCHANGED,
+        };
+        out.write_all(&[wrap | eof])?;
+        write_len(out, self.tape_length)?;
+
+        write_len(out, self.bytecode.len())?;
+        for &instr in self.bytecode.iter() {
+            write_instruction(out, instr)?;
+        }
+
+        write_len(out, self.data.len())?;
+        out.write_all(&self.data)?;
+
+        // Sorted, so that the same program is always saved the same way:
+        let mut blocks: Vec<_> = self.block_starts.iter().collect();
+        blocks.sort_by_key(|(label, _)| label.0);
+        write_len(out, blocks.len())?;
+        for (label, start) in blocks {
+            write_len(out, label.0)?;
+            write_len(out, start.0)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads a program in the binary format described in this module. Anything that would not
+    /// run safely (e.g., a branch past the end of the code) is rejected as
+    /// [io::ErrorKind::InvalidData].
+    pub fn read_from(input: &mut impl Read) -> io::Result<Self> {
+        let mut magic = [0u8; 4];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a brainmuck bytecode file"));
+        }
+
+        let mut version = [0u8; 2];
+        input.read_exact(&mut version)?;
+        if u16::from_le_bytes(version) != VERSION {
+            return Err(invalid("unsupported bytecode version"));
+        }
+        let flags = read_u8(input)?;
+        let config = ProgramConfig {
+            tape_length: read_u32(input)? as usize,
+            wrap_tape: flags & WRAP_TAPE != 0,
+            eof: match flags & EOF_MASK {
+                0 => EofBehavior::MinusOne,
+                EOF_ZERO => EofBehavior::Zero,
+                EOF_UNCHANGED => EofBehavior::Unchanged,
+                _ => return Err(invalid("unknown end of input behavior")),
+            },
+            ..ProgramConfig::default()
+        };
+
+        let length = read_u32(input)? as usize;
+        let mut bytecode = Vec::new();
+        for _ in 0..length {
+            bytecode.push(read_instruction(input)?);
+        }
+
+        let length = read_u32(input)? as usize;
+        let mut data = Vec::new();
+        input.take(length as u64).read_to_end(&mut data)?;
+        if data.len() != length {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let length = read_u32(input)? as usize;
+        let mut block_starts = HashMap::new();
+        for _ in 0..length {
+            let label = BlockLabel(read_u32(input)? as usize);
+            let start = BranchTarget(read_u32(input)? as usize);
+            block_starts.insert(label, start);
+        }
+
+        check(&bytecode, &data)?;
+        Ok(InterpretedProgram::from_parts(
+            bytecode,
+            data,
+            block_starts,
+            &config,
+        ))
+    }
+}
+
+// Internal stuff:
+
+/// Makes sure the interpreter can run the code without going out of bounds. Offsets don't need
+/// checking, since every offset is checked against the tape at runtime.
+fn check(code: &[Bytecode], data: &[u8]) -> io::Result<()> {
+    if !matches!(code.last(), Some(Bytecode::Terminate)) {
+        return Err(invalid("code must end with terminate"));
+    }
+
+    for &instr in code {
+        match instr {
+            Bytecode::BranchIfZero(BranchTarget(target))
+            | Bytecode::BranchTo(BranchTarget(target))
+                if target >= code.len() =>
+            {
+                return Err(invalid("branch target is past the end of the code"));
+            }
+            Bytecode::EmitBytes { start, len } if start + len > data.len() => {
+                return Err(invalid("output is past the end of the data"));
+            }
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+fn write_instruction(out: &mut impl Write, instr: Bytecode) -> io::Result<()> {
+    use Bytecode::*;
+
+    match instr {
+        ChangeVal(value) => out.write_all(&[0x01, value]),
+        ChangeAddr(offset) => {
+            out.write_all(&[0x02])?;
+            out.write_all(&offset.to_le_bytes())
+        }
+        PrintChar => out.write_all(&[0x03]),
+        GetChar => out.write_all(&[0x04]),
+        BranchIfZero(target) => {
+            out.write_all(&[0x05])?;
+            write_len(out, target.0)
+        }
+        BranchTo(target) => {
+            out.write_all(&[0x06])?;
+            write_len(out, target.0)
+        }
+        NoOp => out.write_all(&[0x07]),
+        Zero => out.write_all(&[0x08]),
+        MulAdd { dst_offset, factor } => {
+            out.write_all(&[0x09])?;
+            out.write_all(&dst_offset.to_le_bytes())?;
+            out.write_all(&[factor])
+        }
+        FindZero(step) => {
+            out.write_all(&[0x0A])?;
+            out.write_all(&step.to_le_bytes())
+        }
+        ChangeValAt(offset, value) => {
+            out.write_all(&[0x0B])?;
+            out.write_all(&offset.to_le_bytes())?;
+            out.write_all(&[value])
+        }
+        SetValAt(offset, value) => {
+            out.write_all(&[0x0C])?;
+            out.write_all(&offset.to_le_bytes())?;
+            out.write_all(&[value])
+        }
+        Terminate => out.write_all(&[0x0D]),
+        Extended(op) => out.write_all(&[0x0E, op.to_char() as u8]),
+        EmitBytes { start, len } => {
+            out.write_all(&[0x0F])?;
+            write_len(out, start)?;
+            write_len(out, len)
+        }
+    }
+}
+
+fn read_instruction(input: &mut impl Read) -> io::Result<Bytecode> {
+    use Bytecode::*;
+
+    Ok(match read_u8(input)? {
+        0x01 => ChangeVal(read_u8(input)?),
+        0x02 => ChangeAddr(read_i32(input)?),
+        0x03 => PrintChar,
+        0x04 => GetChar,
+        0x05 => BranchIfZero(BranchTarget(read_u32(input)? as usize)),
+        0x06 => BranchTo(BranchTarget(read_u32(input)? as usize)),
+        0x07 => NoOp,
+        0x08 => Zero,
+        0x09 => MulAdd {
+            dst_offset: read_i32(input)?,
+            factor: read_u8(input)?,
+        },
+        0x0A => FindZero(read_i32(input)?),
+        0x0B => ChangeValAt(read_i32(input)?, read_u8(input)?),
+        0x0C => SetValAt(read_i32(input)?, read_u8(input)?),
+        0x0D => Terminate,
+        0x0E => Extended(
+            ExtendedOp::from_byte(read_u8(input)?)
+                .ok_or_else(|| invalid("unknown extended operation"))?,
+        ),
+        0x0F => EmitBytes {
+            start: read_u32(input)? as usize,
+            len: read_u32(input)? as usize,
+        },
+        _ => return Err(invalid("unknown opcode")),
+    })
+}
+
+/// Lengths, indices, and labels are all stored as u32.
+fn write_len(out: &mut impl Write, n: usize) -> io::Result<()> {
+    let n = u32::try_from(n).map_err(|_| invalid("program is too big to save"))?;
+    out.write_all(&n.to_le_bytes())
+}
+
+fn read_u8(input: &mut impl Read) -> io::Result<u8> {
+    let mut bytes = [0u8; 1];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_i32(input: &mut impl Read) -> io::Result<i32> {
+    let mut bytes = [0u8; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(i32::from_le_bytes(bytes))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::parse;
+
+    fn save(program: &InterpretedProgram) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        program.write_to(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn loads_what_it_saved() {
+        let ast = parse(
+            "<test>",
+            b";# tape=100 wrap=on dialect=ext1 eof=unchanged\n++++[>++++<-]>.,[->+>$<<]<",
+        )
+        .unwrap();
+        let program = crate::compile_to_bytecode(&ast);
+        let bytes = save(&program);
+        assert_eq!(MAGIC, &bytes[..4]);
+
+        let loaded = InterpretedProgram::read_from(&mut &bytes[..]).unwrap();
+        assert!(loaded
+            .bytecode
+            .iter()
+            .any(|instr| matches!(instr, Bytecode::Extended(ExtendedOp::Store))));
+        assert_eq!(program.wrap_tape, loaded.wrap_tape);
+        assert_eq!(100, loaded.tape_length());
+        assert_eq!(EofBehavior::Unchanged, loaded.eof);
+        assert_eq!(program.data, loaded.data);
+        assert_eq!(program.block_starts.len(), loaded.block_starts.len());
+        assert_eq!(bytes, save(&loaded));
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        let program = crate::compile_to_bytecode(&parse("<test>", b",[>.<-]").unwrap());
+        let bytes = save(&program);
+
+        let error = InterpretedProgram::read_from(&mut &b"BMBX"[..])
+            .err()
+            .unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+
+        let truncated = &bytes[..bytes.len() - 1];
+        let error = InterpretedProgram::read_from(&mut &truncated[..])
+            .err()
+            .unwrap();
+        assert_eq!(io::ErrorKind::UnexpectedEof, error.kind());
+
+        // Well-formed, but it would crash the interpreter:
+        let branch = [Bytecode::BranchTo(BranchTarget(2)), Bytecode::Terminate];
+        assert!(check(&branch, &[]).is_err());
+        assert!(check(&[Bytecode::NoOp], &[]).is_err());
+        let output = [
+            Bytecode::EmitBytes { start: 1, len: 2 },
+            Bytecode::Terminate,
+        ];
+        assert!(check(&output, &[0, 0]).is_err());
+    }
+}
@@ -0,0 +1,1175 @@
+//! Defines and implements a "bytecode" interpreter. The more appropriate term for this is probably
+//! [threaded code], (not to be confused with multi-threaded code!).
+//!
+//! Since this is platform-independent code, it will run on any platform, unlike the JIT compiler!
+//!
+//! [threaded code]: https://en.wikipedia.org/wiki/Threaded_code
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+
+use crate::config::{EofBehavior, ProgramConfig};
+use crate::errors::{RuntimeError, RuntimeErrorKind};
+use crate::ir::{BlockLabel, ControlFlowGraph, ThreeAddressInstruction};
+use crate::parsing::{ExtendedOp, SourcePosition, SourceSpan};
+use crate::profile::Profile;
+use crate::program::{self, BrainmuckProgram, GetChar, PutChar, RunStats};
+
+mod file;
+
+/// A [BrainmuckProgram] that is dynamically interpreted from "[Bytecode]"
+pub struct InterpretedProgram {
+    bytecode: Vec<Bytecode>,
+    /// The bytecode, decoded for the interpreter loop. See [Decoded].
+    decoded: Vec<Decoded>,
+    /// Output that was computed at compile time. See [Bytecode::EmitBytes].
+    data: Vec<u8>,
+    /// Where each block of the CFG starts in the bytecode.
+    block_starts: HashMap<BlockLabel, BranchTarget>,
+    /// Where each instruction came from. See [InterpretedProgram::source_map].
+    source_map: Vec<SourceMapEntry>,
+    /// How many cells the program expects the tape to have. See [InterpretedProgram::tape_length].
+    tape_length: usize,
+    wrap_tape: bool,
+    eof: EofBehavior,
+}
+
+/// Where a bytecode instruction came from, so that a program counter can be traced back to the CFG
+/// and to the source text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceMapEntry {
+    /// The block of the CFG that the instruction was lowered from.
+    pub block: BlockLabel,
+    /// The part of the source text that the block came from, if known.
+    pub span: Option<SourceSpan>,
+}
+
+/// Where an [InterpretedProgram] is in its execution.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct MachineState {
+    pub(crate) pc: usize,
+    pub(crate) address: usize,
+    /// Only used by Extended Brainfuck
+    storage: u8,
+    pub(crate) executed: u64,
+}
+
+/// Why an [Interpreter] stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// It can keep going.
+    Paused,
+    /// It executed as many instructions as it was allowed to. It can keep going, given more fuel.
+    OutOfFuel,
+    /// The program ended.
+    Halted,
+    /// It's waiting to read input that hasn't arrived yet. Give it some with
+    /// [Interpreter::push_input] (or [Interpreter::end_input]), and it can keep going.
+    NeedsInput,
+}
+
+/// A [Bytecode] instruction, packed into 8 bytes, so that the whole program is one dense array,
+/// and decoding an instruction is a single load. Instructions that don't fit (or that aren't worth
+/// the trouble, like I/O) are [Opcode::Other], and are looked up in the original bytecode.
+#[derive(Debug, Clone, Copy)]
+struct Decoded {
+    opcode: Opcode,
+    value: u8,
+    /// An offset, a step, or a branch target, depending on the opcode.
+    operand: i32,
+}
+
+/// What a [Decoded] instruction does. Mostly mirrors [Bytecode].
+#[derive(Debug, Clone, Copy)]
+#[repr(u8)]
+enum Opcode {
+    ChangeVal,
+    ChangeAddr,
+    BranchIfZero,
+    BranchTo,
+    Zero,
+    MulAdd,
+    FindZero,
+    ChangeValAt,
+    SetValAt,
+    Terminate,
+    /// Run the [Bytecode] at the same index.
+    Other,
+}
+
+impl InterpretedProgram {
+    pub fn new(cfg: &ControlFlowGraph, config: &ProgramConfig) -> Self {
+        let (mut bytecode, data, block_starts, source_map) = compile_cfg_to_bytecode(cfg);
+        // The interpreter loop never checks whether it ran off the end (or branched to it). A
+        // verified CFG already ends this way, but one that was put together by hand might not.
+        if !matches!(bytecode.last(), Some(Bytecode::Terminate)) {
+            bytecode.push(Bytecode::Terminate);
+        }
+
+        InterpretedProgram {
+            source_map,
+            ..InterpretedProgram::from_parts(bytecode, data, block_starts, config)
+        }
+    }
+
+    /// The bytecode must end with [Bytecode::Terminate], and every branch must be in bounds.
+    fn from_parts(
+        bytecode: Vec<Bytecode>,
+        data: Vec<u8>,
+        block_starts: HashMap<BlockLabel, BranchTarget>,
+        config: &ProgramConfig,
+    ) -> Self {
+        InterpretedProgram {
+            decoded: bytecode.iter().map(|&instr| decode(instr)).collect(),
+            bytecode,
+            data,
+            block_starts,
+            source_map: Vec::new(),
+            tape_length: config.tape_length,
+            wrap_tape: config.wrap_tape,
+            eof: config.eof,
+        }
+    }
+
+    /// How many cells the tape should have, as the program's config said when it was compiled.
+    /// The program can run on a tape of any length, but a program that was loaded from a file has
+    /// no other way of knowing how long its tape should be.
+    pub fn tape_length(&self) -> usize {
+        self.tape_length
+    }
+
+    /// The program's bytecode, e.g., for [disassemble].
+    pub fn bytecode(&self) -> &[Bytecode] {
+        &self.bytecode
+    }
+
+    /// Where each instruction came from, indexed by program counter. A [Bytecode::Terminate] added
+    /// to end the program doesn't come from anywhere, and programs loaded from a file have no
+    /// source map at all, so this can be shorter than [InterpretedProgram::bytecode].
+    pub fn source_map(&self) -> &[SourceMapEntry] {
+        &self.source_map
+    }
+
+    /// Where the given block of the CFG starts in the bytecode.
+    pub(crate) fn block_start(&self, label: BlockLabel) -> Option<usize> {
+        self.block_starts.get(&label).map(|start| start.0)
+    }
+
+    /// Runs the program like [BrainmuckProgram::run_with_custom_io], while counting how many
+    /// times each block of the CFG runs. This is slower than running it normally.
+    pub fn run_with_profile(
+        &self,
+        universe: &mut [u8],
+        putchar: PutChar,
+        getchar: GetChar,
+    ) -> Result<Profile, RuntimeError> {
+        let mut executed = vec![0u64; self.bytecode.len()];
+        let mut state = MachineState::default();
+        self.execute(&mut state, universe, putchar, getchar, |pc| {
+            executed[pc] += 1;
+            true
+        })?;
+
+        // Control always enters a block at its first instruction.
+        let counts = self
+            .block_starts
+            .iter()
+            .map(|(&label, start)| (label, executed.get(start.0).copied().unwrap_or(0)))
+            .collect();
+
+        Ok(Profile::new(counts))
+    }
+
+    /// Runs the bytecode from `state`, until the program ends, or until `visit` returns `false`.
+    /// `visit` is called with the program counter before every instruction.
+    ///
+    /// `state` is updated to where the program stopped, even if it stopped with an error (in which
+    /// case, the program counter is the instruction that caused it).
+    pub(crate) fn execute(
+        &self,
+        state: &mut MachineState,
+        universe: &mut [u8],
+        putchar: PutChar,
+        getchar: GetChar,
+        visit: impl FnMut(usize) -> bool,
+    ) -> Result<Outcome, RuntimeError> {
+        if self.wrap_tape {
+            self.execute_in_mode::<true>(state, universe, putchar, getchar, visit)
+        } else {
+            self.execute_in_mode::<false>(state, universe, putchar, getchar, visit)
+        }
+    }
+
+    /// See [InterpretedProgram::execute]. This is instantiated once for each tape mode, so that
+    /// moving the tape pointer doesn't have to check the mode every time.
+    fn execute_in_mode<const WRAP_TAPE: bool>(
+        &self,
+        state: &mut MachineState,
+        universe: &mut [u8],
+        putchar: PutChar,
+        getchar: GetChar,
+        mut visit: impl FnMut(usize) -> bool,
+    ) -> Result<Outcome, RuntimeError> {
+        let code = &self.decoded[..];
+        let len = universe.len();
+        let error = |pc| move |kind| runtime_error(kind, pc);
+
+        // Work on copies, so that they can live in registers:
+        let MachineState {
+            mut pc,
+            mut address,
+            mut storage,
+            mut executed,
+        } = *state;
+
+        // Instructions assume that the pointer is on the tape, which it isn't on an empty one.
+        if address >= len {
+            return Err(runtime_error(RuntimeErrorKind::AddressBeyondEnd, pc));
+        }
+
+        let mut run = || loop {
+            if !visit(pc) {
+                return Ok(Outcome::Paused);
+            }
+            executed += 1;
+            let Decoded {
+                opcode,
+                value,
+                operand,
+            } = code[pc];
+
+            pc = match opcode {
+                Opcode::ChangeVal => {
+                    universe[address] = universe[address].wrapping_add(value);
+                    pc + 1
+                }
+                Opcode::ChangeAddr => {
+                    address =
+                        offset_address(address, operand, len, WRAP_TAPE).map_err(error(pc))?;
+                    pc + 1
+                }
+                Opcode::BranchIfZero => {
+                    if universe[address] == 0 {
+                        operand as usize
+                    } else {
+                        pc + 1
+                    }
+                }
+                Opcode::BranchTo => operand as usize,
+                Opcode::Zero => {
+                    universe[address] = 0;
+                    pc + 1
+                }
+                Opcode::MulAdd => {
+                    let current = universe[address];
+                    // The original loop would not have touched anything if the value is zero.
+                    if current != 0 {
+                        let dst =
+                            offset_address(address, operand, len, WRAP_TAPE).map_err(error(pc))?;
+                        universe[dst] = universe[dst].wrapping_add(current.wrapping_mul(value));
+                    }
+                    pc + 1
+                }
+                Opcode::FindZero => {
+                    address =
+                        find_zero(universe, address, operand, WRAP_TAPE).map_err(error(pc))?;
+                    pc + 1
+                }
+                Opcode::ChangeValAt => {
+                    let cell =
+                        offset_address(address, operand, len, WRAP_TAPE).map_err(error(pc))?;
+                    universe[cell] = universe[cell].wrapping_add(value);
+                    pc + 1
+                }
+                Opcode::SetValAt => {
+                    let cell =
+                        offset_address(address, operand, len, WRAP_TAPE).map_err(error(pc))?;
+                    universe[cell] = value;
+                    pc + 1
+                }
+                Opcode::Terminate => return Ok(Outcome::Halted),
+                Opcode::Other => {
+                    let cell = &mut universe[address];
+                    self.execute_other(pc, cell, &mut storage, putchar, getchar);
+                    pc + 1
+                }
+            };
+        };
+        let status = run();
+
+        *state = MachineState {
+            pc,
+            address,
+            storage,
+            executed,
+        };
+        status
+    }
+
+    /// Runs one of the rarer instructions that aren't decoded.
+    #[cold]
+    #[inline(never)]
+    fn execute_other(
+        &self,
+        pc: usize,
+        cell: &mut u8,
+        storage: &mut u8,
+        putchar: PutChar,
+        getchar: GetChar,
+    ) {
+        match self.bytecode[pc] {
+            Bytecode::PrintChar => {
+                putchar(*cell as u32);
+            }
+            Bytecode::GetChar => {
+                self.eof.store(getchar(), cell);
+            }
+            Bytecode::Extended(op) => execute_extended(op, cell, storage),
+            Bytecode::EmitBytes { start, len } => {
+                for &byte in &self.data[start..start + len] {
+                    putchar(byte as u32);
+                }
+            }
+            Bytecode::NoOp => (),
+            instr => unreachable!("{:?} should have been decoded", instr),
+        }
+    }
+}
+
+/// Kept out of line, so that the interpreter loop stays small.
+#[cold]
+#[inline(never)]
+fn runtime_error(kind: RuntimeErrorKind, pc: usize) -> RuntimeError {
+    RuntimeError::new(kind, pc)
+}
+
+/// Packs the instruction into a [Decoded] instruction, if it can.
+fn decode(instr: Bytecode) -> Decoded {
+    use Bytecode::*;
+
+    let (opcode, value, operand) = match instr {
+        ChangeVal(value) => (Opcode::ChangeVal, value, 0),
+        ChangeAddr(offset) => (Opcode::ChangeAddr, 0, offset),
+        BranchIfZero(BranchTarget(target)) if target <= i32::MAX as usize => {
+            (Opcode::BranchIfZero, 0, target as i32)
+        }
+        BranchTo(BranchTarget(target)) if target <= i32::MAX as usize => {
+            (Opcode::BranchTo, 0, target as i32)
+        }
+        Zero => (Opcode::Zero, 0, 0),
+        MulAdd { dst_offset, factor } => (Opcode::MulAdd, factor, dst_offset),
+        FindZero(step) => (Opcode::FindZero, 0, step),
+        ChangeValAt(offset, value) => (Opcode::ChangeValAt, value, offset),
+        SetValAt(offset, value) => (Opcode::SetValAt, value, offset),
+        Terminate => (Opcode::Terminate, 0, 0),
+        PrintChar | GetChar | Extended(_) | EmitBytes { .. } | NoOp => (Opcode::Other, 0, 0),
+        BranchIfZero(_) | BranchTo(_) => panic!("program is too big to interpret"),
+    };
+
+    Decoded {
+        opcode,
+        value,
+        operand,
+    }
+}
+
+impl BrainmuckProgram for InterpretedProgram {
+    fn run_with_custom_io(
+        &self,
+        universe: &mut [u8],
+        putchar: PutChar,
+        getchar: GetChar,
+    ) -> Result<RunStats, RuntimeError> {
+        let mut state = MachineState::default();
+        self.execute(&mut state, universe, putchar, getchar, |_| true)?;
+
+        Ok(RunStats {
+            instructions_executed: Some(state.executed),
+            final_address: state.address,
+        })
+    }
+
+    fn source_position(&self, instruction: usize) -> Option<SourcePosition> {
+        Some(self.source_map.get(instruction)?.span?.start)
+    }
+}
+
+/// Runs an [InterpretedProgram] one step at a time, so that you can look at (and poke at) the
+/// program counter, the tape pointer, and the tape in between.
+pub struct Interpreter<'a> {
+    program: &'a InterpretedProgram,
+    tape: Vec<u8>,
+    state: MachineState,
+    putchar: PutChar,
+    getchar: GetChar,
+    /// Input that was pushed, rather than read with `getchar`. See [Interpreter::with_pushed_input].
+    pushed_input: Option<PushedInput>,
+    halted: bool,
+    breakpoints: HashSet<usize>,
+}
+
+/// Input that arrives whenever it arrives.
+#[derive(Default)]
+struct PushedInput {
+    bytes: VecDeque<u8>,
+    /// Whether there will never be any more bytes.
+    ended: bool,
+}
+
+impl<'a> Interpreter<'a> {
+    /// Starts the program at its first instruction, with the default IO (prints to `stdout`;
+    /// accepts input from `stdin`).
+    pub fn new(program: &'a InterpretedProgram, tape: Vec<u8>) -> Self {
+        Interpreter::with_custom_io(program, tape, program::putchar, program::getchar)
+    }
+
+    /// Like [Interpreter::new], but with IO routines of your choosing. See
+    /// [BrainmuckProgram::run_with_custom_io].
+    pub fn with_custom_io(
+        program: &'a InterpretedProgram,
+        tape: Vec<u8>,
+        putchar: PutChar,
+        getchar: GetChar,
+    ) -> Self {
+        Interpreter {
+            program,
+            tape,
+            state: MachineState::default(),
+            putchar,
+            getchar,
+            pushed_input: None,
+            halted: false,
+            breakpoints: HashSet::new(),
+        }
+    }
+
+    /// Like [Interpreter::with_custom_io], but instead of blocking to read input, the interpreter
+    /// stops with [Outcome::NeedsInput] whenever the program wants input that hasn't been pushed
+    /// yet. This lets a program be driven by an event loop, where input arrives whenever it does.
+    pub fn with_pushed_input(
+        program: &'a InterpretedProgram,
+        tape: Vec<u8>,
+        putchar: PutChar,
+    ) -> Self {
+        Interpreter {
+            pushed_input: Some(PushedInput::default()),
+            ..Interpreter::with_custom_io(program, tape, putchar, program::getchar)
+        }
+    }
+
+    /// Gives the program more input.
+    ///
+    /// # Panics
+    ///
+    /// If the interpreter wasn't made with [Interpreter::with_pushed_input].
+    pub fn push_input(&mut self, bytes: &[u8]) {
+        self.pushed_input_mut().bytes.extend(bytes);
+    }
+
+    /// Says that no more input is coming, so the program gets `EOF` once it reads everything
+    /// that was pushed.
+    ///
+    /// # Panics
+    ///
+    /// If the interpreter wasn't made with [Interpreter::with_pushed_input].
+    pub fn end_input(&mut self) {
+        self.pushed_input_mut().ended = true;
+    }
+
+    fn pushed_input_mut(&mut self) -> &mut PushedInput {
+        self.pushed_input
+            .as_mut()
+            .expect("input can only be pushed to an interpreter made with_pushed_input")
+    }
+
+    /// Executes the next instruction.
+    pub fn step(&mut self) -> Result<Outcome, RuntimeError> {
+        let mut budget = 1;
+        self.run_while(|_| {
+            budget -= 1;
+            budget >= 0
+        })
+    }
+
+    /// Runs until the program counter reaches `breakpoint`, or until the program ends. Always
+    /// executes at least one instruction, so calling it again carries on to the next time the
+    /// breakpoint is reached.
+    pub fn run_until(&mut self, breakpoint: usize) -> Result<Outcome, RuntimeError> {
+        let mut first = true;
+        self.run_while(|pc| std::mem::replace(&mut first, false) || pc != breakpoint)
+    }
+
+    /// Runs until the program counter reaches any of the breakpoints (returning
+    /// [Outcome::Paused]), or until the program ends. Like [Interpreter::run_until], it always
+    /// executes at least one instruction.
+    pub fn resume(&mut self) -> Result<Outcome, RuntimeError> {
+        let breakpoints = std::mem::take(&mut self.breakpoints);
+        let mut first = true;
+        let outcome =
+            self.run_while(|pc| std::mem::replace(&mut first, false) || !breakpoints.contains(&pc));
+        self.breakpoints = breakpoints;
+        outcome
+    }
+
+    /// Makes [Interpreter::resume] stop before executing the instruction at `pc`.
+    pub fn add_breakpoint(&mut self, pc: usize) {
+        self.breakpoints.insert(pc);
+    }
+
+    /// Returns whether there was a breakpoint at `pc`.
+    pub fn remove_breakpoint(&mut self, pc: usize) -> bool {
+        self.breakpoints.remove(&pc)
+    }
+
+    /// Adds a breakpoint at the start of every block of code on the given line of the source
+    /// text. Returns how many breakpoints that took, which is zero when nothing on that line made
+    /// it into the bytecode (e.g., it was a comment, or the optimizer folded it away).
+    pub fn add_breakpoints_on_line(&mut self, line: u32) -> usize {
+        let source_map = self.program.source_map();
+        let starts: Vec<_> = (0..source_map.len())
+            .filter(|&pc| pc == 0 || source_map[pc - 1].block != source_map[pc].block)
+            .filter(|&pc| matches!(source_map[pc].span, Some(span) if span.contains_line(line)))
+            .collect();
+
+        self.breakpoints.extend(starts.iter().copied());
+        starts.len()
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// Runs until the program ends, ignoring any breakpoints.
+    pub fn run_to_end(&mut self) -> Result<Outcome, RuntimeError> {
+        self.run_while(|_| true)
+    }
+
+    /// Runs until the program ends, or until it has executed `fuel` more instructions, whichever
+    /// comes first. Use this to put a hard bound on how long an untrusted program can run.
+    pub fn run_with_fuel(&mut self, fuel: u64) -> Result<Outcome, RuntimeError> {
+        let mut fuel = fuel;
+        let outcome = self.run_while(|_| match fuel.checked_sub(1) {
+            Some(left) => {
+                fuel = left;
+                true
+            }
+            None => false,
+        })?;
+
+        Ok(match outcome {
+            Outcome::Paused => Outcome::OutOfFuel,
+            outcome => outcome,
+        })
+    }
+
+    fn run_while(
+        &mut self,
+        keep_going: impl FnMut(usize) -> bool,
+    ) -> Result<Outcome, RuntimeError> {
+        if self.halted {
+            return Ok(Outcome::Halted);
+        }
+
+        let outcome = match self.pushed_input.take() {
+            None => self.program.execute(
+                &mut self.state,
+                &mut self.tape,
+                self.putchar,
+                self.getchar,
+                keep_going,
+            ),
+            Some(mut input) => {
+                let outcome = self.run_with_pushed_input(&mut input, keep_going);
+                self.pushed_input = Some(input);
+                outcome
+            }
+        }?;
+        self.halted = outcome == Outcome::Halted;
+        Ok(outcome)
+    }
+
+    /// Like [Interpreter::run_while], but reads from the pushed input, and stops right before
+    /// reading input that isn't there.
+    fn run_with_pushed_input(
+        &mut self,
+        input: &mut PushedInput,
+        mut keep_going: impl FnMut(usize) -> bool,
+    ) -> Result<Outcome, RuntimeError> {
+        let program = self.program;
+        // Every byte can be read once; after the end of the input, EOF can be read forever.
+        let mut available = input.bytes.len();
+        let ended = input.ended;
+        let mut starved = false;
+
+        let mut output = program::PutCharWriter(self.putchar);
+        let _redirected = program::Redirect::new(&mut input.bytes, &mut output);
+        let outcome = program.execute(
+            &mut self.state,
+            &mut self.tape,
+            program::redirected_putchar,
+            program::redirected_getchar,
+            |pc| {
+                if matches!(program.bytecode[pc], Bytecode::GetChar) {
+                    if available == 0 && !ended {
+                        starved = true;
+                        return false;
+                    }
+                    available = available.saturating_sub(1);
+                }
+                keep_going(pc)
+            },
+        )?;
+
+        Ok(match outcome {
+            Outcome::Paused if starved => Outcome::NeedsInput,
+            outcome => outcome,
+        })
+    }
+
+    /// The index of the next instruction to execute.
+    pub fn pc(&self) -> usize {
+        self.state.pc
+    }
+
+    /// The next instruction to execute.
+    pub fn instruction(&self) -> Bytecode {
+        self.program.bytecode[self.state.pc]
+    }
+
+    /// Where the next instruction came from, if known.
+    pub fn source(&self) -> Option<SourceMapEntry> {
+        self.program.source_map().get(self.state.pc).copied()
+    }
+
+    /// The address of the current cell.
+    pub fn pointer(&self) -> usize {
+        self.state.address
+    }
+
+    pub fn tape(&self) -> &[u8] {
+        &self.tape
+    }
+
+    pub fn tape_mut(&mut self) -> &mut [u8] {
+        &mut self.tape
+    }
+
+    /// How many instructions have been executed so far.
+    pub fn instructions_executed(&self) -> u64 {
+        self.state.executed
+    }
+
+    /// Whether the program has ended.
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn into_tape(self) -> Vec<u8> {
+        self.tape
+    }
+}
+
+// Runtime helpers, shared with the other interpreters:
+
+/// Returns the address `offset` cells away from `address`, wrapping around the tape if
+/// `wrap_tape` is set. It's an error to go beyond either end of the universe.
+#[inline]
+pub(crate) fn offset_address(
+    address: usize,
+    offset: i32,
+    universe_len: usize,
+    wrap_tape: bool,
+) -> Result<usize, RuntimeErrorKind> {
+    let address = (address as isize)
+        .checked_add(offset as isize)
+        .ok_or(RuntimeErrorKind::AddressBeyondEnd)?;
+    let address = match (wrap_tape, universe_len) {
+        // There's nothing to wrap around to:
+        (true, 0) => return Err(RuntimeErrorKind::AddressBeyondEnd),
+        (true, len) => address.rem_euclid(len as isize),
+        (false, _) => address,
+    };
+
+    if address < 0 {
+        Err(RuntimeErrorKind::AddressBelowZero)
+    } else if address as usize >= universe_len {
+        Err(RuntimeErrorKind::AddressBeyondEnd)
+    } else {
+        Ok(address as usize)
+    }
+}
+
+/// Returns the first address with a zero cell, starting from `address` and moving `step`
+/// cells at a time.
+pub(crate) fn find_zero(
+    universe: &[u8],
+    address: usize,
+    step: i32,
+    wrap_tape: bool,
+) -> Result<usize, RuntimeErrorKind> {
+    if !wrap_tape && step != 0 {
+        // Scan the slice directly, instead of checking bounds on every step:
+        let stride = step.unsigned_abs() as usize;
+        return if step > 0 {
+            match universe[address..]
+                .iter()
+                .step_by(stride)
+                .position(|&c| c == 0)
+            {
+                Some(n) => Ok(address + n * stride),
+                None => Err(RuntimeErrorKind::AddressBeyondEnd),
+            }
+        } else {
+            match universe[..=address]
+                .iter()
+                .rev()
+                .step_by(stride)
+                .position(|&c| c == 0)
+            {
+                Some(n) => Ok(address - n * stride),
+                None => Err(RuntimeErrorKind::AddressBelowZero),
+            }
+        };
+    }
+
+    let mut address = address;
+    while universe[address] != 0 {
+        address = offset_address(address, step, universe.len(), wrap_tape)?;
+    }
+
+    Ok(address)
+}
+
+/// Applies an Extended Brainfuck operation to the current cell and the storage register.
+pub(crate) fn execute_extended(op: ExtendedOp, cell: &mut u8, storage: &mut u8) {
+    match op {
+        ExtendedOp::Store => *storage = *cell,
+        ExtendedOp::Load => *cell = *storage,
+        ExtendedOp::ShiftLeft => *cell <<= 1,
+        ExtendedOp::ShiftRight => *cell >>= 1,
+        ExtendedOp::Not => *cell = !*cell,
+        ExtendedOp::Xor => *cell ^= *storage,
+        ExtendedOp::And => *cell &= *storage,
+        ExtendedOp::Or => *cell |= *storage,
+    }
+}
+
+/// "Bytecode" is a misnomer, but it's the best idea for what this is. It's pseudo-assembly and one
+/// can write an intrepretter for it pretty easily 👀
+#[derive(Debug, Clone, Copy)]
+pub enum Bytecode {
+    ChangeVal(u8),
+    ChangeAddr(i32),
+    PrintChar,
+    GetChar,
+    BranchIfZero(BranchTarget),
+    BranchTo(BranchTarget),
+    NoOp,
+    Zero,
+    MulAdd {
+        dst_offset: i32,
+        factor: u8,
+    },
+    FindZero(i32),
+    ChangeValAt(i32, u8),
+    SetValAt(i32, u8),
+    Terminate,
+    Extended(ExtendedOp),
+    /// Writes `len` bytes from the program's data, starting at `start`.
+    EmitBytes {
+        start: usize,
+        len: usize,
+    },
+}
+
+/// A concrete offset from the beginning of a program to a specific instruction.
+#[derive(Debug, Clone, Copy)]
+pub struct BranchTarget(pub usize);
+
+/// Convert a [ControlFlowGraph] to [Bytecode]. Also returns the data that [Bytecode::EmitBytes]
+/// refers to, and where each block starts.
+fn compile_cfg_to_bytecode(
+    cfg: &ControlFlowGraph,
+) -> (
+    Vec<Bytecode>,
+    Vec<u8>,
+    HashMap<BlockLabel, BranchTarget>,
+    Vec<SourceMapEntry>,
+) {
+    let mut branch_targets = HashMap::new();
+    let mut incomplete_instructions = Vec::new();
+    let mut code = Vec::new();
+    let mut data = Vec::new();
+    let mut source_map = Vec::new();
+    let mut pc = 0;
+
+    // A block's code goes on until the next block's code starts, wherever the optimizer put it.
+    let mut starts: Vec<_> = cfg.blocks().iter().filter_map(|b| b.position()).collect();
+    starts.sort();
+    let span_from = |start: SourcePosition| SourceSpan {
+        start,
+        end: starts.iter().copied().find(|&p| p > start),
+    };
+
+    // First pass. Generate code, but don't try making valid branch targets.
+    for block in cfg.blocks().iter() {
+        use ThreeAddressInstruction::*;
+
+        let block_id = block.label();
+        let instructions = block.instructions();
+        branch_targets.insert(block_id, BranchTarget(pc));
+        let source = SourceMapEntry {
+            block: block_id,
+            span: block.position().map(span_from),
+        };
+
+        for (i, &instr) in instructions.iter().enumerate() {
+            let bytecode = match instr {
+                ChangeVal(c) => Bytecode::ChangeVal(c),
+                ChangeAddr(c) => Bytecode::ChangeAddr(c),
+                PutChar => Bytecode::PrintChar,
+                GetChar => Bytecode::GetChar,
+                BranchIfZero(label) => {
+                    incomplete_instructions.push((pc, label));
+                    Bytecode::BranchIfZero(BranchTarget(0))
+                }
+                BranchTo(label) => {
+                    incomplete_instructions.push((pc, label));
+                    Bytecode::BranchTo(BranchTarget(0))
+                }
+                Zero => Bytecode::Zero,
+                MulAdd { dst_offset, factor } => Bytecode::MulAdd { dst_offset, factor },
+                FindZero(step) => Bytecode::FindZero(step),
+                ChangeValAt(offset, val) => Bytecode::ChangeValAt(offset, val),
+                SetValAt(offset, val) => Bytecode::SetValAt(offset, val),
+                NoOp => {
+                    continue;
+                }
+                Terminate => Bytecode::Terminate,
+                Extended(op) => Bytecode::Extended(op),
+                EmitBytes(bytes) => {
+                    let start = data.len();
+                    data.extend_from_slice(bytes.as_slice());
+
+                    // Consecutive output is written by one instruction.
+                    if let Some(Bytecode::EmitBytes { len, .. }) = code.last_mut() {
+                        if i > 0 && matches!(instructions[i - 1], EmitBytes(_)) {
+                            *len += bytes.as_slice().len();
+                            continue;
+                        }
+                    }
+                    Bytecode::EmitBytes {
+                        start,
+                        len: bytes.as_slice().len(),
+                    }
+                }
+            };
+            code.push(bytecode);
+            source_map.push(source);
+
+            pc += 1;
+        }
+    }
+
+    // Second pass: patch in branch targets
+    for (i, ref label) in incomplete_instructions {
+        use Bytecode::*;
+
+        let instr = code[i];
+        let target = *branch_targets
+            .get(label)
+            .expect("branch target should have been determined in the first pass");
+
+        code[i] = match instr {
+            BranchIfZero(_) => BranchIfZero(target),
+            BranchTo(_) => BranchTo(target),
+            _ => panic!("replacing branch not supported for {:?}", instr),
+        };
+    }
+
+    (code, data, branch_targets, source_map)
+}
+
+/// Returns [Bytecode] in a pseudo-assembly format. See [write_disassembly].
+pub fn disassemble(code: &[Bytecode]) -> String {
+    let mut out = String::new();
+    write_disassembly(&mut out, code).expect("writing to a String should not fail");
+    out
+}
+
+/// Writes [Bytecode] in a pseudo-assembly format, one numbered instruction per line. Every
+/// instruction that a branch can go to gets an `L<n>:` label first, where `n` is its index.
+pub fn write_disassembly(out: &mut impl fmt::Write, code: &[Bytecode]) -> fmt::Result {
+    let targets: HashSet<usize> = code
+        .iter()
+        .filter_map(|instr| match instr {
+            Bytecode::BranchIfZero(target) | Bytecode::BranchTo(target) => Some(target.0),
+            _ => None,
+        })
+        .collect();
+
+    for (i, instr) in code.iter().enumerate() {
+        if targets.contains(&i) {
+            writeln!(out, "L{}:", i)?;
+        }
+        writeln!(out, "{:4}: {}", i, instr)?;
+    }
+
+    Ok(())
+}
+
+impl fmt::Display for Bytecode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Bytecode::*;
+        match self {
+            ChangeVal(amount) => write!(f, "[bp] <- [bp] + #{}", amount),
+            ChangeAddr(amount) => write!(f, "bp <- bp + #{}", amount),
+            PrintChar => write!(f, "putchar [bp]"),
+            GetChar => write!(f, "getchar [bp]"),
+            BranchIfZero(target) => write!(f, "beq L{}", target.0),
+            BranchTo(target) => write!(f, "b L{}", target.0),
+            Zero => write!(f, "zro"),
+            MulAdd { dst_offset, factor } => {
+                write!(
+                    f,
+                    "[bp+{}] <- [bp+{}] + [bp] * #{}",
+                    dst_offset, dst_offset, factor
+                )
+            }
+            NoOp => write!(f, "nop"),
+            FindZero(step) => write!(f, "bp <- scan [bp] for #0 by #{}", step),
+            ChangeValAt(offset, amount) => {
+                write!(f, "[bp+{}] <- [bp+{}] + #{}", offset, offset, amount)
+            }
+            SetValAt(offset, val) => write!(f, "[bp+{}] <- #{}", offset, val),
+            Terminate => write!(f, "ret"),
+            Extended(op) => write!(f, "ext {} [bp]", op.to_char()),
+            EmitBytes { start, len } => write!(f, "putchar data[{}..{}]", start, start + len),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::RuntimeErrorKind;
+    use crate::parsing::parse;
+
+    fn ignore_output(_: u32) -> u32 {
+        1
+    }
+
+    fn one() -> u32 {
+        1
+    }
+
+    fn run(source: &[u8], universe: &mut [u8]) -> Result<RunStats, RuntimeError> {
+        let ast = parse("<test>", source).unwrap();
+        crate::compile_to_bytecode(&ast).run_with_custom_io(universe, ignore_output, one)
+    }
+
+    #[test]
+    fn moving_off_the_tape_is_an_error() {
+        let program = crate::compile_to_bytecode(&parse("<test>", b",<.").unwrap());
+        let error = program
+            .run_with_custom_io(&mut [0u8; 4], ignore_output, one)
+            .unwrap_err();
+        assert_eq!(RuntimeErrorKind::AddressBelowZero, error.kind());
+        assert!(matches!(
+            program.bytecode[error.instruction()],
+            Bytecode::ChangeAddr(-1)
+        ));
+
+        let error = run(b",[>+]", &mut [0u8; 4]).unwrap_err();
+        assert_eq!(RuntimeErrorKind::AddressBeyondEnd, error.kind());
+        assert!(error
+            .to_string()
+            .starts_with("error[BF0202]: address went beyond"));
+
+        let stats = run(b",>>>.", &mut [0u8; 4]).unwrap();
+        assert!(stats.instructions_executed.unwrap() > 0);
+    }
+
+    /// Times the interpreter on loops nested five deep. Run it with:
+    ///
+    /// ```text
+    /// cargo test --release -p brainmuck_core -- --ignored --nocapture benchmark
+    /// ```
+    #[test]
+    #[ignore]
+    fn benchmark_nested_loops() {
+        let ast = parse(
+            "<bench>",
+            b",+[>++++++++++++++++++++++++++++++++\
+              [>++++++++++++++++++++++++++++++++\
+              [>++++++++++++++++++++++++++++++++\
+              [>--[>+<--]<-]<-]<-]<-]>>>>>.",
+        )
+        .unwrap();
+        let program = crate::compile_to_bytecode(&ast);
+
+        let mut fastest = std::time::Duration::MAX;
+        for _ in 0..5 {
+            let start = std::time::Instant::now();
+            program
+                .run_with_custom_io(&mut [0u8; 8], ignore_output, one)
+                .unwrap();
+            fastest = fastest.min(start.elapsed());
+        }
+        println!("nested loops: {:?} (fastest of 5 runs)", fastest);
+    }
+
+    #[test]
+    fn offsets_never_overflow_or_panic() {
+        assert_eq!(
+            Err(RuntimeErrorKind::AddressBeyondEnd),
+            offset_address(4, i32::MAX, 8, false)
+        );
+        assert_eq!(
+            Err(RuntimeErrorKind::AddressBelowZero),
+            offset_address(4, i32::MIN, 8, false)
+        );
+        assert_eq!(Ok(3), offset_address(4, i32::MAX, 8, true));
+        assert_eq!(
+            Err(RuntimeErrorKind::AddressBeyondEnd),
+            offset_address(0, 1, 0, true)
+        );
+
+        let error = run(b"+", &mut []).unwrap_err();
+        assert_eq!(RuntimeErrorKind::AddressBeyondEnd, error.kind());
+    }
+
+    #[test]
+    fn steps_through_one_instruction_at_a_time() {
+        let program = crate::compile_to_bytecode(&parse("<test>", b",[>+<--]>.").unwrap());
+        let mut interpreter =
+            Interpreter::with_custom_io(&program, vec![0u8; 4], ignore_output, one);
+
+        assert_eq!(0, interpreter.pc());
+        assert!(matches!(interpreter.instruction(), Bytecode::GetChar));
+        assert_eq!(Outcome::Paused, interpreter.step().unwrap());
+        assert_eq!(1, interpreter.pc());
+        assert_eq!(&[1, 0, 0, 0], interpreter.tape());
+        assert_eq!(1, interpreter.instructions_executed());
+
+        // Stop every time the loop comes back around:
+        let start = interpreter.pc();
+        interpreter.tape_mut()[0] = 4;
+        interpreter.run_until(start).unwrap();
+        assert_eq!(start, interpreter.pc());
+        assert_eq!(0, interpreter.pointer());
+        assert_eq!(&[2, 1, 0, 0], interpreter.tape());
+
+        assert_eq!(Outcome::Halted, interpreter.run_to_end().unwrap());
+        assert!(interpreter.is_halted());
+        assert_eq!(Outcome::Halted, interpreter.step().unwrap());
+        assert_eq!(vec![0, 2, 0, 0], interpreter.into_tape());
+    }
+
+    #[test]
+    fn stops_when_it_runs_out_of_fuel() {
+        let program = crate::compile_to_bytecode(&parse("<test>", b"+[>+<]").unwrap());
+        let mut interpreter =
+            Interpreter::with_custom_io(&program, vec![0u8; 4], ignore_output, one);
+
+        assert_eq!(Outcome::OutOfFuel, interpreter.run_with_fuel(1000).unwrap());
+        assert_eq!(1000, interpreter.instructions_executed());
+        assert_eq!(Outcome::OutOfFuel, interpreter.run_with_fuel(500).unwrap());
+        assert_eq!(1500, interpreter.instructions_executed());
+
+        let program = crate::compile_to_bytecode(&parse("<test>", b",>+.").unwrap());
+        let mut interpreter =
+            Interpreter::with_custom_io(&program, vec![0u8; 4], ignore_output, one);
+        assert_eq!(Outcome::Halted, interpreter.run_with_fuel(1000).unwrap());
+        assert!(interpreter.instructions_executed() < 1000);
+    }
+
+    #[test]
+    fn disassembles_with_labels() {
+        let program = crate::compile_to_bytecode(&parse("<test>", b",[>+<--]").unwrap());
+        let expected = [
+            "   0: getchar [bp]",
+            "L1:",
+            "   1: beq L5",
+            "   2: [bp+1] <- [bp+1] + #1",
+            "   3: [bp] <- [bp] + #254",
+            "   4: b L1",
+            "L5:",
+            "   5: ret",
+            "",
+        ];
+        assert_eq!(expected.join("\n"), disassemble(program.bytecode()));
+    }
+
+    #[test]
+    fn stops_at_breakpoints() {
+        let program = crate::compile_to_bytecode(&parse("<test>", b",[>+<--]>.").unwrap());
+        let mut interpreter =
+            Interpreter::with_custom_io(&program, vec![0u8; 4], ignore_output, one);
+        interpreter.step().unwrap();
+        interpreter.tape_mut()[0] = 6;
+
+        // Stop at the start of the loop body:
+        let body = interpreter.pc() + 1;
+        interpreter.add_breakpoint(body);
+        let mut hits = 0;
+        while interpreter.resume().unwrap() == Outcome::Paused {
+            assert_eq!(body, interpreter.pc());
+            hits += 1;
+        }
+        assert_eq!(3, hits);
+        assert_eq!(&[0, 3, 0, 0], interpreter.tape());
+
+        assert!(interpreter.remove_breakpoint(body));
+        assert_eq!(0, interpreter.breakpoints().count());
+    }
+
+    #[test]
+    fn waits_for_input_that_has_not_arrived() {
+        thread_local! {
+            static OUTPUT: std::cell::RefCell<Vec<u8>> = const { std::cell::RefCell::new(Vec::new()) };
+        }
+        fn capture(c: u32) -> u32 {
+            OUTPUT.with(|output| output.borrow_mut().push(c as u8));
+            c
+        }
+
+        // Echoes everything until EOF:
+        let program = crate::compile_to_bytecode(&parse("<test>", b",+[-.,+]").unwrap());
+        let mut interpreter = Interpreter::with_pushed_input(&program, vec![0u8; 4], capture);
+
+        assert_eq!(Outcome::NeedsInput, interpreter.run_to_end().unwrap());
+        assert!(matches!(interpreter.instruction(), Bytecode::GetChar));
+        assert_eq!(Outcome::NeedsInput, interpreter.run_to_end().unwrap());
+
+        interpreter.push_input(b"ab");
+        assert_eq!(Outcome::NeedsInput, interpreter.run_to_end().unwrap());
+        assert_eq!(b"ab", &OUTPUT.with(|output| output.take())[..]);
+
+        interpreter.push_input(b"c");
+        interpreter.end_input();
+        assert_eq!(Outcome::Halted, interpreter.run_to_end().unwrap());
+        assert_eq!(b"c", &OUTPUT.with(|output| output.take())[..]);
+    }
+
+    #[test]
+    fn traces_instructions_back_to_the_source() {
+        let program = crate::compile_to_bytecode(&parse("<test>", b",\n[>.<-]\n<<").unwrap());
+        assert_eq!(program.bytecode().len(), program.source_map().len());
+
+        let error = program
+            .run_with_custom_io(&mut [0u8; 4], ignore_output, one)
+            .unwrap_err();
+        assert_eq!(
+            Some(3),
+            program.source_position(error.instruction()).map(|p| p.line)
+        );
+
+        let mut interpreter =
+            Interpreter::with_custom_io(&program, vec![0u8; 4], ignore_output, one);
+        assert!(interpreter.add_breakpoints_on_line(2) > 0);
+        assert_eq!(Outcome::Paused, interpreter.resume().unwrap());
+        let span = interpreter.source().and_then(|entry| entry.span).unwrap();
+        assert!(span.contains_line(2));
+    }
+}
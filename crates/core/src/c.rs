@@ -0,0 +1,143 @@
+//! Translates the optimized CFG to portable C, so that a program can be compiled ahead of time
+//! with any C compiler.
+//!
+//! Every basic block becomes a label, and branches become `goto`s, so the C has the same shape as
+//! the optimized program, rather than that of the original source. Like the JIT, the generated
+//! code does not check the bounds of the tape.
+
+use std::collections::HashSet;
+use std::fmt::Write;
+
+use crate::config::{EofBehavior, ProgramConfig};
+use crate::ir::{BlockLabel, ControlFlowGraph, ThreeAddressInstruction};
+use crate::parsing::ExtendedOp;
+
+/// Translates the CFG to a C program, with `main()` running the program.
+pub fn cfg_to_c(cfg: &ControlFlowGraph, config: &ProgramConfig) -> String {
+    use ThreeAddressInstruction::*;
+
+    let instructions = || cfg.blocks().iter().flat_map(|block| block.instructions());
+    // Labels that nothing jumps to would only be warnings.
+    let targets: HashSet<BlockLabel> = instructions()
+        .filter_map(|instr| match *instr {
+            BranchIfZero(target) | BranchTo(target) => Some(target),
+            _ => None,
+        })
+        .collect();
+    let uses_storage = instructions().any(|instr| matches!(instr, Extended(_)));
+
+    let mut c = String::new();
+    c.push_str("/* Generated by brainmuck. */\n");
+    c.push_str("#include <stdint.h>\n#include <stdio.h>\n\n");
+    writeln!(c, "#define TAPE_LENGTH {}", config.tape_length).unwrap();
+    if config.wrap_tape {
+        c.push_str("#define CELL(offset) tape[((p + (offset)) % TAPE_LENGTH + TAPE_LENGTH) % TAPE_LENGTH]\n");
+        c.push_str("#define MOVE(offset) (p = ((p + (offset)) % TAPE_LENGTH + TAPE_LENGTH) % TAPE_LENGTH)\n");
+    } else {
+        c.push_str("#define CELL(offset) tape[p + (offset)]\n");
+        c.push_str("#define MOVE(offset) (p += (offset))\n");
+    }
+    c.push_str("\nstatic uint8_t tape[TAPE_LENGTH];\n\n");
+
+    c.push_str("int main(void) {\n");
+    c.push_str("    long p = 0;\n");
+    if uses_storage {
+        c.push_str("    uint8_t storage = 0;\n");
+    }
+
+    for block in cfg.blocks() {
+        if targets.contains(&block.label()) {
+            writeln!(c, "L{}:;", block.label().0).unwrap();
+        }
+        for &instr in block.instructions() {
+            let statement = match instr {
+                NoOp => continue,
+                ChangeVal(x) => format!("CELL(0) += {};", x),
+                ChangeValAt(offset, x) => format!("CELL({}) += {};", offset, x),
+                Zero => "CELL(0) = 0;".to_owned(),
+                SetValAt(offset, x) => format!("CELL({}) = {};", offset, x),
+                MulAdd { dst_offset, factor } => {
+                    format!("CELL({}) += CELL(0) * {};", dst_offset, factor)
+                }
+                FindZero(step) => format!("while (CELL(0)) MOVE({});", step),
+                ChangeAddr(n) => format!("MOVE({});", n),
+                PutChar => "putchar(CELL(0));".to_owned(),
+                // EOF is -1, so it's already 255 once it's a cell.
+                GetChar => match config.eof {
+                    EofBehavior::MinusOne => "CELL(0) = getchar();".to_owned(),
+                    EofBehavior::Zero => {
+                        "{ int c = getchar(); CELL(0) = c == EOF ? 0 : c; }".to_owned()
+                    }
+                    EofBehavior::Unchanged => {
+                        "{ int c = getchar(); if (c != EOF) CELL(0) = c; }".to_owned()
+                    }
+                },
+                EmitBytes(bytes) => format!(
+                    "fwrite(\"{}\", 1, {}, stdout);",
+                    escape(bytes.as_slice()),
+                    bytes.as_slice().len()
+                ),
+                BranchIfZero(BlockLabel(l)) => format!("if (!CELL(0)) goto L{};", l),
+                BranchTo(BlockLabel(l)) => format!("goto L{};", l),
+                Terminate => "return 0;".to_owned(),
+                Extended(op) => match op {
+                    ExtendedOp::Store => "storage = CELL(0);",
+                    ExtendedOp::Load => "CELL(0) = storage;",
+                    ExtendedOp::ShiftLeft => "CELL(0) <<= 1;",
+                    ExtendedOp::ShiftRight => "CELL(0) >>= 1;",
+                    ExtendedOp::Not => "CELL(0) = ~CELL(0);",
+                    ExtendedOp::Xor => "CELL(0) ^= storage;",
+                    ExtendedOp::And => "CELL(0) &= storage;",
+                    ExtendedOp::Or => "CELL(0) |= storage;",
+                }
+                .to_owned(),
+            };
+            writeln!(c, "    {}", statement).unwrap();
+        }
+    }
+    c.push_str("}\n");
+
+    c
+}
+
+/// Escapes bytes for a C string literal. Octal escapes are used since, unlike hex escapes, they
+/// never swallow the characters that follow.
+fn escape(bytes: &[u8]) -> String {
+    let mut escaped = String::new();
+    for &byte in bytes {
+        match byte {
+            b'"' | b'\\' | b'?' => write!(escaped, "\\{}", byte as char).unwrap(),
+            b' '..=b'~' => escaped.push(byte as char),
+            _ => write!(escaped, "\\{:03o}", byte).unwrap(),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::parse;
+
+    #[test]
+    fn keeps_the_structure_of_the_cfg() {
+        let ast = parse(
+            "<test>",
+            b";# eof=0\n,[>++<-]>[.>]\n++++++++[>+++++++++<-]>.",
+        )
+        .unwrap();
+        let c = crate::compile_to_c(&ast);
+
+        assert!(c.contains("CELL(1) += CELL(0) * 2;"));
+        assert!(c.contains("{ int c = getchar(); CELL(0) = c == EOF ? 0 : c; }"));
+        assert!(c.contains("goto L"));
+        assert!(c.contains("#define CELL(offset) tape[p + (offset)]"));
+        assert!(!c.contains("storage"));
+        assert_eq!(1, c.matches("return 0;").count());
+    }
+
+    #[test]
+    fn escapes_output() {
+        assert_eq!(r#"Hi\" \\o/\?\012\000"#, escape(b"Hi\" \\o/?\n\0"));
+    }
+}
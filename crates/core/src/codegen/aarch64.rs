@@ -0,0 +1,674 @@
+//! Generates AArch64 machine code for a given program.
+
+use std::fmt;
+
+use super::{Label, Target};
+use crate::asm::aarch64::{self as asm, AArch64Assembly, Cond, W, X};
+use crate::asm::CodeBuffer;
+use crate::jit::output::{self, CAPACITY};
+use crate::unwind::{FrameDescription, Rule};
+use registers::Registers;
+
+mod registers;
+
+// REGISTERS:
+//
+// x0                 - working byte
+const VAL: W = W(0);
+// x19 (callee saved) - current pointer on the "tape" (during function)
+const ADDR: X = X(19);
+// x20 (callee saved) - getchar (during function)
+const GETCHAR: X = X(20);
+// x21 (callee saved) - the output buffer (during function)
+const OUT: X = X(21);
+// x22 (callee saved) - start of the tape (during function)
+const TAPE: X = X(22);
+// x9-x13             - scratch with a fixed purpose (caller-saved, so never live across calls)
+const TMP_ADDR: X = X(9);
+const TMP_VAL: W = W(10);
+// x12                - scratch for offsets too big for an immediate
+const TMP_OFFSET: X = X(12);
+// x13                - how many bytes are in the output buffer
+const TMP_LEN: X = X(13);
+// x11, x14, x15      - scratch for anything else; see Registers::scratch()
+// x0  (argument)     - pointer to universe (as argument); the output buffer to flush()
+// x1  (argument)     - the output buffer (as argument)
+// x2  (argument)     - getchar (as argument)
+// x3  (argument)     - start of the tape (as argument)
+// x0  (return)       - pointer to the current cell when the program terminated
+//
+// x29                - frame pointer
+const FP: X = X(29);
+// x30                - link register
+const LR: X = X(30);
+//
+// x31                - stack pointer or zero, depending on context
+const SP: X = X(31);
+const WZR: W = W(31);
+// see: https://en.wikipedia.org/wiki/Calling_convention#ARM_(A64)
+// also useful for addressing modes:
+// https://thinkingeek.com/2016/11/13/exploring-aarch64-assembler-chapter-5/
+
+/// Generates AArch64 machine code, following the Procedure Call Standard for the Arm 64-bit
+/// Architecture.
+pub struct AArch64 {
+    asm: AArch64Assembly,
+    registers: Registers,
+    wrap_mask: Option<u32>,
+    unwind_rules: Vec<(usize, Rule)>,
+}
+
+impl Target for AArch64 {
+    fn new() -> Self {
+        let mut registers = Registers::new();
+        for register in [ADDR, GETCHAR, OUT, TAPE] {
+            registers.keep(register);
+        }
+        for register in [TMP_ADDR, X(TMP_VAL.0), TMP_OFFSET, TMP_LEN] {
+            registers.reserve(register);
+        }
+
+        AArch64 {
+            asm: AArch64Assembly::new().with_peephole(),
+            registers,
+            wrap_mask: None,
+            unwind_rules: Vec::new(),
+        }
+    }
+
+    fn with_wrapping_tape(self, mask: u32) -> Self {
+        AArch64 {
+            wrap_mask: Some(mask),
+            ..self
+        }
+    }
+
+    fn with_listing(self) -> Self {
+        AArch64 {
+            asm: self.asm.with_listing(),
+            ..self
+        }
+    }
+
+    fn listing(&self) -> Option<String> {
+        self.asm.listing()
+    }
+
+    fn annotated_listing(&self) -> Option<String> {
+        self.asm.annotated_listing()
+    }
+
+    fn comment(&mut self, text: &dyn fmt::Display) {
+        self.asm.comment(text);
+    }
+
+    // STACK
+    //
+    // $fp == $sp + 0x00 [previous  fp] | Frame record
+    //        $sp + 0x08 [previous  lr] |
+    //        $sp + 0x10 [previous x19]
+    //        $sp + 0x18 [previous x20]
+    //        $sp + 0x20 [previous x21]
+    //        $sp + 0x28 [previous x22]
+    //
+    // (Or whichever registers are kept; see Registers.)
+
+    // REGISTERS
+    //
+    // x19 <- pointer into the universe
+    // x20 <- pointer to getchar()
+    // x21 <- pointer to the output buffer
+    // x22 <- start of the universe
+
+    fn prologue(&mut self) {
+        //  stp x29, x30, [sp, #-0x30]!
+        //  stp x19, x20, [sp, #0x10]
+        //  stp x21, x22, [sp, #0x20]
+        let frame = self.registers.frame_size();
+        self.unwind(Rule::Cfa {
+            register: SP.0,
+            offset: 0,
+        });
+        self.asm.stp_preindex(FP, LR, SP, -frame);
+        self.unwind(Rule::CfaOffset(frame as u32));
+        self.unwind_saved(FP, -frame);
+        self.unwind_saved(LR, 8 - frame);
+        for (offset, pair) in self.kept_registers() {
+            match pair[..] {
+                [first, second] => self.asm.stp_offset(first, second, SP, offset),
+                [first] => self.asm.str_imm(first, SP, offset as u16),
+                _ => unreachable!(),
+            }
+            for (i, &register) in pair.iter().enumerate() {
+                self.unwind_saved(register, offset + 8 * i as i16 - frame);
+            }
+        }
+
+        // Let the frame pointer point to the current frame record
+        // -- this allows backtraces to work, since the frame pointer,
+        //    and all the frame records is a linked-list of stack frames
+        self.asm.add64(FP, SP, 0);
+        self.unwind(Rule::Cfa {
+            register: FP.0,
+            offset: frame as u32,
+        });
+
+        // mov x19, x0
+        // mov x21, x1
+        // mov x20, x2
+        // mov x22, x3
+        self.asm.mov(ADDR, X(0));
+        self.asm.mov(OUT, X(1));
+        self.asm.mov(GETCHAR, X(2));
+        self.asm.mov(TAPE, X(3));
+    }
+
+    fn epilogue(&mut self) {
+        // Return where the tape pointer ended up, so that a caller that jumped into the middle of
+        // a program (e.g., the tiered runner) can pick up from there.
+        // mov x0, x19
+        self.asm.mov(X(0), ADDR);
+
+        // ldp x19, x20, [sp, #0x10]
+        // ldp x21, x22, [sp, #0x20]
+        // ldp x29, x30, [sp], #0x30
+        for (offset, pair) in self.kept_registers() {
+            match pair[..] {
+                [first, second] => self.asm.ldp_offset(first, second, SP, offset),
+                [first] => self.asm.ldr_imm(first, SP, offset),
+                _ => unreachable!(),
+            }
+        }
+        self.asm
+            .ldp_postindex(FP, LR, SP, self.registers.frame_size());
+        self.asm.ret();
+    }
+
+    fn set_label(&mut self, Label(l): Label) {
+        self.asm.bind(asm::Label(l));
+    }
+
+    fn branch(&mut self, Label(l): Label) {
+        // b    L*
+        self.asm.b(asm::Label(l));
+    }
+
+    fn branch_if_zero(&mut self, Label(l): Label) {
+        // ldbr     x0, [x19]
+        self.asm.ldrb(VAL, ADDR, 0);
+        // cbz    w0, L*
+        self.asm.cbz(VAL, asm::Label(l));
+    }
+
+    fn branch_if_not_zero(&mut self, Label(l): Label) {
+        // ldrb     w0, [x19]
+        self.asm.ldrb(VAL, ADDR, 0);
+        // cbnz    w0, L*
+        self.asm.cbnz(VAL, asm::Label(l));
+    }
+
+    fn add_to_cell(&mut self, offset: i32, x: u8) {
+        let addr = self.cell_address(offset);
+        self.change_val_at(addr, x);
+    }
+
+    fn set_cell(&mut self, offset: i32, x: u8) {
+        let addr = self.cell_address(offset);
+        if x == 0 {
+            self.asm.strb(WZR, addr, 0);
+        } else {
+            self.asm.movz(TMP_VAL, x as u16);
+            self.asm.strb(TMP_VAL, addr, 0);
+        }
+    }
+
+    fn multiply_add(&mut self, offset: i32, factor: u8) {
+        // [x19 + offset] <- [x19 + offset] + w0 * factor
+        self.asm.ldrb(VAL, ADDR, 0);
+        self.offset_address(TMP_ADDR, offset);
+        self.asm.ldrb(TMP_VAL, TMP_ADDR, 0);
+        let tmp_factor = self.registers.scratch();
+        self.asm.movz(W(tmp_factor.0), factor as u16);
+        self.asm.madd(TMP_VAL, VAL, W(tmp_factor.0), TMP_VAL);
+        self.registers.release(tmp_factor);
+        self.asm.strb(TMP_VAL, TMP_ADDR, 0);
+    }
+
+    fn move_pointer(&mut self, x: i32) {
+        self.offset_address(ADDR, x);
+    }
+
+    fn touch_cell(&mut self, offset: i32) {
+        // ldrb w10, [x19 + offset]
+        let addr = self.cell_address(offset);
+        self.asm.ldrb(TMP_VAL, addr, 0);
+    }
+
+    fn buffer_cell(&mut self) {
+        self.asm.ldrb(VAL, ADDR, 0);
+        self.append_to_buffer();
+    }
+
+    fn buffer_byte(&mut self, byte: u8) {
+        self.asm.movz(VAL, byte as u16);
+        self.append_to_buffer();
+    }
+
+    fn branch_if_newline(&mut self, Label(l): Label) {
+        // cmp w0, #'\n'
+        // b.eq L*
+        self.asm.cmp(VAL, b'\n' as u16);
+        self.asm.b_cond(Cond::Eq, asm::Label(l));
+    }
+
+    fn branch_if_buffer_full(&mut self, Label(l): Label) {
+        const _: () = assert!(CAPACITY.is_multiple_of(1 << 12) && CAPACITY < 1 << 24);
+        // sub x12, x13, #CAPACITY
+        // cbz w12, L*
+        self.asm
+            .sub64_lsl12(TMP_OFFSET, TMP_LEN, (CAPACITY >> 12) as u16);
+        self.asm.cbz(W(TMP_OFFSET.0), asm::Label(l));
+    }
+
+    fn flush_output(&mut self) {
+        // mov x0, x21
+        // ldr x9, [x21, #FLUSH]
+        // blr x9
+        self.asm.mov(X(0), OUT);
+        self.asm.ldr_imm(TMP_ADDR, OUT, output::FLUSH as i16);
+        self.asm.blr(TMP_ADDR);
+    }
+
+    fn get_char(&mut self) {
+        self.asm.blr(GETCHAR);
+    }
+
+    fn branch_if_eof(&mut self, Label(l): Label) {
+        // EOF is 0xFFFFFFFF, so it's the only value that's zero after adding one.
+        self.asm.add(TMP_VAL, VAL, 1);
+        self.asm.cbz(TMP_VAL, asm::Label(l));
+    }
+
+    fn store_input(&mut self) {
+        self.asm.strb(VAL, ADDR, 0);
+    }
+
+    fn call_stub(&mut self, stub: usize, index: u32) {
+        // movz x0, #index (; movk x0, #index >> 16, lsl #16)
+        // mov x1, x19
+        // mov x2, x21
+        // movz x9, #stub ; movk x9, ... (up to four halves)
+        // blr x9
+        // mov x19, x0
+        self.asm.load_imm64(X(0), index as u64);
+        self.asm.mov(X(1), ADDR);
+        self.asm.mov(X(2), OUT);
+        self.asm.load_imm64(TMP_ADDR, stub as u64);
+        self.asm.blr(TMP_ADDR);
+        self.asm.mov(ADDR, X(0));
+    }
+
+    fn call_function(&mut self, function: usize) {
+        // mov x0, x19
+        // mov x1, x21
+        // mov x2, x20
+        // mov x3, x22
+        // movz x9, #function ; movk x9, ... (up to four halves)
+        // blr x9
+        // mov x19, x0
+        self.asm.mov(X(0), ADDR);
+        self.asm.mov(X(1), OUT);
+        self.asm.mov(X(2), GETCHAR);
+        self.asm.mov(X(3), TAPE);
+        self.asm.load_imm64(TMP_ADDR, function as u64);
+        self.asm.blr(TMP_ADDR);
+        self.asm.mov(ADDR, X(0));
+    }
+
+    fn count(&mut self, counter: usize) {
+        // movz x9, #counter ; movk x9, ... (up to four halves)
+        // ldr x11, [x9]
+        // add x11, x11, #1
+        // str x11, [x9]
+        self.asm.load_imm64(TMP_ADDR, counter as u64);
+        let count = self.registers.scratch();
+        self.asm.ldr_imm(count, TMP_ADDR, 0);
+        self.asm.add64(count, count, 1);
+        self.asm.str_imm(count, TMP_ADDR, 0);
+        self.registers.release(count);
+    }
+
+    fn offset(&self) -> usize {
+        self.asm.offset()
+    }
+
+    fn frame_description(&self) -> FrameDescription {
+        FrameDescription {
+            // DWARF numbers registers the same way AArch64 does.
+            return_address_register: LR.0,
+            rules: self.unwind_rules.clone(),
+        }
+    }
+
+    fn machine_code(&mut self) -> &[u8] {
+        self.asm.patch_branch_targets();
+        self.asm.machine_code()
+    }
+
+    fn take_machine_code(&mut self) -> CodeBuffer {
+        self.asm.patch_branch_targets();
+        self.asm.take_machine_code()
+    }
+}
+
+impl AArch64 {
+    /// Where each pair of kept registers is saved in the stack frame (with a lone register at the
+    /// end, when there's an odd number of them).
+    fn kept_registers(&self) -> Vec<(i16, Vec<X>)> {
+        self.registers
+            .kept()
+            .chunks(2)
+            .enumerate()
+            .map(|(i, pair)| (16 + 16 * i as i16, pair.to_vec()))
+            .collect()
+    }
+
+    /// Adds a rule for unwinding that holds from the next instruction on.
+    fn unwind(&mut self, rule: Rule) {
+        self.unwind_rules.push((self.asm.offset(), rule));
+    }
+
+    fn unwind_saved(&mut self, register: X, offset: i16) {
+        self.unwind(Rule::Saved {
+            register: register.0,
+            offset: offset.into(),
+        });
+    }
+
+    /// Appends the byte in `w0` to the output buffer, leaving the buffer's new length in `x13`.
+    fn append_to_buffer(&mut self) {
+        // ldr x13, [x21, #LEN]
+        // add x9, x21, #BYTES
+        // strb w0, [x9, x13]
+        // add x13, x13, #1
+        // str x13, [x21, #LEN]
+        self.asm.ldr_imm(TMP_LEN, OUT, output::LEN as i16);
+        self.asm.add64(TMP_ADDR, OUT, output::BYTES as u16);
+        self.asm.strb_reg(VAL, TMP_ADDR, TMP_LEN);
+        self.asm.add64(TMP_LEN, TMP_LEN, 1);
+        self.asm.str_imm(TMP_LEN, OUT, output::LEN as u16);
+    }
+
+    /// Sets `dst` to the address `offset` cells away from the current cell. Any offset works, but
+    /// offsets under 4096 cells (i.e., almost all of them) take a single instruction, plus three
+    /// more when the tape wraps.
+    fn offset_address(&mut self, dst: X, offset: i32) {
+        self.add_offset(dst, offset);
+
+        match self.wrap_mask {
+            None => (),
+            // There's only one cell, and no way to encode an AND with nothing.
+            Some(0) => self.asm.mov(dst, TAPE),
+            Some(mask) => {
+                // sub dst, dst, x22
+                // and dst, dst, #mask
+                // add dst, x22, dst
+                self.asm.sub64_reg(dst, dst, TAPE);
+                self.asm
+                    .and64_low_bits(dst, dst, mask.trailing_ones() as u8);
+                self.asm.add64_reg(dst, TAPE, dst);
+            }
+        }
+    }
+
+    fn add_offset(&mut self, dst: X, offset: i32) {
+        let magnitude = offset.unsigned_abs();
+        let negative = offset < 0;
+
+        if magnitude >= 1 << 24 {
+            // Too big for two shifted immediates, so put it in a register first.
+            self.asm.load_imm64(TMP_OFFSET, magnitude as u64);
+            if negative {
+                self.asm.sub64_reg(dst, ADDR, TMP_OFFSET);
+            } else {
+                self.asm.add64_reg(dst, ADDR, TMP_OFFSET);
+            }
+            return;
+        }
+
+        let (high, low) = ((magnitude >> 12) as u16, (magnitude & 0xFFF) as u16);
+        let mut src = ADDR;
+        if high != 0 {
+            if negative {
+                self.asm.sub64_lsl12(dst, src, high);
+            } else {
+                self.asm.add64_lsl12(dst, src, high);
+            }
+            src = dst;
+        }
+        if low != 0 || high == 0 {
+            if negative {
+                self.asm.sub64(dst, src, low);
+            } else {
+                self.asm.add64(dst, src, low);
+            }
+        }
+    }
+
+    /// Returns a register holding the address of the cell at the offset from the current cell.
+    fn cell_address(&mut self, offset: i32) -> X {
+        if offset == 0 {
+            return ADDR;
+        }
+
+        self.offset_address(TMP_ADDR, offset);
+        TMP_ADDR
+    }
+
+    fn change_val_at(&mut self, addr: X, x: u8) {
+        // x0 <- *p
+        self.asm.ldrb(VAL, addr, 0);
+
+        if (x as i8) >= 0 {
+            // x0 <- x0 + x
+            self.asm.add(VAL, VAL, x as u16);
+        } else {
+            // x0 <- x0 - x
+            self.asm.sub(VAL, VAL, -(x as i8) as u16);
+        }
+
+        // *p = x0
+        self.asm.strb(VAL, addr, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EofBehavior;
+    use crate::ir::{BasicBlock, BlockLabel, ControlFlowGraph, ThreeAddressInstruction};
+
+    type CodeGenerator = crate::codegen::CodeGenerator<AArch64>;
+
+    /// Compiles one block of instructions (plus `terminate`), and returns the machine code as
+    /// 32-bit words.
+    fn compile(instructions: Vec<ThreeAddressInstruction>) -> Vec<u32> {
+        let mut instructions = instructions;
+        instructions.push(ThreeAddressInstruction::Terminate);
+        let cfg = ControlFlowGraph::new(vec![BasicBlock::new(BlockLabel(0), instructions)]);
+
+        CodeGenerator::new()
+            .compile(&cfg)
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn moves_by_offsets_of_any_size() {
+        use ThreeAddressInstruction::*;
+
+        // Straight from the target, since the code generator touches cells on the way past a guard.
+        let length = |x| {
+            let mut target = AArch64::new();
+            target.move_pointer(x);
+            target.machine_code().len() / 4
+        };
+
+        // One immediate, then two, then a register:
+        assert_eq!(1, length(4095));
+        assert_eq!(1, length(-4095));
+        assert_eq!(1, length(1 << 12));
+        assert_eq!(2, length(4097));
+        assert_eq!(2, length(-5000));
+        assert_eq!(2, length((1 << 24) - 1));
+        assert_eq!(2, length(1 << 24));
+        assert_eq!(2, length(-(1 << 24)));
+        assert_eq!(3, length(i32::MAX));
+        assert_eq!(2, length(i32::MIN));
+
+        // add x19, x19, #1, lsl #12 ; add x19, x19, #904
+        let code = compile(vec![ChangeAddr(5000)]);
+        assert!(code.windows(2).any(|w| w == [0x91400673, 0x910e2273]));
+
+        // The destination of a multiply loop can be just as far away.
+        compile(vec![MulAdd {
+            dst_offset: 70_000,
+            factor: 2,
+        }]);
+    }
+
+    #[test]
+    fn only_checks_for_eof_when_it_matters() {
+        use ThreeAddressInstruction::GetChar;
+
+        let cfg = ControlFlowGraph::new(vec![BasicBlock::new(
+            BlockLabel(0),
+            vec![GetChar, ThreeAddressInstruction::Terminate],
+        )]);
+        let length = |eof| {
+            CodeGenerator::new()
+                .with_eof_behavior(eof)
+                .compile(&cfg)
+                .len()
+                / 4
+        };
+        let baseline = compile(vec![]).len();
+
+        // Output is flushed first: mov x0, x21 ; ldr x9, [x21, #8] ; blr x9
+        // blr x20 ; strb w0, [x19]
+        assert_eq!(baseline + 5, length(EofBehavior::MinusOne));
+        // blr x20 ; add w10, w0, #1 ; cbz w10, eof ; strb w0, [x19]
+        // (eof is right after that, so there's no b done to skip over it.)
+        assert_eq!(baseline + 7, length(EofBehavior::Unchanged));
+        // ...then b done ; eof: strb wzr, [x19]
+        assert_eq!(baseline + 9, length(EofBehavior::Zero));
+    }
+
+    #[test]
+    fn wraps_around_the_tape() {
+        use ThreeAddressInstruction::*;
+
+        let cfg = ControlFlowGraph::new(vec![BasicBlock::new(
+            BlockLabel(0),
+            vec![ChangeAddr(-1), Terminate],
+        )]);
+        let code: Vec<u32> = CodeGenerator::new()
+            .with_wrapping_tape(256)
+            .compile(&cfg)
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+
+        // sub x19, x19, #1 ; sub x19, x19, x22 ; and x19, x19, #0xff ; add x19, x22, x19
+        assert!(code
+            .windows(4)
+            .any(|w| w == [0xd1000673, 0xcb160273, 0x92401e73, 0x8b1302d3]));
+    }
+
+    #[test]
+    fn lists_the_assembly_with_the_ir_it_came_from() {
+        use crate::parsing::parse;
+
+        let ast = parse("<test>", b",[->+<]>.").unwrap();
+        let listing = crate::compile_to_aarch64_listing(&ast);
+        let lines: Vec<_> = listing.lines().collect();
+
+        assert_eq!("    // prologue", lines[0]);
+        assert_eq!("    stp x29, x30, [sp, #-48]!", lines[1]);
+        assert!(lines.contains(&"L0:"));
+        assert!(lines.contains(&"    // getchar"));
+        assert!(lines.contains(&"    madd w10, w0, w11, w10"));
+        assert!(lines.contains(&"    stp x21, x22, [sp, #32]"));
+        assert!(lines.contains(&"    ldp x29, x30, [sp], #48"));
+        assert!(lines.contains(&"    strb wzr, [x19, #0]"));
+        assert_eq!("    ret x30", lines[lines.len() - 1]);
+
+        // One line for each instruction, plus labels and comments:
+        let instructions = lines
+            .iter()
+            .filter(|line| !line.ends_with(':') && !line.trim_start().starts_with("//"))
+            .count();
+        let code = CodeGenerator::new()
+            .compile(&crate::ast_to_optimized_cfg(&ast))
+            .len();
+        assert_eq!(code, instructions * 4);
+    }
+
+    #[test]
+    fn annotates_the_listing_with_the_code_it_generated() {
+        use crate::parsing::parse;
+
+        let ast = parse("<test>", b"+[>,.<-]").unwrap();
+        let listing = crate::compile_to_annotated_aarch64_listing(&ast);
+        let encodings: Vec<_> = listing
+            .lines()
+            .filter_map(|line| line.trim_start().split_once(":  "))
+            .map(|(_, rest)| u32::from_str_radix(&rest[..8], 16).unwrap())
+            .collect();
+
+        let code: Vec<_> = CodeGenerator::new()
+            .compile(&crate::ast_to_optimized_cfg(&ast))
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+        assert_eq!(code, encodings);
+    }
+
+    #[test]
+    fn branches_back_into_loops_past_the_check() {
+        use crate::parsing::parse;
+
+        let listing = crate::compile_to_aarch64_listing(&parse("<test>", b",[.-]").unwrap());
+        let lines: Vec<_> = listing.lines().collect();
+
+        // The header checks the cell once, on the way in...
+        assert_eq!(
+            1,
+            lines.iter().filter(|line| line.contains("cbz w0")).count()
+        );
+        // ...and the back edge checks it again, and goes straight back to the body. The cell was
+        // just stored, so it doesn't need loading again.
+        let back_edge = lines
+            .iter()
+            .position(|line| line.starts_with("    cbnz w0, "))
+            .unwrap();
+        assert_eq!("    strb w0, [x19, #0]", lines[back_edge - 3]);
+        assert_eq!("    // b\tL1", lines[back_edge - 2]);
+        assert_eq!("    uxtb w0, w0", lines[back_edge - 1]);
+        let body = &lines[back_edge]["    cbnz w0, ".len()..];
+        assert!(lines.contains(&format!("{}:", body).as_str()));
+    }
+
+    #[test]
+    fn scans_with_strides_of_any_size() {
+        use ThreeAddressInstruction::FindZero;
+
+        // add x19, x19, #1, lsl #12
+        let code = compile(vec![FindZero(4096)]);
+        assert!(code.contains(&0x91400673));
+        let code = compile(vec![FindZero(-(1 << 30))]);
+        // movz x12, #0x4000, lsl #16 ; sub x19, x19, x12
+        assert!(code.windows(2).any(|w| w == [0xd2a8000c, 0xcb0c0273]));
+    }
+}
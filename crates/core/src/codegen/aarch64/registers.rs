@@ -0,0 +1,128 @@
+//! Keeps track of which registers the generated code uses, so that values don't step on each other,
+//! and so that the prologue saves exactly the callee-saved registers that the code uses.
+
+use crate::asm::aarch64::X;
+
+/// Registers that a function can use, but must restore before it returns.
+const CALLEE_SAVED: std::ops::RangeInclusive<u8> = 19..=28;
+/// Registers that a function can use freely, as long as it's done with them before any call.
+/// (x16 and x17 are for the linker, and x18 is for the platform.)
+const CALLER_SAVED: std::ops::RangeInclusive<u8> = 9..=15;
+
+/// Which registers are spoken for.
+pub(super) struct Registers {
+    /// Callee-saved registers that hold a value for the whole function, in the order they're
+    /// saved.
+    kept: Vec<X>,
+    /// Scratch registers that are in use, or reserved for a fixed purpose, by number.
+    taken: u32,
+}
+
+impl Registers {
+    pub fn new() -> Self {
+        Registers {
+            kept: Vec::new(),
+            taken: 0,
+        }
+    }
+
+    /// Keeps a value in a callee-saved register for the whole function. The prologue saves the
+    /// register, and the epilogue restores it, so this has to come before the prologue.
+    pub fn keep(&mut self, register: X) {
+        assert!(
+            CALLEE_SAVED.contains(&register.0),
+            "{} is not callee-saved",
+            register
+        );
+        assert!(
+            self.kept.iter().all(|kept| kept.0 != register.0),
+            "{} is already kept",
+            register
+        );
+        self.kept.push(register);
+    }
+
+    /// Reserves a scratch register for a fixed purpose, so that [Registers::scratch] never hands
+    /// it out.
+    pub fn reserve(&mut self, register: X) {
+        assert!(
+            CALLER_SAVED.contains(&register.0),
+            "{} is not a scratch register",
+            register
+        );
+        assert!(!self.is_taken(register), "{} is already taken", register);
+        self.taken |= 1 << register.0;
+    }
+
+    /// A scratch register that nothing else is using, until it's [released](Registers::release).
+    /// It doesn't survive calls.
+    ///
+    /// # Panics
+    ///
+    /// When every scratch register is taken.
+    pub fn scratch(&mut self) -> X {
+        let register = CALLER_SAVED
+            .map(X)
+            .find(|&register| !self.is_taken(register))
+            .expect("ran out of scratch registers");
+        self.taken |= 1 << register.0;
+        register
+    }
+
+    /// Gives back a register from [Registers::scratch].
+    pub fn release(&mut self, register: X) {
+        assert!(self.is_taken(register), "{} was not taken", register);
+        self.taken &= !(1 << register.0);
+    }
+
+    /// The callee-saved registers that the prologue saves, in order.
+    pub fn kept(&self) -> &[X] {
+        &self.kept
+    }
+
+    /// How big the stack frame is: the frame record (the frame pointer and the link register),
+    /// then the kept registers, rounded up to keep the stack 16-byte aligned.
+    pub fn frame_size(&self) -> i16 {
+        (16 + 8 * self.kept.len() as i16 + 15) & !15
+    }
+
+    fn is_taken(&self, register: X) -> bool {
+        self.taken & (1 << register.0) != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hands_out_each_scratch_register_once() {
+        let mut registers = Registers::new();
+        registers.reserve(X(9));
+        registers.reserve(X(10));
+
+        let (a, b) = (registers.scratch(), registers.scratch());
+        assert_eq!((11, 12), (a.0, b.0));
+        registers.release(a);
+        assert_eq!(11, registers.scratch().0);
+        assert_eq!(13, registers.scratch().0);
+    }
+
+    #[test]
+    fn sizes_the_frame_for_the_kept_registers() {
+        let mut registers = Registers::new();
+        assert_eq!(16, registers.frame_size());
+        registers.keep(X(19));
+        assert_eq!(32, registers.frame_size());
+        registers.keep(X(20));
+        assert_eq!(32, registers.frame_size());
+        registers.keep(X(21));
+        assert_eq!(48, registers.frame_size());
+    }
+
+    #[test]
+    #[should_panic(expected = "x9 is not callee-saved")]
+    fn only_keeps_callee_saved_registers() {
+        Registers::new().keep(X(9));
+    }
+}
@@ -0,0 +1,748 @@
+//! Generates machine code for a given program.
+//!
+//! [CodeGenerator] walks the CFG, and asks a [Target] for the machine code of each operation.
+//! There's a target for each architecture, and [NativeCodeGenerator] uses the one for the machine
+//! this is running on. All targets are always built, so that any of them can be tested anywhere.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::asm::CodeBuffer;
+use crate::config::{EofBehavior, ProgramConfig};
+use crate::guard::GUARD_SIZE;
+use crate::ir::{BlockLabel, ControlFlowGraph, ThreeAddressInstruction};
+use crate::parsing::SourcePosition;
+use crate::unwind::FrameDescription;
+
+pub(crate) mod aarch64;
+#[cfg_attr(not(target_arch = "x86_64"), allow(dead_code))]
+pub(crate) mod x86_64;
+
+#[cfg(not(target_arch = "x86_64"))]
+type Native = aarch64::AArch64;
+#[cfg(target_arch = "x86_64")]
+type Native = x86_64::X86_64;
+
+/// Generates code for the machine this is running on.
+pub type NativeCodeGenerator = CodeGenerator<Native>;
+
+/// Whether there's a code generator for the machine this is running on. Everywhere else, programs
+/// have to be interpreted.
+pub const NATIVE_CODE_SUPPORTED: bool = cfg!(any(target_arch = "aarch64", target_arch = "x86_64"));
+
+/// Whether generated code can wrap around a tape of this length. Wrapping takes a single AND, so
+/// the length has to be a power of two, and the mask has to fit in an immediate on every target.
+pub fn can_wrap_tape(tape_length: usize) -> bool {
+    tape_length.is_power_of_two() && tape_length <= 1 << 31
+}
+
+/// How to generate native code. The defaults are what [crate::compile_to_native_code] does for a
+/// program with the default [ProgramConfig].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodegenOptions {
+    /// How many cells the tape has. The code only depends on it when the tape wraps.
+    pub tape_length: usize,
+    /// How many bits are in each cell. Only 8-bit cells are supported.
+    pub cell_bits: u8,
+    /// Whether the pointer wraps around the tape, instead of being trusted to stay on it.
+    pub wrap_tape: bool,
+    /// What `,` does to the current cell once `getchar()` returns `EOF`.
+    pub eof: EofBehavior,
+    /// Counts how many times each basic block runs. See [BlockCounts].
+    pub profile: bool,
+    /// Remembers where each block starts, so that the code can be named in perf maps and dumps.
+    /// (Unwind info is always registered, since faults on a [GuardedTape](crate::GuardedTape)
+    /// unwind through the code.)
+    pub debug_info: bool,
+    /// Compiles each top-level loop as a function of its own, which the rest of the code calls.
+    /// Each piece of code stays small, and shows up on its own in perf maps and dumps.
+    pub outline_loops: bool,
+}
+
+impl Default for CodegenOptions {
+    fn default() -> Self {
+        CodegenOptions::from(&ProgramConfig::default())
+    }
+}
+
+impl From<&ProgramConfig> for CodegenOptions {
+    fn from(config: &ProgramConfig) -> Self {
+        CodegenOptions {
+            tape_length: config.tape_length,
+            cell_bits: config.cell_bits,
+            wrap_tape: config.wrap_tape,
+            eof: config.eof,
+            profile: false,
+            debug_info: true,
+            outline_loops: false,
+        }
+    }
+}
+
+/// How many times each basic block has run, counted by code generated with
+/// [CodegenOptions::profile]. The counts keep going up for as long as the code is run.
+#[derive(Debug)]
+pub struct BlockCounts {
+    labels: Vec<BlockLabel>,
+    // Boxed, so that the counters stay where the generated code expects them.
+    counts: Box<[AtomicU64]>,
+}
+
+impl BlockCounts {
+    fn new(labels: Vec<BlockLabel>) -> Self {
+        let counts = labels.iter().map(|_| AtomicU64::new(0)).collect();
+        BlockCounts { labels, counts }
+    }
+
+    /// How many times the block has run, if it was counted. Blocks of stubbed loops aren't.
+    pub fn get(&self, label: BlockLabel) -> Option<u64> {
+        let index = self.labels.iter().position(|&l| l == label)?;
+        Some(self.counts[index].load(Ordering::Relaxed))
+    }
+
+    /// Each block that was counted, and how many times it has run, in the order of the code.
+    pub fn iter(&self) -> impl Iterator<Item = (BlockLabel, u64)> + '_ {
+        self.labels
+            .iter()
+            .zip(self.counts.iter())
+            .map(|(&label, count)| (label, count.load(Ordering::Relaxed)))
+    }
+
+    /// Where the generated code adds to the block's count.
+    fn counter(&self, index: usize) -> usize {
+        self.counts[index].as_ptr() as usize
+    }
+}
+
+/// A branch label in the generated code. Basic blocks keep their own labels.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub struct Label(pub usize);
+
+/// Where the machine code for a basic block starts, so that it can be traced back to the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockStart {
+    /// In bytes, from the start of the machine code.
+    pub offset: usize,
+    pub label: BlockLabel,
+    pub position: Option<SourcePosition>,
+}
+
+/// A loop that's left out of the generated code, with a call to a stub (or a function) in its
+/// place. See [CodeGenerator::with_stubs] and [CodeGenerator::with_outlined_loops].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StubbedLoop {
+    /// The only way into the loop.
+    pub header: BlockLabel,
+    /// Every block in the loop, including the header.
+    pub blocks: Vec<BlockLabel>,
+    /// Where the loop goes once it's done.
+    pub exit: BlockLabel,
+}
+
+/// The operations that a machine has to provide to run a program. Code is generated for a single
+/// function, that takes a pointer to the current cell, an [OutputBuffer](crate::jit::OutputBuffer),
+/// `getchar()`, and a pointer to the start of the tape (in that order), and returns where the
+/// pointer ended up.
+///
+/// "The cell" is always the current cell, and offsets are relative to it. Any offset, and any
+/// amount to move the pointer by, has to work.
+pub trait Target {
+    fn new() -> Self;
+
+    /// Wraps the pointer, and every cell addressed through it, around a tape of `mask + 1` cells.
+    /// See [can_wrap_tape].
+    fn with_wrapping_tape(self, mask: u32) -> Self
+    where
+        Self: Sized;
+
+    /// Also keeps a listing of the assembly, if the target can.
+    fn with_listing(self) -> Self
+    where
+        Self: Sized,
+    {
+        self
+    }
+
+    /// The listing so far, if there is one.
+    fn listing(&self) -> Option<String> {
+        None
+    }
+
+    /// The listing so far, with the offset and encoding of each instruction, if there is one.
+    fn annotated_listing(&self) -> Option<String> {
+        None
+    }
+
+    /// Adds a comment to the listing, if there is one.
+    fn comment(&mut self, _text: &dyn fmt::Display) {}
+
+    /// Saves registers, and sets up the arguments.
+    fn prologue(&mut self);
+    /// Restores registers, and returns the pointer to the current cell.
+    fn epilogue(&mut self);
+
+    fn set_label(&mut self, label: Label);
+    fn branch(&mut self, label: Label);
+    /// Branches if the cell is zero.
+    fn branch_if_zero(&mut self, label: Label);
+    /// Branches if the cell isn't zero.
+    fn branch_if_not_zero(&mut self, label: Label);
+
+    /// Adds (or with wrapping, subtracts) `x` to the cell at the offset.
+    fn add_to_cell(&mut self, offset: i32, x: u8);
+    fn set_cell(&mut self, offset: i32, x: u8);
+    /// Adds the cell times `factor` to the cell at the offset.
+    fn multiply_add(&mut self, offset: i32, factor: u8);
+    fn move_pointer(&mut self, x: i32);
+    /// Reads the cell at the offset, and does nothing with it, so that the code faults if the cell
+    /// is in a guard.
+    fn touch_cell(&mut self, offset: i32);
+
+    /// Appends the cell to the output buffer.
+    fn buffer_cell(&mut self);
+    /// Appends a byte known at compile time to the output buffer.
+    fn buffer_byte(&mut self, byte: u8);
+    /// Branches if the byte that was just appended is a newline.
+    fn branch_if_newline(&mut self, label: Label);
+    /// Branches if the output buffer filled up with the byte that was just appended.
+    fn branch_if_buffer_full(&mut self, label: Label);
+    /// Calls the output buffer's `flush()`.
+    fn flush_output(&mut self);
+    /// Calls `getchar()`. Use [Target::branch_if_eof] and [Target::store_input] on what it returned.
+    fn get_char(&mut self);
+    /// Branches if `getchar()` returned `EOF`.
+    fn branch_if_eof(&mut self, label: Label);
+    /// Stores the low byte of what `getchar()` returned in the cell. The low byte of `EOF` is 255.
+    fn store_input(&mut self);
+
+    /// Calls `stub(index, cell, output)`, the address of an
+    /// `extern "C-unwind" fn(u32, *mut u8, *mut OutputBuffer) -> *mut u8`, and carries on from the
+    /// cell that it returns.
+    fn call_stub(&mut self, stub: usize, index: u32);
+    /// Calls generated code at the address, passing it the current cell (and everything else that
+    /// was passed to this code), and carries on from the cell that it returns.
+    fn call_function(&mut self, function: usize);
+    /// Adds one to the `u64` at the address.
+    fn count(&mut self, counter: usize);
+
+    /// How many bytes of machine code there are so far.
+    fn offset(&self) -> usize;
+
+    /// How to unwind through the code, as set up by [Target::prologue].
+    fn frame_description(&self) -> FrameDescription;
+
+    /// Patches the branches, and returns the machine code.
+    fn machine_code(&mut self) -> &[u8];
+    /// Patches the branches, and takes the machine code, already in memory that can be made
+    /// executable.
+    fn take_machine_code(&mut self) -> CodeBuffer;
+}
+
+/// Takes three-address code and compiles it an executable.
+pub struct CodeGenerator<T: Target> {
+    target: T,
+    // Labels that don't correspond to a basic block start from here.
+    next_internal_label: usize,
+    options: CodegenOptions,
+    block_starts: Vec<BlockStart>,
+    block_counts: Option<BlockCounts>,
+    stubs: Stubs,
+    /// Blocks that start by branching if the cell is zero, by their label: where the rest of the
+    /// block starts, and where the branch goes. A branch back to one of them checks the cell
+    /// itself, and goes straight on to the rest of the block.
+    loop_headers: HashMap<BlockLabel, (Label, BlockLabel)>,
+    /// The block after the one whose code is being generated.
+    next_block: Option<BlockLabel>,
+    /// How far off the tape the pointer could be since the code last touched a cell, which is
+    /// always less than a guard. See [CodeGenerator::before_touching].
+    reach: usize,
+    /// How far off the tape the pointer can be when each block starts.
+    entry_reach: HashMap<BlockLabel, usize>,
+}
+
+/// How far off the tape the pointer can be when a block starts, unless the block touches the cell
+/// before anything else. Every way into such a block keeps to this.
+const BLOCK_ENTRY_REACH: usize = GUARD_SIZE / 2;
+
+/// The loops that are left out of the code, and what's called instead.
+#[derive(Default)]
+struct Stubs {
+    stub: usize,
+    /// What's called instead of the loop, and where to go afterwards, by each loop's header.
+    headers: HashMap<BlockLabel, (Callee, BlockLabel)>,
+    /// Every other block of those loops, which are never reached.
+    skipped: HashSet<BlockLabel>,
+}
+
+/// What's called in place of a loop that's left out of the code.
+#[derive(Debug, Clone, Copy)]
+enum Callee {
+    /// The stub, with the loop's index. See [Target::call_stub].
+    Stub(u32),
+    /// Generated code at the address. See [Target::call_function].
+    Function(usize),
+}
+
+impl Stubs {
+    fn leave_out(&mut self, stubbed: &StubbedLoop, callee: Callee) {
+        self.headers.insert(stubbed.header, (callee, stubbed.exit));
+        self.skipped.extend(
+            stubbed
+                .blocks
+                .iter()
+                .filter(|&&label| label != stubbed.header),
+        );
+    }
+}
+
+impl<T: Target> CodeGenerator<T> {
+    pub fn new() -> Self {
+        CodeGenerator {
+            target: T::new(),
+            next_internal_label: 0,
+            options: CodegenOptions::default(),
+            block_starts: Vec::new(),
+            block_counts: None,
+            stubs: Stubs::default(),
+            loop_headers: HashMap::new(),
+            next_block: None,
+            reach: 0,
+            entry_reach: HashMap::new(),
+        }
+    }
+
+    /// Generates code the way the options say.
+    ///
+    /// # Panics
+    ///
+    /// When the cells aren't 8 bits, or the tape wraps and [can_wrap_tape] is false for its length.
+    pub fn with_options(self, options: &CodegenOptions) -> Self {
+        assert_eq!(8, options.cell_bits, "only 8-bit cells are supported");
+        let gen = CodeGenerator {
+            options: CodegenOptions {
+                wrap_tape: false,
+                ..*options
+            },
+            ..self
+        };
+        if options.wrap_tape {
+            gen.with_wrapping_tape(options.tape_length)
+        } else {
+            gen
+        }
+    }
+
+    /// Also keeps a listing of the generated assembly, with the IR that each part came from, if
+    /// the target can. See [CodeGenerator::listing].
+    pub fn with_listing(self) -> Self {
+        CodeGenerator {
+            target: self.target.with_listing(),
+            ..self
+        }
+    }
+
+    /// The generated code as a `.s` listing, if it was asked for with [CodeGenerator::with_listing].
+    pub fn listing(&self) -> Option<String> {
+        self.target.listing()
+    }
+
+    /// Same as [CodeGenerator::listing], but with the offset and encoding of each instruction.
+    pub fn annotated_listing(&self) -> Option<String> {
+        self.target.annotated_listing()
+    }
+
+    /// Wraps the pointer around a tape of `tape_length` cells, instead of trusting the program to
+    /// stay on it. The tape passed to the generated code must be exactly that long.
+    ///
+    /// # Panics
+    ///
+    /// When [can_wrap_tape] is false for the length.
+    pub fn with_wrapping_tape(self, tape_length: usize) -> Self {
+        assert!(
+            can_wrap_tape(tape_length),
+            "cannot wrap around a tape of {} cells",
+            tape_length
+        );
+        CodeGenerator {
+            target: self.target.with_wrapping_tape(tape_length as u32 - 1),
+            options: CodegenOptions {
+                tape_length,
+                wrap_tape: true,
+                ..self.options
+            },
+            ..self
+        }
+    }
+
+    /// Sets what `,` does to the current cell once getchar() returns `EOF`.
+    pub fn with_eof_behavior(mut self, eof: EofBehavior) -> Self {
+        self.options.eof = eof;
+        self
+    }
+
+    /// Leaves each of the loops out of the code, and calls `stub` in its place, with the loop's
+    /// index in `loops` and the current cell. The stub has to run the loop somehow (e.g., by
+    /// compiling it on its own), and return where the pointer ended up. See [Target::call_stub].
+    pub fn with_stubs(self, stub: usize, loops: &[StubbedLoop]) -> Self {
+        let mut stubs = Stubs {
+            stub,
+            ..Stubs::default()
+        };
+        for (index, stubbed) in loops.iter().enumerate() {
+            stubs.leave_out(stubbed, Callee::Stub(index as u32));
+        }
+        CodeGenerator { stubs, ..self }
+    }
+
+    /// Leaves each of the loops out of the code, and calls the generated code at the address
+    /// paired with it instead: the loop, compiled as a function of its own. See
+    /// [Target::call_function].
+    pub fn with_outlined_loops(mut self, loops: &[(StubbedLoop, usize)]) -> Self {
+        for (stubbed, function) in loops {
+            self.stubs.leave_out(stubbed, Callee::Function(*function));
+        }
+        self
+    }
+
+    /// Where each basic block ended up in the code from the last call to [CodeGenerator::compile].
+    pub fn block_starts(&self) -> &[BlockStart] {
+        &self.block_starts
+    }
+
+    /// The counters that the code from the last call to [CodeGenerator::compile] adds to, if it
+    /// was generated with [CodegenOptions::profile]. They have to outlive the code.
+    pub fn take_block_counts(&mut self) -> Option<BlockCounts> {
+        self.block_counts.take()
+    }
+
+    /// Takes the code from the last call to [CodeGenerator::compile], without copying it. See
+    /// [CompiledProgram::from_code](crate::CompiledProgram::from_code).
+    pub fn take_machine_code(&mut self) -> CodeBuffer {
+        self.target.take_machine_code()
+    }
+
+    /// How to unwind through the code from the last call to [CodeGenerator::compile].
+    pub fn frame_description(&self) -> FrameDescription {
+        self.target.frame_description()
+    }
+
+    pub fn compile(&mut self, cfg: &ControlFlowGraph) -> &[u8] {
+        self.next_internal_label = cfg
+            .blocks()
+            .iter()
+            .map(|block| block.label().0 + 1)
+            .max()
+            .unwrap_or(0);
+
+        self.target.comment(&"prologue");
+        self.target.prologue();
+
+        self.block_starts.clear();
+        self.block_counts = self.options.profile.then(|| {
+            let labels = cfg.blocks().iter().map(|block| block.label());
+            BlockCounts::new(
+                labels
+                    .filter(|label| !self.stubs.skipped.contains(label))
+                    .collect(),
+            )
+        });
+        self.loop_headers.clear();
+        self.entry_reach = cfg
+            .blocks()
+            .iter()
+            .map(|block| {
+                let reach = match block.instructions().first() {
+                    Some(&instr) if touches_the_cell_first(instr) => GUARD_SIZE - 1,
+                    _ => BLOCK_ENTRY_REACH,
+                };
+                (block.label(), reach)
+            })
+            .collect();
+        // Only the header of a loop is reachable from outside of it.
+        let blocks: Vec<_> = cfg
+            .blocks()
+            .iter()
+            .filter(|block| !self.stubs.skipped.contains(&block.label()))
+            .collect();
+        for (i, block) in blocks.iter().enumerate() {
+            let BlockLabel(l) = block.label();
+            self.next_block = blocks.get(i + 1).map(|next| next.label());
+            // Binding the label can take back a branch to it, so the block starts after that.
+            self.target.set_label(Label(l));
+            self.reach = self.entry_reach[&block.label()];
+            self.block_starts.push(BlockStart {
+                offset: self.target.offset(),
+                label: block.label(),
+                position: block.position(),
+            });
+            if let Some(counts) = &self.block_counts {
+                let counter = counts.counter(self.block_starts.len() - 1);
+                self.target.comment(&"count");
+                self.target.count(counter);
+            }
+            if let Some(&(callee, exit)) = self.stubs.headers.get(&block.label()) {
+                // Skipping a loop that doesn't run at all doesn't need the stub.
+                if let Some(&ThreeAddressInstruction::BranchIfZero(target)) =
+                    block.instructions().first()
+                {
+                    if target == exit {
+                        self.target.branch_if_zero(Label(exit.0));
+                    }
+                }
+                // The callee starts wherever the loop does, so it can start with any reach, and
+                // it can end up anywhere code can.
+                self.keep_reach_within(BLOCK_ENTRY_REACH);
+                match callee {
+                    Callee::Stub(index) => {
+                        self.target
+                            .comment(&format_args!("stub for loop {}", index));
+                        self.target.call_stub(self.stubs.stub, index);
+                    }
+                    Callee::Function(function) => {
+                        self.target
+                            .comment(&format_args!("call loop at {:#x}", function));
+                        self.target.call_function(function);
+                    }
+                }
+                self.reach = GUARD_SIZE - 1;
+                self.leave_for(exit);
+                self.target.branch(Label(exit.0));
+                continue;
+            }
+            for (j, &instr) in block.instructions().iter().enumerate() {
+                self.target.comment(&instr);
+                self.generate_instructions(instr);
+                // Counting every block that runs means going through the header every time.
+                if let (0, ThreeAddressInstruction::BranchIfZero(exit)) = (j, instr) {
+                    if !self.options.profile {
+                        let rest = self.internal_label();
+                        self.target.set_label(rest);
+                        self.loop_headers.insert(block.label(), (rest, exit));
+                    }
+                }
+            }
+            if let (true, Some(next)) = (block.falls_through(), self.next_block) {
+                self.leave_for(next);
+            }
+        }
+        assert!(
+            matches!(
+                cfg.last_instruction(),
+                Some(ThreeAddressInstruction::Terminate)
+            ),
+            "expected terminate as last instruction, so that the function returns"
+        );
+
+        self.target.machine_code()
+    }
+
+    /// Flushes the output buffer after a newline, or once it's full. Pass the byte that was just
+    /// appended, if it's known at compile time.
+    fn flush_if_needed(&mut self, byte: Option<u8>) {
+        if byte == Some(b'\n') {
+            self.target.flush_output();
+            return;
+        }
+
+        let flush = self.internal_label();
+        let done = self.internal_label();
+        if byte.is_none() {
+            self.target.branch_if_newline(flush);
+        }
+        self.target.branch_if_buffer_full(flush);
+        self.target.branch(done);
+        self.target.set_label(flush);
+        self.target.flush_output();
+        self.target.set_label(done);
+    }
+
+    /// Call right before the code touches the cell at the offset. The code only faults once it
+    /// touches a cell in a guard, so a cell past a guard could be touched without faulting. When
+    /// the cell could be that far off the tape, the code first touches cells on the way there,
+    /// each less than a guard from the last, starting from the current cell.
+    ///
+    /// Nothing is touched when the tape wraps, since the pointer never leaves it.
+    fn before_touching(&mut self, offset: i32) {
+        if self.options.wrap_tape {
+            return;
+        }
+        let distance = offset.unsigned_abs() as usize;
+        if self.reach + distance < GUARD_SIZE {
+            self.reach = self.reach.min(distance);
+            return;
+        }
+
+        if self.reach > 0 {
+            self.target.touch_cell(0);
+        }
+        let step = (GUARD_SIZE - 1) as i64 * i64::from(offset.signum());
+        let mut at = step;
+        while at.unsigned_abs() < offset.unsigned_abs() as u64 {
+            self.target.touch_cell(at as i32);
+            at += step;
+        }
+        self.reach = 0;
+    }
+
+    /// Call right before the code moves the pointer. Like [CodeGenerator::before_touching], but
+    /// for where the pointer is going.
+    fn before_moving(&mut self, x: i32) {
+        if self.options.wrap_tape {
+            return;
+        }
+        let distance = x.unsigned_abs() as usize;
+        if self.reach + distance < GUARD_SIZE {
+            self.reach += distance;
+            return;
+        }
+
+        self.before_touching(x);
+        self.target.touch_cell(x);
+    }
+
+    /// Touches the cell, if the pointer could be further than `reach` off the tape.
+    fn keep_reach_within(&mut self, reach: usize) {
+        if !self.options.wrap_tape && self.reach > reach {
+            self.target.touch_cell(0);
+            self.reach = 0;
+        }
+    }
+
+    /// Call right before the code goes to the block.
+    fn leave_for(&mut self, block: BlockLabel) {
+        let reach = self.entry_reach[&block];
+        self.keep_reach_within(reach);
+    }
+
+    /// Returns a label that is distinct from every basic block's label.
+    fn internal_label(&mut self) -> Label {
+        let label = Label(self.next_internal_label);
+        self.next_internal_label += 1;
+        label
+    }
+
+    fn generate_instructions(&mut self, instr: ThreeAddressInstruction) {
+        use ThreeAddressInstruction::*;
+
+        match instr {
+            NoOp => (),
+            Zero => {
+                self.before_touching(0);
+                self.target.set_cell(0, 0);
+            }
+            ChangeVal(x) => {
+                self.before_touching(0);
+                self.target.add_to_cell(0, x);
+            }
+            ChangeValAt(offset, x) => {
+                self.before_touching(offset);
+                self.target.add_to_cell(offset, x);
+            }
+            SetValAt(offset, x) => {
+                self.before_touching(offset);
+                self.target.set_cell(offset, x);
+            }
+            MulAdd { dst_offset, factor } => {
+                // The original loop never touches the destination when the current cell is zero.
+                let skip = self.internal_label();
+                self.before_touching(0);
+                self.target.branch_if_zero(skip);
+                self.before_touching(dst_offset);
+                self.target.multiply_add(dst_offset, factor);
+                self.target.set_label(skip);
+            }
+            FindZero(step) => {
+                let top = self.internal_label();
+                let done = self.internal_label();
+
+                self.target.set_label(top);
+                self.before_touching(0);
+                self.target.branch_if_zero(done);
+                self.before_moving(step);
+                self.target.move_pointer(step);
+                self.target.branch(top);
+
+                self.target.set_label(done);
+                // The only way out is finding a zero.
+                self.before_touching(0);
+            }
+            ChangeAddr(x) => {
+                if x != 0 {
+                    self.before_moving(x);
+                    self.target.move_pointer(x);
+                }
+            }
+            PutChar => {
+                self.before_touching(0);
+                self.target.buffer_cell();
+                self.flush_if_needed(None);
+            }
+            EmitBytes(bytes) => {
+                for &byte in bytes.as_slice() {
+                    self.target.buffer_byte(byte);
+                    self.flush_if_needed(Some(byte));
+                }
+            }
+            GetChar => {
+                // Anything the program printed (e.g., a prompt) should be out before it waits.
+                self.target.flush_output();
+                self.target.get_char();
+                if self.options.eof == EofBehavior::MinusOne {
+                    self.target.store_input();
+                    return;
+                }
+
+                let at_eof = self.internal_label();
+                let done = self.internal_label();
+                self.target.branch_if_eof(at_eof);
+                self.target.store_input();
+                self.target.branch(done);
+                self.target.set_label(at_eof);
+                if self.options.eof == EofBehavior::Zero {
+                    self.target.set_cell(0, 0);
+                }
+                self.target.set_label(done);
+            }
+            BranchIfZero(BlockLabel(l)) => {
+                self.before_touching(0);
+                self.target.branch_if_zero(Label(l));
+            }
+            BranchTo(target) => match self.loop_headers.get(&target) {
+                // Checking the cell right here saves branching to the header just to check it.
+                Some(&(rest, exit)) => {
+                    self.before_touching(0);
+                    self.target.branch_if_not_zero(rest);
+                    if self.next_block != Some(exit) {
+                        self.target.branch(Label(exit.0));
+                    }
+                }
+                None => {
+                    self.leave_for(target);
+                    self.target.branch(Label(target.0));
+                }
+            },
+            Terminate => {
+                self.target.flush_output();
+                self.target.epilogue();
+            }
+            Extended(op) => {
+                panic!(
+                    "Extended Brainfuck ({:?}) is not supported by the JIT; use the interpreter",
+                    op
+                );
+            }
+        }
+    }
+}
+
+/// Whether the code for the instruction touches the cell before it does anything else, which
+/// faults if the pointer is off the tape by less than a guard.
+fn touches_the_cell_first(instr: ThreeAddressInstruction) -> bool {
+    use ThreeAddressInstruction::*;
+
+    matches!(
+        instr,
+        BranchIfZero(_) | ChangeVal(_) | Zero | PutChar | MulAdd { .. } | FindZero(_)
+    )
+}
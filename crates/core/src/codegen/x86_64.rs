@@ -0,0 +1,490 @@
+//! Generates x86_64 machine code for a given program, following the System V calling convention.
+
+use super::{Label, Target};
+use crate::asm::x86_64::{
+    self as asm, Mem, X86_64Assembly, R, R12, R13, R14, RAX, RBP, RBX, RCX, RDI, RDX, RSI, RSP,
+};
+use crate::asm::CodeBuffer;
+use crate::jit::output::{self, CAPACITY};
+use crate::unwind::{FrameDescription, Rule};
+
+// REGISTERS:
+//
+// rax                - working byte, and return value of getchar()
+const VAL: R = RAX;
+// rbx (callee saved) - current pointer on the "tape" (during function)
+const ADDR: R = RBX;
+// r12 (callee saved) - the output buffer (during function)
+const OUT: R = R12;
+// r13 (callee saved) - getchar (during function)
+const GETCHAR: R = R13;
+// r14 (callee saved) - start of the tape (during function)
+const TAPE: R = R14;
+// rdx                - address of a cell, when the tape wraps; where a byte goes in the buffer
+const TMP_ADDR: R = RDX;
+// rcx                - how many bytes are in the output buffer
+const TMP_LEN: R = RCX;
+// rdi (argument)     - pointer to universe (as argument); the output buffer to flush()
+const ARG: R = RDI;
+// rsi (argument)     - the output buffer (as argument)
+// rdx (argument)     - getchar (as argument)
+// rcx (argument)     - start of the tape (as argument)
+// rax (return)       - pointer to the current cell when the program terminated
+//
+// rbp                - frame pointer
+// rsp                - stack pointer
+// see: https://en.wikipedia.org/wiki/X86_calling_conventions#System_V_AMD64_ABI
+
+/// Generates x86_64 machine code.
+pub struct X86_64 {
+    asm: X86_64Assembly,
+    wrap_mask: Option<u32>,
+    unwind_rules: Vec<(usize, Rule)>,
+}
+
+impl Target for X86_64 {
+    fn new() -> Self {
+        X86_64 {
+            asm: X86_64Assembly::new(),
+            wrap_mask: None,
+            unwind_rules: Vec::new(),
+        }
+    }
+
+    fn with_wrapping_tape(self, mask: u32) -> Self {
+        X86_64 {
+            wrap_mask: Some(mask),
+            ..self
+        }
+    }
+
+    // STACK
+    //
+    //        $rsp + 0x28 [return address]
+    // $rbp == $rsp + 0x20 [previous rbp]
+    //        $rsp + 0x18 [previous rbx]
+    //        $rsp + 0x10 [previous r12]
+    //        $rsp + 0x08 [previous r13]
+    // $rsp == $rsp + 0x00 [previous r14]
+    //
+    // Five pushes after the return address leave the stack 16-byte aligned for calls.
+
+    fn prologue(&mut self) {
+        // On entry, the return address is right above the stack pointer.
+        self.unwind(Rule::Cfa {
+            register: dwarf(RSP),
+            offset: 8,
+        });
+        self.unwind(Rule::Saved {
+            register: RETURN_ADDRESS,
+            offset: -8,
+        });
+
+        // push rbp
+        // mov rbp, rsp
+        self.asm.push(RBP);
+        self.unwind(Rule::CfaOffset(16));
+        self.unwind_saved(RBP, -16);
+        self.asm.mov64(RBP, RSP);
+        self.unwind(Rule::CfaRegister(dwarf(RBP)));
+
+        // push rbx
+        // push r12
+        // push r13
+        // push r14
+        for (r, offset) in [(ADDR, -24), (OUT, -32), (GETCHAR, -40), (TAPE, -48)] {
+            self.asm.push(r);
+            self.unwind_saved(r, offset);
+        }
+
+        // mov rbx, rdi
+        // mov r12, rsi
+        // mov r13, rdx
+        // mov r14, rcx
+        self.asm.mov64(ADDR, ARG);
+        self.asm.mov64(OUT, RSI);
+        self.asm.mov64(GETCHAR, RDX);
+        self.asm.mov64(TAPE, RCX);
+    }
+
+    fn epilogue(&mut self) {
+        // Return where the tape pointer ended up, so that a caller that jumped into the middle of
+        // a program (e.g., the tiered runner) can pick up from there.
+        // mov rax, rbx
+        self.asm.mov64(RAX, ADDR);
+
+        // pop r14
+        // pop r13
+        // pop r12
+        // pop rbx
+        // pop rbp
+        self.asm.pop(TAPE);
+        self.asm.pop(GETCHAR);
+        self.asm.pop(OUT);
+        self.asm.pop(ADDR);
+        self.asm.pop(RBP);
+        self.asm.ret();
+    }
+
+    fn set_label(&mut self, Label(l): Label) {
+        self.asm.set_label_target(asm::Label(l));
+    }
+
+    fn branch(&mut self, Label(l): Label) {
+        // jmp L*
+        self.asm.jmp(asm::Label(l));
+    }
+
+    fn branch_if_zero(&mut self, Label(l): Label) {
+        // cmp byte [rbx], 0
+        // je L*
+        self.asm.cmp_byte_imm(CURRENT_CELL, 0);
+        self.asm.je(asm::Label(l));
+    }
+
+    fn branch_if_not_zero(&mut self, Label(l): Label) {
+        // cmp byte [rbx], 0
+        // jne L*
+        self.asm.cmp_byte_imm(CURRENT_CELL, 0);
+        self.asm.jne(asm::Label(l));
+    }
+
+    fn add_to_cell(&mut self, offset: i32, x: u8) {
+        // add byte [rbx + offset], x
+        let cell = self.cell(offset);
+        self.asm.add_byte_imm(cell, x);
+    }
+
+    fn set_cell(&mut self, offset: i32, x: u8) {
+        // mov byte [rbx + offset], x
+        let cell = self.cell(offset);
+        self.asm.mov_byte_imm(cell, x);
+    }
+
+    fn multiply_add(&mut self, offset: i32, factor: u8) {
+        // [rbx + offset] <- [rbx + offset] + [rbx] * factor
+        self.asm.movzx32_byte(VAL, CURRENT_CELL);
+        self.asm.imul32_imm(VAL, VAL, factor as i32);
+        let cell = self.cell(offset);
+        self.asm.add_byte(cell, VAL);
+    }
+
+    fn move_pointer(&mut self, x: i32) {
+        // add rbx, x
+        self.asm.add64_imm(ADDR, x);
+        self.wrap(ADDR);
+    }
+
+    fn touch_cell(&mut self, offset: i32) {
+        // cmp byte [rbx + offset], 0
+        let cell = self.cell(offset);
+        self.asm.cmp_byte_imm(cell, 0);
+    }
+
+    fn buffer_cell(&mut self) {
+        // movzx eax, byte [rbx]
+        self.asm.movzx32_byte(VAL, CURRENT_CELL);
+        self.append_to_buffer();
+    }
+
+    fn buffer_byte(&mut self, byte: u8) {
+        // mov eax, byte
+        self.asm.mov32_imm(VAL, byte as u32);
+        self.append_to_buffer();
+    }
+
+    fn branch_if_newline(&mut self, Label(l): Label) {
+        // cmp eax, '\n'
+        // je L*
+        self.asm.cmp32_imm(VAL, b'\n' as i8);
+        self.asm.je(asm::Label(l));
+    }
+
+    fn branch_if_buffer_full(&mut self, Label(l): Label) {
+        // cmp ecx, CAPACITY
+        // je L*
+        self.asm.cmp32_imm32(TMP_LEN, CAPACITY as i32);
+        self.asm.je(asm::Label(l));
+    }
+
+    fn flush_output(&mut self) {
+        // mov rdi, r12
+        // mov rax, [r12 + FLUSH]
+        // call rax
+        self.asm.mov64(ARG, OUT);
+        self.asm.mov64_load(RAX, buffer(output::FLUSH));
+        self.asm.call(RAX);
+    }
+
+    fn get_char(&mut self) {
+        self.asm.call(GETCHAR);
+    }
+
+    fn branch_if_eof(&mut self, Label(l): Label) {
+        // cmp eax, -1
+        self.asm.cmp32_imm(VAL, -1);
+        self.asm.je(asm::Label(l));
+    }
+
+    fn store_input(&mut self) {
+        self.asm.mov_byte(CURRENT_CELL, VAL);
+    }
+
+    fn call_stub(&mut self, stub: usize, index: u32) {
+        // mov edi, index
+        // mov rsi, rbx
+        // mov rdx, r12
+        // mov rax, stub
+        // call rax
+        // mov rbx, rax
+        self.asm.mov32_imm(ARG, index);
+        self.asm.mov64(RSI, ADDR);
+        self.asm.mov64(RDX, OUT);
+        self.asm.mov64_imm(RAX, stub as u64);
+        self.asm.call(RAX);
+        self.asm.mov64(ADDR, RAX);
+    }
+
+    fn call_function(&mut self, function: usize) {
+        // mov rdi, rbx
+        // mov rsi, r12
+        // mov rdx, r13
+        // mov rcx, r14
+        // mov rax, function
+        // call rax
+        // mov rbx, rax
+        self.asm.mov64(ARG, ADDR);
+        self.asm.mov64(RSI, OUT);
+        self.asm.mov64(RDX, GETCHAR);
+        self.asm.mov64(RCX, TAPE);
+        self.asm.mov64_imm(RAX, function as u64);
+        self.asm.call(RAX);
+        self.asm.mov64(ADDR, RAX);
+    }
+
+    fn count(&mut self, counter: usize) {
+        // mov rax, counter
+        // add qword [rax], 1
+        self.asm.mov64_imm(RAX, counter as u64);
+        self.asm.add64_mem_imm(Mem { base: RAX, disp: 0 }, 1);
+    }
+
+    fn offset(&self) -> usize {
+        self.asm.offset()
+    }
+
+    fn frame_description(&self) -> FrameDescription {
+        FrameDescription {
+            return_address_register: RETURN_ADDRESS,
+            rules: self.unwind_rules.clone(),
+        }
+    }
+
+    fn machine_code(&mut self) -> &[u8] {
+        self.asm.patch_branch_targets();
+        self.asm.machine_code()
+    }
+
+    fn take_machine_code(&mut self) -> CodeBuffer {
+        self.asm.patch_branch_targets();
+        self.asm.take_machine_code()
+    }
+}
+
+/// DWARF's number for the return address, which isn't a real register.
+const RETURN_ADDRESS: u8 = 16;
+
+/// DWARF numbers registers in a different order than x86_64 does.
+fn dwarf(r: R) -> u8 {
+    match r.0 {
+        1 => 2,
+        2 => 1,
+        4 => 7,
+        5 => 6,
+        6 => 4,
+        7 => 5,
+        n => n,
+    }
+}
+
+const CURRENT_CELL: Mem = Mem {
+    base: ADDR,
+    disp: 0,
+};
+
+/// A field of the output buffer.
+fn buffer(field: i32) -> Mem {
+    Mem {
+        base: OUT,
+        disp: field,
+    }
+}
+
+impl X86_64 {
+    /// Appends the byte in `eax` to the output buffer, leaving the buffer's new length in `rcx`.
+    fn append_to_buffer(&mut self) {
+        // mov rcx, [r12 + LEN]
+        // mov rdx, r12
+        // add rdx, rcx
+        // mov byte [rdx + BYTES], al
+        // add rcx, 1
+        // mov [r12 + LEN], rcx
+        self.asm.mov64_load(TMP_LEN, buffer(output::LEN));
+        self.asm.mov64(TMP_ADDR, OUT);
+        self.asm.add64(TMP_ADDR, TMP_LEN);
+        self.asm.mov_byte(
+            Mem {
+                base: TMP_ADDR,
+                disp: output::BYTES,
+            },
+            VAL,
+        );
+        self.asm.add64_imm(TMP_LEN, 1);
+        self.asm.mov64_store(buffer(output::LEN), TMP_LEN);
+    }
+
+    /// The cell at the offset from the current cell. Unlike AArch64, any offset fits in the
+    /// instruction itself, unless the tape wraps.
+    fn cell(&mut self, offset: i32) -> Mem {
+        if self.wrap_mask.is_none() || offset == 0 {
+            return Mem {
+                base: ADDR,
+                disp: offset,
+            };
+        }
+
+        // mov rdx, rbx
+        // add rdx, offset
+        self.asm.mov64(TMP_ADDR, ADDR);
+        self.asm.add64_imm(TMP_ADDR, offset);
+        self.wrap(TMP_ADDR);
+        Mem {
+            base: TMP_ADDR,
+            disp: 0,
+        }
+    }
+
+    /// Adds a rule for unwinding that holds from the next instruction on.
+    fn unwind(&mut self, rule: Rule) {
+        self.unwind_rules.push((self.asm.offset(), rule));
+    }
+
+    fn unwind_saved(&mut self, r: R, offset: i32) {
+        self.unwind(Rule::Saved {
+            register: dwarf(r),
+            offset,
+        });
+    }
+
+    /// Brings the address in `r` back onto the tape, if the tape wraps.
+    fn wrap(&mut self, r: R) {
+        if let Some(mask) = self.wrap_mask {
+            // sub r, r14
+            // and r, mask
+            // add r, r14
+            self.asm.sub64(r, TAPE);
+            self.asm.and64_imm(r, mask as i32);
+            self.asm.add64(r, TAPE);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::{BasicBlock, BlockLabel, ControlFlowGraph, ThreeAddressInstruction};
+
+    type CodeGenerator = crate::codegen::CodeGenerator<X86_64>;
+
+    /// Compiles one block of instructions (plus `terminate`), and returns the machine code.
+    fn compile(instructions: Vec<ThreeAddressInstruction>) -> Vec<u8> {
+        let mut instructions = instructions;
+        instructions.push(ThreeAddressInstruction::Terminate);
+        let cfg = ControlFlowGraph::new(vec![BasicBlock::new(BlockLabel(0), instructions)]);
+
+        CodeGenerator::new().compile(&cfg).to_vec()
+    }
+
+    #[test]
+    fn addresses_cells_at_any_offset() {
+        use ThreeAddressInstruction::*;
+
+        // add byte [rbx + 0x11170], 2
+        let code = compile(vec![ChangeValAt(70_000, 2)]);
+        assert!(code
+            .windows(7)
+            .any(|w| w == [0x80, 0x83, 0x70, 0x11, 0x01, 0x00, 0x02]));
+        // add rbx, i32::MIN
+        let code = compile(vec![ChangeAddr(i32::MIN)]);
+        assert!(code
+            .windows(7)
+            .any(|w| w == [0x48, 0x81, 0xC3, 0x00, 0x00, 0x00, 0x80]));
+    }
+
+    #[test]
+    fn touches_cells_on_the_way_past_a_guard() {
+        use crate::guard::GUARD_SIZE;
+        use ThreeAddressInstruction::*;
+
+        // cmp byte [rbx + offset], 0
+        let touches = |code: &[u8], offset: usize| {
+            let mut touch = vec![0x80, 0xBB];
+            touch.extend_from_slice(&(offset as i32).to_le_bytes());
+            touch.push(0);
+            code.windows(touch.len()).any(|w| w == touch)
+        };
+
+        let far = 3 * GUARD_SIZE;
+        let code = compile(vec![ChangeAddr(far as i32)]);
+        for offset in [
+            GUARD_SIZE - 1,
+            2 * (GUARD_SIZE - 1),
+            3 * (GUARD_SIZE - 1),
+            far,
+        ] {
+            assert!(touches(&code, offset), "{:#x} isn't touched", offset);
+        }
+        let code = compile(vec![ChangeValAt(far as i32, 1)]);
+        assert!(touches(&code, 3 * (GUARD_SIZE - 1)));
+
+        // Nothing is touched for moves within a guard:
+        let code = compile(vec![ChangeAddr(GUARD_SIZE as i32 / 4)]);
+        assert!(!code
+            .windows(2)
+            .any(|w| w == [0x80, 0xBB] || w == [0x80, 0x3B]));
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn runs_programs() {
+        use crate::parsing::parse;
+        use crate::BrainmuckProgram;
+
+        let run = |source: &[u8], input: &[u8]| {
+            let ast = parse("<test>", source).unwrap();
+            let program = crate::compile_to_native_code(&ast);
+            let mut universe = vec![0u8; ast.config().tape_length];
+            let mut output = Vec::new();
+            program
+                .run_with_io(&mut universe, &mut &input[..], &mut output)
+                .unwrap();
+            output
+        };
+
+        // Multiply loops, scans, and output known at compile time:
+        let hello = b"++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.";
+        assert_eq!(b"Hello", &run(hello, b"")[..]);
+        assert_eq!(b"cat", &run(b",+[-.,+]", b"cat")[..]);
+        assert_eq!(vec![3], run(b";# eof=unchanged\n+++,.", b""));
+        assert_eq!(vec![0], run(b";# eof=0\n+++,.", b""));
+        assert_eq!(vec![255], run(b"+++,.", b""));
+
+        // Moving, addressing, and scanning around a wrapping tape:
+        assert_eq!(vec![6], run(b";# tape=4 wrap=on\n,>+>+>+>+.", b"\x05"));
+        assert_eq!(vec![6], run(b";# tape=8 wrap=on\n<,[>++<-]>.", b"\x03"));
+        assert_eq!(
+            vec![4],
+            run(b";# tape=8 wrap=on\n,<,<,>>[<]++++.", b"\x01\x01\x01")
+        );
+    }
+}
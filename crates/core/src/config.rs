@@ -0,0 +1,157 @@
+//! Per-program configuration, declared in the source text with pragma comments.
+//!
+//! A pragma is a comment that starts with `;#` and lasts until the end of the line:
+//!
+//! ```text
+//! ;# tape=65536 cells=8 wrap=on dialect=ext1 eof=0
+//! ```
+//!
+//! Everything after the `;#` is a pragma, so it is **not** parsed as brainfuck commands.
+
+use std::fmt;
+
+use crate::errors::Reason;
+use crate::program::EOF;
+
+/// How many cells are in the tape, unless a program asks for something else.
+pub const DEFAULT_TAPE_LENGTH: usize = 4096;
+
+/// The memory model a program expects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgramConfig {
+    /// How many cells the universe (tape) should have.
+    pub tape_length: usize,
+    /// How many bits are in each cell. Only 8-bit cells are currently supported.
+    pub cell_bits: u8,
+    /// Whether moving the pointer past either end of the tape wraps around to the other end.
+    pub wrap_tape: bool,
+    /// Which commands are understood. Only source text _after_ the pragma uses the new dialect.
+    pub dialect: Dialect,
+    /// What reading input does once there's no more input.
+    pub eof: EofBehavior,
+}
+
+/// Which flavour of brainfuck the program is written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dialect {
+    /// Plain old brainfuck, with eight commands.
+    Brainfuck,
+    /// [Extended Brainfuck Type I](https://esolangs.org/wiki/Extended_Brainfuck#Extended_Type_I),
+    /// which adds `@`, `$`, `!`, `{`, `}`, `~`, `^`, `&`, and `|`.
+    ExtendedTypeI,
+}
+
+/// What reading input does to the current cell once there's no more input to read. Programs
+/// out there rely on each of these.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EofBehavior {
+    /// The cell is set to 255 (that is, -1), like C's `EOF`.
+    MinusOne,
+    /// The cell is set to 0.
+    Zero,
+    /// The cell keeps whatever it had, so a program can tell by setting it first.
+    Unchanged,
+}
+
+impl Default for ProgramConfig {
+    fn default() -> Self {
+        ProgramConfig {
+            tape_length: DEFAULT_TAPE_LENGTH,
+            cell_bits: 8,
+            wrap_tape: false,
+            dialect: Dialect::Brainfuck,
+            eof: EofBehavior::MinusOne,
+        }
+    }
+}
+
+impl EofBehavior {
+    /// Stores a byte returned by `getchar(3)` in the cell, or does whatever this says to do when
+    /// it returned `EOF` instead.
+    #[inline]
+    pub(crate) fn store(self, c: u32, cell: &mut u8) {
+        match (c, self) {
+            (EOF, EofBehavior::Unchanged) => (),
+            (EOF, EofBehavior::Zero) => *cell = 0,
+            (EOF, EofBehavior::MinusOne) => *cell = 255,
+            (c, _) => *cell = c as u8,
+        }
+    }
+}
+
+impl ProgramConfig {
+    /// Updates the config with the settings in a pragma (the text after `;#`).
+    pub fn apply_pragma(&mut self, pragma: &str) -> Result<(), Reason> {
+        for setting in pragma.split_whitespace() {
+            let (key, value) = setting.split_once('=').ok_or(Reason::InvalidPragma)?;
+
+            match key {
+                "tape" => {
+                    self.tape_length = match value.parse() {
+                        Ok(n) if n > 0 => n,
+                        _ => return Err(Reason::InvalidPragma),
+                    }
+                }
+                "cells" => {
+                    self.cell_bits = match value.parse() {
+                        Ok(8) => 8,
+                        Ok(_) => return Err(Reason::UnsupportedCellSize),
+                        Err(_) => return Err(Reason::InvalidPragma),
+                    }
+                }
+                "wrap" => {
+                    self.wrap_tape = match value {
+                        "on" => true,
+                        "off" => false,
+                        _ => return Err(Reason::InvalidPragma),
+                    }
+                }
+                "dialect" => {
+                    self.dialect = match value {
+                        "bf" => Dialect::Brainfuck,
+                        "ext1" => Dialect::ExtendedTypeI,
+                        _ => return Err(Reason::InvalidPragma),
+                    }
+                }
+                "eof" => {
+                    self.eof = match value {
+                        "unchanged" => EofBehavior::Unchanged,
+                        "0" => EofBehavior::Zero,
+                        "255" | "-1" => EofBehavior::MinusOne,
+                        _ => return Err(Reason::InvalidPragma),
+                    }
+                }
+                _ => return Err(Reason::InvalidPragma),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns true when the config is the same as the default.
+    pub fn is_default(&self) -> bool {
+        *self == ProgramConfig::default()
+    }
+}
+
+/// Writes the config as a pragma comment that [ProgramConfig::apply_pragma] can read back.
+impl fmt::Display for ProgramConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            ";# tape={} cells={} wrap={} dialect={} eof={}",
+            self.tape_length,
+            self.cell_bits,
+            if self.wrap_tape { "on" } else { "off" },
+            match self.dialect {
+                Dialect::Brainfuck => "bf",
+                Dialect::ExtendedTypeI => "ext1",
+            },
+            match self.eof {
+                EofBehavior::Unchanged => "unchanged",
+                EofBehavior::Zero => "0",
+                EofBehavior::MinusOne => "255",
+            }
+        )
+    }
+}
@@ -1,4 +1,5 @@
-//! All errors that can be _generated_ by the compiler.
+//! All errors (and warnings) that can be _generated_ by the compiler, and the errors that can
+//! happen while running the compiled program.
 use std::fmt;
 
 /// Any error that occurs as a result of compiling the source code.
@@ -8,7 +9,22 @@ pub struct CompilationError {
     location: Option<Location>,
 }
 
+/// Something suspicious in the source code that does not stop it from compiling.
 #[derive(Debug)]
+pub struct CompilationWarning {
+    kind: WarningKind,
+    location: Location,
+}
+
+/// Something the program did while it was running that it is not allowed to do.
+#[derive(Debug)]
+pub struct RuntimeError {
+    kind: RuntimeErrorKind,
+    instruction: usize,
+    location: Option<Location>,
+}
+
+#[derive(Debug, Clone)]
 pub struct Location {
     filename: String,
     line_no: u32,
@@ -18,6 +34,11 @@ pub struct Location {
 pub enum Reason {
     TooManyCloseBrackets,
     TooManyOpenBrackets,
+    InvalidPragma,
+    UnsupportedCellSize,
+    NestingTooDeep,
+    TooManyStatements,
+    DuplicateLoopId,
 }
 
 impl CompilationError {
@@ -48,12 +69,39 @@ impl CompilationError {
     }
 }
 
+impl CompilationWarning {
+    pub fn new(kind: WarningKind, location: Location) -> Self {
+        CompilationWarning { kind, location }
+    }
+
+    pub fn kind(&self) -> WarningKind {
+        self.kind
+    }
+
+    pub fn location(&self) -> &Location {
+        &self.location
+    }
+
+    pub fn message(&self) -> &'static str {
+        self.kind.message()
+    }
+
+    pub fn message_identifier(&self) -> u32 {
+        self.kind.message_identifier()
+    }
+}
+
 impl Reason {
     pub fn message_identifier(&self) -> u32 {
         use Reason::*;
         match self {
             TooManyCloseBrackets => 0x001,
             TooManyOpenBrackets => 0x002,
+            InvalidPragma => 0x003,
+            UnsupportedCellSize => 0x004,
+            NestingTooDeep => 0x005,
+            TooManyStatements => 0x006,
+            DuplicateLoopId => 0x007,
         }
     }
 
@@ -62,6 +110,107 @@ impl Reason {
         match self {
             TooManyCloseBrackets => "too many ']' brackets. Check that each '[' has a matching ']'",
             TooManyOpenBrackets => "too many '[' brackets. Check that each '[' has a matching ']'",
+            InvalidPragma => {
+                "invalid pragma. Pragmas look like ';# tape=4096 cells=8 wrap=off dialect=bf'"
+            }
+            UnsupportedCellSize => "unsupported cell size. Only 'cells=8' is supported",
+            NestingTooDeep => "loops are nested deeper than the maximum allowed depth",
+            TooManyStatements => "program has more statements than the maximum allowed",
+            DuplicateLoopId => "a loop is nested inside another loop with the same ConditionalID",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuntimeErrorKind {
+    AddressBelowZero,
+    AddressBeyondEnd,
+}
+
+impl RuntimeError {
+    /// `instruction` is the index of the offending instruction, in whatever the program was
+    /// compiled to (e.g., its bytecode).
+    pub fn new(kind: RuntimeErrorKind, instruction: usize) -> Self {
+        RuntimeError {
+            kind,
+            instruction,
+            location: None,
+        }
+    }
+
+    /// Attaches where the offending instruction came from in the source code.
+    pub fn with_location(self, location: Location) -> Self {
+        RuntimeError {
+            location: Some(location),
+            ..self
+        }
+    }
+
+    pub fn kind(&self) -> RuntimeErrorKind {
+        self.kind
+    }
+
+    pub fn instruction(&self) -> usize {
+        self.instruction
+    }
+
+    pub fn location(&self) -> Option<&Location> {
+        self.location.as_ref()
+    }
+
+    pub fn message(&self) -> &'static str {
+        self.kind.message()
+    }
+
+    pub fn message_identifier(&self) -> u32 {
+        self.kind.message_identifier()
+    }
+}
+
+impl RuntimeErrorKind {
+    pub fn message_identifier(&self) -> u32 {
+        use RuntimeErrorKind::*;
+        match self {
+            AddressBelowZero => 0x201,
+            AddressBeyondEnd => 0x202,
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        use RuntimeErrorKind::*;
+        match self {
+            AddressBelowZero => "address went below zero",
+            AddressBeyondEnd => "address went beyond the end of the universe",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WarningKind {
+    DeadLoopAtStart,
+    CurlyBraceInsteadOfBracket,
+    DeepNesting,
+    CodeAfterInfiniteLoop,
+}
+
+impl WarningKind {
+    pub fn message_identifier(&self) -> u32 {
+        use WarningKind::*;
+        match self {
+            DeadLoopAtStart => 0x101,
+            CurlyBraceInsteadOfBracket => 0x102,
+            DeepNesting => 0x103,
+            CodeAfterInfiniteLoop => 0x104,
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        use WarningKind::*;
+        match self {
+            DeadLoopAtStart => "this loop never runs, since all cells start at zero",
+            CurlyBraceInsteadOfBracket => "'{' and '}' are not commands. Did you mean '[' or ']'?",
+            DeepNesting => "loops are nested extremely deeply",
+            CodeAfterInfiniteLoop => "this loop never ends, so the code after it was removed",
         }
     }
 }
@@ -92,6 +241,39 @@ impl fmt::Display for CompilationError {
     }
 }
 
+impl std::error::Error for RuntimeError {}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let location = self
+            .location
+            .as_ref()
+            .map(|l| format!("{}:", l))
+            .unwrap_or_else(|| String::from(""));
+
+        write!(
+            f,
+            "error[BF{:04x}]:{} {} (at instruction {})",
+            self.message_identifier(),
+            location,
+            self.message(),
+            self.instruction
+        )
+    }
+}
+
+impl fmt::Display for CompilationWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "warning[BF{:04x}]:{}: {}",
+            self.message_identifier(),
+            self.location,
+            self.message()
+        )
+    }
+}
+
 impl fmt::Display for Location {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}:{}", self.filename, self.line_no)
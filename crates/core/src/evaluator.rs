@@ -0,0 +1,258 @@
+//! Executes a [ControlFlowGraph] directly, without lowering it any further.
+//!
+//! This is the slowest way to run a program, but it's the most direct. When the bytecode
+//! interpreter or the JIT disagree with the evaluator on the same CFG, the bug is in the lowering,
+//! not in an optimization pass.
+
+use std::collections::HashMap;
+
+use crate::bytecode::{execute_extended, find_zero, offset_address};
+use crate::config::{EofBehavior, ProgramConfig};
+use crate::errors::RuntimeError;
+use crate::ir::{BlockLabel, ControlFlowGraph, ThreeAddressInstruction};
+use crate::program::{BrainmuckProgram, GetChar, PutChar, RunStats};
+
+/// A [BrainmuckProgram] that runs the instructions of a [ControlFlowGraph] one by one.
+pub struct EvaluatedProgram {
+    cfg: ControlFlowGraph,
+    /// Where each block is in [ControlFlowGraph::blocks]
+    block_index: HashMap<BlockLabel, usize>,
+    /// How many instructions come before each block, so that errors can say which instruction
+    /// caused them.
+    block_offsets: Vec<usize>,
+    wrap_tape: bool,
+    eof: EofBehavior,
+}
+
+impl EvaluatedProgram {
+    pub fn new(cfg: ControlFlowGraph, config: &ProgramConfig) -> Self {
+        let block_index = cfg
+            .blocks()
+            .iter()
+            .enumerate()
+            .map(|(i, block)| (block.label(), i))
+            .collect();
+        let block_offsets = cfg
+            .blocks()
+            .iter()
+            .scan(0, |offset, block| {
+                let start = *offset;
+                *offset += block.instructions().len();
+                Some(start)
+            })
+            .collect();
+
+        EvaluatedProgram {
+            cfg,
+            block_index,
+            block_offsets,
+            wrap_tape: config.wrap_tape,
+            eof: config.eof,
+        }
+    }
+
+    /// Returns the graph being evaluated.
+    pub fn cfg(&self) -> &ControlFlowGraph {
+        &self.cfg
+    }
+
+    fn block_index(&self, label: BlockLabel) -> usize {
+        *self
+            .block_index
+            .get(&label)
+            .expect("branch target should be a block in the CFG")
+    }
+}
+
+impl BrainmuckProgram for EvaluatedProgram {
+    fn run_with_custom_io(
+        &self,
+        universe: &mut [u8],
+        putchar: PutChar,
+        getchar: GetChar,
+    ) -> Result<RunStats, RuntimeError> {
+        use ThreeAddressInstruction::*;
+
+        let blocks = self.cfg.blocks();
+        let mut executed = 0;
+        let mut current_address = 0;
+        // Only used by Extended Brainfuck
+        let mut storage = 0u8;
+        let mut current_block = 0;
+
+        'blocks: while current_block < blocks.len() {
+            let block_offset = self.block_offsets[current_block];
+            for (i, &instr) in blocks[current_block].instructions().iter().enumerate() {
+                let error = |kind| RuntimeError::new(kind, block_offset + i);
+                executed += 1;
+
+                match instr {
+                    NoOp => (),
+                    ChangeVal(val) => {
+                        universe[current_address] = val.wrapping_add(universe[current_address]);
+                    }
+                    ChangeAddr(incr) => {
+                        current_address =
+                            offset_address(current_address, incr, universe.len(), self.wrap_tape)
+                                .map_err(error)?;
+                    }
+                    PutChar => {
+                        putchar(universe[current_address] as u32);
+                    }
+                    GetChar => {
+                        self.eof.store(getchar(), &mut universe[current_address]);
+                    }
+                    BranchIfZero(target) => {
+                        if universe[current_address] == 0 {
+                            current_block = self.block_index(target);
+                            continue 'blocks;
+                        }
+                    }
+                    BranchTo(target) => {
+                        current_block = self.block_index(target);
+                        continue 'blocks;
+                    }
+                    Zero => {
+                        universe[current_address] = 0;
+                    }
+                    MulAdd { dst_offset, factor } => {
+                        let value = universe[current_address];
+                        if value != 0 {
+                            let dst = offset_address(
+                                current_address,
+                                dst_offset,
+                                universe.len(),
+                                self.wrap_tape,
+                            )
+                            .map_err(error)?;
+                            universe[dst] = universe[dst].wrapping_add(value.wrapping_mul(factor));
+                        }
+                    }
+                    FindZero(step) => {
+                        current_address =
+                            find_zero(universe, current_address, step, self.wrap_tape)
+                                .map_err(error)?;
+                    }
+                    ChangeValAt(offset, val) => {
+                        let address =
+                            offset_address(current_address, offset, universe.len(), self.wrap_tape)
+                                .map_err(error)?;
+                        universe[address] = val.wrapping_add(universe[address]);
+                    }
+                    SetValAt(offset, val) => {
+                        let address =
+                            offset_address(current_address, offset, universe.len(), self.wrap_tape)
+                                .map_err(error)?;
+                        universe[address] = val;
+                    }
+                    Terminate => {
+                        return Ok(RunStats {
+                            instructions_executed: Some(executed),
+                            final_address: current_address,
+                        })
+                    }
+                    Extended(op) => {
+                        execute_extended(op, &mut universe[current_address], &mut storage);
+                    }
+                    EmitBytes(bytes) => {
+                        for &byte in bytes.as_slice() {
+                            putchar(byte as u32);
+                        }
+                    }
+                }
+            }
+
+            // Fell off the end of the block without branching:
+            current_block += 1;
+        }
+
+        Ok(RunStats {
+            instructions_executed: Some(executed),
+            final_address: current_address,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::lower;
+    use crate::optimize::optimize;
+    use crate::parsing::parse;
+    use std::cell::RefCell;
+
+    thread_local! {
+        static OUTPUT: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn capture(c: u32) -> u32 {
+        OUTPUT.with(|output| output.borrow_mut().push(c as u8));
+        1
+    }
+
+    fn no_input() -> u32 {
+        0
+    }
+
+    /// Runs the program, and returns its output.
+    fn run(program: &dyn BrainmuckProgram, universe: &mut [u8]) -> Vec<u8> {
+        program
+            .run_with_custom_io(universe, capture, no_input)
+            .unwrap();
+        OUTPUT.with(|output| output.take())
+    }
+
+    #[test]
+    fn optimized_and_unoptimized_agree() {
+        let ast = parse("<test>", b"+++++[->++>+++<<]>>[-<+>]<[>]<<+[>+<-]>.").unwrap();
+        let unoptimized = EvaluatedProgram::new(lower(&ast), ast.config());
+        let optimized = EvaluatedProgram::new(optimize(&lower(&ast), ast.config()), ast.config());
+
+        let mut universe = vec![0u8; 8];
+        let expected = run(&unoptimized, &mut universe);
+        assert_eq!(&[0, 26, 0, 0, 0, 0, 0, 0], &universe[..]);
+
+        let mut tape = vec![0u8; 8];
+        let actual = run(&optimized, &mut tape);
+        assert_eq!(vec![26], expected);
+        assert_eq!(expected, actual);
+        assert_eq!(universe, tape);
+    }
+
+    #[test]
+    fn optimizations_honor_the_shape_of_the_tape() {
+        for source in [
+            &b";# tape=4 wrap=on\n>>>>+<<<<."[..],
+            b";# tape=3 wrap=on\n<+>+[<]+.",
+        ] {
+            let ast = parse("<test>", source).unwrap();
+            let unoptimized = EvaluatedProgram::new(lower(&ast), ast.config());
+            let bytecode = crate::compile_to_bytecode(&ast);
+
+            let mut universe = vec![0u8; ast.config().tape_length];
+            let expected = run(&unoptimized, &mut universe);
+            let mut universe = vec![0u8; ast.config().tape_length];
+            assert_eq!(expected, run(&bytecode, &mut universe));
+        }
+
+        // Output that comes before leaving the tape is printed, and nothing after it.
+        for source in [
+            &b";# tape=2\n>>>+."[..],
+            b";# tape=4\n+.>>>>>>>>.",
+            b";# tape=2\n,>>+<<",
+        ] {
+            let ast = parse("<test>", source).unwrap();
+            let unoptimized = EvaluatedProgram::new(lower(&ast), ast.config());
+            let bytecode = crate::compile_to_bytecode(&ast);
+
+            let mut universe = vec![0u8; ast.config().tape_length];
+            let error = unoptimized.run_with_custom_io(&mut universe, capture, no_input);
+            let expected = OUTPUT.with(|output| output.take());
+            let mut universe = vec![0u8; ast.config().tape_length];
+            let actual = bytecode.run_with_custom_io(&mut universe, capture, no_input);
+
+            assert!(error.is_err() && actual.is_err());
+            assert_eq!(expected, OUTPUT.with(|output| output.take()));
+        }
+    }
+}
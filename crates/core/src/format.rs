@@ -0,0 +1,240 @@
+//! Re-emit brainfuck source in a canonical layout.
+//!
+//! Loops get their own lines, and their bodies are indented by how deeply nested they are.
+//! Straight-line code is wrapped at a configurable width. Comments (that is, anything that is not
+//! a command) can be kept or stripped, but they can only be kept when formatting from source
+//! text, since the [AbstractSyntaxTree] does not remember them.
+
+use crate::config::{Dialect, ProgramConfig};
+use crate::errors::CompilationError;
+use crate::parsing::{self, AbstractSyntaxTree, ConditionalID, ExtendedOp, Statement, Visitor};
+
+/// Options that control how code is laid out.
+#[derive(Debug, Clone)]
+pub struct FormatOptions {
+    /// Number of spaces to indent per loop depth.
+    pub indent_width: usize,
+    /// Maximum length of a line, including indentation. Lines always get at least one command,
+    /// even when deeply nested.
+    pub line_width: usize,
+    /// Whether to keep comments from the original source text.
+    pub keep_comments: bool,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        FormatOptions {
+            indent_width: 2,
+            line_width: 80,
+            keep_comments: true,
+        }
+    }
+}
+
+/// Formats an already-parsed program. Since the AST has no comments, none are emitted, except
+/// for a pragma when the program has a non-default config.
+pub fn format_ast(ast: &AbstractSyntaxTree, options: &FormatOptions) -> String {
+    let mut formatter = Formatter::new(options);
+    if !ast.config().is_default() {
+        formatter.comment(&ast.config().to_string());
+    }
+
+    parsing::walk(ast, &mut formatter);
+
+    formatter.finish()
+}
+
+/// Formats source text, keeping comments if requested.
+///
+/// The source is parsed first, so mismatched brackets are reported the same way [parsing::parse]
+/// would report them.
+pub fn format_source(
+    filename: &str,
+    source_text: &[u8],
+    options: &FormatOptions,
+) -> Result<String, CompilationError> {
+    let ast = parsing::parse(filename, source_text)?;
+    if !options.keep_comments {
+        return Ok(format_ast(&ast, options));
+    }
+
+    let mut formatter = Formatter::new(options);
+    let mut comment = Vec::new();
+    // Pragmas only change the dialect of the source text that comes after them.
+    let mut config = ProgramConfig::default();
+
+    let mut i = 0;
+    while i < source_text.len() {
+        let byte = source_text[i];
+        i += 1;
+
+        if byte == b';' && source_text.get(i) == Some(&b'#') {
+            // Pragmas are kept as-is, like any other comment.
+            let rest = &source_text[i + 1..];
+            let length = rest.iter().position(|&b| b == b'\n').unwrap_or(rest.len());
+            config
+                .apply_pragma(&String::from_utf8_lossy(&rest[..length]))
+                .expect("the source was already parsed");
+            comment.extend_from_slice(&source_text[i - 1..i + 1 + length]);
+            i += 1 + length;
+            continue;
+        }
+        if !is_command(byte, config.dialect) {
+            comment.push(byte);
+            continue;
+        }
+
+        formatter.comment(&String::from_utf8_lossy(&comment));
+        comment.clear();
+
+        match byte {
+            b'[' => formatter.open_loop(),
+            b']' => formatter.close_loop(),
+            _ => formatter.command(byte),
+        }
+    }
+    formatter.comment(&String::from_utf8_lossy(&comment));
+
+    Ok(formatter.finish())
+}
+
+// Internal stuff:
+
+fn is_command(byte: u8, dialect: Dialect) -> bool {
+    match byte {
+        b'+' | b'-' | b'>' | b'<' | b'.' | b',' | b'[' | b']' => true,
+        b'@' => dialect == Dialect::ExtendedTypeI,
+        _ => dialect == Dialect::ExtendedTypeI && ExtendedOp::from_byte(byte).is_some(),
+    }
+}
+
+/// Keeps track of the current line and the current depth while emitting code.
+struct Formatter<'a> {
+    options: &'a FormatOptions,
+    output: String,
+    line: String,
+    depth: usize,
+}
+
+impl<'a> Formatter<'a> {
+    fn new(options: &'a FormatOptions) -> Self {
+        Formatter {
+            options,
+            output: String::new(),
+            line: String::new(),
+            depth: 0,
+        }
+    }
+
+    fn command(&mut self, byte: u8) {
+        let indent = self.indentation();
+        if self.line.len() > indent.len() && self.line.len() >= self.options.line_width {
+            self.flush();
+        }
+        if self.line.is_empty() {
+            self.line.push_str(&indent);
+        }
+        self.line.push(byte as char);
+    }
+
+    fn open_loop(&mut self) {
+        self.own_line("[");
+        self.depth += 1;
+    }
+
+    fn close_loop(&mut self) {
+        self.depth = self.depth.saturating_sub(1);
+        self.own_line("]");
+    }
+
+    /// Emits each non-blank line of the comment on its own line.
+    fn comment(&mut self, text: &str) {
+        for line in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            self.own_line(line);
+        }
+    }
+
+    fn own_line(&mut self, text: &str) {
+        self.flush();
+        self.line.push_str(&self.indentation());
+        self.line.push_str(text);
+        self.flush();
+    }
+
+    fn indentation(&self) -> String {
+        " ".repeat(self.depth * self.options.indent_width)
+    }
+
+    fn flush(&mut self) {
+        if !self.line.is_empty() {
+            self.output.push_str(&self.line);
+            self.output.push('\n');
+            self.line.clear();
+        }
+    }
+
+    fn finish(mut self) -> String {
+        self.flush();
+        self.output
+    }
+}
+
+impl Visitor for Formatter<'_> {
+    fn visit_statement(&mut self, statement: Statement) {
+        for _ in 0..statement.count() {
+            self.command(statement.to_char() as u8);
+        }
+    }
+
+    fn enter_loop(&mut self, _id: ConditionalID, _depth: usize) {
+        self.open_loop();
+    }
+
+    fn exit_loop(&mut self, _id: ConditionalID, _depth: usize) {
+        self.close_loop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn indents_loops() {
+        let options = FormatOptions::default();
+        let formatted = format_source("<test>", b"++[>+[-]<-].", &options).unwrap();
+
+        assert_eq!("++\n[\n  >+\n  [\n    -\n  ]\n  <-\n]\n.\n", formatted);
+    }
+
+    #[test]
+    fn keeps_or_strips_comments() {
+        let source = b"add two\n++ then print .";
+        let mut options = FormatOptions::default();
+        let formatted = format_source("<test>", source, &options).unwrap();
+        assert_eq!("add two\n++\nthen print\n.\n", formatted);
+
+        options.keep_comments = false;
+        let formatted = format_source("<test>", source, &options).unwrap();
+        assert_eq!("++.\n", formatted);
+    }
+
+    #[test]
+    fn wraps_long_lines() {
+        let options = FormatOptions {
+            line_width: 4,
+            ..FormatOptions::default()
+        };
+        let formatted = format_source("<test>", b"++++++++++", &options).unwrap();
+
+        assert_eq!("++++\n++++\n++\n", formatted);
+    }
+
+    #[test]
+    fn only_uses_the_dialect_after_it_is_declared() {
+        let source = b"ok@\n;# dialect=ext1\n+@";
+        let formatted = format_source("<test>", source, &FormatOptions::default()).unwrap();
+
+        assert_eq!("ok@\n;# dialect=ext1\n+@\n", formatted);
+    }
+}
@@ -0,0 +1,111 @@
+//! Frontends turn source text into an [AbstractSyntaxTree].
+//!
+//! Brainfuck is just one possible surface syntax. Anything that can be parsed into an
+//! [AbstractSyntaxTree] can reuse the rest of the pipeline (IR, optimizer, interpreter, and JIT)
+//! by implementing [Frontend] and adding itself to a [FrontendRegistry].
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::errors::{CompilationError, CompilationWarning};
+use crate::parsing::{self, AbstractSyntaxTree, ParseLimits};
+
+/// Parses some surface syntax into an [AbstractSyntaxTree].
+pub trait Frontend {
+    /// Parses the source text. `filename` is only used for error messages.
+    fn parse(
+        &self,
+        filename: &str,
+        source_text: &[u8],
+    ) -> Result<AbstractSyntaxTree, CompilationError>;
+
+    /// Like [Frontend::parse], but also returns warnings. By default, there are no warnings.
+    fn parse_with_warnings(
+        &self,
+        filename: &str,
+        source_text: &[u8],
+    ) -> Result<(AbstractSyntaxTree, Vec<CompilationWarning>), CompilationError> {
+        self.parse(filename, source_text)
+            .map(|ast| (ast, Vec::new()))
+    }
+}
+
+/// The frontend for good ol' brainfuck.
+#[derive(Debug, Default, Clone)]
+pub struct BrainfuckFrontend {
+    limits: ParseLimits,
+}
+
+/// Keeps track of which [Frontend] should parse which kind of file, by file extension.
+pub struct FrontendRegistry {
+    frontends: HashMap<String, Box<dyn Frontend>>,
+}
+
+// Implementations
+
+impl BrainfuckFrontend {
+    pub fn new(limits: ParseLimits) -> Self {
+        BrainfuckFrontend { limits }
+    }
+}
+
+impl Frontend for BrainfuckFrontend {
+    fn parse(
+        &self,
+        filename: &str,
+        source_text: &[u8],
+    ) -> Result<AbstractSyntaxTree, CompilationError> {
+        self.parse_with_warnings(filename, source_text)
+            .map(|(ast, _warnings)| ast)
+    }
+
+    fn parse_with_warnings(
+        &self,
+        filename: &str,
+        source_text: &[u8],
+    ) -> Result<(AbstractSyntaxTree, Vec<CompilationWarning>), CompilationError> {
+        parsing::parse_with_limits(filename, source_text, &self.limits)
+    }
+}
+
+impl FrontendRegistry {
+    /// Creates a registry with no frontends at all.
+    pub fn empty() -> Self {
+        FrontendRegistry {
+            frontends: HashMap::new(),
+        }
+    }
+
+    /// Creates a registry that knows about `.bf` and `.b` files.
+    pub fn new() -> Self {
+        let mut registry = FrontendRegistry::empty();
+        registry.register("bf", Box::new(BrainfuckFrontend::default()));
+        registry.register("b", Box::new(BrainfuckFrontend::default()));
+
+        registry
+    }
+
+    /// Uses the frontend for files with the given extension (without the leading dot), replacing
+    /// any frontend that was already registered for it.
+    pub fn register(&mut self, extension: &str, frontend: Box<dyn Frontend>) {
+        self.frontends.insert(extension.to_string(), frontend);
+    }
+
+    /// Returns the frontend registered for the extension, if any.
+    pub fn get(&self, extension: &str) -> Option<&dyn Frontend> {
+        self.frontends.get(extension).map(|f| f.as_ref())
+    }
+
+    /// Returns the frontend for the path's extension, if any.
+    pub fn for_path(&self, path: &Path) -> Option<&dyn Frontend> {
+        path.extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(|ext| self.get(ext))
+    }
+}
+
+impl Default for FrontendRegistry {
+    fn default() -> Self {
+        FrontendRegistry::new()
+    }
+}
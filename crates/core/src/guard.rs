@@ -0,0 +1,364 @@
+//! Catches generated code moving off the tape, without generating a single bounds check.
+//!
+//! A [GuardedTape] has an inaccessible guard on either side of it, so generated code that touches
+//! a cell off either end of the tape faults. While a program runs on a guarded tape, a signal
+//! handler turns a fault in one of its guards into a panic, raised as if the faulting instruction
+//! had called a function that panics. The panic unwinds out of the generated code (see
+//! [unwind](crate::unwind)), and [catch_faults] turns it back into the kind of [RuntimeError] that
+//! the interpreter would have reported.
+//!
+//! Generated code only faults once it touches a cell, so moving further than a guard without
+//! touching a cell could skip right over it. The code generator keeps track of how far off the tape
+//! the pointer could be since the code last touched a cell. When the next cell the code touches
+//! could be past a guard, the code touches cells on the way there first, each less than
+//! [GUARD_SIZE] bytes from the last.
+
+use std::cell::Cell;
+use std::ops::{Deref, DerefMut};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Mutex, OnceLock};
+
+use crate::errors::RuntimeErrorKind;
+use mmap_jit::GuardedRegion;
+
+/// How many bytes of inaccessible memory are on either side of a [GuardedTape].
+pub const GUARD_SIZE: usize = 1 << 20;
+
+/// A tape with guards on either side of it. Give the JIT one as its universe, and moving off the
+/// tape is a [RuntimeError](crate::RuntimeError), instead of undefined behavior.
+///
+/// The tape starts right after the lower guard, but the upper guard starts at the next page
+/// boundary after the end, so a program can use the cells up to there before it faults.
+pub struct GuardedTape {
+    region: GuardedRegion,
+}
+
+/// Where a tape's guards are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Guards {
+    tape: usize,
+    below: (usize, usize),
+    above: (usize, usize),
+}
+
+/// A fault in one of the guards, raised as a panic out of the generated code.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Fault {
+    pub kind: RuntimeErrorKind,
+    /// The address of the instruction that faulted.
+    pub pc: usize,
+}
+
+/// Whether [redirect] works on this platform. Everywhere else, touching a guard still stops the
+/// program, just not with a [RuntimeError](crate::RuntimeError).
+const CAN_REDIRECT: bool = cfg!(all(
+    any(target_os = "linux", target_os = "macos"),
+    any(target_arch = "x86_64", target_arch = "aarch64")
+));
+
+/// Every tape that's alive, so that [catch_faults] can tell whether a universe is one.
+static TAPES: Mutex<Vec<Guards>> = Mutex::new(Vec::new());
+
+/// What the signal handler does when a signal isn't for it.
+static PREVIOUS_HANDLERS: OnceLock<[(libc::c_int, libc::sigaction); 2]> = OnceLock::new();
+
+thread_local! {
+    /// The guards of the tape that generated code is running on, on this thread.
+    static ACTIVE: Cell<Option<Guards>> = const { Cell::new(None) };
+    /// Set by the signal handler, for [raise_fault] to pick up.
+    static FAULT: Cell<Option<Fault>> = const { Cell::new(None) };
+}
+
+impl GuardedTape {
+    /// Allocates a tape of `length` cells, all zero.
+    pub fn new(length: usize) -> Self {
+        install_signal_handler();
+        let region = GuardedRegion::allocate(length, GUARD_SIZE).unwrap();
+        let (below, above) = region.guards();
+        TAPES.lock().unwrap().push(Guards {
+            tape: region.addr_mut() as usize,
+            below: (below.start, below.end),
+            above: (above.start, above.end),
+        });
+
+        GuardedTape { region }
+    }
+}
+
+impl Drop for GuardedTape {
+    fn drop(&mut self) {
+        let tape = self.region.addr_mut() as usize;
+        TAPES.lock().unwrap().retain(|guards| guards.tape != tape);
+    }
+}
+
+impl Deref for GuardedTape {
+    type Target = [u8];
+    fn deref(&self) -> &Self::Target {
+        &self.region
+    }
+}
+
+impl DerefMut for GuardedTape {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.region
+    }
+}
+
+impl Guards {
+    /// Which end of the tape the address is off, if it's in one of the guards.
+    fn classify(&self, address: usize) -> Option<RuntimeErrorKind> {
+        let contains = |(start, end): (usize, usize)| (start..end).contains(&address);
+        if contains(self.below) {
+            Some(RuntimeErrorKind::AddressBelowZero)
+        } else if contains(self.above) {
+            Some(RuntimeErrorKind::AddressBeyondEnd)
+        } else {
+            None
+        }
+    }
+}
+
+/// Runs `f`, which runs generated code on `universe`. When the universe is a [GuardedTape], and
+/// the code touches one of its guards, the code is abandoned, and this returns the [Fault].
+///
+/// The code must have unwind info, since that's how it's abandoned.
+pub(crate) fn catch_faults<T>(universe: &[u8], f: impl FnOnce() -> T) -> Result<T, Fault> {
+    let start = universe.as_ptr() as usize;
+    let guards = TAPES
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|guards| guards.tape == start)
+        .copied();
+    if guards.is_none() || !CAN_REDIRECT {
+        return Ok(f());
+    }
+
+    /// Puts back the guards of whichever tape was active before, even if the code panics.
+    struct Restore(Option<Guards>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            ACTIVE.set(self.0);
+        }
+    }
+    let _restore = Restore(ACTIVE.replace(guards));
+
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| match payload.downcast::<Fault>() {
+        Ok(fault) => *fault,
+        Err(payload) => panic::resume_unwind(payload),
+    })
+}
+
+/// Where the signal handler sends generated code that faulted in a guard. Never returns, so that
+/// the code doesn't carry on from the instruction that faulted.
+extern "C-unwind" fn raise_fault() -> ! {
+    let fault = FAULT
+        .take()
+        .expect("only the signal handler sends code here");
+    // Not panic!(), since this isn't a bug, and the panic hook shouldn't print anything.
+    panic::resume_unwind(Box::new(fault))
+}
+
+fn install_signal_handler() {
+    PREVIOUS_HANDLERS.get_or_init(|| {
+        // Accessing a guard is SIGSEGV on Linux, but can be SIGBUS on macOS.
+        [libc::SIGSEGV, libc::SIGBUS].map(|signal| {
+            // SAFETY: both structs are plain data, and zero is a valid (empty) value for them.
+            unsafe {
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = on_fault as *const () as usize;
+                action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+                libc::sigemptyset(&mut action.sa_mask);
+                let mut previous: libc::sigaction = std::mem::zeroed();
+                libc::sigaction(signal, &action, &mut previous);
+                (signal, previous)
+            }
+        })
+    });
+}
+
+extern "C" fn on_fault(
+    signal: libc::c_int,
+    info: *mut libc::siginfo_t,
+    context: *mut libc::c_void,
+) {
+    // SAFETY: the kernel passes a valid siginfo_t, which has the address that faulted.
+    let address = unsafe { (*info).si_addr() } as usize;
+    let kind = ACTIVE.get().and_then(|guards| guards.classify(address));
+
+    match kind {
+        Some(kind) => {
+            // SAFETY: the kernel passes a valid context for the thread that faulted.
+            let pc = unsafe { redirect(context, raise_fault as *const () as usize) };
+            FAULT.set(Some(Fault { kind, pc }));
+        }
+        // SAFETY: whatever handled the signal before expects exactly what was passed here.
+        None => unsafe { forward(signal, info, context) },
+    }
+}
+
+/// Passes the signal to whatever handled it before [install_signal_handler].
+unsafe fn forward(signal: libc::c_int, info: *mut libc::siginfo_t, context: *mut libc::c_void) {
+    let previous = PREVIOUS_HANDLERS
+        .get()
+        .and_then(|handlers| handlers.iter().find(|&&(s, _)| s == signal))
+        .map(|(_, action)| action);
+    match previous {
+        Some(action) if action.sa_sigaction > libc::SIG_IGN => {
+            if action.sa_flags & libc::SA_SIGINFO != 0 {
+                let handler: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) =
+                    std::mem::transmute(action.sa_sigaction);
+                handler(signal, info, context);
+            } else {
+                let handler: extern "C" fn(libc::c_int) = std::mem::transmute(action.sa_sigaction);
+                handler(signal);
+            }
+        }
+        // Nothing handled it before, so returning faults again, and the default kills the process.
+        _ => {
+            libc::signal(signal, libc::SIG_DFL);
+        }
+    }
+}
+
+/// Makes the thread carry on at `to`, as if the instruction that faulted had called it, and
+/// returns the address of that instruction.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+unsafe fn redirect(context: *mut libc::c_void, to: usize) -> usize {
+    let gregs = &mut (*(context as *mut libc::ucontext_t)).uc_mcontext.gregs;
+    let pc = gregs[libc::REG_RIP as usize] as usize;
+    // push rip
+    let sp = gregs[libc::REG_RSP as usize] as usize - 8;
+    *(sp as *mut usize) = pc;
+    gregs[libc::REG_RSP as usize] = sp as i64;
+    gregs[libc::REG_RIP as usize] = to as i64;
+    pc
+}
+
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+unsafe fn redirect(context: *mut libc::c_void, to: usize) -> usize {
+    let mcontext = &mut (*(context as *mut libc::ucontext_t)).uc_mcontext;
+    let pc = mcontext.pc as usize;
+    // The link register was saved in the prologue, so it's free to hold the "return" address.
+    mcontext.regs[30] = pc as u64;
+    mcontext.pc = to as u64;
+    pc
+}
+
+#[cfg(all(target_os = "macos", target_arch = "x86_64"))]
+unsafe fn redirect(context: *mut libc::c_void, to: usize) -> usize {
+    let state = &mut (*(*(context as *mut libc::ucontext_t)).uc_mcontext).__ss;
+    let pc = state.__rip as usize;
+    // push rip
+    state.__rsp -= 8;
+    *(state.__rsp as *mut usize) = pc;
+    state.__rip = to as u64;
+    pc
+}
+
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+unsafe fn redirect(context: *mut libc::c_void, to: usize) -> usize {
+    let state = &mut (*(*(context as *mut libc::ucontext_t)).uc_mcontext).__ss;
+    let pc = state.__pc as usize;
+    // The link register was saved in the prologue, so it's free to hold the "return" address.
+    state.__lr = pc as u64;
+    state.__pc = to as u64;
+    pc
+}
+
+#[cfg(not(all(
+    any(target_os = "linux", target_os = "macos"),
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+unsafe fn redirect(_context: *mut libc::c_void, _to: usize) -> usize {
+    // No tape is ever active without CAN_REDIRECT, so no fault is ever in a guard.
+    unreachable!()
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+    use crate::parsing::parse;
+    use crate::BrainmuckProgram;
+
+    fn run(source: &[u8], tape: &mut [u8]) -> (Result<usize, RuntimeErrorKind>, Vec<u8>) {
+        let program = crate::compile_to_native_code(&parse("<test>", source).unwrap());
+        let mut output = Vec::new();
+        let result = program
+            .run_with_io(tape, &mut &b""[..], &mut output)
+            .map(|stats| stats.final_address)
+            .map_err(|error| error.kind());
+        (result, output)
+    }
+
+    #[test]
+    fn moving_off_the_tape_is_an_error() {
+        let mut tape = GuardedTape::new(16);
+
+        // Everything printed before the error still comes out:
+        let (result, output) = run(b"+++.<+", &mut tape);
+        assert_eq!(Err(RuntimeErrorKind::AddressBelowZero), result);
+        assert_eq!(vec![3], output);
+
+        let (result, _) = run(b"+[>+]", &mut tape);
+        assert_eq!(Err(RuntimeErrorKind::AddressBeyondEnd), result);
+
+        // The tape is still usable afterwards:
+        tape.fill(0);
+        assert_eq!((Ok(2), vec![1]), run(b"+>+>+<<.>>", &mut tape));
+    }
+
+    #[test]
+    fn moving_further_than_a_guard_is_still_an_error() {
+        let mut tape = GuardedTape::new(16);
+        let past_the_guard = GUARD_SIZE + GUARD_SIZE / 10;
+        let moves = |c: u8, n: usize, rest: &[u8]| [&vec![c; n][..], rest].concat();
+
+        let (result, output) = run(&moves(b'>', past_the_guard, b"+."), &mut tape);
+        assert_eq!(
+            (Err(RuntimeErrorKind::AddressBeyondEnd), vec![]),
+            (result, output)
+        );
+        let (result, _) = run(&moves(b'<', past_the_guard, b"+."), &mut tape);
+        assert_eq!(Err(RuntimeErrorKind::AddressBelowZero), result);
+        let (result, _) = run(&moves(b'<', 3 * GUARD_SIZE, b"+."), &mut tape);
+        assert_eq!(Err(RuntimeErrorKind::AddressBelowZero), result);
+
+        // ...and so is touching a cell that far away, without moving there:
+        let far_cell = [
+            moves(b'>', past_the_guard, b"+"),
+            vec![b'<'; past_the_guard],
+        ]
+        .concat();
+        let (result, _) = run(&far_cell, &mut tape);
+        assert_eq!(Err(RuntimeErrorKind::AddressBeyondEnd), result);
+
+        // None of that touched the tape:
+        assert!(tape.iter().all(|&cell| cell == 0));
+    }
+
+    #[test]
+    fn catches_faults_without_debug_info() {
+        use crate::codegen::CodegenOptions;
+
+        let ast = parse("<test>", b"+.<+").unwrap();
+        let options = CodegenOptions {
+            debug_info: false,
+            ..CodegenOptions::from(ast.config())
+        };
+        let program = crate::compile_cfg_to_native_code_with_options(
+            &crate::ast_to_optimized_cfg(&ast),
+            &options,
+        );
+
+        let mut tape = GuardedTape::new(16);
+        let mut output = Vec::new();
+        let result = program.run_with_io(&mut tape, &mut &b""[..], &mut output);
+        assert_eq!(
+            RuntimeErrorKind::AddressBelowZero,
+            result.unwrap_err().kind()
+        );
+        assert_eq!(vec![1], output);
+    }
+}
@@ -0,0 +1,297 @@
+//! Analyses over a [ControlFlowGraph]: who dominates whom, and where the loops are.
+//!
+//! Brainfuck only has one kind of loop, so every `[ ]` turns into a natural loop, but passes
+//! should ask this module rather than pattern-matching on `beq`/`b` pairs themselves.
+
+use std::collections::{HashMap, HashSet};
+
+use super::{BlockLabel, ControlFlowGraph};
+
+/// The dominator tree of a [ControlFlowGraph]. Block `a` dominates block `b` when every path from
+/// the entry block to `b` goes through `a`.
+#[derive(Debug, Clone)]
+pub struct Dominators {
+    entry: Option<BlockLabel>,
+    /// The immediate dominator of every reachable block, except for the entry block.
+    idom: HashMap<BlockLabel, BlockLabel>,
+}
+
+/// A natural loop: a header block, and every block that can reach a back-edge into the header
+/// without going through the header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NaturalLoop {
+    /// The only way into the loop.
+    pub header: BlockLabel,
+    /// Every block in the loop, including the header, in program order.
+    pub blocks: Vec<BlockLabel>,
+    /// How many loops contain this one, plus one. Outermost loops have a depth of 1.
+    pub depth: usize,
+    /// The header of the innermost loop that contains this one, if any.
+    pub parent: Option<BlockLabel>,
+}
+
+/// All natural loops in a [ControlFlowGraph], with their nesting.
+#[derive(Debug, Clone)]
+pub struct LoopNest {
+    loops: Vec<NaturalLoop>,
+}
+
+/// Computes the dominator tree for the graph, starting from the first block.
+///
+/// Uses the iterative algorithm from Cooper, Harvey, and Kennedy's "A Simple, Fast Dominance
+/// Algorithm".
+pub fn dominators(cfg: &ControlFlowGraph) -> Dominators {
+    let entry = match cfg.blocks().first() {
+        Some(block) => block.label(),
+        None => {
+            return Dominators {
+                entry: None,
+                idom: HashMap::new(),
+            }
+        }
+    };
+
+    let order = reverse_postorder(cfg, entry);
+    let rpo_number: HashMap<BlockLabel, usize> =
+        order.iter().enumerate().map(|(i, &b)| (b, i)).collect();
+
+    // Indexed by reverse postorder number.
+    let mut idom: Vec<Option<usize>> = vec![None; order.len()];
+    idom[0] = Some(0);
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+
+        for (i, &label) in order.iter().enumerate().skip(1) {
+            let mut new_idom = None;
+            for pred in cfg.predecessors(label) {
+                let p = match rpo_number.get(pred) {
+                    Some(&p) if idom[p].is_some() => p,
+                    // Unreachable or not processed yet.
+                    _ => continue,
+                };
+                new_idom = Some(match new_idom {
+                    None => p,
+                    Some(current) => intersect(&idom, p, current),
+                });
+            }
+
+            if new_idom.is_some() && idom[i] != new_idom {
+                idom[i] = new_idom;
+                changed = true;
+            }
+        }
+    }
+
+    let idom = idom
+        .iter()
+        .enumerate()
+        .skip(1)
+        .filter_map(|(i, dom)| dom.map(|d| (order[i], order[d])))
+        .collect();
+
+    Dominators {
+        entry: Some(entry),
+        idom,
+    }
+}
+
+/// Finds the natural loops in the graph.
+pub fn find_loops(cfg: &ControlFlowGraph, dominators: &Dominators) -> LoopNest {
+    // Collect the body of each loop, by header. Back-edges to the same header are one loop.
+    let mut bodies: HashMap<BlockLabel, HashSet<BlockLabel>> = HashMap::new();
+
+    for block in cfg.blocks() {
+        let tail = block.label();
+        for &header in cfg.successors(tail) {
+            if !dominators.dominates(header, tail) {
+                continue;
+            }
+
+            let body = bodies
+                .entry(header)
+                .or_insert_with(|| [header].into_iter().collect());
+            let mut worklist = vec![tail];
+            while let Some(label) = worklist.pop() {
+                if body.insert(label) {
+                    worklist.extend(cfg.predecessors(label));
+                }
+            }
+        }
+    }
+
+    let program_order: HashMap<BlockLabel, usize> = cfg
+        .blocks()
+        .iter()
+        .enumerate()
+        .map(|(i, b)| (b.label(), i))
+        .collect();
+
+    let mut loops: Vec<NaturalLoop> = bodies
+        .iter()
+        .map(|(&header, body)| {
+            let mut blocks: Vec<BlockLabel> = body.iter().copied().collect();
+            blocks.sort_by_key(|label| program_order[label]);
+
+            // The enclosing loops are the ones whose body contains this header.
+            let enclosing: Vec<(BlockLabel, usize)> = bodies
+                .iter()
+                .filter(|&(&other, other_body)| other != header && other_body.contains(&header))
+                .map(|(&other, other_body)| (other, other_body.len()))
+                .collect();
+
+            NaturalLoop {
+                header,
+                blocks,
+                depth: enclosing.len() + 1,
+                parent: enclosing
+                    .iter()
+                    .min_by_key(|&&(_, size)| size)
+                    .map(|&(other, _)| other),
+            }
+        })
+        .collect();
+    loops.sort_by_key(|l| program_order[&l.header]);
+
+    LoopNest { loops }
+}
+
+// Implementation
+
+impl Dominators {
+    /// Returns the block that immediately dominates this one. The entry block and unreachable
+    /// blocks do not have one.
+    pub fn immediate_dominator(&self, label: BlockLabel) -> Option<BlockLabel> {
+        self.idom.get(&label).copied()
+    }
+
+    /// Returns whether every path from the entry to `b` goes through `a`. Every reachable block
+    /// dominates itself.
+    pub fn dominates(&self, a: BlockLabel, b: BlockLabel) -> bool {
+        if !self.is_reachable(b) {
+            return false;
+        }
+
+        let mut current = Some(b);
+        while let Some(label) = current {
+            if label == a {
+                return true;
+            }
+            current = self.immediate_dominator(label);
+        }
+
+        false
+    }
+
+    /// Returns whether the block can be reached from the entry block.
+    pub fn is_reachable(&self, label: BlockLabel) -> bool {
+        self.entry == Some(label) || self.idom.contains_key(&label)
+    }
+}
+
+impl LoopNest {
+    /// Every loop, in program order of their headers.
+    pub fn loops(&self) -> &[NaturalLoop] {
+        &self.loops
+    }
+
+    /// Returns the loop with the given header, if there is one.
+    pub fn loop_with_header(&self, header: BlockLabel) -> Option<&NaturalLoop> {
+        self.loops.iter().find(|l| l.header == header)
+    }
+
+    /// How many loops contain the block. Blocks outside of any loop have a depth of 0.
+    pub fn loop_depth(&self, label: BlockLabel) -> usize {
+        self.loops
+            .iter()
+            .filter(|l| l.blocks.contains(&label))
+            .count()
+    }
+
+    /// The deepest nesting of any loop in the graph.
+    pub fn max_depth(&self) -> usize {
+        self.loops.iter().map(|l| l.depth).max().unwrap_or(0)
+    }
+}
+
+// Internal stuff:
+
+/// Returns the blocks reachable from the entry, in reverse postorder.
+fn reverse_postorder(cfg: &ControlFlowGraph, entry: BlockLabel) -> Vec<BlockLabel> {
+    let mut visited = HashSet::new();
+    let mut postorder = Vec::new();
+    // (block, index of the next successor to visit)
+    let mut stack = vec![(entry, 0)];
+    visited.insert(entry);
+
+    while let Some((label, next)) = stack.pop() {
+        match cfg.successors(label).get(next) {
+            Some(&successor) => {
+                stack.push((label, next + 1));
+                if visited.insert(successor) {
+                    stack.push((successor, 0));
+                }
+            }
+            None => postorder.push(label),
+        }
+    }
+
+    postorder.reverse();
+    postorder
+}
+
+/// Finds the common dominator of two blocks, by their reverse postorder numbers.
+fn intersect(idom: &[Option<usize>], mut a: usize, mut b: usize) -> usize {
+    while a != b {
+        while a > b {
+            a = idom[a].expect("processed blocks have a dominator");
+        }
+        while b > a {
+            b = idom[b].expect("processed blocks have a dominator");
+        }
+    }
+
+    a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::lower;
+    use crate::parsing::parse;
+
+    #[test]
+    fn loop_headers_dominate_their_bodies() {
+        // L0: +  L1: beq L3  L2: . b L1  L3: terminate
+        let cfg = lower(&parse("<test>", b"+[.-]").unwrap());
+        let doms = dominators(&cfg);
+
+        assert_eq!(None, doms.immediate_dominator(BlockLabel(0)));
+        assert_eq!(Some(BlockLabel(0)), doms.immediate_dominator(BlockLabel(1)));
+        assert_eq!(Some(BlockLabel(1)), doms.immediate_dominator(BlockLabel(2)));
+        assert_eq!(Some(BlockLabel(1)), doms.immediate_dominator(BlockLabel(3)));
+        assert!(doms.dominates(BlockLabel(0), BlockLabel(3)));
+        assert!(!doms.dominates(BlockLabel(2), BlockLabel(3)));
+    }
+
+    #[test]
+    fn finds_nested_loops() {
+        let cfg = lower(&parse("<test>", b"+[.[.-]-]+[.-]").unwrap());
+        let nest = find_loops(&cfg, &dominators(&cfg));
+
+        assert_eq!(3, nest.loops().len());
+        assert_eq!(2, nest.max_depth());
+
+        let outer = &nest.loops()[0];
+        let inner = &nest.loops()[1];
+        assert_eq!(1, outer.depth);
+        assert_eq!(None, outer.parent);
+        assert_eq!(2, inner.depth);
+        assert_eq!(Some(outer.header), inner.parent);
+        assert!(inner.blocks.iter().all(|b| outer.blocks.contains(b)));
+        assert_eq!(2, nest.loop_depth(inner.blocks[1]));
+        assert_eq!(0, nest.loop_depth(BlockLabel(0)));
+        assert_eq!(1, nest.loops()[2].depth);
+    }
+}
@@ -0,0 +1,166 @@
+//! Compares two [ControlFlowGraph]s, ignoring how their blocks happen to be numbered.
+//!
+//! Handy for optimizer tests: write the expected output with [super::parse_ir], and compare:
+//!
+//! ```
+//! use brainmuck_core::ir::{assert_same_structure, lower, parse_ir};
+//!
+//! let ast = brainmuck_core::parse("<example>", b"+.").unwrap();
+//! let expected = parse_ir("L7:\n add [p], [p], #1\n putchar\n terminate").unwrap();
+//! assert_same_structure(&expected, &lower(&ast));
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+
+use super::{BlockLabel, ControlFlowGraph, ThreeAddressInstruction};
+
+/// The first place where two [ControlFlowGraph]s differ. See [structural_diff].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CfgDifference {
+    /// The graphs have a different number of blocks.
+    BlockCount { expected: usize, actual: usize },
+    /// The blocks at the same position differ. An instruction is `None` if one block ended
+    /// before the other.
+    Instruction {
+        expected_block: BlockLabel,
+        actual_block: BlockLabel,
+        index: usize,
+        expected: Option<ThreeAddressInstruction>,
+        actual: Option<ThreeAddressInstruction>,
+    },
+}
+
+/// Compares the graphs block by block, in order. Labels are matched up by the position of their
+/// blocks, so `L0, L1` and `L3, L7` are the same, as long as branches to them match up too.
+///
+/// Returns the first difference, or `None` if they're the same.
+pub fn structural_diff(
+    expected: &ControlFlowGraph,
+    actual: &ControlFlowGraph,
+) -> Option<CfgDifference> {
+    if expected.blocks().len() != actual.blocks().len() {
+        return Some(CfgDifference::BlockCount {
+            expected: expected.blocks().len(),
+            actual: actual.blocks().len(),
+        });
+    }
+
+    // Where each label of the actual graph should be in the expected graph.
+    let renamed: HashMap<BlockLabel, BlockLabel> = actual
+        .blocks()
+        .iter()
+        .zip(expected.blocks())
+        .map(|(a, e)| (a.label(), e.label()))
+        .collect();
+    let rename = |instr: ThreeAddressInstruction| {
+        use ThreeAddressInstruction::{BranchIfZero, BranchTo};
+        match instr {
+            BranchIfZero(target) => BranchIfZero(*renamed.get(&target).unwrap_or(&target)),
+            BranchTo(target) => BranchTo(*renamed.get(&target).unwrap_or(&target)),
+            _ => instr,
+        }
+    };
+
+    for (e, a) in expected.blocks().iter().zip(actual.blocks()) {
+        let length = e.instructions().len().max(a.instructions().len());
+        for index in 0..length {
+            let expected_instr = e.instructions().get(index).copied();
+            let actual_instr = a.instructions().get(index).copied();
+
+            if expected_instr != actual_instr.map(rename) {
+                return Some(CfgDifference::Instruction {
+                    expected_block: e.label(),
+                    actual_block: a.label(),
+                    index,
+                    expected: expected_instr,
+                    actual: actual_instr,
+                });
+            }
+        }
+    }
+
+    None
+}
+
+/// Panics with a readable message, unless the graphs have the same structure.
+///
+/// # Panics
+///
+/// When [structural_diff] finds a difference. The message includes both graphs in full.
+#[track_caller]
+pub fn assert_same_structure(expected: &ControlFlowGraph, actual: &ControlFlowGraph) {
+    if let Some(difference) = structural_diff(expected, actual) {
+        panic!(
+            "control flow graphs differ: {}\n\nexpected:\n{}\nactual:\n{}",
+            difference, expected, actual
+        );
+    }
+}
+
+impl fmt::Display for CfgDifference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let show = |instr: &Option<ThreeAddressInstruction>| match instr {
+            Some(instr) => instr.to_string().replace('\t', " "),
+            None => String::from("(end of block)"),
+        };
+
+        match self {
+            CfgDifference::BlockCount { expected, actual } => {
+                write!(f, "expected {} blocks, got {}", expected, actual)
+            }
+            CfgDifference::Instruction {
+                expected_block: BlockLabel(e),
+                actual_block: BlockLabel(a),
+                index,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "instruction {} of L{} (L{} in actual): expected `{}`, got `{}`",
+                index,
+                e,
+                a,
+                show(expected),
+                show(actual)
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::parse_ir;
+
+    #[test]
+    fn ignores_label_numbering() {
+        let a = parse_ir("L0:\n beq [p], L2\nL1:\n b L0\nL2:\n terminate").unwrap();
+        let b = parse_ir("L4:\n beq [p], L9\nL5:\n b L4\nL9:\n terminate").unwrap();
+        assert_eq!(None, structural_diff(&a, &b));
+
+        let c = parse_ir("L4:\n beq [p], L5\nL5:\n b L4\nL9:\n terminate").unwrap();
+        assert_eq!(
+            Some(CfgDifference::Instruction {
+                expected_block: BlockLabel(0),
+                actual_block: BlockLabel(4),
+                index: 0,
+                expected: Some(ThreeAddressInstruction::BranchIfZero(BlockLabel(2))),
+                actual: Some(ThreeAddressInstruction::BranchIfZero(BlockLabel(5))),
+            }),
+            structural_diff(&a, &c)
+        );
+    }
+
+    #[test]
+    fn reports_missing_instructions() {
+        let a = parse_ir("L0:\n putchar\n terminate").unwrap();
+        let b = parse_ir("L0:\n putchar").unwrap();
+        let difference = structural_diff(&a, &b).unwrap();
+
+        assert_eq!(
+            "instruction 1 of L0 (L0 in actual): expected `terminate`, got `(end of block)`",
+            difference.to_string()
+        );
+    }
+}
@@ -0,0 +1,743 @@
+//! The internal representation of a program.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Write;
+
+use crate::parsing::{AbstractSyntaxTree, ConditionalID, ExtendedOp, SourcePosition, Statement};
+
+pub mod analysis;
+mod diff;
+pub mod ssa;
+mod stats;
+mod text;
+
+pub use self::diff::{assert_same_structure, structural_diff, CfgDifference};
+pub use self::stats::CfgStats;
+pub use self::text::{parse_ir, ParseIrError};
+
+/// A basic, internal representation of the code. This is a series of basic blocks
+///
+/// Edges between blocks are implied by branch instructions and by the order of the blocks, but
+/// they are computed up front, so you can ask for a block's [ControlFlowGraph::successors] and
+/// [ControlFlowGraph::predecessors] directly.
+#[derive(Debug, Clone)]
+pub struct ControlFlowGraph {
+    blocks: Vec<BasicBlock>,
+    successors: HashMap<BlockLabel, Vec<BlockLabel>>,
+    predecessors: HashMap<BlockLabel, Vec<BlockLabel>>,
+}
+
+/// A basic block has only one way in and exactly one way out
+#[derive(Debug, Clone)]
+pub struct BasicBlock {
+    block_id: BlockLabel,
+    instructions: Vec<ThreeAddressInstruction>,
+    /// Where the code for this block starts in the source text, if known.
+    position: Option<SourcePosition>,
+}
+
+/// Instructions that manipulate at most three addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreeAddressInstruction {
+    ChangeVal(u8),
+    ChangeAddr(i32),
+    PutChar,
+    GetChar,
+    BranchIfZero(BlockLabel),
+    BranchTo(BlockLabel),
+    NoOp,
+    Zero,
+    /// Adds the current cell times `factor` to the cell at `dst_offset` from the current cell.
+    MulAdd {
+        dst_offset: i32,
+        factor: u8,
+    },
+    /// Moves the pointer by `step` cells at a time until it points at a zero cell.
+    FindZero(i32),
+    /// Adds the delta to the cell at the offset from the current cell, without moving the pointer.
+    ChangeValAt(i32, u8),
+    /// Sets the cell at the offset from the current cell to the value, without moving the pointer.
+    SetValAt(i32, u8),
+    Terminate,
+    Extended(ExtendedOp),
+    /// Writes output that was computed at compile time, without looking at the tape.
+    EmitBytes(EmittedBytes),
+}
+
+/// A few bytes of output, stored inline so that [ThreeAddressInstruction] stays `Copy`. Longer
+/// output is split into several [ThreeAddressInstruction::EmitBytes]. See [EmittedBytes::chunks].
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct EmittedBytes {
+    len: u8,
+    bytes: [u8; EmittedBytes::CAPACITY],
+}
+
+/// An invariant of the [ControlFlowGraph] that does not hold. See [ControlFlowGraph::verify].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerificationError {
+    /// The block branches to a label that does not exist.
+    UnknownBranchTarget {
+        block: BlockLabel,
+        target: BlockLabel,
+    },
+    /// More than one block has the same label.
+    DuplicateLabel(BlockLabel),
+    /// A placeholder [ThreeAddressInstruction::NoOp] was left in this block.
+    LeftoverNoOp(BlockLabel),
+    /// The program can never terminate, since there's no reachable `Terminate`.
+    NoReachableTerminate,
+    /// More than one `Terminate` is reachable. Code that ends the program early should branch to
+    /// the one `Terminate` instead.
+    MoreThanOneTerminate,
+    /// The last block must end in `Terminate`, so that native code returns.
+    LastBlockDoesNotTerminate,
+}
+
+/// A label for a basic block. Also serves as a branch target.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub struct BlockLabel(pub usize);
+
+// Implementation
+
+impl EmittedBytes {
+    /// The most bytes that fit in one instruction.
+    pub const CAPACITY: usize = 8;
+
+    /// Returns `None` if there are more than [EmittedBytes::CAPACITY] bytes.
+    pub fn new(output: &[u8]) -> Option<Self> {
+        if output.len() > Self::CAPACITY {
+            return None;
+        }
+
+        let mut bytes = [0; Self::CAPACITY];
+        bytes[..output.len()].copy_from_slice(output);
+        Some(EmittedBytes {
+            len: output.len() as u8,
+            bytes,
+        })
+    }
+
+    /// Splits the output into as few pieces as possible.
+    pub fn chunks(output: &[u8]) -> impl Iterator<Item = EmittedBytes> + '_ {
+        output
+            .chunks(Self::CAPACITY)
+            .map(|chunk| EmittedBytes::new(chunk).expect("chunks are small enough"))
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+}
+
+impl fmt::Debug for EmittedBytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("EmittedBytes")
+            .field(&self.as_slice())
+            .finish()
+    }
+}
+
+impl ControlFlowGraph {
+    pub fn new(blocks: Vec<BasicBlock>) -> Self {
+        let mut successors = HashMap::new();
+        let mut predecessors: HashMap<BlockLabel, Vec<BlockLabel>> = HashMap::new();
+
+        for (i, block) in blocks.iter().enumerate() {
+            let next = blocks.get(i + 1).map(|b| b.label());
+            let targets = block.successors(next);
+
+            for &target in targets.iter() {
+                predecessors.entry(target).or_default().push(block.label());
+            }
+            predecessors.entry(block.label()).or_default();
+            successors.insert(block.label(), targets);
+        }
+
+        ControlFlowGraph {
+            blocks,
+            successors,
+            predecessors,
+        }
+    }
+
+    pub fn blocks(&self) -> &[BasicBlock] {
+        &self.blocks
+    }
+
+    /// Returns the block with the given label, if it exists.
+    pub fn block(&self, label: BlockLabel) -> Option<&BasicBlock> {
+        self.blocks.iter().find(|block| block.label() == label)
+    }
+
+    /// Returns the labels of blocks that control can flow to directly from the given block.
+    pub fn successors(&self, label: BlockLabel) -> &[BlockLabel] {
+        self.successors
+            .get(&label)
+            .map(Vec::as_slice)
+            .unwrap_or(&[])
+    }
+
+    /// Returns the labels of blocks that can flow directly into the given block.
+    pub fn predecessors(&self, label: BlockLabel) -> &[BlockLabel] {
+        self.predecessors
+            .get(&label)
+            .map(Vec::as_slice)
+            .unwrap_or(&[])
+    }
+
+    pub fn last_instruction(&self) -> Option<ThreeAddressInstruction> {
+        self.blocks()
+            .last()
+            .and_then(|block| block.last_instruction())
+    }
+
+    /// Checks that the graph is well-formed:
+    ///
+    ///  - every branch target refers to an existing block
+    ///  - block labels are unique
+    ///  - no [ThreeAddressInstruction::NoOp] placeholders remain
+    ///  - exactly one `Terminate` is reachable from the first block
+    ///  - the last block ends in `Terminate`
+    ///
+    /// Returns the first problem found.
+    pub fn verify(&self) -> Result<(), VerificationError> {
+        use std::collections::HashSet;
+        use ThreeAddressInstruction::*;
+
+        let mut labels = HashSet::new();
+        for block in self.blocks.iter() {
+            if !labels.insert(block.label()) {
+                return Err(VerificationError::DuplicateLabel(block.label()));
+            }
+        }
+
+        for block in self.blocks.iter() {
+            for instr in block.instructions() {
+                match *instr {
+                    BranchIfZero(target) | BranchTo(target) if !labels.contains(&target) => {
+                        return Err(VerificationError::UnknownBranchTarget {
+                            block: block.label(),
+                            target,
+                        });
+                    }
+                    NoOp => return Err(VerificationError::LeftoverNoOp(block.label())),
+                    _ => (),
+                }
+            }
+        }
+
+        if !matches!(self.last_instruction(), Some(Terminate)) {
+            return Err(VerificationError::LastBlockDoesNotTerminate);
+        }
+
+        // Count the reachable Terminates, starting from the first block.
+        let mut terminates = 0;
+        let mut seen = HashSet::new();
+        let mut to_visit: Vec<BlockLabel> =
+            self.blocks.first().map(|b| b.label()).into_iter().collect();
+        while let Some(label) = to_visit.pop() {
+            if !seen.insert(label) {
+                continue;
+            }
+
+            let block = self.block(label).expect("labels were already checked");
+            terminates += block
+                .instructions()
+                .iter()
+                .filter(|i| matches!(i, Terminate))
+                .count();
+            to_visit.extend_from_slice(self.successors(label));
+        }
+
+        match terminates {
+            0 => Err(VerificationError::NoReachableTerminate),
+            1 => Ok(()),
+            _ => Err(VerificationError::MoreThanOneTerminate),
+        }
+    }
+
+    /// Returns the graph in [Graphviz](https://graphviz.org/)'s DOT language, with one node per
+    /// basic block. Solid edges are taken branches; dashed edges are fallthrough.
+    ///
+    /// Render it with something like `dot -Tsvg cfg.dot -o cfg.svg`.
+    pub fn to_dot(&self) -> String {
+        use ThreeAddressInstruction::*;
+
+        let mut dot = String::new();
+        // Writing to a String never fails, so the results are ignored.
+        let _ = writeln!(dot, "digraph cfg {{");
+        let _ = writeln!(dot, "  node [shape=box, fontname=monospace];");
+
+        for (i, block) in self.blocks.iter().enumerate() {
+            let BlockLabel(n) = block.label();
+
+            let mut label = format!("L{}:\\l", n);
+            for instr in block.instructions() {
+                let text = instr.to_string().replace('\t', " ");
+                label.push_str(&text.replace('\\', "\\\\").replace('"', "\\\""));
+                label.push_str("\\l");
+            }
+            let _ = writeln!(dot, "  L{} [label=\"{}\"];", n, label);
+
+            for instr in block.instructions() {
+                match instr {
+                    BranchIfZero(BlockLabel(target)) => {
+                        let _ = writeln!(dot, "  L{} -> L{} [label=\"zero\"];", n, target);
+                    }
+                    BranchTo(BlockLabel(target)) => {
+                        let _ = writeln!(dot, "  L{} -> L{};", n, target);
+                    }
+                    _ => (),
+                }
+            }
+
+            if let (true, Some(next)) = (block.falls_through(), self.blocks.get(i + 1)) {
+                let _ = writeln!(dot, "  L{} -> L{} [style=dashed];", n, next.label().0);
+            }
+        }
+
+        let _ = writeln!(dot, "}}");
+        dot
+    }
+}
+
+impl BasicBlock {
+    /// Moves instructions into the basic block 👍🏼
+    pub fn new(label: BlockLabel, instructions: Vec<ThreeAddressInstruction>) -> Self {
+        BasicBlock {
+            block_id: label,
+            instructions,
+            position: None,
+        }
+    }
+
+    /// Records where the code for this block starts in the source text.
+    pub fn with_position(self, position: Option<SourcePosition>) -> Self {
+        BasicBlock { position, ..self }
+    }
+
+    /// Where the code for this block starts in the source text, if known.
+    pub fn position(&self) -> Option<SourcePosition> {
+        self.position
+    }
+
+    /// Return a borrowed view into all instructions in this block.
+    pub fn instructions(&self) -> &[ThreeAddressInstruction] {
+        &self.instructions
+    }
+
+    /// Returns this block's label.
+    pub fn label(&self) -> BlockLabel {
+        self.block_id
+    }
+
+    /// Returns the last instruction in this block, or None if this block is empty.
+    pub fn last_instruction(&self) -> Option<ThreeAddressInstruction> {
+        self.instructions.last().copied()
+    }
+
+    /// Returns whether control can reach the end of this block and continue to the next one.
+    pub fn falls_through(&self) -> bool {
+        use ThreeAddressInstruction::{BranchTo, Terminate};
+
+        !self
+            .instructions
+            .iter()
+            .any(|instr| matches!(instr, BranchTo(_) | Terminate))
+    }
+
+    /// Returns where control can go after this block, given the label of the next block in
+    /// program order.
+    fn successors(&self, next: Option<BlockLabel>) -> Vec<BlockLabel> {
+        use ThreeAddressInstruction::{BranchIfZero, BranchTo, Terminate};
+
+        let mut targets = Vec::new();
+        for instr in self.instructions.iter() {
+            match *instr {
+                BranchIfZero(target) if !targets.contains(&target) => targets.push(target),
+                BranchTo(target) => {
+                    if !targets.contains(&target) {
+                        targets.push(target);
+                    }
+                    // Nothing after this ever runs.
+                    return targets;
+                }
+                Terminate => return targets,
+                _ => (),
+            }
+        }
+
+        if let Some(next) = next.filter(|next| !targets.contains(next)) {
+            targets.push(next);
+        }
+
+        targets
+    }
+
+    /// Replaces a basic block with a single no-op instruction to a branch with the given target.
+    ///
+    /// During lowering of the AST to IR, there's a case when the branch target of a conditional
+    /// branch is unknown. This fixes that.
+    pub fn replace_noop_with_branch_target(&mut self, target: BlockLabel) {
+        use ThreeAddressInstruction::{BranchIfZero, NoOp};
+
+        if !matches!(self.instructions[..], [NoOp]) {
+            panic!(
+                "tried to replace the branch of an unexpected basic block: {:?}",
+                self
+            );
+        }
+
+        self.instructions[0] = BranchIfZero(target);
+    }
+
+    /// Replaces the placeholder at the end of a block that ends the program early with a branch to
+    /// the block that terminates it.
+    pub fn replace_last_noop_with_exit(&mut self, exit: BlockLabel) {
+        use ThreeAddressInstruction::{BranchTo, NoOp};
+
+        match self.instructions.last_mut() {
+            Some(last @ NoOp) => *last = BranchTo(exit),
+            _ => panic!(
+                "tried to add an exit to an unexpected basic block: {:?}",
+                self
+            ),
+        }
+    }
+}
+
+/// Compile an AST into a naïve control flow graph.
+pub fn lower(ast: &AbstractSyntaxTree) -> ControlFlowGraph {
+    use ThreeAddressInstruction::*;
+
+    let mut blocks: Vec<BasicBlock> = Vec::new();
+    let mut current_block_instrs: Vec<ThreeAddressInstruction> = Vec::new();
+    // Where the first statement in the current block came from.
+    let mut current_block_position: Option<SourcePosition> = None;
+    let mut block_id = 0;
+
+    let mut associated_start_block: HashMap<ConditionalID, BlockLabel> = HashMap::new();
+    // Blocks that end the program early, and so branch to the one Terminate at the very end.
+    let mut early_exits: Vec<usize> = Vec::new();
+
+    let mut i = 0;
+    let statements = &ast.statements();
+    while i < statements.len() {
+        let position = ast.position(i);
+
+        let statement = statements[i];
+        match statement {
+            Statement::StartConditional(cond_id) => {
+                // We need to create a branch. This means a few things:
+
+                //  1. We need to start another basic block
+                //  2. ...therefore, we need to finish the current block.
+                blocks.push(
+                    BasicBlock::new(BlockLabel(block_id), current_block_instrs)
+                        .with_position(current_block_position),
+                );
+                block_id += 1;
+
+                //  3. This basic block will have exactly one instruction, to be determined later!
+                let this_block_id = BlockLabel(block_id);
+                blocks.push(BasicBlock::new(this_block_id, vec![NoOp]).with_position(position));
+                //  4. We haven't seen the block that matches up with this start conditional, so
+                //     we need to keep track of it for later.
+                associated_start_block.insert(cond_id, this_block_id);
+
+                block_id += 1;
+                current_block_instrs = Vec::new();
+                current_block_position = None;
+            }
+            Statement::EndConditional(ref cond_id) => {
+                // This will always be the end of a basic block
+
+                // We have already seen the branch target and stored it.
+                let start_block = *associated_start_block
+                    .get(cond_id)
+                    .expect("expected to see start block already, but didn't");
+
+                current_block_instrs.push(BranchTo(start_block));
+
+                // This block is done...
+                blocks.push(
+                    BasicBlock::new(BlockLabel(block_id), current_block_instrs)
+                        .with_position(current_block_position.or(position)),
+                );
+                block_id += 1;
+                current_block_instrs = Vec::new();
+                current_block_position = None;
+
+                // ...and we can fix the branch target to the NEXT block
+                blocks[start_block.0].replace_noop_with_branch_target(BlockLabel(block_id));
+            }
+            Statement::EndProgram => {
+                // The branch to the end is filled in once we know where the end is.
+                current_block_instrs.push(NoOp);
+                early_exits.push(blocks.len());
+                blocks.push(
+                    BasicBlock::new(BlockLabel(block_id), current_block_instrs)
+                        .with_position(current_block_position.or(position)),
+                );
+                block_id += 1;
+                current_block_instrs = Vec::new();
+                current_block_position = None;
+            }
+            _ => {
+                current_block_position = current_block_position.or(position);
+                current_block_instrs.push(statement.try_into().expect("bad statement translation"));
+            }
+        }
+        i += 1;
+    }
+
+    if !early_exits.is_empty() && !current_block_instrs.is_empty() {
+        // The Terminate needs a block to itself, so that the early exits can branch to it.
+        blocks.push(
+            BasicBlock::new(BlockLabel(block_id), current_block_instrs)
+                .with_position(current_block_position),
+        );
+        block_id += 1;
+        current_block_instrs = Vec::new();
+        current_block_position = None;
+    }
+    for &i in early_exits.iter() {
+        blocks[i].replace_last_noop_with_exit(BlockLabel(block_id));
+    }
+
+    // The final block should always terminate:
+    current_block_instrs.push(Terminate);
+
+    // Finalize the last block:
+    blocks.push(
+        BasicBlock::new(BlockLabel(block_id), current_block_instrs)
+            .with_position(current_block_position),
+    );
+
+    ControlFlowGraph::new(blocks)
+}
+
+// Internal stuff:
+
+impl TryFrom<Statement> for ThreeAddressInstruction {
+    type Error = String;
+
+    // Converts trivial AST [Statement] into a three-address address intrcution
+    // Returns an [Err] when the conversion is non-trivial.
+    fn try_from(statement: Statement) -> Result<Self, Self::Error> {
+        use self::ThreeAddressInstruction as TAC;
+
+        match statement {
+            // Cells are bytes, so only the count modulo 256 matters
+            Statement::IncrementVal(n) => Ok(TAC::ChangeVal(n as u8)),
+            Statement::DecrementVal(n) => Ok(TAC::ChangeVal((n as u8).wrapping_neg())),
+            Statement::IncrementAddr(n) => Ok(TAC::ChangeAddr(n as i32)),
+            Statement::DecrementAddr(n) => Ok(TAC::ChangeAddr(-(n as i32))),
+            Statement::PutChar => Ok(TAC::PutChar),
+            Statement::GetChar => Ok(TAC::GetChar),
+            Statement::Extended(op) => Ok(TAC::Extended(op)),
+            Statement::StartConditional(_)
+            | Statement::EndConditional(_)
+            | Statement::EndProgram => Err(format!(
+                "Non-trivial conversion from {:?} to branch",
+                statement
+            )),
+        }
+    }
+}
+
+/// Prints the [ControlFlowGraph] to stdout. See the [Display](fmt::Display) impl for the format.
+pub fn print_cfg(cfg: &ControlFlowGraph) {
+    print!("{}", cfg);
+}
+
+/// Writes the textual IR format: a label line per block, then one instruction per line. This is
+/// the format that [parse_ir] reads. Blocks that know where they came from in the source text are
+/// annotated with a `; line:column` comment.
+impl fmt::Display for ControlFlowGraph {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for block in self.blocks().iter() {
+            let BlockLabel(n) = block.label();
+            match block.position() {
+                Some(position) => writeln!(f, "L{}:\t; {}", n, position)?,
+                None => writeln!(f, "L{}:", n)?,
+            }
+
+            for &instr in block.instructions().iter() {
+                writeln!(f, "\t{}", instr)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for VerificationError {}
+
+impl fmt::Display for VerificationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use VerificationError::*;
+        match self {
+            UnknownBranchTarget {
+                block: BlockLabel(block),
+                target: BlockLabel(target),
+            } => write!(
+                f,
+                "L{} branches to L{}, which does not exist",
+                block, target
+            ),
+            DuplicateLabel(BlockLabel(n)) => write!(f, "more than one block is labelled L{}", n),
+            LeftoverNoOp(BlockLabel(n)) => write!(f, "L{} still has a placeholder nop", n),
+            NoReachableTerminate => write!(f, "no terminate instruction is reachable"),
+            MoreThanOneTerminate => write!(f, "more than one terminate instruction is reachable"),
+            LastBlockDoesNotTerminate => write!(f, "the last block does not end in terminate"),
+        }
+    }
+}
+
+impl fmt::Display for ThreeAddressInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use ThreeAddressInstruction::*;
+        match *self {
+            ChangeVal(v) => write!(f, "add\t[p], [p], #{}", v as i8),
+            ChangeAddr(v) => write!(f, "add\tp, p, #{}", v),
+            PutChar => write!(f, "putchar"),
+            GetChar => write!(f, "getchar"),
+            BranchIfZero(BlockLabel(n)) => write!(f, "beq\t[p], L{}", n),
+            BranchTo(BlockLabel(n)) => write!(f, "b\tL{}", n),
+            NoOp => write!(f, "nop"),
+            Zero => write!(f, "zero"),
+            MulAdd { dst_offset, factor } => {
+                write!(f, "mla\t[p+{}], [p], #{}", dst_offset, factor)
+            }
+            FindZero(step) => write!(f, "scan\tp, #{}", step),
+            ChangeValAt(o, v) => write!(f, "add\t[p+{}], [p+{}], #{}", o, o, v as i8),
+            SetValAt(o, v) => write!(f, "mov\t[p+{}], #{}", o, v),
+            Terminate => write!(f, "terminate"),
+            Extended(op) => write!(f, "ext\t{}", op.to_char()),
+            EmitBytes(bytes) => {
+                let bytes: Vec<String> =
+                    bytes.as_slice().iter().map(|b| format!("#{}", b)).collect();
+                write!(f, "emit\t{}", bytes.join(", "))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::parse;
+
+    #[test]
+    fn blocks_know_where_they_came_from() {
+        let ast = parse("<test>", b"+\n[.\n-]").unwrap();
+        let cfg = lower(&ast);
+        let positions: Vec<_> = cfg
+            .blocks()
+            .iter()
+            .map(|block| block.position().map(|p| (p.line, p.column)))
+            .collect();
+
+        assert_eq!(
+            vec![Some((1, 1)), Some((2, 1)), Some((2, 2)), None],
+            positions
+        );
+        assert!(cfg.to_string().starts_with("L0:\t; 1:1\n"));
+    }
+
+    #[test]
+    fn to_dot_has_edges_for_branches_and_fallthrough() {
+        let ast = parse("<test>", b"+[.-]").unwrap();
+        let dot = lower(&ast).to_dot();
+
+        assert!(dot.starts_with("digraph cfg {"));
+        assert!(dot.contains("L0 -> L1 [style=dashed];"));
+        assert!(dot.contains("L1 -> L3 [label=\"zero\"];"));
+        assert!(dot.contains("L1 -> L2 [style=dashed];"));
+        assert!(dot.contains("L2 -> L1;"));
+        assert!(!dot.contains("L3 ->"));
+    }
+
+    #[test]
+    fn computes_successors_and_predecessors() {
+        let ast = parse("<test>", b"+[.-]").unwrap();
+        let cfg = lower(&ast);
+
+        assert_eq!(&[BlockLabel(1)], cfg.successors(BlockLabel(0)));
+        assert_eq!(
+            &[BlockLabel(3), BlockLabel(2)],
+            cfg.successors(BlockLabel(1))
+        );
+        assert_eq!(&[BlockLabel(1)], cfg.successors(BlockLabel(2)));
+        assert!(cfg.successors(BlockLabel(3)).is_empty());
+
+        assert!(cfg.predecessors(BlockLabel(0)).is_empty());
+        assert_eq!(
+            &[BlockLabel(0), BlockLabel(2)],
+            cfg.predecessors(BlockLabel(1))
+        );
+        assert_eq!(&[BlockLabel(1)], cfg.predecessors(BlockLabel(3)));
+    }
+
+    #[test]
+    fn verify_accepts_lowered_code() {
+        let ast = parse("<test>", b"+[>,.<-]>[[-]+[>]<]").unwrap();
+        assert_eq!(Ok(()), lower(&ast).verify());
+
+        // Each `@` branches to the one Terminate at the end.
+        let ast = parse("<test>", b";# dialect=ext1\n,[.@]+@>.").unwrap();
+        assert_eq!(Ok(()), lower(&ast).verify());
+    }
+
+    #[test]
+    fn verify_rejects_broken_graphs() {
+        use ThreeAddressInstruction::*;
+
+        let bad_target = ControlFlowGraph::new(vec![BasicBlock::new(
+            BlockLabel(0),
+            vec![BranchIfZero(BlockLabel(7)), Terminate],
+        )]);
+        assert_eq!(
+            Err(VerificationError::UnknownBranchTarget {
+                block: BlockLabel(0),
+                target: BlockLabel(7)
+            }),
+            bad_target.verify()
+        );
+
+        let no_terminate = ControlFlowGraph::new(vec![BasicBlock::new(BlockLabel(0), vec![])]);
+        assert_eq!(
+            Err(VerificationError::LastBlockDoesNotTerminate),
+            no_terminate.verify()
+        );
+
+        let infinite_loop = ControlFlowGraph::new(vec![
+            BasicBlock::new(BlockLabel(0), vec![BranchTo(BlockLabel(0))]),
+            BasicBlock::new(BlockLabel(1), vec![Terminate]),
+        ]);
+        assert_eq!(
+            Err(VerificationError::NoReachableTerminate),
+            infinite_loop.verify()
+        );
+
+        let two_terminates = ControlFlowGraph::new(vec![
+            BasicBlock::new(BlockLabel(0), vec![BranchIfZero(BlockLabel(2))]),
+            BasicBlock::new(BlockLabel(1), vec![Terminate]),
+            BasicBlock::new(BlockLabel(2), vec![Terminate]),
+        ]);
+        assert_eq!(
+            Err(VerificationError::MoreThanOneTerminate),
+            two_terminates.verify()
+        );
+    }
+
+    #[test]
+    fn unbalanced_loops_stay_loops() {
+        let ast = parse("<test>", b"+[->+>]").unwrap();
+        let cfg = lower(&ast);
+
+        assert!(cfg.blocks().len() > 1);
+    }
+}
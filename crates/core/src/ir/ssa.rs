@@ -0,0 +1,746 @@
+//! An SSA-like view of a [ControlFlowGraph].
+//!
+//! In the three-address code, the pointer is implicit, and every instruction reads and writes
+//! memory. Here, the pointer and the values of cells are _virtual registers_ ([Value]s) instead,
+//! and each one is assigned exactly once. Where control flow merges, the pointer goes through a
+//! [Phi] node.
+//!
+//! Loads of a cell whose value is already in a register are replaced by that register, but every
+//! write is still stored to memory. Values of cells flow from block to block through phis, too,
+//! but only when every block that can come before knows the value of that cell (relative to the
+//! pointer it passes along). Anything else is loaded from memory when it's needed. That's enough
+//! to make value propagation trivial, while keeping the construction simple enough to read in one
+//! sitting.
+//!
+//! Nothing in the pipeline uses this form (yet!). Use [to_ssa] to build it and print it.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use super::{BlockLabel, ControlFlowGraph, ThreeAddressInstruction};
+use crate::parsing::ExtendedOp;
+
+/// A virtual register. Holds either a pointer into the tape or the value of a cell.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Value(pub u32);
+
+/// The whole program in SSA form.
+#[derive(Debug, Clone)]
+pub struct SsaFunction {
+    /// The pointer to the start of the tape, when the program starts.
+    pub tape: Value,
+    pub blocks: Vec<SsaBlock>,
+}
+
+/// A basic block in SSA form. Blocks have the same labels as in the original CFG.
+#[derive(Debug, Clone)]
+pub struct SsaBlock {
+    pub label: BlockLabel,
+    pub phis: Vec<Phi>,
+    pub instructions: Vec<SsaInstruction>,
+}
+
+/// Chooses a value depending on which block control came from. `None` stands for "the start of
+/// the program".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Phi {
+    pub dst: Value,
+    pub sources: Vec<(Option<BlockLabel>, Value)>,
+}
+
+/// An instruction in SSA form. Each `dst` is assigned exactly once in the whole function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SsaInstruction {
+    /// `dst = ptr + offset`
+    PtrAdd { dst: Value, ptr: Value, offset: i32 },
+    /// `dst = [ptr + offset]`
+    Load { dst: Value, ptr: Value, offset: i32 },
+    /// `[ptr + offset] = value`
+    Store {
+        ptr: Value,
+        offset: i32,
+        value: Value,
+    },
+    /// `dst = value`
+    Const { dst: Value, value: u8 },
+    /// `dst = src + value`, wrapping
+    AddImm { dst: Value, src: Value, value: u8 },
+    /// `dst = src * value`, wrapping
+    MulImm { dst: Value, src: Value, value: u8 },
+    /// `dst = lhs + rhs`, wrapping
+    Add { dst: Value, lhs: Value, rhs: Value },
+    /// `dst` = the first pointer, starting at `ptr` and moving by `step`, that points to zero
+    FindZero { dst: Value, ptr: Value, step: i32 },
+    /// `dst = getchar()`
+    Input { dst: Value },
+    /// `putchar(value)`
+    Output { value: Value },
+    /// Applies an Extended Brainfuck operation to `[ptr + offset]` (and the storage register).
+    Extended {
+        op: ExtendedOp,
+        ptr: Value,
+        offset: i32,
+    },
+    /// Goes to `target` if `value` is zero.
+    BranchIfZero { value: Value, target: BlockLabel },
+    /// Goes to `target`.
+    Branch { target: BlockLabel },
+    /// Ends the program.
+    Return,
+}
+
+/// Converts the [ControlFlowGraph] to SSA form.
+pub fn to_ssa(cfg: &ControlFlowGraph) -> SsaFunction {
+    // Which cells get a phi depends on what's known at the end of each block, which depends on
+    // which cells got a phi. Start with no cell phis, and add them until nothing changes.
+    let mut cell_phis: Vec<Vec<i32>> = vec![Vec::new(); cfg.blocks().len()];
+    loop {
+        let conversion = convert(cfg, &cell_phis);
+        let known = conversion.known_on_entry(cfg);
+        if known == cell_phis {
+            return conversion.finish();
+        }
+        cell_phis = known;
+    }
+}
+
+// Implementation
+
+impl SsaInstruction {
+    /// Returns the register this instruction assigns, if any.
+    pub fn dst(&self) -> Option<Value> {
+        use SsaInstruction::*;
+        match *self {
+            PtrAdd { dst, .. }
+            | Load { dst, .. }
+            | Const { dst, .. }
+            | AddImm { dst, .. }
+            | MulImm { dst, .. }
+            | Add { dst, .. }
+            | FindZero { dst, .. }
+            | Input { dst } => Some(dst),
+            Store { .. }
+            | Output { .. }
+            | Extended { .. }
+            | BranchIfZero { .. }
+            | Branch { .. }
+            | Return => None,
+        }
+    }
+
+    /// Returns the same instruction, with `f` applied to every register it reads.
+    fn map_operands(self, f: impl Fn(Value) -> Value) -> Self {
+        use SsaInstruction::*;
+        match self {
+            PtrAdd { dst, ptr, offset } => PtrAdd {
+                dst,
+                ptr: f(ptr),
+                offset,
+            },
+            Load { dst, ptr, offset } => Load {
+                dst,
+                ptr: f(ptr),
+                offset,
+            },
+            Store { ptr, offset, value } => Store {
+                ptr: f(ptr),
+                offset,
+                value: f(value),
+            },
+            AddImm { dst, src, value } => AddImm {
+                dst,
+                src: f(src),
+                value,
+            },
+            MulImm { dst, src, value } => MulImm {
+                dst,
+                src: f(src),
+                value,
+            },
+            Add { dst, lhs, rhs } => Add {
+                dst,
+                lhs: f(lhs),
+                rhs: f(rhs),
+            },
+            FindZero { dst, ptr, step } => FindZero {
+                dst,
+                ptr: f(ptr),
+                step,
+            },
+            Output { value } => Output { value: f(value) },
+            Extended { op, ptr, offset } => Extended {
+                op,
+                ptr: f(ptr),
+                offset,
+            },
+            BranchIfZero { value, target } => BranchIfZero {
+                value: f(value),
+                target,
+            },
+            Const { .. } | Input { .. } | Branch { .. } | Return => self,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "%{}", self.0)
+    }
+}
+
+impl fmt::Display for SsaInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use SsaInstruction::*;
+        match *self {
+            PtrAdd { dst, ptr, offset } => write!(f, "{} = ptradd {}, #{}", dst, ptr, offset),
+            Load { dst, ptr, offset } => write!(f, "{} = load [{}+{}]", dst, ptr, offset),
+            Store { ptr, offset, value } => write!(f, "store [{}+{}], {}", ptr, offset, value),
+            Const { dst, value } => write!(f, "{} = const #{}", dst, value),
+            AddImm { dst, src, value } => write!(f, "{} = add {}, #{}", dst, src, value as i8),
+            MulImm { dst, src, value } => write!(f, "{} = mul {}, #{}", dst, src, value),
+            Add { dst, lhs, rhs } => write!(f, "{} = add {}, {}", dst, lhs, rhs),
+            FindZero { dst, ptr, step } => write!(f, "{} = scan {}, #{}", dst, ptr, step),
+            Input { dst } => write!(f, "{} = getchar", dst),
+            Output { value } => write!(f, "putchar {}", value),
+            Extended { op, ptr, offset } => write!(f, "ext {} [{}+{}]", op.to_char(), ptr, offset),
+            BranchIfZero {
+                value,
+                target: BlockLabel(target),
+            } => write!(f, "beq {}, L{}", value, target),
+            Branch {
+                target: BlockLabel(target),
+            } => write!(f, "b L{}", target),
+            Return => write!(f, "ret"),
+        }
+    }
+}
+
+impl fmt::Display for Phi {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} = phi", self.dst)?;
+        for (i, (from, value)) in self.sources.iter().enumerate() {
+            let separator = if i == 0 { " " } else { ", " };
+            match from {
+                Some(BlockLabel(n)) => write!(f, "{}[L{}: {}]", separator, n, value)?,
+                None => write!(f, "{}[entry: {}]", separator, value)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for SsaFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "; tape: {}", self.tape)?;
+        for block in self.blocks.iter() {
+            writeln!(f, "L{}:", block.label.0)?;
+            for phi in block.phis.iter() {
+                writeln!(f, "\t{}", phi)?;
+            }
+            for instr in block.instructions.iter() {
+                writeln!(f, "\t{}", instr)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Internal stuff:
+
+/// Everything that flowed into a block from one place, where `None` is the start of the program.
+struct Edge {
+    from: Option<BlockLabel>,
+    to: BlockLabel,
+    ptr: Value,
+    /// Known values of cells, by offset from `ptr`.
+    cells: HashMap<i32, Value>,
+}
+
+/// The program in SSA form, before any phis were placed.
+struct Conversion {
+    tape: Value,
+    blocks: Vec<SsaBlock>,
+    edges: Vec<Edge>,
+    /// Every block starts with a phi for the pointer, and one for each cell in `cell_phis`.
+    entry_pointers: Vec<Value>,
+    entry_cells: Vec<Vec<(i32, Value)>>,
+}
+
+/// Converts each block, assuming that the cells at the given offsets get a phi on entry.
+fn convert(cfg: &ControlFlowGraph, cell_phis: &[Vec<i32>]) -> Conversion {
+    let mut builder = Builder::default();
+    let tape = builder.fresh();
+
+    // Trivial phis are cleaned up afterwards.
+    let entry_pointers: Vec<Value> = cfg.blocks().iter().map(|_| builder.fresh()).collect();
+    let entry_cells: Vec<Vec<(i32, Value)>> = cell_phis
+        .iter()
+        .map(|offsets| offsets.iter().map(|&o| (o, builder.fresh())).collect())
+        .collect();
+    let mut edges = Vec::new();
+    if let Some(entry) = cfg.blocks().first() {
+        edges.push(Edge {
+            from: None,
+            to: entry.label(),
+            ptr: tape,
+            cells: HashMap::new(),
+        });
+    }
+
+    let mut blocks = Vec::new();
+    for (i, block) in cfg.blocks().iter().enumerate() {
+        let mut state = BlockState::new(entry_pointers[i]);
+        state.cells.extend(entry_cells[i].iter().copied());
+        let label = block.label();
+
+        for &instr in block.instructions() {
+            if let Some((target, ptr)) = builder.lower(&mut state, instr) {
+                edges.push(Edge {
+                    from: Some(label),
+                    to: target,
+                    ptr,
+                    cells: state.cells.clone(),
+                });
+            }
+        }
+
+        if block.falls_through() {
+            if let Some(next) = cfg.blocks().get(i + 1) {
+                let ptr = builder.materialize(&mut state);
+                edges.push(Edge {
+                    from: Some(label),
+                    to: next.label(),
+                    ptr,
+                    cells: state.cells.clone(),
+                });
+            }
+        }
+
+        blocks.push(SsaBlock {
+            label,
+            phis: Vec::new(),
+            instructions: state.instructions,
+        });
+    }
+
+    Conversion {
+        tape,
+        blocks,
+        edges,
+        entry_pointers,
+        entry_cells,
+    }
+}
+
+impl Conversion {
+    /// For each block, the offsets of the cells that every edge into it knows the value of.
+    fn known_on_entry(&self, cfg: &ControlFlowGraph) -> Vec<Vec<i32>> {
+        cfg.blocks()
+            .iter()
+            .map(|block| {
+                let mut incoming = self.edges.iter().filter(|edge| edge.to == block.label());
+                let mut known: Vec<i32> = match incoming.next() {
+                    Some(edge) => edge.cells.keys().copied().collect(),
+                    None => Vec::new(),
+                };
+                for edge in incoming {
+                    known.retain(|offset| edge.cells.contains_key(offset));
+                }
+                known.sort_unstable();
+                known
+            })
+            .collect()
+    }
+
+    /// Places the phis, and removes the ones that turned out to be trivial.
+    fn finish(self) -> SsaFunction {
+        let Conversion {
+            tape,
+            mut blocks,
+            edges,
+            entry_pointers,
+            entry_cells,
+        } = self;
+
+        let phis: Vec<Vec<Phi>> = blocks
+            .iter()
+            .zip(entry_pointers.into_iter().zip(entry_cells))
+            .map(|(block, (pointer, cells))| {
+                let incoming: Vec<&Edge> = edges.iter().filter(|e| e.to == block.label).collect();
+                let pointer_phi = Phi {
+                    dst: pointer,
+                    sources: incoming.iter().map(|edge| (edge.from, edge.ptr)).collect(),
+                };
+                let cell_phis = cells.into_iter().map(|(offset, dst)| Phi {
+                    dst,
+                    sources: incoming
+                        .iter()
+                        .map(|edge| (edge.from, edge.cells[&offset]))
+                        .collect(),
+                });
+                std::iter::once(pointer_phi).chain(cell_phis).collect()
+            })
+            .collect();
+
+        let replacements = remove_trivial_phis(phis.iter().flatten());
+        let resolve = |value: Value| resolve(&replacements, value);
+
+        for (block, phis) in blocks.iter_mut().zip(phis) {
+            for phi in phis {
+                if !replacements.contains_key(&phi.dst) {
+                    block.phis.push(Phi {
+                        dst: phi.dst,
+                        sources: phi
+                            .sources
+                            .into_iter()
+                            .map(|(from, value)| (from, resolve(value)))
+                            .collect(),
+                    });
+                }
+            }
+            for instr in block.instructions.iter_mut() {
+                *instr = instr.map_operands(resolve);
+            }
+        }
+
+        SsaFunction { tape, blocks }
+    }
+}
+
+/// Hands out fresh [Value]s.
+#[derive(Default)]
+struct Builder {
+    next_value: u32,
+}
+
+/// What's known while converting a single block.
+struct BlockState {
+    instructions: Vec<SsaInstruction>,
+    /// The pointer is always `base + delta`. Moving the pointer only changes `delta`.
+    base: Value,
+    delta: i32,
+    /// Known values of cells, by offset from `base`.
+    cells: HashMap<i32, Value>,
+}
+
+impl BlockState {
+    fn new(base: Value) -> Self {
+        BlockState {
+            instructions: Vec::new(),
+            base,
+            delta: 0,
+            cells: HashMap::new(),
+        }
+    }
+}
+
+impl Builder {
+    fn fresh(&mut self) -> Value {
+        let value = Value(self.next_value);
+        self.next_value += 1;
+        value
+    }
+
+    /// Converts one instruction. If the instruction is a branch, returns the target and the
+    /// pointer that flows into it.
+    fn lower(
+        &mut self,
+        state: &mut BlockState,
+        instr: ThreeAddressInstruction,
+    ) -> Option<(BlockLabel, Value)> {
+        use ThreeAddressInstruction as TAC;
+
+        match instr {
+            TAC::NoOp => (),
+            TAC::ChangeAddr(n) => state.delta += n,
+            TAC::ChangeVal(n) => self.change_val_at(state, 0, n),
+            TAC::ChangeValAt(offset, n) => self.change_val_at(state, offset, n),
+            TAC::Zero => self.set_val_at(state, 0, 0),
+            TAC::SetValAt(offset, n) => self.set_val_at(state, offset, n),
+            TAC::MulAdd { dst_offset, factor } => {
+                let value = self.load(state, 0);
+                let old = self.load(state, dst_offset);
+                let product = self.fresh();
+                state.instructions.push(SsaInstruction::MulImm {
+                    dst: product,
+                    src: value,
+                    value: factor,
+                });
+                let sum = self.fresh();
+                state.instructions.push(SsaInstruction::Add {
+                    dst: sum,
+                    lhs: old,
+                    rhs: product,
+                });
+                self.store(state, dst_offset, sum);
+            }
+            TAC::FindZero(step) => {
+                let ptr = self.materialize(state);
+                let dst = self.fresh();
+                state
+                    .instructions
+                    .push(SsaInstruction::FindZero { dst, ptr, step });
+                // We have no idea where we are now, so nothing is known anymore.
+                *state = BlockState {
+                    base: dst,
+                    delta: 0,
+                    cells: HashMap::new(),
+                    instructions: std::mem::take(&mut state.instructions),
+                };
+            }
+            TAC::PutChar => {
+                let value = self.load(state, 0);
+                state.instructions.push(SsaInstruction::Output { value });
+            }
+            TAC::EmitBytes(bytes) => {
+                for &byte in bytes.as_slice() {
+                    let value = self.fresh();
+                    state.instructions.push(SsaInstruction::Const {
+                        dst: value,
+                        value: byte,
+                    });
+                    state.instructions.push(SsaInstruction::Output { value });
+                }
+            }
+            TAC::GetChar => {
+                let dst = self.fresh();
+                state.instructions.push(SsaInstruction::Input { dst });
+                self.store(state, 0, dst);
+            }
+            TAC::Extended(op) => {
+                state.instructions.push(SsaInstruction::Extended {
+                    op,
+                    ptr: state.base,
+                    offset: state.delta,
+                });
+                state.cells.remove(&state.delta);
+            }
+            TAC::BranchIfZero(target) => {
+                let value = self.load(state, 0);
+                let ptr = self.materialize(state);
+                state
+                    .instructions
+                    .push(SsaInstruction::BranchIfZero { value, target });
+                return Some((target, ptr));
+            }
+            TAC::BranchTo(target) => {
+                let ptr = self.materialize(state);
+                state.instructions.push(SsaInstruction::Branch { target });
+                return Some((target, ptr));
+            }
+            TAC::Terminate => state.instructions.push(SsaInstruction::Return),
+        }
+
+        None
+    }
+
+    /// Makes sure the current pointer is in a register of its own, and returns it.
+    fn materialize(&mut self, state: &mut BlockState) -> Value {
+        if state.delta == 0 {
+            return state.base;
+        }
+
+        let dst = self.fresh();
+        state.instructions.push(SsaInstruction::PtrAdd {
+            dst,
+            ptr: state.base,
+            offset: state.delta,
+        });
+
+        // Known cells are now relative to the new base.
+        let delta = state.delta;
+        state.cells = state
+            .cells
+            .drain()
+            .map(|(offset, value)| (offset - delta, value))
+            .collect();
+        state.base = dst;
+        state.delta = 0;
+
+        dst
+    }
+
+    /// Returns the value of the cell at the offset from the current pointer.
+    fn load(&mut self, state: &mut BlockState, offset: i32) -> Value {
+        let offset = state.delta + offset;
+        if let Some(&value) = state.cells.get(&offset) {
+            return value;
+        }
+
+        let dst = self.fresh();
+        state.instructions.push(SsaInstruction::Load {
+            dst,
+            ptr: state.base,
+            offset,
+        });
+        state.cells.insert(offset, dst);
+
+        dst
+    }
+
+    /// Writes the cell at the offset from the current pointer.
+    fn store(&mut self, state: &mut BlockState, offset: i32, value: Value) {
+        let offset = state.delta + offset;
+        state.instructions.push(SsaInstruction::Store {
+            ptr: state.base,
+            offset,
+            value,
+        });
+        state.cells.insert(offset, value);
+    }
+
+    fn change_val_at(&mut self, state: &mut BlockState, offset: i32, n: u8) {
+        let old = self.load(state, offset);
+        let dst = self.fresh();
+        state.instructions.push(SsaInstruction::AddImm {
+            dst,
+            src: old,
+            value: n,
+        });
+        self.store(state, offset, dst);
+    }
+
+    fn set_val_at(&mut self, state: &mut BlockState, offset: i32, n: u8) {
+        let dst = self.fresh();
+        state
+            .instructions
+            .push(SsaInstruction::Const { dst, value: n });
+        self.store(state, offset, dst);
+    }
+}
+
+/// Removes phis that only ever choose one value (other than themselves), until none are left.
+/// Returns what each removed phi should be replaced with.
+fn remove_trivial_phis<'a>(phis: impl Iterator<Item = &'a Phi> + Clone) -> HashMap<Value, Value> {
+    let mut replacements = HashMap::new();
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+
+        for phi in phis.clone() {
+            if replacements.contains_key(&phi.dst) {
+                continue;
+            }
+
+            let mut unique = None;
+            let mut trivial = true;
+            for &(_, value) in phi.sources.iter() {
+                let value = resolve(&replacements, value);
+                if value == phi.dst || unique == Some(value) {
+                    continue;
+                }
+                if unique.is_some() {
+                    trivial = false;
+                    break;
+                }
+                unique = Some(value);
+            }
+
+            if let (true, Some(value)) = (trivial, unique) {
+                replacements.insert(phi.dst, value);
+                changed = true;
+            }
+        }
+    }
+
+    replacements
+}
+
+/// Follows replacements until reaching a value that was not replaced.
+fn resolve(replacements: &HashMap<Value, Value>, mut value: Value) -> Value {
+    while let Some(&replacement) = replacements.get(&value) {
+        value = replacement;
+    }
+
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::lower;
+    use crate::parsing::parse;
+
+    fn ssa(source: &str) -> SsaFunction {
+        to_ssa(&lower(&parse("<test>", source.as_bytes()).unwrap()))
+    }
+
+    #[test]
+    fn pointer_gets_a_phi_at_loop_heads() {
+        let function = ssa("+[>+]");
+
+        let phis: Vec<_> = function.blocks.iter().map(|b| b.phis.len()).collect();
+        // Only the loop head merges two different pointers (and two values of the current cell).
+        assert_eq!(vec![0, 2, 0, 0], phis);
+
+        let phi = &function.blocks[1].phis[0];
+        assert_eq!(2, phi.sources.len());
+        assert!(phi.sources.contains(&(Some(BlockLabel(0)), function.tape)));
+    }
+
+    #[test]
+    fn known_cells_are_not_reloaded() {
+        let function = ssa("+.");
+        let instructions = &function.blocks[0].instructions;
+
+        let loads = instructions
+            .iter()
+            .filter(|i| matches!(i, SsaInstruction::Load { .. }))
+            .count();
+        assert_eq!(1, loads);
+
+        let incremented = instructions[1].dst().unwrap();
+        assert!(instructions.contains(&SsaInstruction::Output { value: incremented }));
+    }
+
+    #[test]
+    fn cell_values_get_a_phi_at_loop_heads() {
+        let function = ssa("+[-]");
+        let head = &function.blocks[1];
+
+        // Both ways into the loop head know the value of the current cell, so it isn't loaded:
+        assert_eq!(1, head.phis.len());
+        assert!(!head
+            .instructions
+            .iter()
+            .any(|i| matches!(i, SsaInstruction::Load { .. })));
+        assert_eq!(
+            SsaInstruction::BranchIfZero {
+                value: head.phis[0].dst,
+                target: BlockLabel(3)
+            },
+            head.instructions[0]
+        );
+
+        // ...but nothing is known about the cell next to it, so it's loaded every time:
+        let function = ssa("+[>.<-]");
+        let body = &function.blocks[2];
+        assert!(body
+            .instructions
+            .iter()
+            .any(|i| matches!(i, SsaInstruction::Load { offset: 1, .. })));
+    }
+
+    #[test]
+    fn every_value_is_assigned_once() {
+        let function = ssa("+[->>+<<]>[>]<,[.-<]");
+
+        let mut assigned: Vec<Value> = function
+            .blocks
+            .iter()
+            .flat_map(|b| {
+                b.phis
+                    .iter()
+                    .map(|phi| phi.dst)
+                    .chain(b.instructions.iter().filter_map(|i| i.dst()))
+            })
+            .collect();
+        let count = assigned.len();
+        assigned.sort();
+        assigned.dedup();
+        assert_eq!(count, assigned.len());
+    }
+}
@@ -0,0 +1,134 @@
+//! Counts things in a [ControlFlowGraph], mostly to check that optimizations are pulling their
+//! weight.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use super::analysis::{dominators, find_loops};
+use super::{ControlFlowGraph, ThreeAddressInstruction};
+
+/// Summary of a [ControlFlowGraph]. See [ControlFlowGraph::stats].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CfgStats {
+    /// How many basic blocks there are.
+    pub blocks: usize,
+    /// How many instructions there are in total.
+    pub instructions: usize,
+    /// How many instructions there are of each kind, by the variant's name.
+    pub instructions_by_kind: BTreeMap<&'static str, usize>,
+    /// The deepest nesting of loops.
+    pub max_loop_depth: usize,
+    /// Roughly how many bytes of AArch64 machine code the JIT would generate.
+    pub estimated_code_size: usize,
+}
+
+/// Bytes in the prologue and epilogue around every program.
+const PROLOGUE_SIZE: usize = 7 * 4;
+
+impl ControlFlowGraph {
+    /// Counts blocks and instructions, and estimates how big the generated code would be.
+    pub fn stats(&self) -> CfgStats {
+        let mut instructions_by_kind = BTreeMap::new();
+        let mut estimated_code_size = PROLOGUE_SIZE;
+
+        for block in self.blocks() {
+            for &instr in block.instructions() {
+                *instructions_by_kind.entry(kind_name(instr)).or_insert(0) += 1;
+                estimated_code_size += 4 * estimated_machine_instructions(instr);
+            }
+        }
+
+        CfgStats {
+            blocks: self.blocks().len(),
+            instructions: instructions_by_kind.values().sum(),
+            instructions_by_kind,
+            max_loop_depth: find_loops(self, &dominators(self)).max_depth(),
+            estimated_code_size,
+        }
+    }
+}
+
+impl CfgStats {
+    /// How many instructions of the given kind (e.g., `"MulAdd"`) there are.
+    pub fn count(&self, kind: &str) -> usize {
+        self.instructions_by_kind.get(kind).copied().unwrap_or(0)
+    }
+}
+
+impl fmt::Display for CfgStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "blocks:         {}", self.blocks)?;
+        writeln!(f, "instructions:   {}", self.instructions)?;
+        for (kind, count) in self.instructions_by_kind.iter() {
+            writeln!(f, "  {:<12}  {}", kind, count)?;
+        }
+        writeln!(f, "max loop depth: {}", self.max_loop_depth)?;
+        writeln!(f, "code size:      ~{} bytes", self.estimated_code_size)
+    }
+}
+
+// Internal stuff:
+
+fn kind_name(instr: ThreeAddressInstruction) -> &'static str {
+    use ThreeAddressInstruction::*;
+    match instr {
+        ChangeVal(_) => "ChangeVal",
+        ChangeAddr(_) => "ChangeAddr",
+        PutChar => "PutChar",
+        GetChar => "GetChar",
+        BranchIfZero(_) => "BranchIfZero",
+        BranchTo(_) => "BranchTo",
+        NoOp => "NoOp",
+        Zero => "Zero",
+        MulAdd { .. } => "MulAdd",
+        FindZero(_) => "FindZero",
+        ChangeValAt(..) => "ChangeValAt",
+        SetValAt(..) => "SetValAt",
+        Terminate => "Terminate",
+        Extended(_) => "Extended",
+        EmitBytes(_) => "EmitBytes",
+    }
+}
+
+/// How many AArch64 instructions the code generator emits for the instruction (in the common
+/// case; e.g., an offset of zero can save an instruction).
+fn estimated_machine_instructions(instr: ThreeAddressInstruction) -> usize {
+    use ThreeAddressInstruction::*;
+    match instr {
+        NoOp => 0,
+        Zero | BranchTo(_) | ChangeAddr(_) => 1,
+        PutChar | GetChar | BranchIfZero(_) => 2,
+        ChangeVal(_) | SetValAt(..) => 3,
+        ChangeValAt(..) | FindZero(_) => 4,
+        MulAdd { .. } => 7,
+        Terminate => 5,
+        // Not supported by the JIT at all.
+        Extended(_) => 0,
+        EmitBytes(bytes) => 2 * bytes.as_slice().len(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ir::lower;
+    use crate::parsing::parse;
+
+    #[test]
+    fn counts_instructions_and_loops() {
+        let cfg = lower(&parse("<test>", b"++[>[.-]<-]>[-]").unwrap());
+        let stats = cfg.stats();
+
+        assert_eq!(cfg.blocks().len(), stats.blocks);
+        assert_eq!(2, stats.max_loop_depth);
+        assert_eq!(3, stats.count("BranchIfZero"));
+        assert_eq!(1, stats.count("PutChar"));
+        assert_eq!(0, stats.count("MulAdd"));
+        assert_eq!(
+            stats.instructions,
+            cfg.blocks()
+                .iter()
+                .map(|b| b.instructions().len())
+                .sum::<usize>()
+        );
+    }
+}
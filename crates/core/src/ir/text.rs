@@ -0,0 +1,214 @@
+//! Reads the textual IR format that [ControlFlowGraph]'s `Display` impl writes.
+//!
+//! The format is one label per block, followed by one instruction per line:
+//!
+//! ```text
+//! L0:
+//!     add     [p], [p], #1
+//! L1:
+//!     beq     [p], L3
+//! L2:
+//!     putchar
+//!     add     [p], [p], #-1
+//!     b       L1
+//! L3:
+//!     terminate
+//! ```
+//!
+//! Whitespace between a mnemonic and its operands can be spaces or tabs, and anything after a `;`
+//! is a comment. This makes it convenient to write before/after fixtures for optimization tests.
+
+use std::fmt;
+
+use super::{BasicBlock, BlockLabel, ControlFlowGraph, EmittedBytes, ThreeAddressInstruction};
+use crate::parsing::ExtendedOp;
+
+/// A problem with a line of textual IR.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseIrError {
+    /// 1-based line number.
+    pub line_no: usize,
+    pub message: String,
+}
+
+/// Parses textual IR into a [ControlFlowGraph].
+pub fn parse_ir(text: &str) -> Result<ControlFlowGraph, ParseIrError> {
+    let mut blocks: Vec<BasicBlock> = Vec::new();
+    let mut current: Option<(BlockLabel, Vec<ThreeAddressInstruction>)> = None;
+
+    for (i, line) in text.lines().enumerate() {
+        let error = |message: &str| ParseIrError {
+            line_no: i + 1,
+            message: message.to_string(),
+        };
+
+        let line = line.split(';').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(label) = line.strip_suffix(':') {
+            let label = parse_label(label).ok_or_else(|| error("expected a label like L0:"))?;
+            if let Some((label, instructions)) = current.take() {
+                blocks.push(BasicBlock::new(label, instructions));
+            }
+            current = Some((label, Vec::new()));
+            continue;
+        }
+
+        let instr = parse_instruction(line).map_err(|message| error(&message))?;
+        match current.as_mut() {
+            Some((_, instructions)) => instructions.push(instr),
+            None => return Err(error("instruction before the first label")),
+        }
+    }
+
+    if let Some((label, instructions)) = current {
+        blocks.push(BasicBlock::new(label, instructions));
+    }
+
+    Ok(ControlFlowGraph::new(blocks))
+}
+
+// Internal stuff:
+
+fn parse_instruction(line: &str) -> Result<ThreeAddressInstruction, String> {
+    use ThreeAddressInstruction::*;
+
+    let (mnemonic, operands) = match line.split_once(char::is_whitespace) {
+        Some((mnemonic, rest)) => (mnemonic, rest.trim()),
+        None => (line, ""),
+    };
+    let operands: Vec<&str> = if operands.is_empty() {
+        Vec::new()
+    } else {
+        operands.split(',').map(str::trim).collect()
+    };
+
+    let bad_operands = || format!("bad operands for {}: {:?}", mnemonic, operands);
+
+    let instr = match (mnemonic, &operands[..]) {
+        ("add", ["[p]", "[p]", imm]) => {
+            ChangeVal(parse_imm::<i8>(imm).ok_or_else(bad_operands)? as u8)
+        }
+        ("add", ["p", "p", imm]) => ChangeAddr(parse_imm(imm).ok_or_else(bad_operands)?),
+        ("add", [dst, src, imm]) if dst == src => {
+            let offset = parse_offset(dst).ok_or_else(bad_operands)?;
+            let v: i8 = parse_imm(imm).ok_or_else(bad_operands)?;
+            ChangeValAt(offset, v as u8)
+        }
+        ("mov", [dst, imm]) => {
+            let offset = parse_offset(dst).ok_or_else(bad_operands)?;
+            SetValAt(offset, parse_imm(imm).ok_or_else(bad_operands)?)
+        }
+        ("mla", [dst, "[p]", imm]) => MulAdd {
+            dst_offset: parse_offset(dst).ok_or_else(bad_operands)?,
+            factor: parse_imm(imm).ok_or_else(bad_operands)?,
+        },
+        ("scan", ["p", imm]) => FindZero(parse_imm(imm).ok_or_else(bad_operands)?),
+        ("beq", ["[p]", label]) => BranchIfZero(parse_label(label).ok_or_else(bad_operands)?),
+        ("b", [label]) => BranchTo(parse_label(label).ok_or_else(bad_operands)?),
+        ("ext", [op]) => {
+            let byte = match op.as_bytes() {
+                [byte] => *byte,
+                _ => return Err(bad_operands()),
+            };
+            Extended(ExtendedOp::from_byte(byte).ok_or_else(bad_operands)?)
+        }
+        ("emit", bytes) if !bytes.is_empty() => {
+            let bytes: Vec<u8> = bytes
+                .iter()
+                .map(|imm| parse_imm(imm))
+                .collect::<Option<_>>()
+                .ok_or_else(bad_operands)?;
+            EmitBytes(EmittedBytes::new(&bytes).ok_or_else(bad_operands)?)
+        }
+        ("putchar", []) => PutChar,
+        ("getchar", []) => GetChar,
+        ("nop", []) => NoOp,
+        ("zero", []) => Zero,
+        ("terminate", []) => Terminate,
+        _ => return Err(format!("unknown instruction: {}", line)),
+    };
+
+    Ok(instr)
+}
+
+/// Parses `L<n>`
+fn parse_label(text: &str) -> Option<BlockLabel> {
+    text.strip_prefix('L')?.parse().ok().map(BlockLabel)
+}
+
+/// Parses `#<n>`
+fn parse_imm<T: std::str::FromStr>(text: &str) -> Option<T> {
+    text.strip_prefix('#')?.parse().ok()
+}
+
+/// Parses `[p+<n>]` (where n may be negative)
+fn parse_offset(text: &str) -> Option<i32> {
+    text.strip_prefix("[p+")?.strip_suffix(']')?.parse().ok()
+}
+
+impl std::error::Error for ParseIrError {}
+
+impl fmt::Display for ParseIrError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line_no, self.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::lower;
+    use crate::parsing::parse;
+
+    #[test]
+    fn parses_a_loop() {
+        let cfg = parse_ir(
+            "
+            L0:
+                add [p], [p], #1    ; comments are okay
+            L1:
+                beq [p], L3
+            L2:
+                putchar
+                add [p], [p], #-1
+                b   L1
+            L3:
+                terminate
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(4, cfg.blocks().len());
+        assert_eq!(Ok(()), cfg.verify());
+        assert!(matches!(
+            cfg.blocks()[2].instructions(),
+            [
+                ThreeAddressInstruction::PutChar,
+                ThreeAddressInstruction::ChangeVal(0xFF),
+                ThreeAddressInstruction::BranchTo(BlockLabel(1))
+            ]
+        ));
+    }
+
+    #[test]
+    fn reads_what_display_writes() {
+        let ast = parse("<test>", b"+[->++>+<<]>[>]<<-[.<]>>[-]>>>,").unwrap();
+        let cfg = lower(&ast);
+
+        let reparsed = parse_ir(&cfg.to_string()).unwrap();
+        assert_eq!(cfg.blocks().len(), reparsed.blocks().len());
+        for (a, b) in cfg.blocks().iter().zip(reparsed.blocks()) {
+            assert_eq!(a.label(), b.label());
+            assert_eq!(a.instructions(), b.instructions());
+        }
+    }
+
+    #[test]
+    fn reports_the_line_number() {
+        let error = parse_ir("L0:\n  add [p], #1\n").unwrap_err();
+        assert_eq!(2, error.line_no);
+    }
+}
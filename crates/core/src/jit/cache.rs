@@ -0,0 +1,133 @@
+//! Keeps generated machine code on disk, so that running the same program again skips compiling
+//! it.
+//!
+//! Each entry is a file named after a hash of its key (e.g., the source text), holding the versions
+//! of brainmuck and of its code generator that generated the code, the whole key, and then the
+//! program in the format described in `jit/file.rs`. A different key with the same hash, or code
+//! from another version of either, is just a miss.
+
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+
+use super::{CompiledProgram, CODEGEN_VERSION};
+
+/// Code from other versions of brainmuck, or of its code generator, is never reused, even when the
+/// source hasn't changed. The package version alone hardly ever changes.
+fn compiler_version() -> String {
+    format!("{} codegen {}", env!("CARGO_PKG_VERSION"), CODEGEN_VERSION)
+}
+
+/// A directory of [CompiledProgram]s, looked up by whatever they were compiled from.
+///
+/// The machine code is run exactly as it's found, so the directory must not be writable by anyone
+/// you wouldn't let run code as you.
+pub struct NativeCodeCache {
+    dir: PathBuf,
+}
+
+impl NativeCodeCache {
+    /// Keeps code in `dir`, which is created when the first program is saved.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        NativeCodeCache { dir: dir.into() }
+    }
+
+    /// Keeps code in `$XDG_CACHE_HOME/brainmuck`, or `~/.cache/brainmuck`. Returns `None` when
+    /// neither variable is set.
+    pub fn in_user_cache_dir() -> Option<Self> {
+        let base = match std::env::var_os("XDG_CACHE_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+        };
+        Some(NativeCodeCache::new(base.join("brainmuck")))
+    }
+
+    /// Loads the program that was saved with the same key, in a fresh executable mapping. Returns
+    /// `None` if there isn't one, or it can't be read.
+    pub fn load(&self, key: &[u8]) -> Option<CompiledProgram> {
+        let mut input = BufReader::new(File::open(self.path(key)).ok()?);
+        if read_chunk(&mut input).ok()? != compiler_version().as_bytes()
+            || read_chunk(&mut input).ok()? != key
+        {
+            return None;
+        }
+        CompiledProgram::read_from(&mut input).ok()
+    }
+
+    /// Saves the program, so that [NativeCodeCache::load] finds it from now on. The key should
+    /// cover everything that affects the generated code, e.g., the source text, and which frontend
+    /// parsed it.
+    pub fn save(&self, key: &[u8], program: &CompiledProgram) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path(key);
+        // Written to the side and renamed, so that a concurrent run never loads half a program.
+        let temporary = path.with_extension(format!("{}.tmp", std::process::id()));
+
+        let mut out = BufWriter::new(File::create(&temporary)?);
+        write_chunk(&mut out, compiler_version().as_bytes())?;
+        write_chunk(&mut out, key)?;
+        program.write_to(&mut out)?;
+        out.flush()?;
+        drop(out);
+
+        fs::rename(&temporary, &path)
+    }
+
+    fn path(&self, key: &[u8]) -> PathBuf {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        self.dir.join(format!("{:016x}.bmnc", hasher.finish()))
+    }
+}
+
+fn write_chunk(out: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    out.write_all(&(bytes.len() as u64).to_le_bytes())?;
+    out.write_all(bytes)
+}
+
+fn read_chunk(input: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut length = [0u8; 8];
+    input.read_exact(&mut length)?;
+    let length = u64::from_le_bytes(length);
+
+    let mut bytes = Vec::new();
+    input.take(length).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != length {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(bytes)
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reruns_programs_from_the_cache() {
+        use crate::parsing::parse;
+        use crate::BrainmuckProgram;
+
+        let dir = std::env::temp_dir().join(format!("brainmuck-cache-{}", std::process::id()));
+        let cache = NativeCodeCache::new(&dir);
+        let source = b";# tape=16 wrap=on\n,<+[->++<]>.";
+
+        assert!(cache.load(source).is_none());
+        let ast = parse("<test>", source).unwrap();
+        cache
+            .save(source, &crate::compile_to_native_code(&ast))
+            .unwrap();
+        let program = cache.load(source).unwrap();
+        assert!(cache.load(b"+").is_none());
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut universe = vec![0u8; program.tape_length()];
+        let mut output = Vec::new();
+        program
+            .run_with_io(&mut universe, &mut &b"\x03"[..], &mut output)
+            .unwrap();
+        assert_eq!(16, universe.len());
+        assert_eq!(vec![5], output);
+    }
+}
@@ -0,0 +1,307 @@
+//! Writes a [CompiledProgram] out, and reads it back into a fresh executable mapping. Generated
+//! code is position-independent (branches are relative, and everything else it needs is passed
+//! in registers), so it runs wherever it's loaded, without any fixups.
+//!
+//! The format is little-endian throughout:
+//!
+//! ```text
+//! magic    "BMNC"
+//! version  u16
+//! codegen  u32      the code generator's version (see CODEGEN_VERSION)
+//! arch     u8 length, then the architecture the code is for, e.g., "x86_64"
+//! flags    u8       bit 0: the tape wraps around
+//! tape     u32      how many cells the tape should have
+//! code     u32 length, then the machine code
+//! blocks   u32 count, then each block: u32 offset, u32 label, u32 line, u32 column
+//!                   (line 0: the block has no position in the source)
+//! unwind   u8 return address register (0xff: no unwind info), u32 count, then each rule:
+//!                   u32 offset, u8 kind, then its operands
+//! ```
+
+use std::env::consts::ARCH;
+use std::io::{self, Read, Write};
+
+use super::{CompiledProgram, CODEGEN_VERSION};
+use crate::codegen::{BlockStart, CodegenOptions};
+use crate::ir::BlockLabel;
+use crate::parsing::SourcePosition;
+use crate::unwind::{FrameDescription, Rule};
+
+const MAGIC: &[u8; 4] = b"BMNC";
+/// Bump this whenever the format changes, so that old files are rejected instead of misread.
+const VERSION: u16 = 2;
+
+const WRAP_TAPE: u8 = 0b1;
+const NO_UNWIND_INFO: u8 = 0xff;
+
+impl CompiledProgram {
+    /// Writes the program in the binary format described in this module. Code that counts how
+    /// often its blocks run has the counters' addresses built in, and so does code that calls
+    /// functions, so neither can be written.
+    pub(crate) fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        if self.block_counts.is_some() {
+            return Err(io::Error::other(
+                "code with profiling counters only runs where it was generated",
+            ));
+        }
+        if !self.functions.is_empty() {
+            return Err(io::Error::other(
+                "code that calls functions only runs where it was generated",
+            ));
+        }
+        out.write_all(MAGIC)?;
+        out.write_all(&VERSION.to_le_bytes())?;
+        out.write_all(&CODEGEN_VERSION.to_le_bytes())?;
+        out.write_all(&[ARCH.len() as u8])?;
+        out.write_all(ARCH.as_bytes())?;
+        out.write_all(&[if self.wrap_tape { WRAP_TAPE } else { 0 }])?;
+        write_u32(out, self.tape_length)?;
+
+        write_u32(out, self.size)?;
+        out.write_all(self.code_bytes())?;
+
+        write_u32(out, self.block_starts.len())?;
+        for block in &self.block_starts {
+            let position = block
+                .position
+                .unwrap_or(SourcePosition { line: 0, column: 0 });
+            write_u32(out, block.offset)?;
+            write_u32(out, block.label.0)?;
+            write_u32(out, position.line as usize)?;
+            write_u32(out, position.column as usize)?;
+        }
+
+        let Some(frame) = &self.frame else {
+            return out.write_all(&[NO_UNWIND_INFO]);
+        };
+        out.write_all(&[frame.return_address_register])?;
+        write_u32(out, frame.rules.len())?;
+        for &(offset, rule) in &frame.rules {
+            write_u32(out, offset)?;
+            match rule {
+                Rule::Cfa { register, offset } => {
+                    out.write_all(&[0, register])?;
+                    out.write_all(&offset.to_le_bytes())?;
+                }
+                Rule::CfaOffset(offset) => {
+                    out.write_all(&[1])?;
+                    out.write_all(&offset.to_le_bytes())?;
+                }
+                Rule::CfaRegister(register) => out.write_all(&[2, register])?,
+                Rule::Saved { register, offset } => {
+                    out.write_all(&[3, register])?;
+                    out.write_all(&offset.to_le_bytes())?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads a program in the binary format described in this module, and makes it executable.
+    /// Code for another architecture is rejected as [io::ErrorKind::InvalidData], but nothing can
+    /// tell whether the machine code itself is sound, so only read what [CompiledProgram::write_to]
+    /// wrote.
+    pub(crate) fn read_from(input: &mut impl Read) -> io::Result<Self> {
+        let mut magic = [0u8; 4];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a brainmuck native code file"));
+        }
+
+        let mut version = [0u8; 2];
+        input.read_exact(&mut version)?;
+        if u16::from_le_bytes(version) != VERSION {
+            return Err(invalid("unsupported native code version"));
+        }
+        if read_u32(input)? != CODEGEN_VERSION {
+            return Err(invalid(
+                "native code is from another version of the code generator",
+            ));
+        }
+        let length = read_u8(input)? as usize;
+        let arch = read_bytes(input, length)?;
+        if arch != ARCH.as_bytes() {
+            return Err(invalid("native code is for another architecture"));
+        }
+        let flags = read_u8(input)?;
+        let options = CodegenOptions {
+            tape_length: read_u32(input)? as usize,
+            wrap_tape: flags & WRAP_TAPE != 0,
+            ..CodegenOptions::default()
+        };
+
+        let length = read_u32(input)? as usize;
+        let code = read_bytes(input, length)?;
+
+        let count = read_u32(input)? as usize;
+        let mut block_starts = Vec::new();
+        for _ in 0..count {
+            let offset = read_u32(input)? as usize;
+            if offset > code.len() {
+                return Err(invalid("block starts past the end of the code"));
+            }
+            let label = BlockLabel(read_u32(input)? as usize);
+            let line = read_u32(input)?;
+            let column = read_u32(input)?;
+            block_starts.push(BlockStart {
+                offset,
+                label,
+                position: (line != 0).then_some(SourcePosition { line, column }),
+            });
+        }
+
+        let program = CompiledProgram::from_binary(&code)
+            .with_block_starts(&block_starts)
+            .with_tape(&options);
+
+        let return_address_register = read_u8(input)?;
+        if return_address_register == NO_UNWIND_INFO {
+            return Ok(program);
+        }
+        let count = read_u32(input)? as usize;
+        let mut rules = Vec::new();
+        for _ in 0..count {
+            let offset = read_u32(input)? as usize;
+            if offset > code.len() || rules.last().is_some_and(|&(last, _)| offset < last) {
+                return Err(invalid("unwind rules are out of order"));
+            }
+            let rule = match read_u8(input)? {
+                0 => Rule::Cfa {
+                    register: read_u8(input)?,
+                    offset: read_u32(input)?,
+                },
+                1 => Rule::CfaOffset(read_u32(input)?),
+                2 => Rule::CfaRegister(read_u8(input)?),
+                3 => Rule::Saved {
+                    register: read_u8(input)?,
+                    offset: read_u32(input)? as i32,
+                },
+                _ => return Err(invalid("unknown unwind rule")),
+            };
+            rules.push((offset, rule));
+        }
+
+        Ok(program.with_unwind_info(&FrameDescription {
+            return_address_register,
+            rules,
+        }))
+    }
+}
+
+// Internal stuff:
+
+fn write_u32(out: &mut impl Write, n: usize) -> io::Result<()> {
+    let n = u32::try_from(n).map_err(|_| invalid("program is too big to save"))?;
+    out.write_all(&n.to_le_bytes())
+}
+
+fn read_u8(input: &mut impl Read) -> io::Result<u8> {
+    let mut bytes = [0u8; 1];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_bytes(input: &mut impl Read, length: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    input.take(length as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != length {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(bytes)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_everything_but_the_address() {
+        let frame = FrameDescription {
+            return_address_register: 30,
+            rules: vec![
+                (
+                    0,
+                    Rule::Cfa {
+                        register: 31,
+                        offset: 0,
+                    },
+                ),
+                (4, Rule::CfaOffset(32)),
+                (4, Rule::CfaRegister(29)),
+                (
+                    8,
+                    Rule::Saved {
+                        register: 19,
+                        offset: -16,
+                    },
+                ),
+            ],
+        };
+        let block_starts = [
+            BlockStart {
+                offset: 4,
+                label: BlockLabel(0),
+                position: Some(SourcePosition { line: 3, column: 7 }),
+            },
+            BlockStart {
+                offset: 8,
+                label: BlockLabel(2),
+                position: None,
+            },
+        ];
+        let program = CompiledProgram::from_binary(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12])
+            .with_block_starts(&block_starts)
+            .with_unwind_info(&frame)
+            .with_tape(&CodegenOptions {
+                tape_length: 256,
+                wrap_tape: true,
+                ..CodegenOptions::default()
+            });
+
+        let mut file = Vec::new();
+        program.write_to(&mut file).unwrap();
+        let loaded = CompiledProgram::read_from(&mut &file[..]).unwrap();
+
+        assert_eq!(program.code_bytes(), loaded.code_bytes());
+        assert_eq!(&block_starts[..], &loaded.block_starts[..]);
+        assert_eq!(Some(frame), loaded.frame);
+        assert_eq!(256, loaded.tape_length());
+        assert!(loaded.wrap_tape);
+    }
+
+    #[test]
+    fn rejects_code_for_another_architecture() {
+        let mut file = Vec::new();
+        CompiledProgram::from_binary(&[0xc3])
+            .write_to(&mut file)
+            .unwrap();
+        // The first letter of the architecture's name:
+        file[11] ^= 0x20;
+
+        let error = CompiledProgram::read_from(&mut &file[..]).err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+    }
+
+    #[test]
+    fn rejects_code_from_another_code_generator() {
+        let mut file = Vec::new();
+        CompiledProgram::from_binary(&[0xc3])
+            .write_to(&mut file)
+            .unwrap();
+        file[6..10].copy_from_slice(&(CODEGEN_VERSION - 1).to_le_bytes());
+
+        let error = CompiledProgram::read_from(&mut &file[..]).err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+    }
+}
@@ -0,0 +1,471 @@
+//! Entry point for using the JIT-compiler.
+
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::ops::Range;
+
+use crate::asm::CodeBuffer;
+use crate::codegen::{BlockCounts, BlockStart, CodegenOptions};
+use crate::config::DEFAULT_TAPE_LENGTH;
+use crate::errors::RuntimeError;
+use crate::guard;
+use crate::parsing::SourcePosition;
+use crate::program::{getchar, BrainmuckProgram, GetChar, PutChar, RunStats};
+use crate::unwind::{self, FrameDescription, RegisteredFrame};
+use mmap_jit::{ExecutableRegion, WritableRegion};
+
+pub use self::cache::NativeCodeCache;
+pub(crate) use self::output::OutputBuffer;
+
+mod cache;
+mod file;
+pub(crate) mod output;
+
+/// A CompiledProgram takes an [ExecutableRegion] of code and allows you to run it as a Brainfuck
+/// program.
+pub struct CompiledProgram {
+    // Dropped first, so that the unwinder never knows about code that's gone.
+    _unwind_info: Option<RegisteredFrame>,
+    code: ExecutableRegion,
+    // How many bytes of the region are code.
+    size: usize,
+    // The tape the code was generated for. It must be exactly this long when the code wraps.
+    tape_length: usize,
+    wrap_tape: bool,
+    block_starts: Vec<BlockStart>,
+    // Kept so that the code can be saved, and registered again once it's loaded.
+    frame: Option<FrameDescription>,
+    // The code adds to these, so they live exactly as long as it does.
+    block_counts: Option<BlockCounts>,
+    // Code that this code calls, so it lives exactly as long as this does.
+    functions: Vec<CompiledProgram>,
+}
+
+/// The type of function generated by the compiler, as expressed in function pointer syntax. The
+/// arguments are the current cell, where to put output, getchar, and the start of the tape. A
+/// fault in a guard unwinds out of it.
+///
+/// getchar() is a plain pointer here, since it's a Rust function, which C has no type for. The
+/// code calls it like a C function, which, with no arguments and a u32 result, is the same thing.
+type Program = extern "C-unwind" fn(*mut u8, *mut OutputBuffer, *const (), *mut u8) -> u64;
+
+/// Which version of the generated code this is: how it's called (see [Program]), and what the
+/// code generator makes of each instruction. Bump it with any change to either, so that code saved
+/// by another version, in a file or a [NativeCodeCache], is never run.
+pub(crate) const CODEGEN_VERSION: u32 = 1;
+
+impl CompiledProgram {
+    /// Initializes a CompiledProgram from the passed binary machine code.
+    pub fn from_binary(binary: &[u8]) -> CompiledProgram {
+        let mut code = CodeBuffer::new();
+        code.extend_from_slice(binary);
+        CompiledProgram::from_code(code)
+    }
+
+    /// Initializes a CompiledProgram from code that a [CodeGenerator](crate::codegen::CodeGenerator)
+    /// generated, making the memory it's already in executable, instead of copying it.
+    pub(crate) fn from_code(code: CodeBuffer) -> CompiledProgram {
+        let (region, size) = code.into_region();
+        // Even no code at all has to be somewhere.
+        let region = region.unwrap_or_else(|| WritableRegion::allocate(1).unwrap());
+
+        CompiledProgram {
+            _unwind_info: None,
+            code: region.into_executable().unwrap(),
+            size,
+            tape_length: DEFAULT_TAPE_LENGTH,
+            wrap_tape: false,
+            block_starts: Vec::new(),
+            frame: None,
+            block_counts: None,
+            functions: Vec::new(),
+        }
+    }
+
+    /// Tells the unwinder how to get through the code, so that backtraces from inside `putchar()`
+    /// or `getchar()` reach whoever ran the program.
+    pub(crate) fn with_unwind_info(self, description: &FrameDescription) -> CompiledProgram {
+        let eh_frame = unwind::eh_frame(description, self.code.addr() as usize, self.size);
+        CompiledProgram {
+            _unwind_info: Some(RegisteredFrame::register(eh_frame)),
+            frame: Some(description.clone()),
+            ..self
+        }
+    }
+
+    /// Remembers where each basic block starts, so that the code can be named in a perf map. See
+    /// [CompiledProgram::write_perf_map].
+    pub(crate) fn with_block_starts(self, block_starts: &[BlockStart]) -> CompiledProgram {
+        CompiledProgram {
+            block_starts: block_starts.to_vec(),
+            ..self
+        }
+    }
+
+    /// Appends the code to `/tmp/perf-<pid>.map`, so that Linux `perf` can tell which part of the
+    /// program it's looking at, instead of showing anonymous memory. Each basic block gets its own
+    /// symbol, named after the line it starts on, e.g., `brainmuck L3 (line 12)`.
+    pub fn write_perf_map(&self) -> io::Result<()> {
+        let path = format!("/tmp/perf-{}.map", std::process::id());
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        file.write_all(self.perf_map().as_bytes())
+    }
+
+    /// One line for each symbol, formatted as `START SIZE name`, with the addresses in hex. Each
+    /// function's symbols are named after the loop it is, e.g., `brainmuck loop 0 L3 (line 12)`.
+    fn perf_map(&self) -> String {
+        let mut map = String::new();
+        for (program, prefix) in self.programs() {
+            let start = program.code.addr() as usize;
+            for (code, name) in program.parts() {
+                map.push_str(&format!(
+                    "{:x} {:x} brainmuck {}{}\n",
+                    start + code.start,
+                    code.len(),
+                    prefix,
+                    name
+                ));
+            }
+        }
+        map
+    }
+
+    /// This code, then each of its functions, with what to put in front of the names of their
+    /// parts.
+    fn programs(&self) -> impl Iterator<Item = (&CompiledProgram, String)> {
+        let functions = self
+            .functions
+            .iter()
+            .enumerate()
+            .map(|(index, function)| (function, format!("loop {} ", index)));
+        [(self, String::new())].into_iter().chain(functions)
+    }
+
+    /// Where the code starts, for other code to call it.
+    pub(crate) fn code_addr(&self) -> usize {
+        self.code.addr() as usize
+    }
+
+    /// The machine code, exactly as it was generated.
+    pub fn code_bytes(&self) -> &[u8] {
+        &self.code[..self.size]
+    }
+
+    /// The machine code in hex, with a heading for each basic block that says where in the source
+    /// it came from, e.g., `L3 (line 12):`. Each function follows, with its headings named after
+    /// the loop it is, e.g., `loop 0 L3 (line 12):`.
+    pub fn annotated_dump(&self) -> String {
+        let mut dump = String::new();
+        for (program, prefix) in self.programs() {
+            for (code, name) in program.parts() {
+                dump.push_str(&format!("{}{}:\n", prefix, name));
+                let end = code.end;
+                for offset in code.step_by(16) {
+                    let row = &program.code_bytes()[offset..end.min(offset + 16)];
+                    let hex: Vec<_> = row.iter().map(|byte| format!("{:02x}", byte)).collect();
+                    dump.push_str(&format!("    {:04x}  {}\n", offset, hex.join(" ")));
+                }
+            }
+        }
+        dump
+    }
+
+    /// Splits the code into the prologue, and each basic block, named after the line it starts
+    /// on. Blocks with nothing to do generate no code, so they're left out.
+    fn parts(&self) -> Vec<(Range<usize>, String)> {
+        let mut starts = vec![(0, String::from("prologue"))];
+        starts.extend(self.block_starts.iter().map(|block| {
+            let name = match block.position {
+                Some(position) => format!("L{} (line {})", block.label.0, position.line),
+                None => format!("L{}", block.label.0),
+            };
+            (block.offset, name)
+        }));
+
+        let ends: Vec<_> = starts.iter().skip(1).map(|&(offset, _)| offset).collect();
+        starts
+            .into_iter()
+            .zip(ends.into_iter().chain([self.size]))
+            .filter(|((start, _), end)| end > start)
+            .map(|((start, name), end)| (start..end, name))
+            .collect()
+    }
+
+    /// Remembers the tape from the options the code was generated with. When the tape wraps (see
+    /// [CodeGenerator::with_wrapping_tape](crate::codegen::CodeGenerator::with_wrapping_tape)),
+    /// the code only ever runs on a tape that it can wrap around.
+    pub(crate) fn with_tape(self, options: &CodegenOptions) -> CompiledProgram {
+        CompiledProgram {
+            tape_length: options.tape_length,
+            wrap_tape: options.wrap_tape,
+            ..self
+        }
+    }
+
+    /// Keeps the counters that code generated with [CodegenOptions::profile] adds to.
+    pub(crate) fn with_block_counts(self, block_counts: Option<BlockCounts>) -> CompiledProgram {
+        CompiledProgram {
+            block_counts,
+            ..self
+        }
+    }
+
+    /// How many times each basic block has run so far, if the code was generated with
+    /// [CodegenOptions::profile]. Each of the [functions](CompiledProgram::functions) counts its
+    /// own blocks.
+    pub fn block_counts(&self) -> Option<&BlockCounts> {
+        self.block_counts.as_ref()
+    }
+
+    /// Keeps the code that this code calls, in the order of the loops that it is. See
+    /// [CodegenOptions::outline_loops].
+    pub(crate) fn with_functions(self, functions: Vec<CompiledProgram>) -> CompiledProgram {
+        CompiledProgram { functions, ..self }
+    }
+
+    /// Each top-level loop, when the code was generated with [CodegenOptions::outline_loops].
+    pub fn functions(&self) -> &[CompiledProgram] {
+        &self.functions
+    }
+
+    /// How many cells the tape should have, as the program's config said when it was compiled.
+    /// A program that was loaded from a [NativeCodeCache] has no other way of knowing.
+    pub fn tape_length(&self) -> usize {
+        self.tape_length
+    }
+
+    /// Runs the code with the tape pointer starting at `cell`, and returns where the tape pointer
+    /// was when the code terminated.
+    ///
+    /// # Safety
+    ///
+    /// The generated code does not check the bounds of the tape, so the caller must make sure
+    /// that the code can never move `cell` off of the tape it points into, or, if the code wraps,
+    /// that `tape` is the start of a tape of the right length.
+    pub(crate) unsafe fn run_from(
+        &self,
+        cell: *mut u8,
+        output: &mut OutputBuffer,
+        getchar: GetChar,
+        tape: *mut u8,
+    ) -> *mut u8 {
+        let program = self.code.as_fn::<Program>();
+
+        program(cell, output, getchar as *const (), tape) as *mut u8
+    }
+
+    /// Runs the code on the whole universe, with everything it prints going through `output`.
+    /// Moving off a [GuardedTape](crate::GuardedTape) stops the program with a [RuntimeError],
+    /// whose instruction is the offset of the code that touched the guard.
+    ///
+    /// # Panics
+    ///
+    /// When the program wraps around the tape, and the universe isn't exactly as long as the tape
+    /// it was compiled for.
+    pub(crate) fn run_with_output(
+        &self,
+        universe: &mut [u8],
+        output: &mut OutputBuffer,
+        getchar: GetChar,
+    ) -> Result<RunStats, RuntimeError> {
+        if self.wrap_tape {
+            assert_eq!(
+                self.tape_length,
+                universe.len(),
+                "the program wraps around a tape of a different length"
+            );
+        }
+
+        let tape = universe.as_mut_ptr();
+        // SAFETY: the code can only leave the tape if the program does, which is on whoever
+        // compiled it, or it touches a guard, and is abandoned.
+        let mut run = || unsafe { self.run_from(tape, &mut *output, getchar, tape) } as usize;
+        // Only code that the unwinder can get through can be abandoned. All generated code can be,
+        // but not code from CompiledProgram::from_binary.
+        let result = match self._unwind_info {
+            Some(_) => guard::catch_faults(universe, run),
+            None => Ok(run()),
+        };
+
+        match result {
+            Ok(end) => Ok(RunStats {
+                instructions_executed: None,
+                final_address: end.wrapping_sub(tape as usize),
+            }),
+            Err(fault) => {
+                output.flush();
+                Err(RuntimeError::new(fault.kind, self.instruction_at(fault.pc)))
+            }
+        }
+    }
+
+    /// The offset of the instruction at the address, as if each function's code followed this
+    /// code's, or the total size of all of them, if the address isn't in any of them.
+    fn instruction_at(&self, address: usize) -> usize {
+        let mut offset = 0;
+        for (program, _) in self.programs() {
+            let start = program.code.addr() as usize;
+            if (start..start + program.size).contains(&address) {
+                return offset + (address - start);
+            }
+            offset += program.size;
+        }
+        offset
+    }
+}
+
+impl BrainmuckProgram for CompiledProgram {
+    /// Writes output to `stdout` a line (or a buffer) at a time, instead of through `putchar()`.
+    fn run(&self, universe: &mut [u8]) -> Result<RunStats, RuntimeError> {
+        self.run_with_output(universe, &mut OutputBuffer::stdout(), getchar)
+    }
+
+    /// # Panics
+    ///
+    /// When the program wraps around the tape, and the universe isn't exactly as long as the tape
+    /// it was compiled for.
+    fn run_with_custom_io(
+        &self,
+        universe: &mut [u8],
+        putchar: PutChar,
+        getchar: GetChar,
+    ) -> Result<RunStats, RuntimeError> {
+        self.run_with_output(universe, &mut OutputBuffer::new(putchar), getchar)
+    }
+
+    /// The instruction is an offset into the code (with each function's code following it), which
+    /// is from the block that contains it.
+    fn source_position(&self, instruction: usize) -> Option<SourcePosition> {
+        if instruction >= self.size {
+            let mut instruction = instruction - self.size;
+            for function in &self.functions {
+                if instruction < function.size {
+                    return function.source_position(instruction);
+                }
+                instruction -= function.size;
+            }
+            return None;
+        }
+        self.block_starts
+            .iter()
+            .take_while(|block| block.offset <= instruction)
+            .last()?
+            .position
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::BlockLabel;
+    use crate::parsing::SourcePosition;
+
+    #[test]
+    fn names_each_block_in_the_perf_map() {
+        let block = |offset, label, line: Option<u32>| BlockStart {
+            offset,
+            label: BlockLabel(label),
+            position: line.map(|line| SourcePosition { line, column: 1 }),
+        };
+        let program = CompiledProgram::from_binary(&[0; 12]).with_block_starts(&[
+            block(4, 0, Some(1)),
+            block(4, 1, Some(2)),
+            block(8, 2, None),
+        ]);
+        let start = program.code.addr() as usize;
+
+        assert_eq!(
+            format!(
+                "{:x} 4 brainmuck prologue\n{:x} 4 brainmuck L1 (line 2)\n{:x} 4 brainmuck L2\n",
+                start,
+                start + 4,
+                start + 8
+            ),
+            program.perf_map()
+        );
+    }
+
+    #[test]
+    fn dumps_the_code_of_each_block() {
+        let binary: Vec<u8> = (0..24).collect();
+        let program = CompiledProgram::from_binary(&binary).with_block_starts(&[BlockStart {
+            offset: 4,
+            label: BlockLabel(0),
+            position: Some(SourcePosition { line: 1, column: 1 }),
+        }]);
+
+        assert_eq!(&binary[..], program.code_bytes());
+        assert_eq!(
+            concat!(
+                "prologue:\n",
+                "    0000  00 01 02 03\n",
+                "L0 (line 1):\n",
+                "    0004  04 05 06 07 08 09 0a 0b 0c 0d 0e 0f 10 11 12 13\n",
+                "    0014  14 15 16 17\n",
+            ),
+            program.annotated_dump()
+        );
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn counts_how_many_times_each_block_runs() {
+        use crate::parsing::parse;
+
+        let ast = parse("<test>", b";# eof=0\n,[.-]").unwrap();
+        let cfg = crate::ast_to_optimized_cfg(&ast);
+        let options = CodegenOptions {
+            profile: true,
+            debug_info: false,
+            ..CodegenOptions::from(ast.config())
+        };
+        let program = crate::compile_cfg_to_native_code_with_options(&cfg, &options);
+        assert!(program.block_starts.is_empty());
+
+        let mut output = Vec::new();
+        program
+            .run_with_io(&mut [0u8; 4], &mut &b"\x03"[..], &mut output)
+            .unwrap();
+        assert_eq!(vec![3, 2, 1], output);
+
+        let counts: Vec<_> = program.block_counts().unwrap().iter().collect();
+        assert_eq!(cfg.blocks().len(), counts.len());
+        // The first block runs once, and the body of the loop three times:
+        assert_eq!(1, counts[0].1);
+        assert!(counts.iter().any(|&(_, count)| count == 3));
+        // ...and the counters keep going:
+        program
+            .run_with_io(&mut [0u8; 4], &mut &b"\x01"[..], &mut output)
+            .unwrap();
+        assert_eq!(Some(2), program.block_counts().unwrap().get(counts[0].0));
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn calls_each_top_level_loop_as_a_function() {
+        use crate::parsing::parse;
+
+        let ast = parse("<test>", b",[.-]>,[.-]\n>,[.-]").unwrap();
+        let options = CodegenOptions {
+            outline_loops: true,
+            ..CodegenOptions::from(ast.config())
+        };
+        let program = crate::compile_cfg_to_native_code_with_options(
+            &crate::ast_to_optimized_cfg(&ast),
+            &options,
+        );
+        assert_eq!(3, program.functions().len());
+
+        let mut output = Vec::new();
+        program
+            .run_with_io(&mut [0u8; 4], &mut &b"\x02\x01\x01"[..], &mut output)
+            .unwrap();
+        assert_eq!(vec![2, 1, 1, 1], output);
+
+        let map = program.perf_map();
+        assert!(map.contains("brainmuck loop 2 L"));
+        assert!(!map.contains("brainmuck loop 3"));
+        // The functions' addresses are built into the code:
+        assert!(program.write_to(&mut Vec::new()).is_err());
+    }
+}
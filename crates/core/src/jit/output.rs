@@ -0,0 +1,117 @@
+//! Buffers what generated code prints. Calling out to `putchar()` for every byte is slow (e.g.,
+//! Rust's `print!()` locks `stdout` every time), so generated code appends each byte to an
+//! [OutputBuffer] itself, and only calls out to flush it: on a newline, when it's full, before
+//! reading input, and before returning.
+
+use std::io::{self, Write};
+use std::mem::offset_of;
+
+use crate::program::PutChar;
+
+/// How many bytes generated code buffers before flushing.
+pub(crate) const CAPACITY: usize = 4096;
+
+// Where generated code finds each field:
+pub(crate) const LEN: i32 = offset_of!(OutputBuffer, len) as i32;
+pub(crate) const FLUSH: i32 = offset_of!(OutputBuffer, flush) as i32;
+pub(crate) const BYTES: i32 = offset_of!(OutputBuffer, bytes) as i32;
+
+/// Output that generated code appends to. The layout is fixed, since generated code reads and
+/// writes the fields itself.
+#[repr(C)]
+pub(crate) struct OutputBuffer {
+    len: usize,
+    /// Writes out the bytes so far, and empties the buffer.
+    flush: extern "C-unwind" fn(&mut OutputBuffer),
+    bytes: [u8; CAPACITY],
+    putchar: PutChar,
+}
+
+impl OutputBuffer {
+    /// Flushes to `putchar()`, a byte at a time.
+    pub(crate) fn new(putchar: PutChar) -> Self {
+        OutputBuffer {
+            len: 0,
+            flush: flush_to_putchar,
+            bytes: [0; CAPACITY],
+            putchar,
+        }
+    }
+
+    /// Flushes straight to `stdout`, all at once.
+    pub(crate) fn stdout() -> Self {
+        OutputBuffer {
+            flush: flush_to_stdout,
+            ..OutputBuffer::new(crate::program::putchar)
+        }
+    }
+
+    /// Writes out whatever generated code left in the buffer, e.g., when it was abandoned.
+    pub(crate) fn flush(&mut self) {
+        (self.flush)(self)
+    }
+
+    fn contents(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+extern "C-unwind" fn flush_to_putchar(buffer: &mut OutputBuffer) {
+    for &byte in buffer.contents() {
+        (buffer.putchar)(byte as u32);
+    }
+    buffer.len = 0;
+}
+
+extern "C-unwind" fn flush_to_stdout(buffer: &mut OutputBuffer) {
+    // Same as print!(), which the default putchar() uses.
+    io::stdout()
+        .write_all(buffer.contents())
+        .expect("failed printing to stdout");
+    buffer.len = 0;
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+    use crate::parsing::parse;
+    use crate::BrainmuckProgram;
+    use std::cell::RefCell;
+
+    thread_local! {
+        static PRINTED: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+        static PRINTED_BEFORE_INPUT: RefCell<Option<usize>> = const { RefCell::new(None) };
+    }
+
+    fn record(byte: u32) -> u32 {
+        PRINTED.with(|printed| printed.borrow_mut().push(byte as u8));
+        byte
+    }
+
+    fn input() -> u32 {
+        let printed = PRINTED.with(|printed| printed.borrow().len());
+        PRINTED_BEFORE_INPUT.with(|before| *before.borrow_mut() = Some(printed));
+        b'z' as u32
+    }
+
+    #[test]
+    fn flushes_when_full_and_before_reading_input() {
+        // Prints 'A' 20 * 255 times, which is more than fits in the buffer, then echoes a byte.
+        let source = b"++++++++[>++++++++<-]>+>++++++++++++++++++++[>-[<<.>>-]<-]<,.";
+        let program = crate::compile_to_native_code(&parse("<test>", source).unwrap());
+        program
+            .run_with_custom_io(&mut [0u8; 8], record, input)
+            .unwrap();
+
+        let printed = PRINTED.with(|printed| printed.take());
+        const { assert!(20 * 255 > CAPACITY) };
+        assert_eq!(20 * 255 + 1, printed.len());
+        assert!(printed[..20 * 255].iter().all(|&byte| byte == b'A'));
+        assert_eq!(Some(&b'z'), printed.last());
+        // Everything before the input was out by the time it was read:
+        assert_eq!(
+            Some(20 * 255),
+            PRINTED_BEFORE_INPUT.with(|before| before.take())
+        );
+    }
+}
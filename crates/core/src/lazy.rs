@@ -0,0 +1,223 @@
+//! Compiles a program to native code one top-level loop at a time, the first time each loop runs.
+//!
+//! Up front, only the code outside of loops is compiled, with a call to a stub in place of each
+//! top-level loop. The stub compiles the loop the first time it's called (cutting it out of the
+//! [ControlFlowGraph] the same way that [TieredProgram](crate::tiered::TieredProgram) does), and
+//! runs it from then on. Large programs that only ever run a few of their loops start much faster,
+//! since the rest are never compiled.
+
+use std::cell::{Cell, OnceCell};
+
+use crate::codegen::{CodegenOptions, NativeCodeGenerator, StubbedLoop};
+use crate::config::ProgramConfig;
+use crate::errors::RuntimeError;
+use crate::ir::analysis::{dominators, find_loops};
+use crate::ir::ControlFlowGraph;
+use crate::jit::{CompiledProgram, OutputBuffer};
+use crate::program::{getchar, BrainmuckProgram, GetChar, PutChar, RunStats};
+use crate::tiered::extract_region;
+
+/// A [BrainmuckProgram] whose top-level loops are compiled to native code the first time they
+/// run.
+pub struct LazyProgram {
+    /// Everything but the loops, which call [run_loop] instead.
+    outer: CompiledProgram,
+    /// Loops that call [run_loop], by the index they pass to it.
+    loops: Vec<LazyLoop>,
+    /// The outer code and every loop are generated the same way.
+    options: CodegenOptions,
+}
+
+/// A top-level loop, cut out of the CFG so that it can be compiled on its own.
+struct LazyLoop {
+    cfg: ControlFlowGraph,
+    /// Compiled the first time the loop runs.
+    compiled: OnceCell<CompiledProgram>,
+}
+
+/// What [run_loop] needs to know about the program that called it.
+#[derive(Clone, Copy)]
+struct Running {
+    program: *const LazyProgram,
+    getchar: GetChar,
+    tape: *mut u8,
+}
+
+thread_local! {
+    /// The innermost program that's running on this thread.
+    static RUNNING: Cell<Option<Running>> = const { Cell::new(None) };
+}
+
+impl LazyProgram {
+    /// # Panics
+    ///
+    /// When [can_compile_to_native_code](crate::can_compile_to_native_code) is false for the
+    /// config.
+    pub fn new(cfg: &ControlFlowGraph, config: &ProgramConfig) -> Self {
+        assert!(
+            crate::can_compile_to_native_code(config),
+            "the JIT cannot honor this program's config: {}",
+            config
+        );
+
+        let (stubbed, loops): (Vec<_>, Vec<_>) = top_level_loops(cfg)
+            .into_iter()
+            .map(|(stubbed, region)| {
+                let lazy_loop = LazyLoop {
+                    cfg: region,
+                    compiled: OnceCell::new(),
+                };
+                (stubbed, lazy_loop)
+            })
+            .unzip();
+
+        let options = CodegenOptions::from(config);
+        let mut gen = NativeCodeGenerator::new()
+            .with_options(&options)
+            .with_stubs(run_loop as *const () as usize, &stubbed);
+        gen.compile(cfg);
+        let outer = CompiledProgram::from_code(gen.take_machine_code())
+            .with_unwind_info(&gen.frame_description())
+            .with_tape(&options);
+
+        LazyProgram {
+            outer,
+            loops,
+            options,
+        }
+    }
+
+    /// How many top-level loops are only compiled once they run.
+    pub fn lazy_loops(&self) -> usize {
+        self.loops.len()
+    }
+
+    /// How many of those loops have run, and so have been compiled, so far.
+    pub fn compiled_loops(&self) -> usize {
+        self.loops
+            .iter()
+            .filter(|lazy_loop| lazy_loop.compiled.get().is_some())
+            .count()
+    }
+
+    /// Runs the generated code, with `run_loop()` able to find this program while it does.
+    fn run_with_output(
+        &self,
+        universe: &mut [u8],
+        output: &mut OutputBuffer,
+        getchar: GetChar,
+    ) -> Result<RunStats, RuntimeError> {
+        let running = Running {
+            program: self,
+            getchar,
+            tape: universe.as_mut_ptr(),
+        };
+        // putchar() and getchar() could run another program, so only this one's code sees this.
+        let _restore = Restore(RUNNING.replace(Some(running)));
+
+        self.outer.run_with_output(universe, output, getchar)
+    }
+
+    /// The loop's code, compiling it if this is the first time it runs.
+    fn compiled_loop(&self, index: usize) -> &CompiledProgram {
+        let lazy_loop = &self.loops[index];
+        lazy_loop.compiled.get_or_init(|| {
+            let mut gen = NativeCodeGenerator::new().with_options(&self.options);
+            gen.compile(&lazy_loop.cfg);
+            CompiledProgram::from_code(gen.take_machine_code())
+                .with_unwind_info(&gen.frame_description())
+        })
+    }
+}
+
+/// Cuts each top-level loop out of the CFG, as a CFG of its own. Loops that can't be cut out (e.g.,
+/// ones with more than one exit) are left in.
+pub(crate) fn top_level_loops(cfg: &ControlFlowGraph) -> Vec<(StubbedLoop, ControlFlowGraph)> {
+    find_loops(cfg, &dominators(cfg))
+        .loops()
+        .iter()
+        .filter(|natural_loop| natural_loop.depth == 1)
+        .filter_map(|natural_loop| {
+            let (region, exit) = extract_region(cfg, natural_loop, natural_loop.header)?;
+            let stubbed = StubbedLoop {
+                header: natural_loop.header,
+                blocks: natural_loop.blocks.clone(),
+                exit,
+            };
+            Some((stubbed, region))
+        })
+        .collect()
+}
+
+/// Where the generated code goes in place of each top-level loop. Runs the loop (compiling it
+/// first, if it has never run), and returns where the pointer ended up.
+extern "C-unwind" fn run_loop(index: u32, cell: *mut u8, output: *mut OutputBuffer) -> *mut u8 {
+    let running = RUNNING
+        .get()
+        .expect("only generated code calls the stub, while its program is running");
+    // SAFETY: the program set this before running its code, and that code is what called this.
+    let program = unsafe { &*running.program };
+    let code = program.compiled_loop(index as usize);
+
+    // SAFETY: the loop was cut out of the same program, so it's as safe to run as the rest of it.
+    unsafe { code.run_from(cell, &mut *output, running.getchar, running.tape) }
+}
+
+/// Puts back whichever program was running before, even if this one panics.
+struct Restore(Option<Running>);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        RUNNING.set(self.0);
+    }
+}
+
+impl BrainmuckProgram for LazyProgram {
+    fn run(&self, universe: &mut [u8]) -> Result<RunStats, RuntimeError> {
+        self.run_with_output(universe, &mut OutputBuffer::stdout(), getchar)
+    }
+
+    /// # Panics
+    ///
+    /// When the program wraps around the tape, and the universe isn't exactly as long as the tape
+    /// it was compiled for.
+    fn run_with_custom_io(
+        &self,
+        universe: &mut [u8],
+        putchar: PutChar,
+        getchar: GetChar,
+    ) -> Result<RunStats, RuntimeError> {
+        self.run_with_output(universe, &mut OutputBuffer::new(putchar), getchar)
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+    use crate::ast_to_optimized_cfg;
+    use crate::parsing::parse;
+
+    #[test]
+    fn compiles_loops_the_first_time_they_run() {
+        let ast = parse("<test>", b";# eof=0\n,[>+<-.]>.,[.-]").unwrap();
+        let program = LazyProgram::new(&ast_to_optimized_cfg(&ast), ast.config());
+        assert_eq!(2, program.lazy_loops());
+        assert_eq!(0, program.compiled_loops());
+
+        let mut output = Vec::new();
+        program
+            .run_with_io(&mut [0u8; 8], &mut &b"\x02"[..], &mut output)
+            .unwrap();
+        assert_eq!(vec![1, 0, 2], output);
+        // The second loop never ran.
+        assert_eq!(1, program.compiled_loops());
+
+        // Loops that already ran don't get compiled again:
+        output.clear();
+        program
+            .run_with_io(&mut [0u8; 8], &mut &b"\x01\x01"[..], &mut output)
+            .unwrap();
+        assert_eq!(vec![0, 1, 1], output);
+        assert_eq!(2, program.compiled_loops());
+    }
+}
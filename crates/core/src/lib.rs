@@ -17,41 +17,301 @@
 extern crate mmap_jit;
 
 use crate::bytecode::InterpretedProgram;
-use crate::codegen::CodeGenerator;
+use crate::codegen::{CodeGenerator, NativeCodeGenerator};
+use crate::config::Dialect;
+use crate::evaluator::EvaluatedProgram;
 use crate::ir::ControlFlowGraph;
-use crate::jit::CompiledProgram;
+use crate::lazy::LazyProgram;
 use crate::parsing::AbstractSyntaxTree;
+use crate::tiered::TieredProgram;
 
 pub mod bytecode;
+pub mod config;
 pub mod errors;
+pub mod evaluator;
+pub mod format;
+pub mod frontend;
 pub mod ir;
+pub mod lazy;
 pub mod parsing;
+pub mod profile;
+pub mod tiered;
 
 mod asm;
+mod c;
 mod codegen;
+mod guard;
 mod jit;
 mod optimize;
 mod program;
+mod unwind;
 
-pub use crate::errors::CompilationError;
-pub use crate::parsing::parse;
-pub use crate::program::BrainmuckProgram;
+pub use crate::codegen::{BlockCounts, CodegenOptions};
+pub use crate::config::ProgramConfig;
+pub use crate::errors::{CompilationError, CompilationWarning, RuntimeError};
+pub use crate::frontend::{Frontend, FrontendRegistry};
+pub use crate::guard::GuardedTape;
+pub use crate::jit::{CompiledProgram, NativeCodeCache};
+pub use crate::optimize::{OptimizationReport, Remark};
+pub use crate::parsing::{parse, parse_with_limits, parse_with_warnings, ParseLimits};
+pub use crate::profile::Profile;
+pub use crate::program::{BrainmuckProgram, RunStats};
 
 /// Compile the AST down to bytecode, that can then be interpreted.
 pub fn compile_to_bytecode(ast: &AbstractSyntaxTree) -> InterpretedProgram {
-    InterpretedProgram::new(&ast_to_optimized_cfg(ast))
+    InterpretedProgram::new(&ast_to_optimized_cfg(ast), ast.config())
+}
+
+/// Compile the AST down to bytecode again, using a [Profile] collected by
+/// [InterpretedProgram::run_with_profile] from what [compile_to_bytecode] made of the same AST.
+/// Hot loops are unrolled, and blocks are laid out so that the hot path falls through.
+pub fn compile_with_profile(ast: &AbstractSyntaxTree, profile: &Profile) -> InterpretedProgram {
+    let cfg = optimize::optimize_with_profile(&ast_to_optimized_cfg(ast), profile);
+    debug_assert_eq!(
+        Ok(()),
+        cfg.verify(),
+        "profile-guided optimizations produced an invalid CFG"
+    );
+
+    InterpretedProgram::new(&cfg, ast.config())
+}
+
+/// Run the CFG directly, without lowering it to bytecode or machine code. Pass `optimize: false` to
+/// evaluate the CFG exactly as it came out of [ir::lower], before any optimization passes.
+///
+/// This is slow! It's meant for figuring out whether a miscompilation is caused by an
+/// optimization pass or by the lowering that happens after it.
+pub fn compile_to_evaluator(ast: &AbstractSyntaxTree, optimize: bool) -> EvaluatedProgram {
+    let cfg = if optimize {
+        ast_to_optimized_cfg(ast)
+    } else {
+        ir::lower(ast)
+    };
+
+    EvaluatedProgram::new(cfg, ast.config())
+}
+
+/// Returns whether [compile_to_native_code] can honor everything in the program's config, on the
+/// machine this is running on.
+///
+/// Native code can only be generated for AArch64 and x86_64. It does not check the bounds of the
+/// tape, so it can only honor [ProgramConfig::wrap_tape] for tapes whose length is a power of two
+/// (so that wrapping is cheap), and it does not understand any dialect other than plain
+/// brainfuck. Use [compile_to_bytecode] for such programs.
+pub fn can_compile_to_native_code(config: &ProgramConfig) -> bool {
+    codegen::NATIVE_CODE_SUPPORTED && can_generate_code_for(config)
+}
+
+fn can_generate_code_for(config: &ProgramConfig) -> bool {
+    (!config.wrap_tape || codegen::can_wrap_tape(config.tape_length))
+        && config.dialect == Dialect::Brainfuck
 }
 
 /// Compile the AST to native code, injected into the current process's image.
+///
+/// # Panics
+///
+/// When [can_compile_to_native_code] is false for the AST's config.
 pub fn compile_to_native_code(ast: &AbstractSyntaxTree) -> CompiledProgram {
-    let mut gen = CodeGenerator::new();
-    let code = gen.compile(&ast_to_optimized_cfg(ast));
+    assert!(
+        can_compile_to_native_code(ast.config()),
+        "the JIT cannot honor this program's config: {}",
+        ast.config()
+    );
+    compile_cfg_to_native_code(&ast_to_optimized_cfg(ast), ast.config())
+}
+
+/// Compile the AST to native code when [can_compile_to_native_code] says the JIT can run it here,
+/// or to bytecode when it can't (e.g., on a machine without a code generator).
+pub fn compile_best_available(ast: &AbstractSyntaxTree) -> Box<dyn BrainmuckProgram> {
+    let cfg = ast_to_optimized_cfg(ast);
+    if can_compile_to_native_code(ast.config()) {
+        Box::new(compile_cfg_to_native_code(&cfg, ast.config()))
+    } else {
+        Box::new(InterpretedProgram::new(&cfg, ast.config()))
+    }
+}
+
+/// Same as [compile_to_native_code], but for a CFG that's already been optimized, e.g., by
+/// [ast_to_optimized_cfg_with_report]. Check [can_compile_to_native_code] first.
+pub fn compile_cfg_to_native_code(
+    cfg: &ControlFlowGraph,
+    config: &ProgramConfig,
+) -> CompiledProgram {
+    compile_cfg_to_native_code_with_options(cfg, &CodegenOptions::from(config))
+}
+
+/// Same as [compile_cfg_to_native_code], but generates code the way the options say, e.g., with
+/// [CodegenOptions::profile] to count how many times each block runs.
+///
+/// # Panics
+///
+/// When the options ask for something the JIT can't do; see [CodeGenerator::with_options].
+pub fn compile_cfg_to_native_code_with_options(
+    cfg: &ControlFlowGraph,
+    options: &CodegenOptions,
+) -> CompiledProgram {
+    if options.outline_loops {
+        return compile_with_outlined_loops(cfg, options);
+    }
+
+    let mut gen = NativeCodeGenerator::new().with_options(options);
+    gen.compile(cfg);
+    let program = CompiledProgram::from_code(gen.take_machine_code())
+        .with_block_counts(gen.take_block_counts())
+        .with_tape(options)
+        .with_unwind_info(&gen.frame_description());
+    if !options.debug_info {
+        return program;
+    }
+    program.with_block_starts(gen.block_starts())
+}
+
+/// Compiles each top-level loop as a function of its own, then the rest of the code, which calls
+/// them. See [CodegenOptions::outline_loops].
+fn compile_with_outlined_loops(
+    cfg: &ControlFlowGraph,
+    options: &CodegenOptions,
+) -> CompiledProgram {
+    let inner = CodegenOptions {
+        outline_loops: false,
+        ..*options
+    };
+    let (outlined, functions): (Vec<_>, Vec<_>) = lazy::top_level_loops(cfg)
+        .into_iter()
+        .map(|(stubbed, region)| {
+            let function = compile_cfg_to_native_code_with_options(&region, &inner);
+            ((stubbed, function.code_addr()), function)
+        })
+        .unzip();
+
+    let mut gen = NativeCodeGenerator::new()
+        .with_options(&inner)
+        .with_outlined_loops(&outlined);
+    gen.compile(cfg);
+    let program = CompiledProgram::from_code(gen.take_machine_code())
+        .with_block_counts(gen.take_block_counts())
+        .with_tape(options)
+        .with_functions(functions)
+        .with_unwind_info(&gen.frame_description());
+    if !options.debug_info {
+        return program;
+    }
+    program.with_block_starts(gen.block_starts())
+}
 
-    CompiledProgram::from_binary(code)
+/// Compile the AST to native code a top-level loop at a time, the first time each loop runs,
+/// instead of all up front. See [lazy].
+///
+/// # Panics
+///
+/// When [can_compile_to_native_code] is false for the AST's config.
+pub fn compile_to_lazy_native_code(ast: &AbstractSyntaxTree) -> LazyProgram {
+    LazyProgram::new(&ast_to_optimized_cfg(ast), ast.config())
+}
+
+/// Disassembles AArch64 machine code (e.g., from [CompiledProgram::code_bytes], on an AArch64
+/// machine), a line for each instruction: its offset, its encoding, then the assembly. Only the
+/// instructions that the JIT generates are decoded; anything else is written as a `.word`.
+pub fn disassemble_aarch64(code: &[u8]) -> String {
+    code.chunks(4)
+        .enumerate()
+        .map(|(i, word)| {
+            let mut bytes = [0; 4];
+            bytes[..word.len()].copy_from_slice(word);
+            let word = u32::from_le_bytes(bytes);
+            let assembly = asm::aarch64::disasm::disassemble(word)
+                .unwrap_or_else(|| format!(".word {:#010x}", word));
+            format!("{:04x}  {:08x}  {}\n", 4 * i, word, assembly)
+        })
+        .collect()
+}
+
+/// Compile the AST to AArch64 assembly, as a human-readable `.s` listing. Each part of the listing
+/// is commented with the IR that it came from. This works on any machine, so that what the JIT
+/// would generate on AArch64 can be inspected anywhere.
+///
+/// # Panics
+///
+/// When the JIT could not honor the AST's config on AArch64; see [can_compile_to_native_code].
+pub fn compile_to_aarch64_listing(ast: &AbstractSyntaxTree) -> String {
+    aarch64_code_generator(ast).listing().unwrap_or_default()
+}
+
+/// Same as [compile_to_aarch64_listing], but with the offset and encoding of each instruction in
+/// front of it.
+///
+/// # Panics
+///
+/// When the JIT could not honor the AST's config on AArch64; see [can_compile_to_native_code].
+pub fn compile_to_annotated_aarch64_listing(ast: &AbstractSyntaxTree) -> String {
+    aarch64_code_generator(ast)
+        .annotated_listing()
+        .unwrap_or_default()
+}
+
+/// An AArch64 code generator, with a listing, that has compiled the AST.
+fn aarch64_code_generator(ast: &AbstractSyntaxTree) -> CodeGenerator<codegen::aarch64::AArch64> {
+    let config = ast.config();
+    assert!(
+        can_generate_code_for(config),
+        "the JIT cannot honor this program's config: {}",
+        config
+    );
+
+    let mut gen = CodeGenerator::<codegen::aarch64::AArch64>::new()
+        .with_options(&config.into())
+        .with_listing();
+    gen.compile(&ast_to_optimized_cfg(ast));
+    gen
+}
+
+/// Translate the AST to a C program, which can be compiled ahead of time on any platform with a C
+/// compiler. Like native code, the C does not check the bounds of the tape.
+pub fn compile_to_c(ast: &AbstractSyntaxTree) -> String {
+    compile_cfg_to_c(&ast_to_optimized_cfg(ast), ast.config())
+}
+
+/// Same as [compile_to_c], but for a CFG that's already been optimized, e.g., by
+/// [ast_to_optimized_cfg_with_report].
+pub fn compile_cfg_to_c(cfg: &ControlFlowGraph, config: &ProgramConfig) -> String {
+    c::cfg_to_c(cfg, config)
+}
+
+/// Compile the AST to bytecode that starts out interpreted, but whose loops are compiled to native
+/// code once they get hot.
+///
+/// Loops are only ever promoted when [can_compile_to_native_code] is true for the AST's config.
+/// Otherwise, this is the same as [compile_to_bytecode].
+pub fn compile_to_tiered(ast: &AbstractSyntaxTree) -> TieredProgram {
+    TieredProgram::new(&ast_to_optimized_cfg(ast), ast.config())
 }
 
 /// Go from [AbstractSyntaxTree] straight to [ControlFlowGraph], with optimizations
-fn ast_to_optimized_cfg(ast: &AbstractSyntaxTree) -> ControlFlowGraph {
+pub fn ast_to_optimized_cfg(ast: &AbstractSyntaxTree) -> ControlFlowGraph {
     let initial_cfg = ir::lower(ast);
-    optimize::optimize(&initial_cfg)
+    let cfg = optimize::optimize(&initial_cfg, ast.config());
+    debug_assert_eq!(
+        Ok(()),
+        cfg.verify(),
+        "optimizations produced an invalid CFG"
+    );
+
+    cfg
+}
+
+/// Same as [ast_to_optimized_cfg], but also returns remarks on what the optimizer did to the
+/// program, like which loops it replaced and how many instructions it folded away.
+pub fn ast_to_optimized_cfg_with_report(
+    ast: &AbstractSyntaxTree,
+) -> (ControlFlowGraph, OptimizationReport) {
+    let initial_cfg = ir::lower(ast);
+    let (cfg, report) = optimize::optimize_with_report(&initial_cfg, ast.config());
+    debug_assert_eq!(
+        Ok(()),
+        cfg.verify(),
+        "optimizations produced an invalid CFG"
+    );
+
+    (cfg, report)
 }
@@ -1,31 +1,842 @@
 //! Optimize a [ControlFlowGraph].
 
-use crate::ir::{BasicBlock, ControlFlowGraph, ThreeAddressInstruction};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 
-/// Perform all of the optimizations I bothered implementing.
-pub fn optimize(cfg: &ControlFlowGraph) -> ControlFlowGraph {
-    let blocks = cfg
+use crate::config::{EofBehavior, ProgramConfig};
+use crate::errors::{CompilationWarning, Location, WarningKind};
+use crate::ir::{BasicBlock, BlockLabel, ControlFlowGraph, EmittedBytes, ThreeAddressInstruction};
+use crate::parsing::SourcePosition;
+use crate::profile::Profile;
+
+/// Human-readable notes on what each optimization pass did to the program. See
+/// [optimize_with_report].
+#[derive(Debug, Clone)]
+pub struct OptimizationReport {
+    /// When false, remarks are thrown away without even formatting them.
+    recording: bool,
+    remarks: Vec<Remark>,
+    /// Warnings are always kept, since they're about the program, not about the optimizer.
+    warnings: Vec<(WarningKind, Option<SourcePosition>)>,
+}
+
+/// One thing an optimization pass did, like replacing a loop, or folding instructions together.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Remark {
+    /// The name of the pass, e.g., `"clear-loops"`.
+    pub pass: &'static str,
+    /// Where in the source code the change happened, if it's about one place in particular.
+    pub position: Option<SourcePosition>,
+    pub message: String,
+}
+
+/// Perform all of the optimizations I bothered implementing. The config says what the tape looks
+/// like, which matters to passes that reason about addresses.
+pub fn optimize(cfg: &ControlFlowGraph, config: &ProgramConfig) -> ControlFlowGraph {
+    run_passes(cfg, config, &mut OptimizationReport::ignored())
+}
+
+/// Same as [optimize], but also says what each pass did.
+pub fn optimize_with_report(
+    cfg: &ControlFlowGraph,
+    config: &ProgramConfig,
+) -> (ControlFlowGraph, OptimizationReport) {
+    let mut report = OptimizationReport::new();
+    let cfg = run_passes(cfg, config, &mut report);
+
+    (cfg, report)
+}
+
+fn run_passes(
+    cfg: &ControlFlowGraph,
+    config: &ProgramConfig,
+    report: &mut OptimizationReport,
+) -> ControlFlowGraph {
+    let cfg = optimize_clear_loops(cfg, report);
+    let cfg = optimize_multiply_loops(&cfg, report);
+    let cfg = optimize_scan_loops(&cfg, report);
+    let cfg = propagate_constants(&cfg, config, report);
+    let cfg = merge_blocks(&cfg, report);
+    let cfg = sink_across_edges(&cfg, report);
+    let cfg = peephole_optimize_blocks(&cfg, report);
+    let cfg = fuse_offsets(&cfg, report);
+
+    eliminate_dead_stores(&cfg, config, report)
+}
+
+/// Optimizations that only pay off where the program spends its time. `cfg` should already be
+/// optimized, and be the same graph that the profile was collected from.
+pub fn optimize_with_profile(cfg: &ControlFlowGraph, profile: &Profile) -> ControlFlowGraph {
+    let mut report = OptimizationReport::ignored();
+    let cfg = unroll_hot_loops(cfg, profile, &mut report);
+
+    layout_blocks(&cfg, profile, &mut report)
+}
+
+/// Merges each block into the block right before it, when control can only flow from the previous
+/// block into it, and the previous block can only flow into it.
+pub fn merge_blocks(cfg: &ControlFlowGraph, report: &mut OptimizationReport) -> ControlFlowGraph {
+    let mut merged: Vec<(
+        BlockLabel,
+        Vec<ThreeAddressInstruction>,
+        Option<SourcePosition>,
+    )> = Vec::new();
+    let mut previous: Option<BlockLabel> = None;
+
+    for block in cfg.blocks() {
+        let label = block.label();
+
+        match merged.last_mut() {
+            Some((_, instructions, position))
+                if previous.is_some_and(|p| can_merge(cfg, p, label)) =>
+            {
+                // A branch to the very next block is redundant once they're merged.
+                use ThreeAddressInstruction::{BranchIfZero, BranchTo};
+                if let Some(BranchTo(target) | BranchIfZero(target)) = instructions[..].last() {
+                    if *target == label {
+                        instructions.pop();
+                    }
+                }
+                instructions.extend_from_slice(block.instructions());
+                *position = position.or(block.position());
+            }
+            _ => merged.push((label, block.instructions().to_vec(), block.position())),
+        }
+
+        previous = Some(label);
+    }
+
+    let count = cfg.blocks().len() - merged.len();
+    if count > 0 {
+        report.note("merge-blocks", None, || {
+            format!("merged {} blocks into the block before them", count)
+        });
+    }
+
+    ControlFlowGraph::new(
+        merged
+            .into_iter()
+            .map(|(label, instructions, position)| {
+                BasicBlock::new(label, instructions).with_position(position)
+            })
+            .collect(),
+    )
+}
+
+/// Replaces loops like «[-]» and «[+]» with a single [ThreeAddressInstruction::Zero].
+///
+/// Adding any odd number to a byte over and over will eventually reach zero, so bodies like
+/// «[---]» are cleared too.
+pub fn optimize_clear_loops(
+    cfg: &ControlFlowGraph,
+    report: &mut OptimizationReport,
+) -> ControlFlowGraph {
+    use ThreeAddressInstruction::{ChangeVal, Zero};
+
+    replace_simple_loops(cfg, report, "clear-loops", |body| match body {
+        [ChangeVal(n)] if n % 2 == 1 => Some(vec![Zero]),
+        _ => None,
+    })
+}
+
+/// Replaces loops like «[->+++>+<<]» with [ThreeAddressInstruction::MulAdd]s, followed by a
+/// [ThreeAddressInstruction::Zero].
+///
+/// The body must only change values and move the pointer, end up where it started, and change the
+/// starting cell by exactly one. Then each iteration adds a multiple of the starting cell to some
+/// other cells, so the loop can be replaced with straight-line code.
+pub fn optimize_multiply_loops(
+    cfg: &ControlFlowGraph,
+    report: &mut OptimizationReport,
+) -> ControlFlowGraph {
+    replace_simple_loops(cfg, report, "multiply-loops", multiply_loop_replacement)
+}
+
+/// Replaces loops like «[>]» or «[<<]» with a [ThreeAddressInstruction::FindZero].
+pub fn optimize_scan_loops(
+    cfg: &ControlFlowGraph,
+    report: &mut OptimizationReport,
+) -> ControlFlowGraph {
+    use ThreeAddressInstruction::{ChangeAddr, FindZero};
+
+    replace_simple_loops(cfg, report, "scan-loops", |body| match body {
+        [ChangeAddr(step)] if *step != 0 => Some(vec![FindZero(*step)]),
+        _ => None,
+    })
+}
+
+/// Finds loops that are just a head that branches past the loop, followed by a body that has no
+/// branches of its own (other than going back to the head). This is what lowering produces for
+/// innermost loops.
+///
+/// `replace` gets the body's instructions, without the branch back, and returns straight-line code
+/// that does the same thing as the whole loop, if it can. The loop head is replaced with that code
+/// and the body is removed. Every replacement is noted in the report, under the name of the `pass`.
+fn replace_simple_loops(
+    cfg: &ControlFlowGraph,
+    report: &mut OptimizationReport,
+    pass: &'static str,
+    replace: impl Fn(&[ThreeAddressInstruction]) -> Option<Vec<ThreeAddressInstruction>>,
+) -> ControlFlowGraph {
+    use ThreeAddressInstruction::{BranchIfZero, BranchTo};
+
+    let blocks = cfg.blocks();
+    let mut new_blocks = Vec::new();
+
+    let mut i = 0;
+    while i < blocks.len() {
+        let head = &blocks[i];
+
+        if let [_, body, exit, ..] = &blocks[i..] {
+            let replacement = match (head.instructions(), body.instructions()) {
+                ([BranchIfZero(target)], [body_instrs @ .., BranchTo(back)])
+                    if *target == exit.label()
+                        && *back == head.label()
+                        && cfg.predecessors(body.label()) == [head.label()] =>
+                {
+                    replace(body_instrs)
+                }
+                _ => None,
+            };
+
+            if let Some(replacement) = replacement {
+                report.note(pass, head.position(), || {
+                    let code: Vec<String> = replacement
+                        .iter()
+                        .map(|instr| instr.to_string().replace('\t', " "))
+                        .collect();
+                    format!("replaced loop with `{}`", code.join("; "))
+                });
+                new_blocks.push(
+                    BasicBlock::new(head.label(), replacement).with_position(head.position()),
+                );
+                // Skip the body; the head now falls through to the exit.
+                i += 2;
+                continue;
+            }
+        }
+
+        new_blocks.push(head.clone());
+        i += 1;
+    }
+
+    ControlFlowGraph::new(new_blocks)
+}
+
+fn multiply_loop_replacement(
+    body: &[ThreeAddressInstruction],
+) -> Option<Vec<ThreeAddressInstruction>> {
+    use ThreeAddressInstruction::*;
+
+    let mut offset: i32 = 0;
+    // Net change to each cell, in the order they're first touched.
+    let mut deltas: Vec<(i32, u8)> = Vec::new();
+
+    for &instr in body {
+        let delta = match instr {
+            ChangeVal(n) => n,
+            ChangeAddr(n) => {
+                offset = offset.checked_add(n)?;
+                continue;
+            }
+            _ => return None,
+        };
+
+        match deltas.iter_mut().find(|(o, _)| *o == offset) {
+            Some((_, d)) => *d = d.wrapping_add(delta),
+            None => deltas.push((offset, delta)),
+        }
+    }
+
+    if offset != 0 {
+        return None;
+    }
+
+    let guard = deltas.iter().find(|(o, _)| *o == 0).map(|&(_, d)| d);
+    // When the guard is decremented, the loop runs [p] times;
+    // when it's incremented, it runs 256 - [p] times, which is like running -[p] times.
+    let negate = match guard {
+        Some(0xFF) => false,
+        Some(0x01) => true,
+        _ => return None,
+    };
+
+    let mut replacement: Vec<_> = deltas
+        .iter()
+        .filter(|&&(offset, delta)| offset != 0 && delta != 0)
+        .map(|&(dst_offset, delta)| MulAdd {
+            dst_offset,
+            factor: if negate { delta.wrapping_neg() } else { delta },
+        })
+        .collect();
+    replacement.push(Zero);
+
+    Some(replacement)
+}
+
+/// How many instructions [propagate_constants] will run at compile time, at most.
+const CONSTANT_PROPAGATION_FUEL: usize = 100_000;
+
+/// Offsets further than this from the start of the tape can't be encoded in the JIT's addressing.
+const MAX_KNOWN_ADDRESS: i32 = (1 << 12) - 1;
+
+/// Every cell starts at zero, so the beginning of a program -- up until the first input -- can be
+/// run at compile time. The instructions that were run are replaced by writing out whatever they
+/// printed (with [ThreeAddressInstruction::EmitBytes]), setting only the cells that ended up
+/// nonzero, and moving the pointer to where it ended up. Loops whose guard cell is known to be
+/// zero are skipped and disappear, since nothing else can reach them.
+///
+/// Simulation stops at the first instruction whose effect is not known (input, [Extended
+/// Brainfuck](ThreeAddressInstruction::Extended)), at anything that would leave the tape (or the
+/// part of it that's known), or when it runs out of fuel. Leaving the tape is a runtime error, so
+/// it has to happen at runtime, after any output that came before it. The program picks up from
+/// there. A program that never reads input, like "Hello, World!", becomes nothing more than its
+/// output.
+///
+/// When simulation comes back to the same place without anything having changed, the program
+/// never ends, and everything after that is removed (with a
+/// [WarningKind::CodeAfterInfiniteLoop]). This only ever finds loops that simulation reaches, so
+/// a loop that can't be simulated, like the one in `,+[]`, is never detected, even though it
+/// obviously never ends.
+///
+/// The tape is simulated the way `config` says it is: moving past either end wraps around when
+/// [ProgramConfig::wrap_tape] is on.
+pub fn propagate_constants(
+    cfg: &ControlFlowGraph,
+    config: &ProgramConfig,
+    report: &mut OptimizationReport,
+) -> ControlFlowGraph {
+    let blocks = cfg.blocks();
+    if blocks.is_empty() {
+        return cfg.clone();
+    }
+
+    let block_index: HashMap<BlockLabel, usize> = blocks
+        .iter()
+        .enumerate()
+        .map(|(i, block)| (block.label(), i))
+        .collect();
+
+    // Run until we stop at instruction k of block b.
+    let mut tape = KnownTape::new(config);
+    let (mut b, mut k) = (0, 0);
+    let mut steps = 0;
+    // What the tape's version was, the last time we jumped to each block.
+    let mut last_jump: HashMap<BlockLabel, u64> = HashMap::new();
+    let mut never_ends = false;
+    while steps < CONSTANT_PROPAGATION_FUEL {
+        let instructions = blocks[b].instructions();
+        if k == instructions.len() {
+            if b + 1 == blocks.len() {
+                break;
+            }
+            b += 1;
+            k = 0;
+            continue;
+        }
+
+        match tape.step(instructions[k]) {
+            Step::Next => k += 1,
+            Step::Jump(target) => {
+                b = block_index[&target];
+                k = 0;
+                if last_jump.insert(target, tape.version) == Some(tape.version) {
+                    // Nothing happened since the last time we got here, so nothing ever will.
+                    never_ends = true;
+                    break;
+                }
+            }
+            Step::Stop => break,
+        }
+        steps += 1;
+    }
+
+    if steps == 0 {
+        return cfg.clone();
+    }
+
+    use ThreeAddressInstruction::{
+        BranchIfZero, BranchTo, ChangeAddr, EmitBytes, SetValAt, Terminate,
+    };
+
+    let next_label = blocks
+        .iter()
+        .map(|block| block.label().0)
+        .max()
+        .unwrap_or(0)
+        + 1;
+
+    if never_ends {
+        let position = blocks[b].position();
+        report.warn(WarningKind::CodeAfterInfiniteLoop, position);
+        report.note("propagate-constants", position, || {
+            String::from("this loop never ends, so everything after it was removed")
+        });
+
+        // Nothing that happens to the tape from here on can be observed, so all that's left is to
+        // print what was printed so far, and spin forever. That's spelled as `+[]`, so that the
+        // Terminate is still reachable.
+        let mut entry: Vec<ThreeAddressInstruction> =
+            EmittedBytes::chunks(&tape.output).map(EmitBytes).collect();
+        entry.push(SetValAt(0, 1));
+        let (spin, body, end) = (
+            BlockLabel(next_label + 1),
+            BlockLabel(next_label + 2),
+            BlockLabel(next_label + 3),
+        );
+        return ControlFlowGraph::new(vec![
+            BasicBlock::new(BlockLabel(next_label), entry).with_position(blocks[0].position()),
+            BasicBlock::new(spin, vec![BranchIfZero(end)]).with_position(position),
+            BasicBlock::new(body, vec![BranchTo(spin)]).with_position(position),
+            BasicBlock::new(end, vec![Terminate]),
+        ]);
+    }
+
+    let resume = &blocks[b];
+
+    let mut known: Vec<(i32, u8)> = tape.cells.iter().map(|(&a, &v)| (a, v)).collect();
+    known.sort_unstable();
+    let mut entry: Vec<ThreeAddressInstruction> =
+        EmittedBytes::chunks(&tape.output).map(EmitBytes).collect();
+    entry.extend(
+        known
+            .into_iter()
+            .filter(|&(_, value)| value != 0)
+            .map(|(address, value)| SetValAt(address, value)),
+    );
+    if tape.pointer != 0 {
+        entry.push(ChangeAddr(tape.pointer));
+    }
+
+    let mut new_blocks = Vec::new();
+    if k == 0 {
+        entry.push(BranchTo(resume.label()));
+        new_blocks.push(BasicBlock::new(BlockLabel(next_label), entry));
+    } else {
+        // Continue from the middle of the block, with a copy of the rest of it.
+        let mut rest = resume.instructions()[k..].to_vec();
+        if let (true, Some(next)) = (resume.falls_through(), blocks.get(b + 1)) {
+            rest.push(BranchTo(next.label()));
+        }
+        new_blocks.push(BasicBlock::new(BlockLabel(next_label), entry));
+        new_blocks.push(BasicBlock::new(BlockLabel(next_label + 1), rest));
+    }
+    new_blocks[0] = new_blocks[0].clone().with_position(blocks[0].position());
+    if let Some(rest) = new_blocks.get_mut(1) {
+        *rest = rest.clone().with_position(resume.position());
+    }
+    new_blocks.extend(blocks.iter().cloned());
+
+    let result = remove_unreachable_blocks(&ControlFlowGraph::new(new_blocks));
+    if !matches!(
+        result.last_instruction(),
+        Some(ThreeAddressInstruction::Terminate)
+    ) {
+        // The code that would have terminated was folded away. Just leave it alone.
+        return cfg.clone();
+    }
+
+    report.note("propagate-constants", blocks[0].position(), || {
+        format!(
+            "ran {} instructions at compile time, printing {} bytes",
+            steps,
+            tape.output.len()
+        )
+    });
+
+    result
+}
+
+/// Loops whose body ran at least this many times are unrolled.
+const HOT_LOOP_ITERATIONS: u64 = 1000;
+
+/// How many copies of the body a hot loop gets.
+const UNROLL_FACTOR: usize = 4;
+
+/// Unrolls loops whose body is a single block, if the profile says the body ran a lot. Each copy
+/// of the body except for the last checks whether the loop is done, so
+///
+/// ```text
+/// L1: beq [p], L3     L2: <body>; b L1
+/// ```
+///
+/// becomes
+///
+/// ```text
+/// L1: beq [p], L3     L2: <body>; beq [p], L3     L4: <body>; beq [p], L3     ...     <body>; b L1
+/// ```
+///
+/// which saves a branch per iteration, and gives later passes longer straight-line code to work
+/// with.
+pub fn unroll_hot_loops(
+    cfg: &ControlFlowGraph,
+    profile: &Profile,
+    report: &mut OptimizationReport,
+) -> ControlFlowGraph {
+    use ThreeAddressInstruction::{BranchIfZero, BranchTo};
+
+    let blocks = cfg.blocks();
+    let mut next_label = blocks.iter().map(|b| b.label().0).max().unwrap_or(0) + 1;
+    let mut new_blocks = Vec::new();
+
+    for (i, block) in blocks.iter().enumerate() {
+        let head = match i.checked_sub(1).map(|h| &blocks[h]) {
+            Some(head) => head,
+            None => {
+                new_blocks.push(block.clone());
+                continue;
+            }
+        };
+
+        let unrollable = match (head.instructions(), block.instructions()) {
+            ([BranchIfZero(exit)], [body @ .., BranchTo(back)])
+                if *back == head.label()
+                    && cfg.predecessors(block.label()) == [head.label()]
+                    && profile.count(block.label()) >= HOT_LOOP_ITERATIONS =>
+            {
+                Some((*exit, body))
+            }
+            _ => None,
+        };
+        let (exit, body) = match unrollable {
+            Some(loop_parts) => loop_parts,
+            None => {
+                new_blocks.push(block.clone());
+                continue;
+            }
+        };
+
+        report.note("unroll-hot-loops", block.position(), || {
+            format!(
+                "unrolled loop {} times; its body ran {} times",
+                UNROLL_FACTOR,
+                profile.count(block.label())
+            )
+        });
+
+        for copy in 0..UNROLL_FACTOR {
+            let label = if copy == 0 {
+                block.label()
+            } else {
+                next_label += 1;
+                BlockLabel(next_label - 1)
+            };
+            let mut instructions = body.to_vec();
+            instructions.push(if copy + 1 == UNROLL_FACTOR {
+                BranchTo(head.label())
+            } else {
+                BranchIfZero(exit)
+            });
+            new_blocks.push(BasicBlock::new(label, instructions).with_position(block.position()));
+        }
+    }
+
+    ControlFlowGraph::new(new_blocks)
+}
+
+/// Reorders blocks so that the hot path falls through, and cold blocks (like loops that never
+/// ran) are moved out of the way, towards the end.
+///
+/// Blocks are laid out in chains: starting from a block, keep placing whichever of its successors
+/// ran the most, until every successor has been placed already. The first chain starts at the
+/// entry block; the rest start from whichever block that hasn't been placed yet ran the most. A
+/// `b` to the very next block is removed, and blocks that used to fall through into a block that
+/// was moved away get a `b` to it instead. The last block stays last, so that the program still
+/// ends in `terminate`.
+pub fn layout_blocks(
+    cfg: &ControlFlowGraph,
+    profile: &Profile,
+    report: &mut OptimizationReport,
+) -> ControlFlowGraph {
+    use ThreeAddressInstruction::BranchTo;
+
+    let blocks = cfg.blocks();
+    if blocks.len() < 3 {
+        return cfg.clone();
+    }
+    let last = blocks.len() - 1;
+    let index: HashMap<BlockLabel, usize> = blocks
+        .iter()
+        .enumerate()
+        .map(|(i, block)| (block.label(), i))
+        .collect();
+    let count = |i: usize| profile.count(blocks[i].label());
+
+    let mut chain_starts: Vec<usize> = (1..last).collect();
+    // Sorting is stable, so blocks that ran equally often stay in program order.
+    chain_starts.sort_by_key(|&i| std::cmp::Reverse(count(i)));
+
+    let mut placed = vec![false; blocks.len()];
+    placed[last] = true;
+    let mut order = Vec::with_capacity(blocks.len());
+    for start in std::iter::once(0).chain(chain_starts) {
+        let mut current = Some(start).filter(|&i| !placed[i]);
+        while let Some(i) = current {
+            placed[i] = true;
+            order.push(i);
+            // Ties go to the block that comes first, which is usually the one that falls through.
+            current = cfg
+                .successors(blocks[i].label())
+                .iter()
+                .map(|label| index[label])
+                .filter(|&s| !placed[s])
+                .min_by_key(|&s| (std::cmp::Reverse(count(s)), s));
+        }
+    }
+    order.push(last);
+
+    if order.iter().copied().eq(0..blocks.len()) {
+        return cfg.clone();
+    }
+    let moved = order
+        .iter()
+        .enumerate()
+        .filter(|&(position, &i)| position != i)
+        .count();
+    report.note("layout-blocks", None, || {
+        format!("moved {} blocks, so that hot code falls through", moved)
+    });
+
+    let mut new_blocks: Vec<BasicBlock> = Vec::with_capacity(blocks.len());
+    for (position, &i) in order.iter().enumerate() {
+        let block = &blocks[i];
+        let next_in_order = order.get(position + 1).map(|&n| blocks[n].label());
+        let mut instructions = block.instructions().to_vec();
+
+        if block.falls_through() && order.get(position + 1) != Some(&(i + 1)) {
+            instructions.push(BranchTo(blocks[i + 1].label()));
+        } else if let (Some(BranchTo(target)), Some(next)) =
+            (block.last_instruction(), next_in_order)
+        {
+            if target == next {
+                instructions.pop();
+            }
+        }
+
+        new_blocks
+            .push(BasicBlock::new(block.label(), instructions).with_position(block.position()));
+    }
+
+    ControlFlowGraph::new(new_blocks)
+}
+
+/// Removes every block that cannot be reached from the first block.
+pub fn remove_unreachable_blocks(cfg: &ControlFlowGraph) -> ControlFlowGraph {
+    let entry = match cfg.blocks().first() {
+        Some(block) => block.label(),
+        None => return cfg.clone(),
+    };
+
+    let mut reachable = HashSet::new();
+    let mut worklist = vec![entry];
+    while let Some(label) = worklist.pop() {
+        if reachable.insert(label) {
+            worklist.extend(cfg.successors(label));
+        }
+    }
+
+    let mut blocks: Vec<BasicBlock> = cfg
         .blocks()
         .iter()
-        .map(|block| BasicBlock::new(block.label(), peephole_optimize(block.instructions())))
+        .filter(|block| reachable.contains(&block.label()))
+        .cloned()
         .collect();
 
+    // Branching to the very next block is the same as falling through.
+    for i in 0..blocks.len().saturating_sub(1) {
+        let next = blocks[i + 1].label();
+        if let Some(ThreeAddressInstruction::BranchTo(target)) = blocks[i].last_instruction() {
+            if target == next {
+                let mut instructions = blocks[i].instructions().to_vec();
+                instructions.pop();
+                blocks[i] = BasicBlock::new(blocks[i].label(), instructions)
+                    .with_position(blocks[i].position());
+            }
+        }
+    }
+
     ControlFlowGraph::new(blocks)
 }
 
+/// Within each block, stops moving the pointer for every «>» and «<». Instead, changes to cells are
+/// made at an offset from the pointer, and the pointer is moved once, right before something
+/// needs it to be in the right place (like I/O, or a branch).
+///
+/// Since the changes to each cell are collected as the pointer moves back and forth, «+>+<+»
+/// becomes two instructions, instead of five.
+pub fn fuse_offsets(cfg: &ControlFlowGraph, report: &mut OptimizationReport) -> ControlFlowGraph {
+    let result = map_blocks(cfg, fuse_offsets_in_block);
+
+    let moves = |cfg: &ControlFlowGraph| cfg.stats().count("ChangeAddr");
+    let removed = moves(cfg).saturating_sub(moves(&result));
+    if removed > 0 {
+        report.note("fuse-offsets", None, || {
+            format!("removed {} pointer movements", removed)
+        });
+    }
+
+    result
+}
+
+/// [peephole_optimize] only sees one block at a time. When a block's only way out is an
+/// unconditional edge into a block whose only way in is that edge, the «+-<>» at the end of the
+/// first block are moved to the start of the second one, so that they can be folded with whatever
+/// is there. [merge_blocks] already takes care of this when the two blocks are next to each other.
+pub fn sink_across_edges(
+    cfg: &ControlFlowGraph,
+    report: &mut OptimizationReport,
+) -> ControlFlowGraph {
+    use ThreeAddressInstruction::{BranchTo, ChangeAddr, ChangeVal};
+
+    let entry = match cfg.blocks().first() {
+        Some(block) => block.label(),
+        None => return cfg.clone(),
+    };
+    let index: HashMap<BlockLabel, usize> = cfg
+        .blocks()
+        .iter()
+        .enumerate()
+        .map(|(i, block)| (block.label(), i))
+        .collect();
+    let mut instructions: Vec<Vec<ThreeAddressInstruction>> = cfg
+        .blocks()
+        .iter()
+        .map(|block| block.instructions().to_vec())
+        .collect();
+
+    let mut moved = 0;
+    for (i, block) in cfg.blocks().iter().enumerate() {
+        let label = block.label();
+        let next = match cfg.successors(label) {
+            &[next] if next != entry && next != label && cfg.predecessors(next) == [label] => next,
+            _ => continue,
+        };
+
+        let body = &mut instructions[i];
+        let end = match body[..].last() {
+            Some(BranchTo(_)) => body.len() - 1,
+            _ => body.len(),
+        };
+        let start = body[..end]
+            .iter()
+            .rposition(|instr| !matches!(instr, ChangeVal(_) | ChangeAddr(_)))
+            .map_or(0, |last_other| last_other + 1);
+        if start == end {
+            continue;
+        }
+
+        let tail: Vec<_> = body.drain(start..end).collect();
+        moved += tail.len();
+        instructions[index[&next]].splice(0..0, tail);
+    }
+
+    if moved > 0 {
+        report.note("sink-across-edges", None, || {
+            format!("moved {} instructions into the block after them", moved)
+        });
+    }
+
+    ControlFlowGraph::new(
+        cfg.blocks()
+            .iter()
+            .zip(instructions)
+            .map(|(block, instructions)| {
+                BasicBlock::new(block.label(), instructions).with_position(block.position())
+            })
+            .collect(),
+    )
+}
+
+/// Within each block, removes writes to cells that nothing reads before the cell is written again,
+/// like the «+» in «+,» or the «-» in «-[-]» (once that loop is a `zero`).
+///
+/// A write to a cell that might be off the tape is kept, even if it's dead, since it's where the
+/// program would stop with an error. On a tape that wraps around, different offsets can be the
+/// same cell, so this pass does nothing at all.
+pub fn eliminate_dead_stores(
+    cfg: &ControlFlowGraph,
+    config: &ProgramConfig,
+    report: &mut OptimizationReport,
+) -> ControlFlowGraph {
+    if config.wrap_tape {
+        return cfg.clone();
+    }
+    let result = map_blocks(cfg, |instructions| {
+        eliminate_dead_stores_in_block(instructions, config.eof)
+    });
+
+    let removed = cfg.stats().instructions - result.stats().instructions;
+    if removed > 0 {
+        report.note("dead-stores", None, || {
+            format!("removed {} writes that nothing reads", removed)
+        });
+    }
+
+    result
+}
+
+/// Folds runs of «+» and «-», and of «>» and «<», within each block. See [peephole_optimize].
+fn peephole_optimize_blocks(
+    cfg: &ControlFlowGraph,
+    report: &mut OptimizationReport,
+) -> ControlFlowGraph {
+    let result = map_blocks(cfg, peephole_optimize);
+
+    let folded = cfg.stats().instructions - result.stats().instructions;
+    if folded > 0 {
+        report.note("peephole", None, || {
+            format!("folded away {} instructions", folded)
+        });
+    }
+
+    result
+}
+
+/// Replaces the instructions of every block, keeping its label and position.
+fn map_blocks(
+    cfg: &ControlFlowGraph,
+    f: impl Fn(&[ThreeAddressInstruction]) -> Vec<ThreeAddressInstruction>,
+) -> ControlFlowGraph {
+    ControlFlowGraph::new(
+        cfg.blocks()
+            .iter()
+            .map(|block| {
+                BasicBlock::new(block.label(), f(block.instructions()))
+                    .with_position(block.position())
+            })
+            .collect(),
+    )
+}
+
+/// Returns whether `next` can be merged into `prev`, where `next` directly follows `prev`.
+fn can_merge(cfg: &ControlFlowGraph, prev: BlockLabel, next: BlockLabel) -> bool {
+    cfg.successors(prev) == [next] && cfg.predecessors(next) == [prev]
+}
+
 /// Performs optimizations within a basic block.
 fn peephole_optimize(instructions: &[ThreeAddressInstruction]) -> Vec<ThreeAddressInstruction> {
     use ThreeAddressInstruction::*;
 
     let mut new_instructions = vec![NoOp];
 
+    // new_instructions is used like a stack: when a fold cancels out completely (like «+-» or
+    // «><»), it's popped, so the next instruction gets a chance to fold with whatever came before
+    // it. That way, «+><+» becomes «++» in one pass.
     for &instr in instructions {
-        match (new_instructions.last(), instr) {
+        let instr = match (new_instructions.last(), instr) {
             (ChangeVal(x), ChangeVal(y)) => {
-                new_instructions.replace_last(ChangeVal(x.wrapping_add(y)));
+                new_instructions.pop();
+                ChangeVal(x.wrapping_add(y))
             }
-            (ChangeAddr(x), ChangeAddr(y)) => new_instructions.replace_last(ChangeAddr(x + y)),
-            (_, instr) => new_instructions.push(instr),
+            (ChangeAddr(x), ChangeAddr(y)) => {
+                new_instructions.pop();
+                ChangeAddr(x + y)
+            }
+            (_, instr) => instr,
+        };
+
+        if !matches!(instr, ChangeVal(0) | ChangeAddr(0) | NoOp) {
+            new_instructions.push(instr);
         }
     }
 
@@ -34,11 +845,409 @@ fn peephole_optimize(instructions: &[ThreeAddressInstruction]) -> Vec<ThreeAddre
     new_instructions
 }
 
-// Makes it easier to get and replace the last element of a vector.
+/// The largest offset the JIT can encode directly.
+const MAX_OFFSET: i32 = (1 << 12) - 1;
+
+/// What happens to a cell, relative to the pointer at the start of a run of instructions.
+#[derive(Clone, Copy)]
+enum CellChange {
+    Add(u8),
+    Set(u8),
+}
+
+fn fuse_offsets_in_block(instructions: &[ThreeAddressInstruction]) -> Vec<ThreeAddressInstruction> {
+    use ThreeAddressInstruction::*;
+
+    let mut new_instructions = Vec::new();
+    // How far the pointer would have moved by now.
+    let mut offset: i32 = 0;
+    // Changes to each cell, in the order they're first touched.
+    let mut changes: Vec<(i32, CellChange)> = Vec::new();
+
+    let flush = |new_instructions: &mut Vec<ThreeAddressInstruction>,
+                 changes: &mut Vec<(i32, CellChange)>,
+                 offset: &mut i32| {
+        for (cell, change) in changes.drain(..) {
+            new_instructions.push(match (cell, change) {
+                (_, CellChange::Add(0)) => continue,
+                (0, CellChange::Add(n)) => ChangeVal(n),
+                (0, CellChange::Set(0)) => Zero,
+                (cell, CellChange::Add(n)) => ChangeValAt(cell, n),
+                (cell, CellChange::Set(n)) => SetValAt(cell, n),
+            });
+        }
+        if *offset != 0 {
+            new_instructions.push(ChangeAddr(*offset));
+            *offset = 0;
+        }
+    };
+
+    for &instr in instructions {
+        let (cell, change) = match instr {
+            ChangeAddr(n) => {
+                match offset.checked_add(n).filter(|o| o.abs() <= MAX_OFFSET) {
+                    Some(new_offset) => offset = new_offset,
+                    None => {
+                        flush(&mut new_instructions, &mut changes, &mut offset);
+                        new_instructions.push(instr);
+                    }
+                }
+                continue;
+            }
+            ChangeVal(n) => (0, CellChange::Add(n)),
+            Zero => (0, CellChange::Set(0)),
+            ChangeValAt(o, n) => (o, CellChange::Add(n)),
+            SetValAt(o, n) => (o, CellChange::Set(n)),
+            _ => {
+                // Everything else needs the pointer to be where it's supposed to be.
+                flush(&mut new_instructions, &mut changes, &mut offset);
+                new_instructions.push(instr);
+                continue;
+            }
+        };
+
+        let cell = match offset.checked_add(cell).filter(|o| o.abs() <= MAX_OFFSET) {
+            Some(cell) => cell,
+            None => {
+                flush(&mut new_instructions, &mut changes, &mut offset);
+                new_instructions.push(instr);
+                continue;
+            }
+        };
+
+        match changes.iter_mut().find(|(o, _)| *o == cell) {
+            Some((_, existing)) => {
+                *existing = match (*existing, change) {
+                    (CellChange::Add(a), CellChange::Add(b)) => CellChange::Add(a.wrapping_add(b)),
+                    (CellChange::Set(a), CellChange::Add(b)) => CellChange::Set(a.wrapping_add(b)),
+                    (_, CellChange::Set(b)) => CellChange::Set(b),
+                }
+            }
+            None => changes.push((cell, change)),
+        }
+    }
+
+    flush(&mut new_instructions, &mut changes, &mut offset);
+
+    new_instructions
+}
+
+/// Cells, relative to the pointer, that will be written before anything reads them. A write to
+/// such a cell is dead.
+struct DeadCells(HashSet<i32>);
+
+impl DeadCells {
+    fn none() -> Self {
+        DeadCells(HashSet::new())
+    }
+
+    fn contains(&self, cell: i32) -> bool {
+        self.0.contains(&cell)
+    }
+
+    /// The cell is written without being read first.
+    fn written(&mut self, cell: i32) {
+        self.0.insert(cell);
+    }
+
+    fn read(&mut self, cell: i32) {
+        self.0.remove(&cell);
+    }
+
+    /// The pointer moves by `n` cells.
+    fn moved(&mut self, n: i32) {
+        self.0 = self.0.iter().map(|c| c.wrapping_add(n)).collect();
+    }
+}
+
+/// Goes forwards through the block, and returns whether each instruction can only write to a cell
+/// that's certainly on the tape. The pointer is always on the tape, and so is every cell that was
+/// already accessed without an error, and everything in between.
+fn writes_within_bounds(instructions: &[ThreeAddressInstruction]) -> Vec<bool> {
+    use ThreeAddressInstruction::*;
+
+    // The cells that are known to be on the tape, relative to the pointer.
+    let (mut lo, mut hi) = (0i64, 0i64);
+    let mut within_bounds = Vec::with_capacity(instructions.len());
+
+    for &instr in instructions {
+        let cell = match instr {
+            ChangeVal(_) | Zero => Some(0),
+            ChangeValAt(cell, _)
+            | SetValAt(cell, _)
+            | MulAdd {
+                dst_offset: cell, ..
+            } => Some(cell as i64),
+            _ => None,
+        };
+        within_bounds.push(matches!(cell, Some(cell) if (lo..=hi).contains(&cell)));
+
+        match instr {
+            ChangeAddr(n) => {
+                lo = (lo - n as i64).min(0);
+                hi = (hi - n as i64).max(0);
+            }
+            // Wherever the pointer ends up is on the tape, but nothing else is known about it.
+            FindZero(_) => (lo, hi) = (0, 0),
+            _ => {
+                if let Some(cell) = cell {
+                    lo = lo.min(cell);
+                    hi = hi.max(cell);
+                }
+            }
+        }
+    }
+
+    within_bounds
+}
+
+/// Goes backwards through the block, keeping track of which cells are dead.
+fn eliminate_dead_stores_in_block(
+    instructions: &[ThreeAddressInstruction],
+    eof: EofBehavior,
+) -> Vec<ThreeAddressInstruction> {
+    use ThreeAddressInstruction::*;
+
+    // Whatever comes after the block might read anything.
+    let mut dead = DeadCells::none();
+    let mut kept = Vec::with_capacity(instructions.len());
+    let within_bounds = writes_within_bounds(instructions);
+
+    for (&instr, &within_bounds) in instructions.iter().zip(&within_bounds).rev() {
+        match instr {
+            ChangeVal(_) | Zero if within_bounds && dead.contains(0) => continue,
+            ChangeValAt(cell, _) | SetValAt(cell, _) if within_bounds && dead.contains(cell) => {
+                continue
+            }
+            MulAdd { dst_offset, .. } if within_bounds && dead.contains(dst_offset) => continue,
+            ChangeVal(_) => dead.read(0),
+            ChangeValAt(cell, _) => dead.read(cell),
+            Zero => dead.written(0),
+            SetValAt(cell, _) => dead.written(cell),
+            MulAdd { dst_offset, .. } => {
+                dead.read(0);
+                dead.read(dst_offset);
+            }
+            // Reading input has a side effect, so it has to stay, even if the value is dead. At the
+            // end of the input, the cell might keep its old value, so that value isn't dead.
+            GetChar if eof == EofBehavior::Unchanged => dead.read(0),
+            GetChar => dead.written(0),
+            PutChar | Extended(_) => dead.read(0),
+            // The addresses that these read aren't known.
+            FindZero(_) | BranchIfZero(_) | BranchTo(_) => dead = DeadCells::none(),
+            ChangeAddr(n) => dead.moved(n),
+            NoOp | EmitBytes(_) | Terminate => (),
+        }
+        kept.push(instr);
+    }
+
+    kept.reverse();
+    kept
+}
+
+/// The tape while running a program at compile time. Cells that aren't in the map are zero.
+struct KnownTape {
+    cells: HashMap<i32, u8>,
+    pointer: i32,
+    /// How many cells the tape has at runtime.
+    length: i32,
+    wrap: bool,
+    /// Everything the program wrote so far.
+    output: Vec<u8>,
+    /// Changes whenever the cells, the pointer, or the output might have changed.
+    version: u64,
+}
+
+/// What to do after running an instruction at compile time.
+enum Step {
+    Next,
+    Jump(BlockLabel),
+    /// The effect of the instruction is not known at compile time, so it was not run.
+    Stop,
+}
+
+impl KnownTape {
+    fn new(config: &ProgramConfig) -> Self {
+        KnownTape {
+            cells: HashMap::new(),
+            pointer: 0,
+            length: i32::try_from(config.tape_length).unwrap_or(i32::MAX).max(1),
+            wrap: config.wrap_tape,
+            output: Vec::new(),
+            version: 0,
+        }
+    }
+
+    fn get(&self, address: i32) -> u8 {
+        self.cells.get(&address).copied().unwrap_or(0)
+    }
+
+    /// Returns the address `offset` cells away from the pointer, if it's within the known tape.
+    fn address(&self, offset: i32) -> Option<i32> {
+        self.offset_from(self.pointer, offset)
+    }
+
+    /// Returns the address `offset` cells away from `address`, if it's within the known tape.
+    fn offset_from(&self, address: i32, offset: i32) -> Option<i32> {
+        let address = address.checked_add(offset)?;
+        let address = if self.wrap {
+            address.rem_euclid(self.length)
+        } else {
+            address
+        };
+
+        Some(address)
+            .filter(|&address| address < self.length)
+            .filter(|address| (0..=MAX_KNOWN_ADDRESS).contains(address))
+    }
+
+    fn step(&mut self, instr: ThreeAddressInstruction) -> Step {
+        use ThreeAddressInstruction::*;
+
+        if !matches!(instr, NoOp | BranchIfZero(_) | BranchTo(_)) {
+            self.version += 1;
+        }
+
+        match instr {
+            NoOp => (),
+            ChangeVal(n) => {
+                let value = self.get(self.pointer).wrapping_add(n);
+                self.cells.insert(self.pointer, value);
+            }
+            ChangeAddr(n) => match self.address(n) {
+                Some(address) => self.pointer = address,
+                None => return Step::Stop,
+            },
+            Zero => {
+                self.cells.insert(self.pointer, 0);
+            }
+            ChangeValAt(offset, n) | SetValAt(offset, n) => {
+                let address = match self.address(offset) {
+                    Some(address) => address,
+                    None => return Step::Stop,
+                };
+                let value = match instr {
+                    ChangeValAt(..) => self.get(address).wrapping_add(n),
+                    _ => n,
+                };
+                self.cells.insert(address, value);
+            }
+            MulAdd { dst_offset, factor } => {
+                let value = self.get(self.pointer);
+                if value != 0 {
+                    let address = match self.address(dst_offset) {
+                        Some(address) => address,
+                        None => return Step::Stop,
+                    };
+                    let product = value.wrapping_mul(factor);
+                    self.cells
+                        .insert(address, self.get(address).wrapping_add(product));
+                }
+            }
+            FindZero(step) => {
+                let mut address = self.pointer;
+                // On a wrapping tape full of nonzero cells, the search would go around forever.
+                let mut remaining = self.length;
+                while self.get(address) != 0 {
+                    remaining -= 1;
+                    address = match self.offset_from(address, step) {
+                        Some(address) if remaining > 0 => address,
+                        _ => return Step::Stop,
+                    };
+                }
+                self.pointer = address;
+            }
+            BranchIfZero(target) if self.get(self.pointer) == 0 => return Step::Jump(target),
+            BranchIfZero(_) => (),
+            BranchTo(target) => return Step::Jump(target),
+            PutChar => self.output.push(self.get(self.pointer)),
+            EmitBytes(bytes) => self.output.extend_from_slice(bytes.as_slice()),
+            GetChar | Extended(_) | Terminate => return Step::Stop,
+        }
+
+        Step::Next
+    }
+}
+
+impl OptimizationReport {
+    /// An empty report that records remarks.
+    fn new() -> Self {
+        OptimizationReport {
+            recording: true,
+            remarks: Vec::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    /// A report that throws every remark away.
+    fn ignored() -> Self {
+        OptimizationReport {
+            recording: false,
+            remarks: Vec::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Every remark, in the order the passes made them.
+    pub fn remarks(&self) -> &[Remark] {
+        &self.remarks
+    }
+
+    /// Things the optimizer found out about the program that are probably mistakes, like code
+    /// that can never run.
+    pub fn warnings(&self, filename: &str) -> Vec<CompilationWarning> {
+        self.warnings
+            .iter()
+            .map(|&(kind, position)| {
+                let line_no = position.map_or(1, |p| p.line);
+                CompilationWarning::new(kind, Location::new(filename.to_string(), line_no))
+            })
+            .collect()
+    }
+
+    fn warn(&mut self, kind: WarningKind, position: Option<SourcePosition>) {
+        self.warnings.push((kind, position));
+    }
+
+    /// Records a remark. The message is only formatted when the report is recording.
+    fn note(
+        &mut self,
+        pass: &'static str,
+        position: Option<SourcePosition>,
+        message: impl FnOnce() -> String,
+    ) {
+        if self.recording {
+            self.remarks.push(Remark {
+                pass,
+                position,
+                message: message(),
+            });
+        }
+    }
+}
+
+impl fmt::Display for OptimizationReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for remark in self.remarks.iter() {
+            writeln!(f, "{}", remark)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Remark {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.position {
+            Some(position) => write!(f, "{}: {}: {}", position, self.pass, self.message),
+            None => write!(f, "{}: {}", self.pass, self.message),
+        }
+    }
+}
+
+// Makes it easier to get the last element of a vector.
 trait LastNonEmptyVector<T> {
     fn last(&self) -> T;
-
-    fn replace_last(&mut self, x: T);
 }
 
 impl<T> LastNonEmptyVector<T> for Vec<T>
@@ -48,9 +1257,529 @@ where
     fn last(&self) -> T {
         self[self.len() - 1]
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::{assert_same_structure, parse_ir, BlockLabel};
+    use ThreeAddressInstruction::*;
+
+    #[test]
+    fn merges_straight_line_blocks() {
+        let cfg = ControlFlowGraph::new(vec![
+            BasicBlock::new(BlockLabel(0), vec![ChangeVal(1)]),
+            BasicBlock::new(BlockLabel(1), vec![ChangeVal(2), BranchTo(BlockLabel(2))]),
+            BasicBlock::new(BlockLabel(2), vec![PutChar, Terminate]),
+        ]);
+
+        let merged = merge_blocks(&cfg, &mut OptimizationReport::ignored());
+        assert_eq!(1, merged.blocks().len());
+        assert!(matches!(
+            merged.blocks()[0].instructions(),
+            [ChangeVal(1), ChangeVal(2), PutChar, Terminate]
+        ));
+
+        // All cells start at zero, so the additions are folded into one constant, and so is the
+        // output.
+        let expected = parse_ir("L0:\n emit #3\n mov [p+0], #3\n terminate").unwrap();
+        assert_same_structure(&expected, &optimize(&cfg, &ProgramConfig::default()));
+    }
+
+    #[test]
+    fn does_not_merge_loop_heads() {
+        let cfg = ControlFlowGraph::new(vec![
+            BasicBlock::new(BlockLabel(0), vec![ChangeVal(1)]),
+            BasicBlock::new(BlockLabel(1), vec![BranchIfZero(BlockLabel(3))]),
+            BasicBlock::new(BlockLabel(2), vec![PutChar, BranchTo(BlockLabel(1))]),
+            BasicBlock::new(BlockLabel(3), vec![Terminate]),
+        ]);
+
+        assert_eq!(
+            4,
+            merge_blocks(&cfg, &mut OptimizationReport::ignored())
+                .blocks()
+                .len()
+        );
+    }
+
+    #[test]
+    fn folds_across_unconditional_branches() {
+        let cfg = parse_ir(
+            "L0:
+                getchar
+                add [p], [p], #1
+                b L2
+             L1:
+                add p, p, #1
+                putchar
+                terminate
+             L2:
+                add [p], [p], #2
+                b L1",
+        )
+        .unwrap();
+        let cfg = sink_across_edges(&cfg, &mut OptimizationReport::ignored());
+
+        let expected = parse_ir(
+            "L0:
+                getchar
+                b L2
+             L1:
+                add [p], [p], #1
+                add [p], [p], #2
+                add p, p, #1
+                putchar
+                terminate
+             L2:
+                b L1",
+        )
+        .unwrap();
+        assert_same_structure(&expected, &cfg);
+    }
+
+    #[test]
+    fn unrolls_hot_loops() {
+        let cfg = parse_ir(
+            "L0:
+                getchar
+             L1:
+                beq [p], L3
+             L2:
+                add [p], [p], #-2
+                b L1
+             L3:
+                terminate",
+        )
+        .unwrap();
+        let cold = Profile::new([(BlockLabel(2), 10)].into_iter().collect());
+        let hot = Profile::new([(BlockLabel(2), 5000)].into_iter().collect());
+
+        let report = &mut OptimizationReport::ignored();
+        assert_same_structure(&cfg, &unroll_hot_loops(&cfg, &cold, report));
+
+        let expected = parse_ir(
+            "L0:
+                getchar
+             L1:
+                beq [p], L3
+             L2:
+                add [p], [p], #-2
+                beq [p], L3
+             L4:
+                add [p], [p], #-2
+                beq [p], L3
+             L5:
+                add [p], [p], #-2
+                beq [p], L3
+             L6:
+                add [p], [p], #-2
+                b L1
+             L3:
+                terminate",
+        )
+        .unwrap();
+        assert_same_structure(&expected, &unroll_hot_loops(&cfg, &hot, report));
+    }
+
+    #[test]
+    fn moves_cold_blocks_out_of_the_way() {
+        let cfg = parse_ir(
+            "L0:
+                getchar
+             L1:
+                beq [p], L3
+             L2:
+                putchar
+                b L1
+             L3:
+                add p, p, #1
+                getchar
+             L4:
+                beq [p], L6
+             L5:
+                add [p], [p], #-1
+                b L4
+             L6:
+                terminate",
+        )
+        .unwrap();
+        // The first loop never ran, but the second one did.
+        let profile = Profile::new(
+            [(BlockLabel(0), 1), (BlockLabel(3), 1), (BlockLabel(5), 100)]
+                .into_iter()
+                .collect(),
+        );
+        let cfg = layout_blocks(&cfg, &profile, &mut OptimizationReport::ignored());
+
+        let expected = parse_ir(
+            "L0:
+                getchar
+             L1:
+                beq [p], L3
+                b L2
+             L3:
+                add p, p, #1
+                getchar
+             L4:
+                beq [p], L6
+             L5:
+                add [p], [p], #-1
+                b L4
+             L2:
+                putchar
+                b L1
+             L6:
+                terminate",
+        )
+        .unwrap();
+        assert_same_structure(&expected, &cfg);
+        assert_eq!(Ok(()), cfg.verify());
+    }
+
+    #[test]
+    fn removes_dead_stores() {
+        assert_eq!(
+            vec![
+                GetChar,
+                SetValAt(2, 7),
+                ChangeAddr(1),
+                Zero,
+                ChangeValAt(1, 1),
+                PutChar,
+                GetChar,
+                ChangeVal(3),
+                BranchTo(BlockLabel(0)),
+            ],
+            eliminate_dead_stores_in_block(
+                &[
+                    ChangeVal(1),
+                    GetChar,
+                    SetValAt(2, 7),
+                    SetValAt(1, 3),
+                    ChangeAddr(1),
+                    Zero,
+                    ChangeValAt(1, 1),
+                    PutChar,
+                    GetChar,
+                    ChangeVal(3),
+                    BranchTo(BlockLabel(0)),
+                ],
+                EofBehavior::Zero
+            )
+        );
+        // The first write might be off the tape, and the tape is still there after the program
+        // terminates, so nothing can go.
+        let might_fail = [
+            ChangeValAt(2, 1),
+            GetChar,
+            SetValAt(2, 5),
+            ChangeVal(1),
+            Terminate,
+        ];
+        assert_eq!(
+            might_fail.to_vec(),
+            eliminate_dead_stores_in_block(&might_fail, EofBehavior::Zero)
+        );
+        // Unless the cell is always overwritten, «+,» still has to add one.
+        let keeps_cell = [ChangeVal(1), GetChar, PutChar, Terminate];
+        assert_eq!(
+            keeps_cell.to_vec(),
+            eliminate_dead_stores_in_block(&keeps_cell, EofBehavior::Unchanged)
+        );
+    }
+
+    #[test]
+    fn reduces_instruction_counts() {
+        use crate::parsing::{AbstractSyntaxTree, Statement::*};
+
+        // Reading input first keeps constant propagation out of the way.
+        let ast = AbstractSyntaxTree::from_statements(vec![
+            GetChar,
+            IncrementVal(1),
+            IncrementVal(2),
+            IncrementAddr(1),
+            IncrementAddr(3),
+            PutChar,
+        ])
+        .unwrap();
+        let before = crate::ir::lower(&ast).stats();
+        let after = optimize(&crate::ir::lower(&ast), ast.config()).stats();
+
+        assert_eq!((7, 5), (before.instructions, after.instructions));
+        assert_eq!(1, after.count("ChangeVal"));
+        assert!(after.estimated_code_size < before.estimated_code_size);
+    }
+
+    #[test]
+    fn runs_the_start_of_the_program_at_compile_time() {
+        let ast = crate::parsing::parse("<test>", b">++++[<++>-]>+++<<,").unwrap();
+        let cfg = propagate_constants(
+            &crate::ir::lower(&ast),
+            ast.config(),
+            &mut OptimizationReport::ignored(),
+        );
+
+        let expected = parse_ir(
+            "L0:
+                mov [p+0], #8
+                mov [p+2], #3
+             L1:
+                getchar
+                terminate",
+        )
+        .unwrap();
+        assert_same_structure(&expected, &cfg);
+    }
+
+    #[test]
+    fn precomputes_output_until_the_first_input() {
+        let ast =
+            crate::parsing::parse("<test>", b"++++++++[>+++++++++<-]>.+.>++++++++++.,.").unwrap();
+        let cfg = propagate_constants(
+            &crate::ir::lower(&ast),
+            ast.config(),
+            &mut OptimizationReport::ignored(),
+        );
+
+        let expected = parse_ir(
+            "L0:
+                emit #72, #73, #10
+                mov [p+1], #73
+                mov [p+2], #10
+                add p, p, #2
+             L1:
+                getchar
+                putchar
+                terminate",
+        )
+        .unwrap();
+        assert_same_structure(&expected, &cfg);
+    }
+
+    #[test]
+    fn removes_code_after_loops_that_never_end() {
+        let ast = crate::parsing::parse("<test>", b"+.\n>+[-]<[]>>.").unwrap();
+        let (cfg, report) = optimize_with_report(&crate::ir::lower(&ast), ast.config());
+
+        let expected = parse_ir(
+            "L0:
+                emit #1
+                mov [p+0], #1
+             L1:
+                beq [p], L3
+             L2:
+                b L1
+             L3:
+                terminate",
+        )
+        .unwrap();
+        assert_same_structure(&expected, &cfg);
+        assert_eq!(Ok(()), cfg.verify());
+
+        let warnings = report.warnings("test.bf");
+        assert_eq!(1, warnings.len());
+        assert_eq!(WarningKind::CodeAfterInfiniteLoop, warnings[0].kind());
+        assert_eq!("test.bf:2", warnings[0].location().to_string());
+    }
+
+    #[test]
+    fn removes_loops_that_never_run() {
+        let ast = crate::parsing::parse("<test>", b"[.>]+>,[.,]").unwrap();
+        let cfg = propagate_constants(
+            &crate::ir::lower(&ast),
+            ast.config(),
+            &mut OptimizationReport::ignored(),
+        );
+
+        let expected = parse_ir(
+            "L0:
+                mov [p+0], #1
+                add p, p, #1
+             L1:
+                getchar
+             L2:
+                beq [p], L4
+             L3:
+                putchar
+                getchar
+                b L2
+             L4:
+                terminate",
+        )
+        .unwrap();
+        assert_same_structure(&expected, &cfg);
+        assert_eq!(Ok(()), cfg.verify());
+    }
+
+    #[test]
+    fn replaces_clear_loops_with_zero() {
+        let ast = crate::parsing::parse("<test>", b",[-]>,[+++].[--]").unwrap();
+        let cfg = optimize_clear_loops(&crate::ir::lower(&ast), &mut OptimizationReport::ignored());
+
+        let expected = parse_ir(
+            "L0:
+                getchar
+             L1:
+                zero
+             L3:
+                add p, p, #1
+                getchar
+             L4:
+                zero
+             L6:
+                putchar
+             L7:
+                beq [p], L9
+             L8:
+                add [p], [p], #-2
+                b L7
+             L9:
+                terminate",
+        )
+        .unwrap();
+        assert_same_structure(&expected, &cfg);
+    }
+
+    #[test]
+    fn replaces_a_clear_loop_at_the_end_of_the_program() {
+        let ast = crate::parsing::parse("<test>", b",[-]").unwrap();
+        let cfg = optimize_clear_loops(&crate::ir::lower(&ast), &mut OptimizationReport::ignored());
+
+        let expected = parse_ir(
+            "L0:
+                getchar
+             L1:
+                zero
+             L3:
+                terminate",
+        )
+        .unwrap();
+        assert_same_structure(&expected, &cfg);
+    }
+
+    #[test]
+    fn replaces_multiply_loops_with_muladds() {
+        let ast = crate::parsing::parse("<test>", b",[->+++>+<<],[+>-<]>[->+<<]").unwrap();
+        let cfg =
+            optimize_multiply_loops(&crate::ir::lower(&ast), &mut OptimizationReport::ignored());
+
+        let expected = parse_ir(
+            "L0:
+                getchar
+             L1:
+                mla [p+1], [p], #3
+                mla [p+2], [p], #1
+                zero
+             L3:
+                getchar
+             L4:
+                mla [p+1], [p], #1
+                zero
+             L6:
+                add p, p, #1
+             L7:
+                beq [p], L9
+             L8:
+                add [p], [p], #-1
+                add p, p, #1
+                add [p], [p], #1
+                add p, p, #-2
+                b L7
+             L9:
+                terminate",
+        )
+        .unwrap();
+        assert_same_structure(&expected, &cfg);
+    }
+
+    #[test]
+    fn replaces_scan_loops_with_find_zero() {
+        let ast = crate::parsing::parse("<test>", b",[<<],[>]").unwrap();
+        let cfg = optimize_scan_loops(&crate::ir::lower(&ast), &mut OptimizationReport::ignored());
+
+        let expected = parse_ir(
+            "L0:
+                getchar
+             L1:
+                scan p, #-2
+             L3:
+                getchar
+             L4:
+                scan p, #1
+             L6:
+                terminate",
+        )
+        .unwrap();
+        assert_same_structure(&expected, &cfg);
+
+        // However far the stride, the JIT can still compile the scan.
+        let source = [&b",["[..], &[b'>'; 5000], b"]"].concat();
+        let cfg = crate::ast_to_optimized_cfg(&crate::parsing::parse("<test>", &source).unwrap());
+        assert_eq!(1, cfg.stats().count("FindZero"));
+        assert!(!crate::codegen::NativeCodeGenerator::new()
+            .compile(&cfg)
+            .is_empty());
+    }
+
+    #[test]
+    fn reports_what_each_pass_did() {
+        let ast = crate::parsing::parse("<test>", b",\n[-]>>+<<.").unwrap();
+        let (_, report) = optimize_with_report(&crate::ir::lower(&ast), ast.config());
+
+        let remarks: Vec<String> = report.remarks().iter().map(|r| r.to_string()).collect();
+        assert_eq!("2:1: clear-loops: replaced loop with `zero`", remarks[0]);
+        assert!(remarks.contains(&String::from("fuse-offsets: removed 2 pointer movements")));
+        assert!(report
+            .remarks()
+            .iter()
+            .all(|r| r.pass != "propagate-constants"));
+    }
+
+    #[test]
+    fn removes_operations_that_cancel_out() {
+        assert_eq!(
+            vec![ChangeVal(2), PutChar],
+            peephole_optimize(&[
+                ChangeVal(1),
+                ChangeAddr(1),
+                ChangeAddr(-1),
+                ChangeVal(1),
+                ChangeAddr(3),
+                ChangeVal(5),
+                ChangeVal(0xFB),
+                ChangeAddr(-3),
+                PutChar,
+            ])
+        );
+    }
 
-    fn replace_last(&mut self, x: T) {
-        let n = self.len();
-        self[n - 1] = x;
+    #[test]
+    fn fuses_pointer_movement_into_offsets() {
+        assert_eq!(
+            vec![
+                ChangeVal(2),
+                ChangeValAt(1, 1),
+                SetValAt(-1, 4),
+                ChangeAddr(2),
+                PutChar,
+                ChangeAddr(-1),
+                BranchTo(BlockLabel(0)),
+            ],
+            fuse_offsets_in_block(&[
+                ChangeVal(1),
+                ChangeAddr(1),
+                ChangeVal(1),
+                ChangeAddr(-2),
+                Zero,
+                ChangeVal(4),
+                ChangeAddr(1),
+                ChangeVal(1),
+                ChangeAddr(2),
+                PutChar,
+                ChangeAddr(-1),
+                BranchTo(BlockLabel(0)),
+            ])
+        );
     }
 }
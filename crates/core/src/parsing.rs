@@ -1,46 +1,175 @@
 //! "Parse" brainfuck source text.
 
-use crate::errors::{CompilationError, Location, Reason};
+use std::fmt;
+
+use crate::config::{Dialect, ProgramConfig};
+use crate::errors::{CompilationError, CompilationWarning, Location, Reason, WarningKind};
 
 /// A representation of Brainfuck's source code that's easier to deal with than text.
 /// ...at least, that would be the case in most programming languages.
+#[derive(Debug, Clone)]
 pub struct AbstractSyntaxTree {
     statements: Vec<Statement>,
+    /// Where each statement starts in the source text. Empty if the AST was not parsed from text.
+    positions: Vec<SourcePosition>,
+    config: ProgramConfig,
+}
+
+/// A line and column in the source text, both starting at 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SourcePosition {
+    pub line: u32,
+    pub column: u32,
+}
+
+/// A stretch of the source text, from `start` up to (but not including) `end`. There is no `end`
+/// when the span goes on to the end of the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceSpan {
+    pub start: SourcePosition,
+    pub end: Option<SourcePosition>,
 }
 
 /// Representation of a Brainfuck statement in an "easier" form.
-#[derive(Debug, Copy, Clone)]
+///
+/// Runs of the same command are collapsed into one statement with a repetition count, so `+++`
+/// becomes `IncrementVal(3)`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Statement {
-    IncrementVal,
-    DecrementVal,
-    IncrementAddr,
-    DecrementAddr,
+    IncrementVal(u32),
+    DecrementVal(u32),
+    IncrementAddr(u32),
+    DecrementAddr(u32),
     PutChar,
     GetChar,
     StartConditional(ConditionalID),
     EndConditional(ConditionalID),
+    /// `@` in [Dialect::ExtendedTypeI]
+    EndProgram,
+    /// Everything else in [Dialect::ExtendedTypeI]
+    Extended(ExtendedOp),
+}
+
+/// The commands in Extended Brainfuck Type I that use the storage register.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ExtendedOp {
+    /// `$` copies the current cell into storage.
+    Store,
+    /// `!` copies storage into the current cell.
+    Load,
+    /// `{` shifts the current cell left by one bit.
+    ShiftLeft,
+    /// `}` shifts the current cell right by one bit.
+    ShiftRight,
+    /// `~` inverts every bit of the current cell.
+    Not,
+    /// `^` sets the current cell to cell XOR storage.
+    Xor,
+    /// `&` sets the current cell to cell AND storage.
+    And,
+    /// `|` sets the current cell to cell OR storage.
+    Or,
 }
 
 /// An arbitrary ID assigned to a pair of [ ] branches, to associate the two.
 #[derive(Debug, Clone, Hash, Copy, PartialEq, Eq)]
-pub struct ConditionalID(u32);
+pub struct ConditionalID(pub u32);
+
+/// Walks an [AbstractSyntaxTree] with [walk]. Every hook does nothing by default, so implement
+/// only the ones you need.
+pub trait Visitor {
+    /// Called for every statement that is not the start or end of a loop.
+    fn visit_statement(&mut self, _statement: Statement) {}
+
+    /// Called at the start of a loop. `depth` is 1 for the outermost loops.
+    fn enter_loop(&mut self, _id: ConditionalID, _depth: usize) {}
+
+    /// Called at the end of a loop, with the same `depth` as the matching [Visitor::enter_loop].
+    fn exit_loop(&mut self, _id: ConditionalID, _depth: usize) {}
+}
+
+/// Bounds on how big of a program [parse_with_limits] will accept, so that compiling untrusted
+/// input takes a bounded amount of time and memory.
+#[derive(Debug, Clone, Copy)]
+pub struct ParseLimits {
+    /// The deepest that loops can be nested.
+    pub max_nesting_depth: usize,
+    /// The most statements the AST may have (after collapsing runs of the same command).
+    pub max_statements: usize,
+}
+
+impl Default for ParseLimits {
+    fn default() -> Self {
+        ParseLimits {
+            max_nesting_depth: 4096,
+            max_statements: 1 << 24,
+        }
+    }
+}
+
+/// Loops nested deeper than this get a [WarningKind::DeepNesting] warning.
+const DEEP_NESTING: usize = 256;
 
 // public functions
 
 /// Parses source text (really, just a bunch of bytes) into a list of statements.
 pub fn parse(filename: &str, source_text: &[u8]) -> Result<AbstractSyntaxTree, CompilationError> {
+    parse_with_warnings(filename, source_text).map(|(ast, _warnings)| ast)
+}
+
+/// Like [parse], but also returns warnings about suspicious, but valid, code.
+pub fn parse_with_warnings(
+    filename: &str,
+    source_text: &[u8],
+) -> Result<(AbstractSyntaxTree, Vec<CompilationWarning>), CompilationError> {
+    parse_with_limits(filename, source_text, &ParseLimits::default())
+}
+
+/// Like [parse_with_warnings], but with custom [ParseLimits].
+pub fn parse_with_limits(
+    filename: &str,
+    source_text: &[u8],
+    limits: &ParseLimits,
+) -> Result<(AbstractSyntaxTree, Vec<CompilationWarning>), CompilationError> {
     use Statement::*;
 
-    let mut statements: Vec<_> = Vec::new();
+    let mut statements: Vec<Statement> = Vec::new();
+    let mut positions: Vec<SourcePosition> = Vec::new();
     let mut labels = ConditionalStack::new();
     let mut location = LocationTracker::new(filename);
+    let mut config = ProgramConfig::default();
+    let mut warnings = Vec::new();
+    let mut warned_about_nesting = false;
+
+    let mut i = 0;
+    while i < source_text.len() {
+        let byte = source_text[i];
+        i += 1;
+        location.increment_column();
+
+        match byte {
+            b'[' if statements.is_empty() => {
+                warnings.push(location.warning(WarningKind::DeadLoopAtStart));
+            }
+            b'[' if labels.depth() >= DEEP_NESTING && !warned_about_nesting => {
+                warnings.push(location.warning(WarningKind::DeepNesting));
+                warned_about_nesting = true;
+            }
+            b'{' | b'}' if config.dialect == Dialect::Brainfuck => {
+                warnings.push(location.warning(WarningKind::CurlyBraceInsteadOfBracket));
+            }
+            _ => (),
+        }
+
+        if byte == b'[' && labels.depth() >= limits.max_nesting_depth {
+            return Err(location.into_error(Reason::NestingTooDeep));
+        }
 
-    for byte in source_text {
-        statements.push(match byte {
-            b'+' => Some(IncrementVal),
-            b'-' => Some(DecrementVal),
-            b'>' => Some(IncrementAddr),
-            b'<' => Some(DecrementAddr),
+        let statement = match byte {
+            b'+' => Some(IncrementVal(1)),
+            b'-' => Some(DecrementVal(1)),
+            b'>' => Some(IncrementAddr(1)),
+            b'<' => Some(DecrementAddr(1)),
             b'.' => Some(PutChar),
             b',' => Some(GetChar),
             b'[' => Some(StartConditional(labels.next())),
@@ -54,24 +183,248 @@ pub fn parse(filename: &str, source_text: &[u8]) -> Result<AbstractSyntaxTree, C
                 location.increment_line_number();
                 None
             }
+            b';' if source_text.get(i) == Some(&b'#') => {
+                // The rest of the line is a pragma, so skip over it (but not the newline!)
+                let rest = &source_text[i + 1..];
+                let length = rest.iter().position(|&b| b == b'\n').unwrap_or(rest.len());
+                let pragma = String::from_utf8_lossy(&rest[..length]);
+                if let Err(reason) = config.apply_pragma(&pragma) {
+                    return Err(location.into_error(reason));
+                }
+
+                i += 1 + length;
+                None
+            }
+            b'@' if config.dialect == Dialect::ExtendedTypeI => Some(EndProgram),
+            _ if config.dialect == Dialect::ExtendedTypeI => {
+                ExtendedOp::from_byte(byte).map(Extended)
+            }
             _ => None,
-        })
+        };
+
+        if let Some(statement) = statement {
+            push_or_extend_run(&mut statements, statement);
+            if statements.len() > positions.len() {
+                positions.push(location.position());
+            }
+            if statements.len() > limits.max_statements {
+                return Err(location.into_error(Reason::TooManyStatements));
+            }
+        }
     }
 
     if labels.has_unmatched_brackets() {
         return Err(location.into_error(Reason::TooManyOpenBrackets));
     }
 
-    Ok(AbstractSyntaxTree {
-        statements: statements.into_iter().flatten().collect(),
-    })
+    let ast = AbstractSyntaxTree {
+        statements,
+        positions,
+        config,
+    };
+
+    Ok((ast, warnings))
+}
+
+/// Calls the visitor's hooks for each statement in the AST, in order.
+pub fn walk<V: Visitor + ?Sized>(ast: &AbstractSyntaxTree, visitor: &mut V) {
+    let mut depth = 0;
+
+    for &statement in ast.statements() {
+        match statement {
+            Statement::StartConditional(id) => {
+                depth += 1;
+                visitor.enter_loop(id, depth);
+            }
+            Statement::EndConditional(id) => {
+                visitor.exit_loop(id, depth);
+                depth -= 1;
+            }
+            _ => visitor.visit_statement(statement),
+        }
+    }
 }
 
 // Implementations
 impl AbstractSyntaxTree {
+    /// Builds an AST from statements constructed by hand.
+    ///
+    /// Returns an error if a [Statement::StartConditional] does not have a matching
+    /// [Statement::EndConditional] with the same [ConditionalID], or vice-versa, or if a loop
+    /// reuses the ID of a loop that it's inside of.
+    pub fn from_statements(statements: Vec<Statement>) -> Result<Self, CompilationError> {
+        let mut open = Vec::new();
+
+        for statement in statements.iter() {
+            match *statement {
+                Statement::StartConditional(id) if open.contains(&id) => {
+                    return Err(CompilationError::without_location(Reason::DuplicateLoopId));
+                }
+                Statement::StartConditional(id) => open.push(id),
+                Statement::EndConditional(id) if open.pop() != Some(id) => {
+                    return Err(CompilationError::without_location(
+                        Reason::TooManyCloseBrackets,
+                    ));
+                }
+                _ => (),
+            }
+        }
+
+        if !open.is_empty() {
+            return Err(CompilationError::without_location(
+                Reason::TooManyOpenBrackets,
+            ));
+        }
+
+        Ok(AbstractSyntaxTree {
+            statements,
+            positions: Vec::new(),
+            config: ProgramConfig::default(),
+        })
+    }
+
+    /// Replaces the [ProgramConfig] that would otherwise be declared with pragmas.
+    pub fn with_config(self, config: ProgramConfig) -> Self {
+        AbstractSyntaxTree { config, ..self }
+    }
+
     pub fn statements(&self) -> &[Statement] {
         &self.statements[..]
     }
+
+    /// Where the statement at `index` starts in the source text. For a run like `+++`, this is
+    /// where the first `+` is. Returns None if the AST was built with
+    /// [AbstractSyntaxTree::from_statements].
+    pub fn position(&self, index: usize) -> Option<SourcePosition> {
+        self.positions.get(index).copied()
+    }
+
+    /// The memory model this program asked for.
+    pub fn config(&self) -> &ProgramConfig {
+        &self.config
+    }
+
+    /// Serializes the AST back into brainfuck source text, without any comments or whitespace.
+    ///
+    /// If the program has a non-default [ProgramConfig], it is written out as a pragma line first.
+    pub fn to_source(&self) -> String {
+        let code: String = self.statements.iter().map(|s| s.to_source()).collect();
+        if self.config.is_default() {
+            code
+        } else {
+            format!("{}\n{}", self.config, code)
+        }
+    }
+}
+
+/// Two ASTs are equal when they have the same statements and config, regardless of where the
+/// statements came from in the source text.
+impl PartialEq for AbstractSyntaxTree {
+    fn eq(&self, other: &Self) -> bool {
+        self.statements == other.statements && self.config == other.config
+    }
+}
+
+impl Eq for AbstractSyntaxTree {}
+
+impl fmt::Display for SourcePosition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+impl SourceSpan {
+    /// Whether any of the span is on the given line.
+    pub fn contains_line(&self, line: u32) -> bool {
+        let ends_before = match self.end {
+            Some(end) => end.line < line || (end.line == line && end.column == 1),
+            None => false,
+        };
+        self.start.line <= line && !ends_before
+    }
+}
+
+impl Statement {
+    /// Returns the brainfuck command that this statement was parsed from.
+    pub fn to_char(self) -> char {
+        use Statement::*;
+        match self {
+            IncrementVal(_) => '+',
+            DecrementVal(_) => '-',
+            IncrementAddr(_) => '>',
+            DecrementAddr(_) => '<',
+            PutChar => '.',
+            GetChar => ',',
+            StartConditional(_) => '[',
+            EndConditional(_) => ']',
+            EndProgram => '@',
+            Extended(op) => op.to_char(),
+        }
+    }
+
+    /// Returns how many times the command is repeated.
+    pub fn count(self) -> u32 {
+        use Statement::*;
+        match self {
+            IncrementVal(n) | DecrementVal(n) | IncrementAddr(n) | DecrementAddr(n) => n,
+            _ => 1,
+        }
+    }
+
+    /// Returns the brainfuck source text for this statement, including repetitions.
+    pub fn to_source(self) -> String {
+        self.to_char().to_string().repeat(self.count() as usize)
+    }
+}
+
+impl ExtendedOp {
+    /// Returns the op for the given command, if it is one.
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        use ExtendedOp::*;
+        match byte {
+            b'$' => Some(Store),
+            b'!' => Some(Load),
+            b'{' => Some(ShiftLeft),
+            b'}' => Some(ShiftRight),
+            b'~' => Some(Not),
+            b'^' => Some(Xor),
+            b'&' => Some(And),
+            b'|' => Some(Or),
+            _ => None,
+        }
+    }
+
+    pub fn to_char(self) -> char {
+        use ExtendedOp::*;
+        match self {
+            Store => '$',
+            Load => '!',
+            ShiftLeft => '{',
+            ShiftRight => '}',
+            Not => '~',
+            Xor => '^',
+            And => '&',
+            Or => '|',
+        }
+    }
+}
+
+/// Adds the statement to the end of the list, or adds to the count of the last statement if it's
+/// the same command.
+fn push_or_extend_run(statements: &mut Vec<Statement>, statement: Statement) {
+    use Statement::*;
+
+    match (statements.last_mut(), statement) {
+        (Some(IncrementVal(n)), IncrementVal(m))
+        | (Some(DecrementVal(n)), DecrementVal(m))
+        | (Some(IncrementAddr(n)), IncrementAddr(m))
+        | (Some(DecrementAddr(n)), DecrementAddr(m))
+            if n.checked_add(m).is_some() =>
+        {
+            *n += m;
+        }
+        _ => statements.push(statement),
+    }
 }
 
 // Private data structurs
@@ -93,6 +446,10 @@ impl ConditionalStack {
         !self.stack.is_empty()
     }
 
+    pub fn depth(&self) -> usize {
+        self.stack.len()
+    }
+
     pub fn next(&mut self) -> ConditionalID {
         let current_branch = ConditionalID(self.next_id);
         self.next_id += 1;
@@ -108,6 +465,7 @@ impl ConditionalStack {
 
 struct LocationTracker {
     line_number: u32,
+    column: u32,
     filename: String,
 }
 
@@ -119,14 +477,234 @@ impl LocationTracker {
         LocationTracker {
             filename,
             line_number,
+            column: 0,
         }
     }
 
     fn increment_line_number(&mut self) {
         self.line_number += 1;
+        self.column = 0;
+    }
+
+    fn increment_column(&mut self) {
+        self.column += 1;
+    }
+
+    fn position(&self) -> SourcePosition {
+        SourcePosition {
+            line: self.line_number,
+            column: self.column,
+        }
+    }
+
+    fn location(&self) -> Location {
+        Location::new(self.filename.clone(), self.line_number)
+    }
+
+    fn warning(&self, kind: WarningKind) -> CompilationWarning {
+        CompilationWarning::new(kind, self.location())
     }
 
     fn into_error(self, reason: Reason) -> CompilationError {
         CompilationError::new(reason, Location::new(self.filename, self.line_number))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_source_round_trips() {
+        let source = "+[->>+<[,.]<]-";
+        let ast = parse("<test>", source.as_bytes()).unwrap();
+        assert_eq!(source, ast.to_source());
+
+        let reparsed = parse("<test>", ast.to_source().as_bytes()).unwrap();
+        assert_eq!(ast, reparsed);
+    }
+
+    #[test]
+    fn collapses_runs() {
+        use Statement::*;
+
+        let ast = parse("<test>", b"+++ comment ++>><.--").unwrap();
+        assert_eq!(
+            &[
+                IncrementVal(5),
+                IncrementAddr(2),
+                DecrementAddr(1),
+                PutChar,
+                DecrementVal(2)
+            ],
+            ast.statements()
+        );
+    }
+
+    #[test]
+    fn remembers_where_statements_start() {
+        let ast = parse("<test>", b"+++\n [->+<]").unwrap();
+        let position = |line, column| Some(SourcePosition { line, column });
+
+        assert_eq!(position(1, 1), ast.position(0));
+        assert_eq!(position(2, 2), ast.position(1));
+        assert_eq!(position(2, 4), ast.position(3));
+        assert_eq!(None, ast.position(7));
+    }
+
+    #[test]
+    fn pragmas_set_the_config() {
+        let ast = parse("<test>", b";# tape=65536 wrap=on\n+[-]").unwrap();
+        assert_eq!(65536, ast.config().tape_length);
+        assert!(ast.config().wrap_tape);
+        assert_eq!(4, ast.statements().len());
+        assert_eq!(Statement::DecrementVal(1), ast.statements()[2]);
+
+        let reparsed = parse("<test>", ast.to_source().as_bytes()).unwrap();
+        assert_eq!(ast, reparsed);
+
+        // Pragmas are never commands, whatever the dialect.
+        let ast = parse("<test>", b";# dialect=ext1\n;# wrap=on\n;# tape=8\n+").unwrap();
+        assert!(ast.config().wrap_tape);
+        assert_eq!(8, ast.config().tape_length);
+        assert_eq!(&[Statement::IncrementVal(1)], ast.statements());
+        assert!(parse("<test>", b";# dialect=ext1\n;# tape=$!@").is_err());
+
+        assert!(parse("<test>", b";# cells=16").is_err());
+        assert!(parse("<test>", b";# bogus").is_err());
+    }
+
+    #[test]
+    fn warns_about_suspicious_code() {
+        let kinds = |source: &[u8]| -> Vec<WarningKind> {
+            let (_, warnings) = parse_with_warnings("<test>", source).unwrap();
+            warnings.iter().map(|w| w.kind()).collect()
+        };
+
+        assert_eq!(vec![WarningKind::DeadLoopAtStart], kinds(b"comment [-]+"));
+        assert_eq!(vec![WarningKind::CurlyBraceInsteadOfBracket], kinds(b"+{-"));
+        assert!(kinds(b"+[-]").is_empty());
+
+        let deep = [b"+".to_vec(), vec![b'['; 300], vec![b']'; 300]].concat();
+        assert_eq!(vec![WarningKind::DeepNesting], kinds(&deep));
+    }
+
+    #[test]
+    fn walk_pairs_up_loops() {
+        #[derive(Default)]
+        struct Recorder {
+            events: Vec<String>,
+        }
+
+        impl Visitor for Recorder {
+            fn visit_statement(&mut self, statement: Statement) {
+                self.events.push(statement.to_source());
+            }
+
+            fn enter_loop(&mut self, ConditionalID(id): ConditionalID, depth: usize) {
+                self.events.push(format!("enter {} @{}", id, depth));
+            }
+
+            fn exit_loop(&mut self, ConditionalID(id): ConditionalID, depth: usize) {
+                self.events.push(format!("exit {} @{}", id, depth));
+            }
+        }
+
+        let ast = parse("<test>", b"++[[.]]").unwrap();
+        let mut recorder = Recorder::default();
+        walk(&ast, &mut recorder);
+
+        assert_eq!(
+            vec![
+                "++",
+                "enter 0 @1",
+                "enter 1 @2",
+                ".",
+                "exit 1 @2",
+                "exit 0 @1"
+            ],
+            recorder.events
+        );
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let limits = ParseLimits {
+            max_nesting_depth: 2,
+            max_statements: 8,
+        };
+
+        assert!(parse_with_limits("<test>", b"+[[-]]", &limits).is_ok());
+        assert!(parse_with_limits("<test>", b"+[[[-]]]", &limits).is_err());
+        assert!(parse_with_limits("<test>", b"+++++>.", &limits).is_ok());
+        assert!(parse_with_limits("<test>", b"+>+>+>+>+", &limits).is_err());
+    }
+
+    #[test]
+    fn extended_commands_need_the_dialect() {
+        let ast = parse("<test>", b"$!@").unwrap();
+        assert!(ast.statements().is_empty());
+
+        let ast = parse("<test>", b";# dialect=ext1\n+$}!@").unwrap();
+        assert_eq!(
+            &[
+                Statement::IncrementVal(1),
+                Statement::Extended(ExtendedOp::Store),
+                Statement::Extended(ExtendedOp::ShiftRight),
+                Statement::Extended(ExtendedOp::Load),
+                Statement::EndProgram,
+            ],
+            ast.statements()
+        );
+        assert_eq!(ast, parse("<test>", ast.to_source().as_bytes()).unwrap());
+
+        // Pragmas that come after the dialect changes are still pragmas.
+        let ast = parse(
+            "<test>",
+            b";# dialect=ext1\n$ ;# wrap=on tape=16\n;# cells=8\n!",
+        )
+        .unwrap();
+        assert_eq!(
+            &[
+                Statement::Extended(ExtendedOp::Store),
+                Statement::Extended(ExtendedOp::Load),
+            ],
+            ast.statements()
+        );
+        assert!(ast.config().wrap_tape);
+        assert_eq!(16, ast.config().tape_length);
+    }
+
+    #[test]
+    fn from_statements_checks_brackets() {
+        use Statement::*;
+
+        let ast = AbstractSyntaxTree::from_statements(vec![
+            IncrementVal(1),
+            StartConditional(ConditionalID(0)),
+            DecrementVal(1),
+            EndConditional(ConditionalID(0)),
+        ])
+        .unwrap();
+        assert_eq!("+[-]", ast.to_source());
+
+        let unmatched = AbstractSyntaxTree::from_statements(vec![
+            StartConditional(ConditionalID(0)),
+            EndConditional(ConditionalID(1)),
+        ]);
+        assert!(unmatched.is_err());
+
+        // Loops one after the other can share an ID, but nested loops can't.
+        let id = ConditionalID(0);
+        let sequential = [StartConditional(id), EndConditional(id)];
+        assert!(AbstractSyntaxTree::from_statements(sequential.repeat(2)).is_ok());
+        let nested = vec![
+            StartConditional(id),
+            StartConditional(id),
+            EndConditional(id),
+            EndConditional(id),
+        ];
+        let error = AbstractSyntaxTree::from_statements(nested).unwrap_err();
+        assert_eq!(0x007, error.message_identifier());
+    }
+}
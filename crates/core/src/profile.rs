@@ -0,0 +1,83 @@
+//! How often each block of a program ran. Collected by
+//! [InterpretedProgram::run_with_profile](crate::bytecode::InterpretedProgram::run_with_profile),
+//! and fed back into [compile_with_profile](crate::compile_with_profile).
+
+use std::collections::HashMap;
+
+use crate::ir::BlockLabel;
+
+/// Execution counts for each block of an optimized [ControlFlowGraph](crate::ir::ControlFlowGraph).
+///
+/// Labels only make sense for the graph the profile was collected from, so feed the profile back
+/// into a compilation of the same [AbstractSyntaxTree](crate::parsing::AbstractSyntaxTree).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile {
+    counts: HashMap<BlockLabel, u64>,
+}
+
+impl Profile {
+    pub fn new(counts: HashMap<BlockLabel, u64>) -> Self {
+        Profile { counts }
+    }
+
+    /// How many times control entered the block. Blocks that never ran (or that the profile
+    /// doesn't know about) have a count of zero.
+    pub fn count(&self, label: BlockLabel) -> u64 {
+        self.counts.get(&label).copied().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ir::BlockLabel;
+    use crate::parsing::parse;
+    use crate::program::BrainmuckProgram;
+
+    fn ignore_output(_: u32) -> u32 {
+        1
+    }
+
+    fn no_input() -> u32 {
+        0
+    }
+
+    #[test]
+    fn counts_how_often_blocks_run() {
+        // Reading input keeps the loop from running at compile time.
+        let ast = parse("<test>", b",++++++++++[>+<--]").unwrap();
+        let program = crate::compile_to_bytecode(&ast);
+        let cfg = crate::ast_to_optimized_cfg(&ast);
+
+        let mut universe = vec![0u8; 16];
+        let profile = program
+            .run_with_profile(&mut universe, ignore_output, no_input)
+            .unwrap();
+
+        assert_eq!(&[0, 5], &universe[..2]);
+        let counts: Vec<u64> = cfg
+            .blocks()
+            .iter()
+            .map(|b| profile.count(b.label()))
+            .collect();
+        assert_eq!(1, counts[0]);
+        assert!(counts.contains(&5));
+        assert_eq!(0, profile.count(BlockLabel(9999)));
+    }
+
+    #[test]
+    fn profile_guided_code_does_the_same_thing() {
+        let ast = parse("<test>", b",--[>--[>+<--]<--]").unwrap();
+        let mut expected = vec![0u8; 16];
+        let profile = crate::compile_to_bytecode(&ast)
+            .run_with_profile(&mut expected, ignore_output, no_input)
+            .unwrap();
+
+        let mut actual = vec![0u8; 16];
+        crate::compile_with_profile(&ast, &profile)
+            .run_with_custom_io(&mut actual, ignore_output, no_input)
+            .unwrap();
+
+        assert_eq!(&[0, 0, (127 * 127 % 256) as u8], &expected[..3]);
+        assert_eq!(expected, actual);
+    }
+}
@@ -1,35 +1,292 @@
 //! Defines [BrainmuckProgram] that allows you to run a program, regardless of how it's
 //! implemented.
 
+use std::cell::Cell;
+use std::io::{self, Read, Write};
+
+use crate::errors::RuntimeError;
+use crate::parsing::SourcePosition;
+
 /// Has the same signature as `libc`'s `putchar(3)`.
 pub type PutChar = fn(u32) -> u32;
-/// Has the same signature as `libc`'s `getchar(3)`.
+/// Has the same signature as `libc`'s `getchar(3)`, and returns `EOF` (`u32::MAX`, that is, -1)
+/// at the end of the input, the same way. What that does to the cell is up to the program's
+/// [EofBehavior](crate::config::EofBehavior), and every backend does the same thing.
 pub type GetChar = fn() -> u32;
 
+/// What happened while running a [BrainmuckProgram] to completion.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RunStats {
+    /// How many instructions were executed, if the program kept count (the JIT doesn't).
+    pub instructions_executed: Option<u64>,
+    /// Which cell the pointer was on when the program terminated.
+    pub final_address: usize,
+}
+
 /// A [BrainmuckProgram] is ready to be executed. Just give it some memory!
 pub trait BrainmuckProgram {
     /// Run the program with a universe (array of bytes), and a set of IO routines of your
     /// choosing. They must be compatiable with `libc`'s idea of IO.
-    fn run_with_custom_io(&self, universe: &mut [u8], putchar: PutChar, getchar: GetChar);
+    ///
+    /// Returns a [RuntimeError] if the program tries to do something it can't, like moving off
+    /// either end of the universe. The JIT does not check, so don't let it run untrusted programs,
+    /// unless the universe is a [GuardedTape](crate::GuardedTape)!
+    fn run_with_custom_io(
+        &self,
+        universe: &mut [u8],
+        putchar: PutChar,
+        getchar: GetChar,
+    ) -> Result<RunStats, RuntimeError>;
 
     /// Runs the program with the default IO (prints to `stdout`; accepts input from `stdin`)
-    fn run(&self, universe: &mut [u8]) {
-        self.run_with_custom_io(universe, putchar, getchar);
+    fn run(&self, universe: &mut [u8]) -> Result<RunStats, RuntimeError> {
+        self.run_with_custom_io(universe, putchar, getchar)
+    }
+
+    /// Runs the program, reading input from `input` and writing output to `output`, e.g., to
+    /// feed it input from a slice and collect its output in a `Vec<u8>`.
+    ///
+    /// At the end of the input, the program gets `EOF` like it would from `getchar(3)`. Errors
+    /// reading input count as the end of the input, and errors writing output are ignored, the
+    /// same way that a brainfuck program ignores whatever `putchar(3)` returns.
+    fn run_with_io(
+        &self,
+        universe: &mut [u8],
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> Result<RunStats, RuntimeError> {
+        let _redirected = Redirect::new(input, output);
+        let result = self.run_with_custom_io(universe, redirected_putchar, redirected_getchar);
+        Redirect::with(|_, output| {
+            let _ = output.flush();
+        });
+        result
+    }
+
+    /// Where the instruction that [RuntimeError::instruction] refers to came from in the source
+    /// text, if the program knows.
+    fn source_position(&self, _instruction: usize) -> Option<SourcePosition> {
+        None
     }
 }
 
 /// Emulates libc's `putchar(3)`
-fn putchar(c: u32) -> u32 {
+pub(crate) fn putchar(c: u32) -> u32 {
     print!("{}", (c & 0xFF) as u8 as char);
     1
 }
 
-/// Emulates libc's `getchar(3)`
-fn getchar() -> u32 {
-    use std::io::{self, Read};
+/// Emulates libc's `getchar(3)`, including returning [EOF] at the end of the input.
+pub(crate) fn getchar() -> u32 {
     let mut one_byte = [0u8];
-    io::stdin()
-        .read_exact(&mut one_byte)
-        .expect("could not read even a single byte!");
-    one_byte[0] as u32
+    match io::stdin().read_exact(&mut one_byte) {
+        Ok(()) => one_byte[0] as u32,
+        Err(_) => EOF,
+    }
+}
+
+/// What `getchar(3)` returns at the end of the input.
+pub(crate) const EOF: u32 = u32::MAX;
+
+/// Writes to a [PutChar], one byte at a time.
+pub(crate) struct PutCharWriter(pub(crate) PutChar);
+
+impl Write for PutCharWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            (self.0)(byte as u32);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Internal stuff:
+
+thread_local! {
+    /// Where [redirected_putchar] and [redirected_getchar] do their IO. The generated code can
+    /// only call plain function pointers, so this is how they find the reader and the writer.
+    static REDIRECTED: Cell<Option<(*mut dyn Read, *mut dyn Write)>> = const { Cell::new(None) };
+}
+
+/// Redirects IO on this thread to a reader and a writer, until it's dropped. While it exists,
+/// [redirected_putchar] and [redirected_getchar] use them.
+pub(crate) struct Redirect {
+    previous: Option<(*mut dyn Read, *mut dyn Write)>,
+}
+
+impl Redirect {
+    pub(crate) fn new(input: &mut dyn Read, output: &mut dyn Write) -> Self {
+        // SAFETY: this only erases the lifetimes. The pointers are only used while the redirect
+        // exists, and it can't outlive the borrows it was made from.
+        let (input, output): (*mut dyn Read, *mut dyn Write) = unsafe {
+            (
+                std::mem::transmute::<&mut dyn Read, &'static mut dyn Read>(input),
+                std::mem::transmute::<&mut dyn Write, &'static mut dyn Write>(output),
+            )
+        };
+        let previous = REDIRECTED.with(|redirected| redirected.replace(Some((input, output))));
+
+        Redirect { previous }
+    }
+
+    /// Calls `f` with the reader and writer that IO is currently redirected to.
+    fn with<T>(f: impl FnOnce(&mut dyn Read, &mut dyn Write) -> T) -> T {
+        let (input, output) = REDIRECTED
+            .with(Cell::get)
+            .expect("IO should be redirected while the program runs");
+        // SAFETY: the pointers came from mutable borrows that outlive the redirect, and nothing
+        // else uses them while the program runs.
+        unsafe { f(&mut *input, &mut *output) }
+    }
+}
+
+impl Drop for Redirect {
+    fn drop(&mut self) {
+        REDIRECTED.with(|redirected| redirected.set(self.previous));
+    }
+}
+
+pub(crate) fn redirected_putchar(c: u32) -> u32 {
+    Redirect::with(|_, output| match output.write_all(&[c as u8]) {
+        Ok(()) => c & 0xFF,
+        Err(_) => EOF,
+    })
+}
+
+pub(crate) fn redirected_getchar() -> u32 {
+    Redirect::with(|input, _| {
+        let mut one_byte = [0u8];
+        loop {
+            match input.read(&mut one_byte) {
+                Ok(1) => return one_byte[0] as u32,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                _ => return EOF,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::parse;
+
+    #[test]
+    fn runs_with_any_reader_and_writer() {
+        let program = crate::compile_to_bytecode(&parse("<test>", b",[.,]").unwrap());
+        let mut output = Vec::new();
+        program
+            .run_with_io(&mut [0u8; 4], &mut &b"hello\0"[..], &mut output)
+            .unwrap();
+        assert_eq!(b"hello", &output[..]);
+
+        // Programs can be run from inside the IO of another program:
+        struct Nested(Vec<u8>);
+        impl Write for Nested {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                let program = crate::compile_to_bytecode(&parse("<test>", b",+.").unwrap());
+                program
+                    .run_with_io(&mut [0u8; 1], &mut &buf[..1], &mut self.0)
+                    .unwrap();
+                Ok(1)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let mut nested = Nested(Vec::new());
+        program
+            .run_with_io(&mut [0u8; 4], &mut &b"HAL\0"[..], &mut nested)
+            .unwrap();
+        assert_eq!(b"IBM", &nested.0[..]);
+    }
+
+    #[test]
+    fn reads_past_the_end_of_input_as_configured() {
+        let run = |program: &dyn BrainmuckProgram| {
+            let mut output = Vec::new();
+            program
+                .run_with_io(&mut [0u8; 4], &mut &b""[..], &mut output)
+                .unwrap();
+            output
+        };
+
+        for (pragma, expected) in [("unchanged", 7), ("0", 0), ("255", 255)] {
+            let source = format!(";# eof={}\n+++++++,.", pragma);
+            let ast = parse("<test>", source.as_bytes()).unwrap();
+            assert_eq!(vec![expected], run(&crate::compile_to_bytecode(&ast)));
+            assert_eq!(
+                vec![expected],
+                run(&crate::compile_to_evaluator(&ast, true))
+            );
+        }
+    }
+
+    #[test]
+    fn every_backend_agrees() {
+        let run = |program: &dyn BrainmuckProgram, input: &[u8]| {
+            let mut output = Vec::new();
+            let stats = program
+                .run_with_io(&mut [0u8; 64], &mut &input[..], &mut output)
+                .unwrap();
+            (output, stats.final_address)
+        };
+
+        // Each of these depends on its input, so that the optimizer leaves the instruction in the
+        // comment for the backends to run, instead of running the program at compile time.
+        let programs: [(&[u8], &[u8]); 9] = [
+            // zero
+            (b",[-]>,[+]<+.>.", b"\x03\x04"),
+            // mla, with factors that overflow and that go backwards
+            (b">,[-<+++>>--<]<.>>.", b"\x05"),
+            (
+                b",[->+++++++++++++++++++++++++++++++++++++++++++++++++++++++++<]>.",
+                b"\x09",
+            ),
+            // scan, with strides of one or more cells
+            (b",>,>,<<[>]<.", b"\x03\x02\x01"),
+            (b",>>,<<[>>]<<.>>>[<<]>.", b"\x03\x02"),
+            // add/set at an offset, and output known at compile time
+            (b">>>,<<<+++++[>>>.<+<<-]>>>.<.[-]++.", b"A"),
+            // reading past the end of the input, with each EOF behavior
+            (b",+[-.,+]", b"hi"),
+            (b";# eof=0\n,[.,]", b"hi"),
+            (b";# eof=unchanged\n,.,.,.", b"hi"),
+        ];
+        for (source, input) in programs {
+            let ast = parse("<test>", source).unwrap();
+            let expected = run(&crate::compile_to_evaluator(&ast, false), input);
+            assert!(!expected.0.is_empty());
+
+            assert_eq!(
+                expected,
+                run(&crate::compile_to_evaluator(&ast, true), input)
+            );
+            assert_eq!(expected, run(&crate::compile_to_bytecode(&ast), input));
+            assert_eq!(expected, run(&*crate::compile_best_available(&ast), input));
+            if crate::can_compile_to_native_code(ast.config()) {
+                assert_eq!(expected, run(&crate::compile_to_native_code(&ast), input));
+                assert_eq!(
+                    expected,
+                    run(&crate::compile_to_lazy_native_code(&ast), input)
+                );
+                let options = crate::CodegenOptions {
+                    outline_loops: true,
+                    ..crate::CodegenOptions::from(ast.config())
+                };
+                let cfg = crate::ast_to_optimized_cfg(&ast);
+                assert_eq!(
+                    expected,
+                    run(
+                        &crate::compile_cfg_to_native_code_with_options(&cfg, &options),
+                        input
+                    )
+                );
+            }
+        }
+    }
 }
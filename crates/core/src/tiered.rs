@@ -0,0 +1,537 @@
+//! Runs a program in the bytecode interpreter until one of its loops gets hot, then compiles just
+//! that loop to native code and runs it there.
+//!
+//! Each loop that can be promoted is cut out of the [ControlFlowGraph] as a standalone region: its
+//! blocks, in program order, with every exit retargeted to a new block that terminates. The
+//! [NativeCodeGenerator] compiles a region like any other program, and since the generated code returns
+//! where the tape pointer ended up, the interpreter can carry on from the loop's exit.
+//!
+//! When a loop gets hot, the interpreter might be in the middle of a bigger loop that contains it.
+//! Rather than promoting just the inner loop, the outermost loop that can be promoted is compiled
+//! with its entry at the inner loop's header, and the interpreter's state (the pointer and the
+//! tape) carries on there. That way, a program that is one giant loop still ends up running
+//! natively, even though the giant loop itself only ever goes around a few times.
+
+use std::cell::OnceCell;
+use std::collections::{HashMap, HashSet};
+
+use crate::bytecode::{InterpretedProgram, MachineState, Outcome};
+use crate::codegen::NativeCodeGenerator;
+use crate::config::{EofBehavior, ProgramConfig};
+use crate::errors::RuntimeError;
+use crate::ir::analysis::{dominators, find_loops, NaturalLoop};
+use crate::ir::{BasicBlock, BlockLabel, ControlFlowGraph, ThreeAddressInstruction};
+use crate::jit::{CompiledProgram, OutputBuffer};
+use crate::parsing::SourcePosition;
+use crate::program::{BrainmuckProgram, GetChar, PutChar, RunStats};
+
+/// How many times the interpreter runs a loop's header before the loop is compiled, by default.
+const HOT_LOOP_ITERATIONS: u64 = 1000;
+
+/// A [BrainmuckProgram] that starts in the interpreter, and promotes hot loops to the JIT.
+pub struct TieredProgram {
+    interpreted: InterpretedProgram,
+    /// Where to go once a loop gets hot, by where its header starts in the bytecode.
+    regions: HashMap<usize, Region>,
+    promotion: Promotion,
+    hot_loop_iterations: u64,
+    /// Compiled regions have to read input the same way the interpreter does.
+    eof: EofBehavior,
+    /// ...and wrap around the same tape, if it wraps.
+    wrapping_tape: Option<usize>,
+}
+
+/// What hot loops are promoted to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Promotion {
+    /// Nothing: the JIT can't run on this machine, or can't honor the program's config.
+    Never,
+    NativeCode,
+    /// Bytecode of the region on its own, so that promotion can be tested on any machine.
+    #[cfg(test)]
+    Bytecode,
+}
+
+/// A loop, cut out of the CFG so that it can be compiled on its own. It's entered at the header of
+/// the loop that got hot, which is either its own header, or the header of a loop inside of it.
+struct Region {
+    cfg: ControlFlowGraph,
+    /// Where the interpreter picks up once the loop exits.
+    exit: usize,
+    /// The lowest and highest cells the loop can move to or access, relative to the pointer at
+    /// the region's entry. The generated code doesn't check bounds, so the loop is only promoted
+    /// when all of these cells are on the tape. When the tape wraps, the code can't leave it, so
+    /// this can be `None`, if there's no telling.
+    reach: Option<(i64, i64)>,
+    /// Compiled the first time the loop gets hot.
+    compiled: OnceCell<CompiledRegion>,
+}
+
+enum CompiledRegion {
+    NativeCode(CompiledProgram),
+    #[cfg(test)]
+    Bytecode(InterpretedProgram),
+}
+
+impl TieredProgram {
+    pub fn new(cfg: &ControlFlowGraph, config: &ProgramConfig) -> Self {
+        let interpreted = InterpretedProgram::new(cfg, config);
+        let nest = find_loops(cfg, &dominators(cfg));
+        let regions = nest
+            .loops()
+            .iter()
+            .filter_map(|hot_loop| {
+                let header = interpreted.block_start(hot_loop.header)?;
+                // The loop that got hot, and every loop that it's inside of, from the outside in:
+                let mut enclosing = vec![hot_loop];
+                while let Some(parent) = enclosing.last()?.parent {
+                    enclosing.push(nest.loop_with_header(parent)?);
+                }
+                let region = enclosing.iter().rev().find_map(|natural_loop| {
+                    let (region, exit) = extract_region(cfg, natural_loop, hot_loop.header)?;
+                    let reach = reach(&region);
+                    if reach.is_none() && !config.wrap_tape {
+                        return None;
+                    }
+                    Some(Region {
+                        reach,
+                        cfg: region,
+                        exit: interpreted.block_start(exit)?,
+                        compiled: OnceCell::new(),
+                    })
+                })?;
+                Some((header, region))
+            })
+            .collect();
+
+        let promotion = if crate::can_compile_to_native_code(config) {
+            Promotion::NativeCode
+        } else {
+            Promotion::Never
+        };
+
+        TieredProgram {
+            interpreted,
+            regions,
+            promotion,
+            hot_loop_iterations: HOT_LOOP_ITERATIONS,
+            eof: config.eof,
+            wrapping_tape: config.wrap_tape.then_some(config.tape_length),
+        }
+    }
+
+    /// Promotes loops once their header has run `iterations` times, instead of the default.
+    pub fn with_hot_loop_iterations(self, iterations: u64) -> Self {
+        TieredProgram {
+            hot_loop_iterations: iterations,
+            ..self
+        }
+    }
+
+    /// The program as it runs before anything gets hot.
+    pub fn interpreted(&self) -> &InterpretedProgram {
+        &self.interpreted
+    }
+
+    /// How many loops could be promoted to native code.
+    pub fn promotable_loops(&self) -> usize {
+        self.regions.len()
+    }
+
+    /// How many loops have been promoted to native code so far.
+    pub fn compiled_loops(&self) -> usize {
+        self.regions
+            .values()
+            .filter(|region| region.compiled.get().is_some())
+            .count()
+    }
+
+    fn compile(&self, region: &Region) -> CompiledRegion {
+        match self.promotion {
+            Promotion::Never => unreachable!("nothing should be promoted"),
+            Promotion::NativeCode => {
+                let mut gen = NativeCodeGenerator::new().with_eof_behavior(self.eof);
+                if let Some(tape_length) = self.wrapping_tape {
+                    gen = gen.with_wrapping_tape(tape_length);
+                }
+                gen.compile(&region.cfg);
+                let program = CompiledProgram::from_code(gen.take_machine_code());
+                CompiledRegion::NativeCode(program.with_unwind_info(&gen.frame_description()))
+            }
+            #[cfg(test)]
+            Promotion::Bytecode => CompiledRegion::Bytecode(InterpretedProgram::new(
+                &region.cfg,
+                &ProgramConfig {
+                    eof: self.eof,
+                    wrap_tape: self.wrapping_tape.is_some(),
+                    tape_length: self
+                        .wrapping_tape
+                        .unwrap_or(crate::config::DEFAULT_TAPE_LENGTH),
+                    ..ProgramConfig::default()
+                },
+            )),
+        }
+    }
+}
+
+impl BrainmuckProgram for TieredProgram {
+    fn run_with_custom_io(
+        &self,
+        universe: &mut [u8],
+        putchar: PutChar,
+        getchar: GetChar,
+    ) -> Result<RunStats, RuntimeError> {
+        let mut state = MachineState::default();
+        let mut iterations = vec![0u64; self.interpreted.bytecode().len()];
+        let mut went_native = false;
+        // A hot loop that can't be promoted right now carries on in the interpreter.
+        let mut keep_interpreting = None;
+
+        loop {
+            let outcome =
+                self.interpreted
+                    .execute(&mut state, universe, putchar, getchar, |pc| {
+                        if self.promotion == Promotion::Never || !self.regions.contains_key(&pc) {
+                            return true;
+                        }
+                        if keep_interpreting == Some(pc) {
+                            keep_interpreting = None;
+                            return true;
+                        }
+                        iterations[pc] += 1;
+                        iterations[pc] < self.hot_loop_iterations
+                    })?;
+
+            if outcome == Outcome::Halted {
+                // Instructions run as native code aren't counted.
+                let instructions_executed = if went_native {
+                    None
+                } else {
+                    Some(state.executed)
+                };
+                return Ok(RunStats {
+                    instructions_executed,
+                    final_address: state.address,
+                });
+            }
+
+            // Paused at the header of a hot loop:
+            let region = &self.regions[&state.pc];
+            let address = state.address as i64;
+            let fits = match (self.wrapping_tape, region.reach) {
+                (Some(tape_length), _) => universe.len() == tape_length,
+                (None, Some((lowest, highest))) => {
+                    address + lowest >= 0 && address + highest < universe.len() as i64
+                }
+                (None, None) => unreachable!("only loops that wrap can go anywhere"),
+            };
+            if !fits {
+                // The loop might leave the tape, and only the interpreter can report that.
+                keep_interpreting = Some(state.pc);
+                continue;
+            }
+
+            let code = region.compiled.get_or_init(|| self.compile(region));
+            state.address = match code {
+                CompiledRegion::NativeCode(code) => {
+                    let start = universe.as_mut_ptr();
+                    // The code flushes everything it printed before it returns, so the output
+                    // stays in order with what the interpreter prints.
+                    let output = &mut OutputBuffer::new(putchar);
+                    // SAFETY: everywhere the loop can go is on the tape, as checked above.
+                    unsafe {
+                        let end = code.run_from(start.add(state.address), output, getchar, start);
+                        end.offset_from(start) as usize
+                    }
+                }
+                #[cfg(test)]
+                CompiledRegion::Bytecode(program) => {
+                    let mut region_state = MachineState::default();
+                    region_state.address = state.address;
+                    program.execute(&mut region_state, universe, putchar, getchar, |_| true)?;
+                    region_state.address
+                }
+            };
+
+            state.pc = region.exit;
+            went_native = true;
+        }
+    }
+
+    fn source_position(&self, instruction: usize) -> Option<SourcePosition> {
+        self.interpreted.source_position(instruction)
+    }
+}
+
+/// Works out the lowest and highest cells that the region can move to or access, relative to the
+/// pointer when it starts. That's only possible when the pointer is always at the same offset
+/// whenever control reaches a block, like in a loop that ends up where it started; otherwise,
+/// returns `None`.
+fn reach(region: &ControlFlowGraph) -> Option<(i64, i64)> {
+    use ThreeAddressInstruction::*;
+
+    let blocks = region.blocks();
+    let index: HashMap<BlockLabel, usize> = blocks
+        .iter()
+        .enumerate()
+        .map(|(i, block)| (block.label(), i))
+        .collect();
+
+    let mut offsets: Vec<Option<i64>> = vec![None; blocks.len()];
+    offsets[0] = Some(0);
+    let mut worklist = vec![0];
+    let (mut lowest, mut highest) = (0, 0);
+
+    while let Some(b) = worklist.pop() {
+        let mut offset = offsets[b]?;
+        let mut touch = |cell: i64| {
+            lowest = cell.min(lowest);
+            highest = cell.max(highest);
+        };
+        touch(offset);
+
+        let mut successors = Vec::new();
+        for &instr in blocks[b].instructions() {
+            match instr {
+                ChangeAddr(n) => {
+                    offset += n as i64;
+                    touch(offset);
+                }
+                ChangeValAt(cell, _) | SetValAt(cell, _) => touch(offset + cell as i64),
+                MulAdd { dst_offset, .. } => touch(offset + dst_offset as i64),
+                // Where a scan ends up isn't known until it runs.
+                FindZero(_) => return None,
+                BranchIfZero(target) | BranchTo(target) => successors.push(index[&target]),
+                _ => (),
+            }
+        }
+        if blocks[b].falls_through() && b + 1 < blocks.len() {
+            successors.push(b + 1);
+        }
+
+        for successor in successors {
+            match offsets[successor] {
+                None => {
+                    offsets[successor] = Some(offset);
+                    worklist.push(successor);
+                }
+                Some(existing) if existing != offset => return None,
+                Some(_) => (),
+            }
+        }
+    }
+
+    Some((lowest, highest))
+}
+
+/// Cuts the loop out of the CFG as a program of its own, that starts at `entry` (the loop's
+/// header, or the header of a loop inside of it), and terminates where the loop would exit.
+/// Returns the region, and the block the loop exits to.
+///
+/// Returns `None` when the loop can't be compiled on its own: when it has more than one exit, or
+/// contains instructions the JIT can't handle.
+pub(crate) fn extract_region(
+    cfg: &ControlFlowGraph,
+    natural_loop: &NaturalLoop,
+    entry: BlockLabel,
+) -> Option<(ControlFlowGraph, BlockLabel)> {
+    use ThreeAddressInstruction::*;
+
+    let in_loop: HashSet<_> = natural_loop.blocks.iter().copied().collect();
+    let next_label = cfg.blocks().iter().map(|b| b.label().0 + 1).max()?;
+    let exit_label = BlockLabel(next_label);
+    let entry_label = BlockLabel(next_label + 1);
+
+    let mut exits = HashSet::new();
+    let mut retarget = |target: BlockLabel| {
+        if in_loop.contains(&target) {
+            target
+        } else {
+            exits.insert(target);
+            exit_label
+        }
+    };
+
+    let mut blocks = Vec::new();
+    if natural_loop.blocks.first() != Some(&entry) {
+        blocks.push(BasicBlock::new(entry_label, vec![BranchTo(entry)]));
+    }
+
+    for (i, &label) in natural_loop.blocks.iter().enumerate() {
+        let index = cfg.blocks().iter().position(|b| b.label() == label)?;
+        let block = &cfg.blocks()[index];
+
+        let mut instructions = Vec::with_capacity(block.instructions().len() + 1);
+        for &instr in block.instructions() {
+            instructions.push(match instr {
+                Terminate | Extended(_) => return None,
+                BranchIfZero(target) => BranchIfZero(retarget(target)),
+                BranchTo(target) => BranchTo(retarget(target)),
+                other => other,
+            });
+        }
+
+        // The blocks in the region aren't necessarily adjacent in the original program, so make
+        // falling through explicit wherever the next block isn't the same one anymore.
+        if block.falls_through() {
+            let falls_to = retarget(cfg.blocks().get(index + 1)?.label());
+            let next_in_region = natural_loop
+                .blocks
+                .get(i + 1)
+                .copied()
+                .unwrap_or(exit_label);
+            if falls_to != next_in_region {
+                instructions.push(BranchTo(falls_to));
+            }
+        }
+
+        blocks.push(BasicBlock::new(label, instructions).with_position(block.position()));
+    }
+
+    if exits.len() != 1 {
+        return None;
+    }
+    blocks.push(BasicBlock::new(exit_label, vec![Terminate]));
+
+    let exit = exits.into_iter().next()?;
+    Some((ControlFlowGraph::new(blocks), exit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast_to_optimized_cfg;
+    use crate::parsing::parse;
+    use std::cell::RefCell;
+
+    thread_local! {
+        static OUTPUT: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn capture(c: u32) -> u32 {
+        OUTPUT.with(|output| output.borrow_mut().push(c as u8));
+        1
+    }
+
+    fn four() -> u32 {
+        4
+    }
+
+    /// Runs the program, and returns its output.
+    fn run(program: &dyn BrainmuckProgram) -> Vec<u8> {
+        program
+            .run_with_custom_io(&mut [0u8; 16], capture, four)
+            .unwrap();
+        OUTPUT.with(|output| output.take())
+    }
+
+    #[test]
+    fn cuts_loops_into_standalone_regions() {
+        let ast = parse("<test>", b",[>,[>+.<--]>[-<+>]<<-]>>.").unwrap();
+        let cfg = ast_to_optimized_cfg(&ast);
+        let program = TieredProgram::new(&cfg, ast.config());
+
+        assert!(program.promotable_loops() >= 2);
+        for region in program.regions.values() {
+            assert_eq!(Ok(()), region.cfg.verify());
+            assert_eq!(
+                Some(ThreeAddressInstruction::Terminate),
+                region.cfg.last_instruction()
+            );
+            assert!(!NativeCodeGenerator::new().compile(&region.cfg).is_empty());
+        }
+    }
+
+    #[test]
+    fn promotes_hot_loops() {
+        let ast = parse("<test>", b",[>,[>+.<--]>[-<+>]<<-]>>.").unwrap();
+        let cfg = ast_to_optimized_cfg(&ast);
+        let expected = run(&crate::compile_to_bytecode(&ast));
+
+        let mut tiered = TieredProgram::new(&cfg, ast.config()).with_hot_loop_iterations(2);
+        tiered.promotion = Promotion::Bytecode;
+        OUTPUT.with(|output| output.take());
+        let stats = tiered
+            .run_with_custom_io(&mut [0u8; 16], capture, four)
+            .unwrap();
+
+        assert_eq!(expected, OUTPUT.with(|output| output.take()));
+        assert!(tiered.compiled_loops() > 0);
+        assert_eq!(None, stats.instructions_executed);
+    }
+
+    #[test]
+    fn enters_the_loop_around_a_hot_loop() {
+        // The outer loop only goes around four times, but the inner loop gets hot on the first.
+        let ast = parse("<test>", b",[>,[>+.<-]>[-<+>]<<-]>>.").unwrap();
+        let cfg = ast_to_optimized_cfg(&ast);
+        let expected = run(&crate::compile_to_bytecode(&ast));
+
+        let mut tiered = TieredProgram::new(&cfg, ast.config()).with_hot_loop_iterations(3);
+        tiered.promotion = Promotion::Bytecode;
+        OUTPUT.with(|output| output.take());
+        tiered
+            .run_with_custom_io(&mut [0u8; 16], capture, four)
+            .unwrap();
+        assert_eq!(expected, OUTPUT.with(|output| output.take()));
+
+        // Only one region was compiled, and it has the rest of the outer loop in it, too.
+        assert_eq!(1, tiered.compiled_loops());
+        let region = tiered
+            .regions
+            .values()
+            .find(|region| region.compiled.get().is_some())
+            .unwrap();
+        assert!(region
+            .cfg
+            .blocks()
+            .iter()
+            .flat_map(|block| block.instructions())
+            .any(|instr| matches!(instr, ThreeAddressInstruction::MulAdd { .. })));
+    }
+
+    #[test]
+    fn only_promotes_loops_that_stay_on_the_tape() {
+        // The loop moves one cell right every time, so how far it goes isn't known.
+        let ast = parse("<test>", b",[>,]").unwrap();
+        let cfg = ast_to_optimized_cfg(&ast);
+        assert_eq!(0, TieredProgram::new(&cfg, ast.config()).promotable_loops());
+
+        // The loop can reach the cell right before the pointer, which isn't on the tape at first.
+        let ast = parse("<test>", b",[<+>-]>,[<+>-]<.").unwrap();
+        let cfg = ast_to_optimized_cfg(&ast);
+        let mut tiered = TieredProgram::new(&cfg, ast.config()).with_hot_loop_iterations(2);
+        tiered.promotion = Promotion::Bytecode;
+        assert!(tiered
+            .run_with_custom_io(&mut [0u8; 4], capture, four)
+            .is_err());
+        OUTPUT.with(|output| output.take());
+    }
+
+    #[test]
+    fn promotes_loops_that_wrap_around_the_tape() {
+        let ast = parse("<test>", b";# tape=4 wrap=on\n,[<+.>-]<.").unwrap();
+        let cfg = ast_to_optimized_cfg(&ast);
+        let mut tiered = TieredProgram::new(&cfg, ast.config()).with_hot_loop_iterations(2);
+        tiered.promotion = Promotion::Bytecode;
+        OUTPUT.with(|output| output.take());
+        tiered
+            .run_with_custom_io(&mut [0u8; 4], capture, four)
+            .unwrap();
+
+        assert_eq!(vec![1, 2, 3, 4, 4], OUTPUT.with(|output| output.take()));
+        assert_eq!(1, tiered.compiled_loops());
+
+        // Where the loop goes isn't known, but it can't leave the tape either way.
+        let ast = parse("<test>", b";# tape=4 wrap=on\n,[>+]>.").unwrap();
+        let cfg = ast_to_optimized_cfg(&ast);
+        let mut tiered = TieredProgram::new(&cfg, ast.config()).with_hot_loop_iterations(2);
+        tiered.promotion = Promotion::Bytecode;
+        tiered
+            .run_with_custom_io(&mut [0u8; 4], capture, four)
+            .unwrap();
+
+        assert_eq!(vec![252], OUTPUT.with(|output| output.take()));
+        assert_eq!(1, tiered.compiled_loops());
+    }
+}
@@ -0,0 +1,318 @@
+//! Unwind information for generated code, so that backtraces taken while it's running (e.g., from
+//! a panic in `putchar()`) can get past it, back to whoever ran the program.
+//!
+//! Generated code is a single function, so it gets a single Frame Description Entry, in the same
+//! `.eh_frame` format that a compiler would put in an object file. Only the prologue changes how
+//! to find the caller's frame, so the rules describe the prologue, and hold for the rest of the
+//! body.
+//!
+//! See: https://refspecs.linuxfoundation.org/LSB_5.0.0/LSB-Core-generic/LSB-Core-generic/ehframechpt.html
+
+// Call frame instructions, from the DWARF 4 standard, section 6.4.2:
+const DW_CFA_ADVANCE_LOC: u8 = 0x40;
+const DW_CFA_ADVANCE_LOC1: u8 = 0x02;
+const DW_CFA_ADVANCE_LOC2: u8 = 0x03;
+const DW_CFA_OFFSET: u8 = 0x80;
+const DW_CFA_DEF_CFA: u8 = 0x0c;
+const DW_CFA_DEF_CFA_REGISTER: u8 = 0x0d;
+const DW_CFA_DEF_CFA_OFFSET: u8 = 0x0e;
+const DW_CFA_NOP: u8 = 0x00;
+
+/// Every register is saved at a multiple of 8 bytes from the CFA.
+const DATA_ALIGNMENT: i64 = -8;
+
+/// How to find the caller's frame, as of some instruction. The Canonical Frame Address (CFA) is
+/// the value of the stack pointer in the caller, right before the call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rule {
+    /// The CFA is at an offset from a register.
+    Cfa { register: u8, offset: u32 },
+    /// The CFA is at a new offset from the same register.
+    CfaOffset(u32),
+    /// The CFA is at the same offset from a new register.
+    CfaRegister(u8),
+    /// The register was saved at an offset (a negative multiple of 8) from the CFA.
+    Saved { register: u8, offset: i32 },
+}
+
+/// How to unwind through generated code. Registers are numbered the way DWARF numbers them on
+/// the machine.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameDescription {
+    pub return_address_register: u8,
+    /// The rules that hold from each offset in the code (in bytes) onwards, in order.
+    pub rules: Vec<(usize, Rule)>,
+}
+
+/// Describes `size` bytes of code starting at `start` as an `.eh_frame` section: a Common
+/// Information Entry, a Frame Description Entry, and the zero that ends the section.
+pub fn eh_frame(description: &FrameDescription, start: usize, size: usize) -> Vec<u8> {
+    let mut section = Vec::new();
+
+    // CIE:
+    let cie = entry(&mut section, |out| {
+        out.extend_from_slice(&0u32.to_le_bytes()); // CIE id
+        out.push(1); // version
+        out.extend_from_slice(b"zR\0"); // augmentation: has data; says how FDEs encode addresses
+        write_uleb128(out, 1); // code alignment factor
+        write_sleb128(out, DATA_ALIGNMENT);
+        out.push(description.return_address_register);
+        write_uleb128(out, 1); // augmentation data length
+        out.push(0x00); // DW_EH_PE_absptr: addresses are plain pointers
+    });
+
+    // FDE:
+    entry(&mut section, |out| {
+        // The CIE pointer is how far back the CIE starts, from the pointer itself.
+        let cie_pointer = (out.len() - cie) as u32;
+        out.extend_from_slice(&cie_pointer.to_le_bytes());
+        out.extend_from_slice(&(start as u64).to_le_bytes());
+        out.extend_from_slice(&(size as u64).to_le_bytes());
+        write_uleb128(out, 0); // augmentation data length
+
+        let mut location = 0;
+        for &(offset, rule) in &description.rules {
+            advance(out, offset - location);
+            location = offset;
+            write_rule(out, rule);
+        }
+    });
+
+    section.extend_from_slice(&0u32.to_le_bytes());
+    section
+}
+
+/// Unwind information that the unwinder knows about, until it's dropped.
+pub struct RegisteredFrame {
+    // Boxed, so that it doesn't move while it's registered.
+    eh_frame: Box<[u8]>,
+}
+
+impl RegisteredFrame {
+    /// Tells the unwinder about the `.eh_frame` section from [eh_frame].
+    pub fn register(eh_frame: Vec<u8>) -> Self {
+        let frame = RegisteredFrame {
+            eh_frame: eh_frame.into_boxed_slice(),
+        };
+        // SAFETY: the section is well-formed, and lives as long as the registration.
+        unsafe { ffi::__register_frame(frame.entry()) };
+        frame
+    }
+
+    /// libgcc wants the whole section, but libunwind wants the FDE on its own.
+    fn entry(&self) -> *const u8 {
+        if cfg!(target_os = "macos") {
+            let cie_length = u32::from_le_bytes(self.eh_frame[..4].try_into().unwrap());
+            self.eh_frame[4 + cie_length as usize..].as_ptr()
+        } else {
+            self.eh_frame.as_ptr()
+        }
+    }
+}
+
+impl Drop for RegisteredFrame {
+    fn drop(&mut self) {
+        // SAFETY: this is the same pointer that was registered.
+        unsafe { ffi::__deregister_frame(self.entry()) };
+    }
+}
+
+mod ffi {
+    extern "C" {
+        // Provided by the unwinder that Rust already links against: libgcc_s, or libunwind.
+        pub fn __register_frame(begin: *const u8);
+        pub fn __deregister_frame(begin: *const u8);
+    }
+}
+
+/// Writes a CIE or FDE: its length, then its contents, padded so that the next entry is aligned.
+/// Returns where the entry starts.
+fn entry(section: &mut Vec<u8>, contents: impl FnOnce(&mut Vec<u8>)) -> usize {
+    let start = section.len();
+    section.extend_from_slice(&[0; 4]);
+    contents(section);
+    while !section.len().is_multiple_of(8) {
+        section.push(DW_CFA_NOP);
+    }
+
+    let length = (section.len() - start - 4) as u32;
+    section[start..start + 4].copy_from_slice(&length.to_le_bytes());
+    start
+}
+
+fn advance(out: &mut Vec<u8>, delta: usize) {
+    match delta {
+        0 => (),
+        1..=0x3f => out.push(DW_CFA_ADVANCE_LOC | delta as u8),
+        0x40..=0xff => out.extend_from_slice(&[DW_CFA_ADVANCE_LOC1, delta as u8]),
+        _ => {
+            out.push(DW_CFA_ADVANCE_LOC2);
+            out.extend_from_slice(&(delta as u16).to_le_bytes());
+        }
+    }
+}
+
+fn write_rule(out: &mut Vec<u8>, rule: Rule) {
+    match rule {
+        Rule::Cfa { register, offset } => {
+            out.push(DW_CFA_DEF_CFA);
+            write_uleb128(out, register as u64);
+            write_uleb128(out, offset as u64);
+        }
+        Rule::CfaOffset(offset) => {
+            out.push(DW_CFA_DEF_CFA_OFFSET);
+            write_uleb128(out, offset as u64);
+        }
+        Rule::CfaRegister(register) => {
+            out.push(DW_CFA_DEF_CFA_REGISTER);
+            write_uleb128(out, register as u64);
+        }
+        Rule::Saved { register, offset } => {
+            assert!(
+                register < 64,
+                "register {} needs DW_CFA_offset_extended",
+                register
+            );
+            out.push(DW_CFA_OFFSET | register);
+            write_uleb128(out, (offset as i64 / DATA_ALIGNMENT) as u64);
+        }
+    }
+}
+
+fn write_uleb128(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn write_sleb128(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        let done = (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0);
+        if done {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_the_code_in_eh_frame_format() {
+        let description = FrameDescription {
+            return_address_register: 16,
+            rules: vec![
+                (
+                    0,
+                    Rule::Cfa {
+                        register: 7,
+                        offset: 8,
+                    },
+                ),
+                (
+                    0,
+                    Rule::Saved {
+                        register: 16,
+                        offset: -8,
+                    },
+                ),
+                (1, Rule::CfaOffset(16)),
+                (100, Rule::CfaRegister(6)),
+            ],
+        };
+        let section = eh_frame(&description, 0x1000, 0x20);
+
+        // CIE
+        assert_eq!(&[20, 0, 0, 0], &section[0..4]);
+        assert_eq!(
+            &[0, 0, 0, 0, 1, b'z', b'R', 0, 1, 0x78, 16, 1, 0],
+            &section[4..17]
+        );
+        // FDE, pointing back to the CIE, and covering the code:
+        let fde = &section[24..];
+        assert_eq!(28, u32::from_le_bytes(fde[4..8].try_into().unwrap()));
+        assert_eq!(0x1000, u64::from_le_bytes(fde[8..16].try_into().unwrap()));
+        assert_eq!(0x20, u64::from_le_bytes(fde[16..24].try_into().unwrap()));
+        #[rustfmt::skip]
+        assert_eq!(
+            &[
+                0,                      // no augmentation data
+                DW_CFA_DEF_CFA, 7, 8,
+                DW_CFA_OFFSET | 16, 1,
+                DW_CFA_ADVANCE_LOC | 1,
+                DW_CFA_DEF_CFA_OFFSET, 16,
+                DW_CFA_ADVANCE_LOC1, 99,
+                DW_CFA_DEF_CFA_REGISTER, 6,
+            ],
+            &fde[24..37]
+        );
+        // Every entry is aligned, and the section ends with a zero:
+        assert_eq!(4, section.len() % 8);
+        assert_eq!(&[0, 0, 0, 0], &section[section.len() - 4..]);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn backtraces_get_through_generated_code() {
+        use crate::parsing::parse;
+        use crate::BrainmuckProgram;
+        use std::backtrace::Backtrace;
+        use std::cell::RefCell;
+
+        thread_local! {
+            static TRACE: RefCell<String> = const { RefCell::new(String::new()) };
+        }
+        fn trace(_: u32) -> u32 {
+            TRACE.with(|trace| *trace.borrow_mut() = Backtrace::force_capture().to_string());
+            1
+        }
+        fn letter() -> u32 {
+            b'a' as u32
+        }
+
+        let program = crate::compile_to_native_code(&parse("<test>", b",.").unwrap());
+        program
+            .run_with_custom_io(&mut [0u8; 4], trace, letter)
+            .unwrap();
+
+        // What called the generated code is only in the backtrace if the unwinder got through it.
+        let trace = TRACE.with(|trace| trace.take());
+        assert!(
+            trace.contains("run_with_custom_io"),
+            "the backtrace stopped at the generated code:\n{}",
+            trace
+        );
+    }
+
+    #[test]
+    fn encodes_leb128() {
+        let uleb = |n| {
+            let mut out = Vec::new();
+            write_uleb128(&mut out, n);
+            out
+        };
+        let sleb = |n| {
+            let mut out = Vec::new();
+            write_sleb128(&mut out, n);
+            out
+        };
+
+        assert_eq!(vec![2], uleb(2));
+        assert_eq!(vec![0xe5, 0x8e, 0x26], uleb(624485));
+        assert_eq!(vec![0x78], sleb(-8));
+        assert_eq!(vec![0xc0, 0xbb, 0x78], sleb(-123456));
+        assert_eq!(vec![0x3f], sleb(63));
+        assert_eq!(vec![0xc0, 0x00], sleb(64));
+    }
+}
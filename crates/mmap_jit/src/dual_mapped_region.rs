@@ -0,0 +1,167 @@
+use std::ops::{Deref, DerefMut};
+use std::ptr;
+
+use errno::errno;
+use libc::{c_int, c_void, size_t};
+
+use crate::mapped_region::{flush_instruction_cache, round_up_to_page};
+use crate::MappingError;
+
+/// The same memory, mapped twice: once readable and writable, and once readable and executable.
+/// Code can be patched through the writable view while it's running from the executable one,
+/// without changing the protection of either.
+///
+/// Indexing and borrowing from the region goes through the writable view. After patching code
+/// that has already run, call [DualMappedRegion::flush_instruction_cache] before running it again.
+///
+/// The memory is a `memfd` on Linux, and an unlinked POSIX shared memory object elsewhere.
+///
+/// ```
+/// use mmap_jit::{as_function, examples, DualMappedRegion};
+///
+/// let mut code = DualMappedRegion::allocate(4096).unwrap();
+/// examples::write_square_function(&mut code);
+/// code.flush_instruction_cache();
+/// assert_ne!(code.addr(), code.writable_addr() as *const u8);
+///
+/// let f = unsafe { as_function!(code, fn(u64) -> u64) };
+/// assert_eq!(16, f(4));
+/// ```
+pub struct DualMappedRegion {
+    writable: *mut c_void,
+    executable: *mut c_void,
+    len: size_t,
+    capacity: size_t,
+}
+
+impl DualMappedRegion {
+    /// Allocate a region of the given size (in bytes), rounded up to whole pages, and map it both
+    /// ways.
+    pub fn allocate(size: usize) -> crate::Result<Self> {
+        use libc::{PROT_EXEC, PROT_READ, PROT_WRITE};
+
+        if size == 0 {
+            return Err(MappingError::InvalidSize(size));
+        }
+        let capacity = round_up_to_page(size)?;
+        let file_size =
+            libc::off_t::try_from(capacity).map_err(|_| MappingError::InvalidSize(size))?;
+
+        let fd = shared_memory()?;
+        let mapped = unsafe {
+            if libc::ftruncate(fd, file_size) < 0 {
+                Err(errno().into())
+            } else {
+                map_shared(fd, capacity, PROT_READ | PROT_WRITE).and_then(|writable| {
+                    match map_shared(fd, capacity, PROT_READ | PROT_EXEC) {
+                        Ok(executable) => Ok((writable, executable)),
+                        Err(e) => {
+                            libc::munmap(writable, capacity);
+                            Err(e)
+                        }
+                    }
+                })
+            }
+        };
+        // The mappings keep the memory alive without the file descriptor.
+        unsafe { libc::close(fd) };
+
+        let (writable, executable) = mapped?;
+        Ok(DualMappedRegion {
+            writable,
+            executable,
+            len: size,
+            capacity,
+        })
+    }
+
+    /// Returns the address of the executable view.
+    ///
+    /// Use [as_function!](crate::as_function) to call this region of memory like a function.
+    pub fn addr(&self) -> *const u8 {
+        self.executable as *const u8
+    }
+
+    /// Returns the address of the writable view.
+    pub fn writable_addr(&self) -> *mut u8 {
+        self.writable as *mut u8
+    }
+
+    /// Makes sure that the executable view runs what was written, and not stale instructions
+    /// from the instruction cache. Only AArch64 needs this.
+    pub fn flush_instruction_cache(&self) {
+        unsafe { flush_instruction_cache(self.executable, self.len) }
+    }
+}
+
+// Both views belong to the region alone, like a MappedRegion's memory.
+unsafe impl Send for DualMappedRegion {}
+unsafe impl Sync for DualMappedRegion {}
+
+impl Drop for DualMappedRegion {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.writable, self.capacity);
+            libc::munmap(self.executable, self.capacity);
+        }
+    }
+}
+
+impl Deref for DualMappedRegion {
+    type Target = [u8];
+    fn deref(&self) -> &Self::Target {
+        unsafe { std::slice::from_raw_parts(self.writable as *const u8, self.len) }
+    }
+}
+
+impl DerefMut for DualMappedRegion {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { std::slice::from_raw_parts_mut(self.writable as *mut u8, self.len) }
+    }
+}
+
+unsafe fn map_shared(fd: c_int, size: usize, protection: c_int) -> crate::Result<*mut c_void> {
+    let memory = libc::mmap(ptr::null_mut(), size, protection, libc::MAP_SHARED, fd, 0);
+    if memory == libc::MAP_FAILED {
+        return Err(errno().into());
+    }
+    Ok(memory)
+}
+
+/// A new, empty file in memory, which nothing else can open.
+#[cfg(target_os = "linux")]
+fn shared_memory() -> crate::Result<c_int> {
+    let fd = unsafe { libc::memfd_create(c"mmap_jit".as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(errno().into());
+    }
+    Ok(fd)
+}
+
+/// A new, empty file in memory, which nothing else can open, since it's unlinked right away.
+#[cfg(not(target_os = "linux"))]
+fn shared_memory() -> crate::Result<c_int> {
+    use std::ffi::CString;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let name = format!(
+        "/mmap_jit.{}.{}",
+        std::process::id(),
+        COUNT.fetch_add(1, Ordering::Relaxed)
+    );
+    let name = CString::new(name).unwrap();
+
+    unsafe {
+        let fd = libc::shm_open(
+            name.as_ptr(),
+            libc::O_RDWR | libc::O_CREAT | libc::O_EXCL,
+            0o600,
+        );
+        if fd < 0 {
+            return Err(errno().into());
+        }
+        libc::shm_unlink(name.as_ptr());
+        Ok(fd)
+    }
+}
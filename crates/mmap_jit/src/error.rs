@@ -1,11 +1,28 @@
+use std::error::Error;
+use std::fmt;
+
 use errno::Errno;
 
+use crate::Protection;
+
 pub type Result<T> = std::result::Result<T, MappingError>;
 
 /// Any error thrown while mapping memory.
 #[derive(Debug, Clone)]
 pub enum MappingError {
+    /// A call to the operating system failed, with this error number.
     Internal(Errno),
+    /// Nothing can be mapped with this many bytes: either none, or too many to round up to whole
+    /// pages.
+    InvalidSize(usize),
+    /// The memory couldn't be made accessible in a different way.
+    ProtectFailed {
+        from: Protection,
+        to: Protection,
+        error: Errno,
+    },
+    /// This platform can't do what was asked.
+    Unsupported(&'static str),
 }
 
 impl From<Errno> for MappingError {
@@ -13,3 +30,27 @@ impl From<Errno> for MappingError {
         MappingError::Internal(e)
     }
 }
+
+impl fmt::Display for MappingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MappingError::Internal(e) => write!(f, "could not map memory: {}", e),
+            MappingError::InvalidSize(size) => write!(f, "cannot map a region of {} bytes", size),
+            MappingError::ProtectFailed { from, to, error } => write!(
+                f,
+                "could not make memory {} instead of {}: {}",
+                to, from, error
+            ),
+            MappingError::Unsupported(what) => write!(f, "{} is not supported here", what),
+        }
+    }
+}
+
+impl Error for MappingError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MappingError::Internal(e) | MappingError::ProtectFailed { error: e, .. } => Some(e),
+            _ => None,
+        }
+    }
+}
@@ -10,7 +10,16 @@ use crate::{ExecutableRegion, WritableRegion};
 /// If the target architecture is not supported. Currently, only `x86_64` and `aarch64` are
 /// supported.
 pub fn write_square_function(buffer: &mut [u8]) {
-    let instructions = if cfg!(target_arch = "x86_64") {
+    let instructions = if cfg!(all(target_arch = "x86_64", windows)) {
+        [
+            // move rax, rcx
+            0x48, 0x89, 0xC8, //
+            // imul rax, rcx
+            0x48, 0x0F, 0xAF, 0xC1, //
+            // ret
+            0xC3u8, //
+        ]
+    } else if cfg!(target_arch = "x86_64") {
         [
             // move rax, rdi
             0x48, 0x89, 0xF8, //
@@ -34,6 +43,28 @@ pub fn write_square_function(buffer: &mut [u8]) {
     buffer[0..n].copy_from_slice(&instructions);
 }
 
+/// Writes a program equivilent to `fn(x: u64) -> u64 { x }` to the given buffer.
+///
+/// # Panics
+///
+/// If the target architecture is not supported, like [write_square_function].
+pub fn write_identity_function(buffer: &mut [u8]) {
+    let instructions: &[u8] = if cfg!(all(target_arch = "x86_64", windows)) {
+        // mov rax, rcx ; ret
+        &[0x48, 0x89, 0xC8, 0xC3]
+    } else if cfg!(target_arch = "x86_64") {
+        // mov rax, rdi ; ret
+        &[0x48, 0x89, 0xF8, 0xC3]
+    } else if cfg!(target_arch = "aarch64") {
+        // ret
+        &[0xc0, 0x03, 0x5f, 0xd6]
+    } else {
+        panic!("no program for arch")
+    };
+
+    buffer[..instructions.len()].copy_from_slice(instructions);
+}
+
 /// Returns an [ExecutableRegion] with the program created by [write_square_function].
 pub fn generate_square_program() -> ExecutableRegion {
     let mut mem = WritableRegion::allocate(4096).unwrap();
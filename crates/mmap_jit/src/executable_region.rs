@@ -1,31 +1,126 @@
-use errno::errno;
+use std::borrow::Borrow;
+use std::ops::{Deref, Index};
+use std::sync::Arc;
 
+use crate::mapped_region::Protection;
+use crate::JitCallable;
 use crate::MappedRegion;
+use crate::WritableRegion;
 
-/// An executable region of memory. Use [as_function!] to run code from here!
+/// An executable region of memory. Use [ExecutableRegion::as_fn] to run code from here!
 pub struct ExecutableRegion {
     region: MappedRegion,
 }
 
 impl ExecutableRegion {
-    /// Consumes the [MappedRegion] and marks its memory as read-only and executable.
-    pub fn from(region: MappedRegion) -> crate::Result<Self> {
-        use libc::{PROT_EXEC, PROT_READ};
-
-        unsafe {
-            let addr = region.addr_mut() as *mut libc::c_void;
-            if libc::mprotect(addr, region.len(), PROT_READ | PROT_EXEC) < 0 {
-                return Err(errno().into());
-            }
-        }
-
+    /// Consumes the [MappedRegion] and marks its memory as read-only and executable. The
+    /// instruction cache is flushed, on processors that need it, so that the code that was
+    /// written is what runs.
+    pub fn from(mut region: MappedRegion) -> crate::Result<Self> {
+        region.protect_all(Protection::ReadExecute)?;
+        region.flush_instruction_cache();
         Ok(Self { region })
     }
 
     /// Returns the address of the mapped memory.
     ///
-    /// Use [as_function!] to call this region of memory like a function.
+    /// Use [ExecutableRegion::as_fn] to call this region of memory like a function.
     pub fn addr(&self) -> *const u8 {
         self.region.addr()
     }
+
+    /// The code at the start of the region, as a function of type `F`.
+    ///
+    /// ```
+    /// let code = mmap_jit::examples::generate_square_program();
+    /// let square = unsafe { code.as_fn::<extern "C" fn(u64) -> u64>() };
+    /// assert_eq!(16, square(4));
+    /// ```
+    ///
+    /// # Safety
+    ///
+    /// The code must be a function of type `F`, that follows the platform's calling convention, and
+    /// leaves memory the way that Rust expects it. The function must not be called once the region
+    /// is dropped.
+    pub unsafe fn as_fn<F: JitCallable>(&self) -> F {
+        F::from_addr(self.addr())
+    }
+
+    /// The code in the region, e.g., to disassemble or save it.
+    ///
+    /// ```
+    /// use mmap_jit::{examples, WritableRegion};
+    ///
+    /// let mut w = WritableRegion::allocate(64).unwrap();
+    /// examples::write_square_function(&mut w);
+    /// let written = w.to_vec();
+    ///
+    /// let code = w.into_executable().unwrap();
+    /// assert_eq!(&written[..], code.as_slice());
+    /// ```
+    pub fn as_slice(&self) -> &[u8] {
+        &self.region[..]
+    }
+
+    /// Consumes the region and makes its memory writable (and no longer executable) again, e.g.,
+    /// to patch the code. Make it executable again with [WritableRegion::into_executable].
+    pub fn into_writable(self) -> crate::Result<WritableRegion> {
+        WritableRegion::from(self.region)
+    }
+}
+
+/// An [ExecutableRegion] that can be cloned, so that several threads can run its code at once. The
+/// memory is unmapped once the last clone is dropped.
+///
+/// ```
+/// use mmap_jit::{examples, SharedExecutableRegion};
+///
+/// let code = SharedExecutableRegion::from(examples::generate_square_program());
+/// let threads: Vec<_> = (0..4u64)
+///     .map(|x| {
+///         let code = code.clone();
+///         std::thread::spawn(move || {
+///             let square = unsafe { code.as_fn::<extern "C" fn(u64) -> u64>() };
+///             square(x)
+///         })
+///     })
+///     .collect();
+/// let squares: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+/// assert_eq!(vec![0, 1, 4, 9], squares);
+/// ```
+#[derive(Clone)]
+pub struct SharedExecutableRegion {
+    region: Arc<ExecutableRegion>,
+}
+
+impl From<ExecutableRegion> for SharedExecutableRegion {
+    fn from(region: ExecutableRegion) -> Self {
+        SharedExecutableRegion {
+            region: Arc::new(region),
+        }
+    }
+}
+
+impl Deref for SharedExecutableRegion {
+    type Target = ExecutableRegion;
+    fn deref(&self) -> &Self::Target {
+        &self.region
+    }
+}
+
+impl<I> Index<I> for ExecutableRegion
+where
+    I: std::slice::SliceIndex<[u8]>,
+{
+    type Output = I::Output;
+
+    fn index(&self, index: I) -> &Self::Output {
+        &self.region[index]
+    }
+}
+
+impl Borrow<[u8]> for ExecutableRegion {
+    fn borrow(&self) -> &[u8] {
+        &self.region[..]
+    }
 }
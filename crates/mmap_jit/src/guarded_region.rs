@@ -0,0 +1,84 @@
+use std::ops::{Deref, DerefMut, Range};
+
+use crate::mapped_region::{round_up_to_page, Protection};
+use crate::{MappedRegion, MappingError};
+
+/// Readable, writable memory with an inaccessible guard region on either side of it. Touching a
+/// guard raises `SIGSEGV` (or `SIGBUS`, or an access violation on Windows), instead of quietly
+/// reading or writing whatever happens to be mapped next to the memory.
+///
+/// The memory starts right after the lower guard, but the upper guard starts at a page boundary,
+/// so up to a page's worth of bytes past the end are accessible.
+///
+/// ```
+/// use mmap_jit::GuardedRegion;
+///
+/// let mut memory = GuardedRegion::allocate(100, 4096).unwrap();
+/// memory[99] = 42;
+/// assert_eq!(100, memory.len());
+/// assert!(memory.guard_len() >= 4096);
+///
+/// let (below, above) = memory.guards();
+/// assert_eq!(memory.as_ptr() as usize, below.end);
+/// assert!(memory.as_ptr() as usize + 4096 <= above.start);
+/// ```
+pub struct GuardedRegion {
+    region: MappedRegion,
+    guard: usize,
+    len: usize,
+}
+
+impl GuardedRegion {
+    /// Allocates `size` bytes between two guards of at least `guard` bytes. Both are rounded up
+    /// to whole pages.
+    pub fn allocate(size: usize, guard: usize) -> crate::Result<Self> {
+        let guard = round_up_to_page(guard)?;
+        let accessible = round_up_to_page(size)?;
+        let total = accessible
+            .checked_add(guard)
+            .and_then(|total| total.checked_add(guard))
+            .ok_or(MappingError::InvalidSize(size))?;
+        // Mapped memory starts out inaccessible, so only the middle needs to change.
+        let region = MappedRegion::allocate(total)?;
+
+        if accessible > 0 {
+            region.protect(guard, accessible, Protection::ReadWrite)?;
+        }
+
+        Ok(GuardedRegion {
+            region,
+            guard,
+            len: size,
+        })
+    }
+
+    /// Returns a mutable pointer to the first accessible byte.
+    pub fn addr_mut(&self) -> *mut u8 {
+        unsafe { self.region.addr_mut().add(self.guard) }
+    }
+
+    /// How many bytes are in each guard.
+    pub fn guard_len(&self) -> usize {
+        self.guard
+    }
+
+    /// The addresses of the guard below the memory, and the guard above it.
+    pub fn guards(&self) -> (Range<usize>, Range<usize>) {
+        let start = self.region.addr() as usize;
+        let end = start + self.region.len();
+        (start..start + self.guard, end - self.guard..end)
+    }
+}
+
+impl Deref for GuardedRegion {
+    type Target = [u8];
+    fn deref(&self) -> &Self::Target {
+        unsafe { std::slice::from_raw_parts(self.addr_mut(), self.len) }
+    }
+}
+
+impl DerefMut for GuardedRegion {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { std::slice::from_raw_parts_mut(self.addr_mut(), self.len) }
+    }
+}
@@ -0,0 +1,43 @@
+/// The function pointer types that [ExecutableRegion::as_fn](crate::ExecutableRegion::as_fn) can
+/// cast code to: `extern "C"` and `extern "C-unwind"` functions of up to six arguments, which is
+/// as many as the C calling convention passes in registers on x86_64 and AArch64.
+///
+/// Use `extern "C-unwind"` when the code can unwind, e.g., when it calls a Rust function that
+/// panics.
+pub trait JitCallable: sealed::Sealed + Copy {
+    /// The code at `addr`, as this type of function.
+    ///
+    /// # Safety
+    ///
+    /// The code must be a function of this type.
+    #[doc(hidden)]
+    unsafe fn from_addr(addr: *const u8) -> Self;
+}
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+macro_rules! impl_jit_callable {
+    ($($arg: ident),*) => {
+        impl_jit_callable!("C"; $($arg),*);
+        impl_jit_callable!("C-unwind"; $($arg),*);
+    };
+    ($abi: literal; $($arg: ident),*) => {
+        impl<R, $($arg),*> sealed::Sealed for extern $abi fn($($arg),*) -> R {}
+
+        impl<R, $($arg),*> JitCallable for extern $abi fn($($arg),*) -> R {
+            unsafe fn from_addr(addr: *const u8) -> Self {
+                std::mem::transmute::<*const u8, Self>(addr)
+            }
+        }
+    };
+}
+
+impl_jit_callable!();
+impl_jit_callable!(A);
+impl_jit_callable!(A, B);
+impl_jit_callable!(A, B, C);
+impl_jit_callable!(A, B, C, D);
+impl_jit_callable!(A, B, C, D, E);
+impl_jit_callable!(A, B, C, D, E, F);
@@ -0,0 +1,122 @@
+use std::cell::Cell;
+use std::ops::Deref;
+use std::ptr;
+
+use errno::errno;
+use libc::{c_void, size_t};
+
+use crate::mapped_region::flush_instruction_cache;
+use crate::MappingError;
+
+thread_local! {
+    /// How many calls to [JitRegion::write] this thread is in, so that only the outermost one
+    /// protects the memory again.
+    static WRITING: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Memory that's mapped readable, writable, and executable all at once, with `MAP_JIT`, for
+/// processes with the hardened runtime, which can't make memory executable with `mprotect(2)`.
+///
+/// Each thread sees `MAP_JIT` memory as either writable or executable, never both. The memory is
+/// executable, except inside [JitRegion::write], which makes it writable on this thread for as
+/// long as it takes to write the code. Other threads can run code from it all the while.
+///
+/// ```
+/// use mmap_jit::{as_function, examples, JitRegion};
+///
+/// let mut code = JitRegion::allocate(4096).unwrap();
+/// code.write(|bytes| examples::write_square_function(bytes));
+/// let f = unsafe { as_function!(code, fn(u64) -> u64) };
+/// assert_eq!(16, f(4));
+/// ```
+pub struct JitRegion {
+    addr: *mut c_void,
+    len: size_t,
+}
+
+impl JitRegion {
+    /// Allocate a region of the given size (in bytes), for code.
+    pub fn allocate(size: usize) -> crate::Result<Self> {
+        use libc::{MAP_ANON, MAP_FAILED, MAP_JIT, MAP_PRIVATE, PROT_EXEC, PROT_READ, PROT_WRITE};
+
+        if unsafe { libc::pthread_jit_write_protect_supported_np() } == 0 {
+            return Err(MappingError::Unsupported(
+                "toggling MAP_JIT write protection",
+            ));
+        }
+
+        let memory = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                PROT_READ | PROT_WRITE | PROT_EXEC,
+                MAP_PRIVATE | MAP_ANON | MAP_JIT,
+                -1,
+                0,
+            )
+        };
+        if memory == MAP_FAILED {
+            return Err(errno().into());
+        }
+
+        Ok(JitRegion {
+            addr: memory,
+            len: size,
+        })
+    }
+
+    /// Makes the memory writable on this thread, and passes it to `f` to write code to. Once `f`
+    /// returns (or panics), the memory is executable again, and the instruction cache is flushed.
+    ///
+    /// Don't run code from any [JitRegion] inside `f`: it isn't executable on this thread until
+    /// `f` returns.
+    pub fn write<T>(&mut self, f: impl FnOnce(&mut [u8]) -> T) -> T {
+        /// Protects the memory again, even if `f` panics.
+        struct Protect;
+        impl Drop for Protect {
+            fn drop(&mut self) {
+                if WRITING.replace(WRITING.get() - 1) == 1 {
+                    unsafe { libc::pthread_jit_write_protect_np(1) };
+                }
+            }
+        }
+
+        if WRITING.replace(WRITING.get() + 1) == 0 {
+            unsafe { libc::pthread_jit_write_protect_np(0) };
+        }
+        let protect = Protect;
+        let result = f(unsafe { std::slice::from_raw_parts_mut(self.addr as *mut u8, self.len) });
+        drop(protect);
+
+        unsafe { flush_instruction_cache(self.addr, self.len) };
+        result
+    }
+
+    /// Returns the address of the mapped memory.
+    ///
+    /// Use [as_function!](crate::as_function) to call this region of memory like a function.
+    pub fn addr(&self) -> *const u8 {
+        self.addr as *const u8
+    }
+}
+
+// Like a MappedRegion, it owns its memory. Writing takes a &mut, so a thread can only make it
+// writable for itself when no other thread has it.
+unsafe impl Send for JitRegion {}
+unsafe impl Sync for JitRegion {}
+
+impl Drop for JitRegion {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.addr, self.len);
+        }
+    }
+}
+
+impl Deref for JitRegion {
+    type Target = [u8];
+    fn deref(&self) -> &Self::Target {
+        // MAP_JIT memory is always readable.
+        unsafe { std::slice::from_raw_parts(self.addr as *const u8, self.len) }
+    }
+}
@@ -1,7 +1,8 @@
 //! Map some memory for writing and executing.
 //!
-//! This crate is a wrapper around `mmap(2)`, `mprotect(2)`, and `munmap(2)` calls that uses Rust's
-//! type system to enforce what you can and can't do with a dynamically mapped region of memory.
+//! This crate is a wrapper around `mmap(2)`, `mprotect(2)`, and `munmap(2)` calls (or
+//! `VirtualAlloc()`, `VirtualProtect()`, and `VirtualFree()` on Windows) that uses Rust's type
+//! system to enforce what you can and can't do with a dynamically mapped region of memory.
 //! The intent is to allocate memory in order to inject machine code into the running executable
 //! and run it. This allows you to create, among other things, a JIT compiler.
 //!
@@ -12,7 +13,7 @@
 //! ```
 //! extern crate mmap_jit;
 //!
-//! use mmap_jit::{MappedRegion, as_function};
+//! use mmap_jit::MappedRegion;
 //!
 //! // Allocate some amount of memory.
 //! let mem = MappedRegion::allocate(4096).unwrap();
@@ -27,27 +28,51 @@
 //! let code = mem.into_executable().unwrap();
 //!
 //! // Congrats, now you have a function!
-//! let f = unsafe { as_function!(code, fn() -> ()) };
+//! let f = unsafe { code.as_fn::<extern "C" fn()>() };
 //! ```
+//!
+//! Processes with the hardened runtime on Apple Silicon can't make memory executable like this.
+//! For them, there's `JitRegion`, which is writable and executable at once, one thread at a time.
+//!
+//! # Threads
+//!
+//! Every region owns its memory, so it can be sent to another thread. Regions can also be shared
+//! between threads, since nothing can write to memory through a shared reference. To run the same
+//! code from several threads, wrap it in a [SharedExecutableRegion], which can be cloned.
 
 extern crate errno;
 extern crate libc;
 
+#[cfg(unix)]
+mod dual_mapped_region;
 mod error;
 mod executable_region;
+mod guarded_region;
+mod jit_callable;
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+mod jit_region;
 mod mapped_region;
 mod writable_region;
 
 pub mod examples;
 
-pub use crate::executable_region::ExecutableRegion;
-pub use crate::mapped_region::MappedRegion;
+#[cfg(unix)]
+pub use crate::dual_mapped_region::DualMappedRegion;
+pub use crate::executable_region::{ExecutableRegion, SharedExecutableRegion};
+pub use crate::guarded_region::GuardedRegion;
+pub use crate::jit_callable::JitCallable;
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+pub use crate::jit_region::JitRegion;
+pub use crate::mapped_region::{page_size, MappedRegion, Protection};
 pub use crate::writable_region::WritableRegion;
 
 pub use crate::error::{MappingError, Result};
 
 /// Cast an [ExecutableRegion] to a function pointer of your choosing.
 ///
+/// [ExecutableRegion::as_fn] does the same, but only casts to `extern "C"` function types, which
+/// is what machine code can actually be called as.
+///
 /// # Examples
 ///
 /// ```
@@ -78,12 +103,26 @@ mod tests {
 
     #[test]
     fn mapping_gives_a_valid_address() -> Result<()> {
-        use libc::{c_void, MAP_FAILED};
-
         let region = MappedRegion::allocate(MAPPING_SIZE)?;
         assert_eq!(MAPPING_SIZE, region.len());
-        assert_ne!(region.addr() as *const c_void, ptr::null());
-        assert_ne!(region.addr() as *const c_void, MAP_FAILED);
+        assert_ne!(region.addr(), ptr::null());
+        #[cfg(unix)]
+        assert_ne!(region.addr() as *const libc::c_void, libc::MAP_FAILED);
+        Ok(())
+    }
+
+    #[test]
+    fn maps_whole_pages_but_only_indexes_what_was_asked_for() -> Result<()> {
+        let region = MappedRegion::allocate(100)?;
+        assert_eq!(100, region.len());
+        assert_eq!(page_size(), region.capacity());
+
+        let mut p = region.into_writable()?;
+        assert_eq!(100, p.len());
+        p.grow(page_size())?;
+        assert_eq!(page_size(), p.len());
+        assert!(p.get(page_size()).is_none());
+
         Ok(())
     }
 
@@ -111,24 +150,138 @@ mod tests {
         let exec = p.into_executable()?;
         assert_eq!(initial_addr, exec.addr());
 
-        let function = unsafe { as_function!(exec, fn(u64) -> u64) };
+        let function = unsafe { exec.as_fn::<extern "C" fn(u64) -> u64>() };
         let res = function(4);
         assert_eq!(16, res);
 
         Ok(())
     }
 
+    #[test]
+    fn growing_keeps_what_was_written() -> Result<()> {
+        let mut p = WritableRegion::allocate(MAPPING_SIZE)?;
+        p[MAPPING_SIZE - 1] = 42;
+
+        p.grow(4 * MAPPING_SIZE)?;
+        assert_eq!(4 * MAPPING_SIZE, p.len());
+        assert_eq!(42, p[MAPPING_SIZE - 1]);
+        p[4 * MAPPING_SIZE - 1] = 43;
+
+        // It never shrinks:
+        p.grow(MAPPING_SIZE)?;
+        assert_eq!(4 * MAPPING_SIZE, p.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn runs_code_written_past_where_the_region_grew() -> Result<()> {
+        let mut p = WritableRegion::allocate(MAPPING_SIZE)?;
+        p.grow(4 * MAPPING_SIZE)?;
+        let offset = 3 * MAPPING_SIZE;
+        examples::write_square_function(&mut p[offset..]);
+
+        let exec = p.into_executable()?;
+        let function: extern "C" fn(u64) -> u64 =
+            unsafe { std::mem::transmute(exec.addr().add(offset)) };
+        assert_eq!(16, function(4));
+
+        Ok(())
+    }
+
+    #[test]
+    fn convert_executable_region_back_to_writable() -> Result<()> {
+        let exec = examples::generate_square_program();
+        let initial_addr = exec.addr();
+
+        let mut p = exec.into_writable()?;
+        assert_eq!(initial_addr, p.as_ptr());
+        examples::write_identity_function(&mut p);
+
+        let exec = p.into_executable()?;
+        let function = unsafe { exec.as_fn::<extern "C" fn(u64) -> u64>() };
+        assert_eq!(4, function(4));
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn patches_code_while_it_stays_executable() -> Result<()> {
+        let mut region = DualMappedRegion::allocate(MAPPING_SIZE)?;
+        examples::write_square_function(&mut region);
+        region.flush_instruction_cache();
+        let function = unsafe { as_function!(region, fn(u64) -> u64) };
+        assert_eq!(16, function(4));
+
+        examples::write_identity_function(&mut region);
+        region.flush_instruction_cache();
+        assert_eq!(4, function(4));
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn dual_mapping_rejects_sizes_it_cannot_map() -> Result<()> {
+        let region = DualMappedRegion::allocate(100)?;
+        assert_eq!(100, region.len());
+
+        assert!(matches!(
+            DualMappedRegion::allocate(0),
+            Err(MappingError::InvalidSize(0))
+        ));
+        assert!(matches!(
+            DualMappedRegion::allocate(usize::MAX),
+            Err(MappingError::InvalidSize(usize::MAX))
+        ));
+
+        Ok(())
+    }
+
     #[test]
     fn should_error_if_mapping_entire_address_space() {
+        match MappedRegion::allocate(usize::MAX) {
+            Ok(_) => {
+                panic!("that should not have worked...");
+            }
+            Err(MappingError::InvalidSize(size)) => {
+                assert_eq!(usize::MAX, size);
+            }
+            Err(e) => panic!("expected an invalid size, not {:?}", e),
+        }
+        assert!(matches!(
+            MappedRegion::allocate(0),
+            Err(MappingError::InvalidSize(0))
+        ));
+    }
+
+    #[test]
+    fn should_error_if_the_guards_do_not_fit() {
+        for (size, guard) in [(usize::MAX, 4096), (100, usize::MAX), (100, usize::MAX / 2)] {
+            assert!(matches!(
+                GuardedRegion::allocate(size, guard),
+                Err(MappingError::InvalidSize(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn should_error_if_the_system_cannot_map_that_much() {
         use errno::Errno;
+        use std::error::Error;
 
-        match MappedRegion::allocate(usize::MAX) {
+        // A multiple of any page size, but far more than there is address space for:
+        match MappedRegion::allocate(1 << 62) {
             Ok(_) => {
                 panic!("that should not have worked...");
             }
-            Err(MappingError::Internal(Errno(c))) => {
-                assert!(c > 0, "expected an error value, such as EINVAL");
+            Err(e @ MappingError::Internal(Errno(c))) => {
+                assert!(c > 0, "expected an error value, such as ENOMEM");
+                assert!(e.to_string().starts_with("could not map memory: "));
+                assert!(e.source().is_some());
             }
+            Err(e) => panic!("expected an error from the system, not {:?}", e),
         }
     }
 }
@@ -2,48 +2,123 @@ use std::borrow::Borrow;
 use std::ops::{Drop, Index};
 use std::ptr;
 
-use errno::errno;
+use errno::{errno, Errno};
 use libc::{c_void, size_t};
 
-use crate::WritableRegion;
+use crate::{MappingError, WritableRegion};
 
-#[cfg(target_os = "macos")]
-const MAP_FAILED: *mut c_void = (!0usize) as *mut c_void;
-
-/// A region of memory mapped by `mmap(2)`.
+/// A region of memory mapped by `mmap(2)` (or `VirtualAlloc()` on Windows).
+///
+/// Memory is mapped a page at a time, so the region's [capacity](MappedRegion::capacity) is its
+/// size rounded up to a whole number of pages. Only the first [len](MappedRegion::len) bytes can be
+/// indexed, though.
 ///
-/// The `munmap(2)` is automatically called when the value is dropped.
+/// The `munmap(2)` (or `VirtualFree()`) is automatically called when the value is dropped.
 pub struct MappedRegion {
     addr: *mut c_void,
     len: size_t,
+    capacity: size_t,
+    // What the whole region was last made
+    protection: Protection,
 }
 
-impl MappedRegion {
-    /// Allocate a region of the given size (in bytes).
-    pub fn allocate(size: usize) -> crate::Result<Self> {
-        use libc::{MAP_ANON, MAP_JIT, MAP_PRIVATE};
-        let memory;
-        unsafe {
-            memory = libc::mmap(
-                ptr::null_mut(),
-                size,
-                0,
-                MAP_PRIVATE | MAP_ANON | MAP_JIT,
-                -1,
-                0,
-            );
+/// What can be done with mapped memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protection {
+    /// Nothing; any access faults.
+    None,
+    ReadWrite,
+    ReadExecute,
+}
+
+impl std::fmt::Display for Protection {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Protection::None => write!(f, "inaccessible"),
+            Protection::ReadWrite => write!(f, "readable and writable"),
+            Protection::ReadExecute => write!(f, "readable and executable"),
         }
+    }
+}
 
-        if memory == MAP_FAILED {
-            return Err(errno().into());
+impl MappedRegion {
+    /// Allocate a region of the given size (in bytes), rounded up to whole pages. Its memory can't
+    /// be accessed at all until it's made writable.
+    pub fn allocate(size: usize) -> crate::Result<Self> {
+        if size == 0 {
+            return Err(MappingError::InvalidSize(size));
         }
+        let capacity = round_up_to_page(size)?;
+        let memory = map(capacity)?;
 
         Ok(MappedRegion {
             addr: memory,
             len: size,
+            capacity,
+            protection: Protection::None,
         })
     }
 
+    /// Changes what can be done with `len` bytes of the region, starting `offset` bytes in. Both
+    /// should be page-aligned.
+    pub(crate) fn protect(
+        &self,
+        offset: usize,
+        len: usize,
+        protection: Protection,
+    ) -> crate::Result<()> {
+        assert!(
+            offset + len <= self.capacity,
+            "protecting beyond the region"
+        );
+        unsafe { protect(self.addr.add(offset), len, protection) }.map_err(|error| {
+            MappingError::ProtectFailed {
+                from: self.protection,
+                to: protection,
+                error,
+            }
+        })
+    }
+
+    /// Changes what can be done with the whole region.
+    pub(crate) fn protect_all(&mut self, protection: Protection) -> crate::Result<()> {
+        self.protect(0, self.capacity, protection)?;
+        self.protection = protection;
+        Ok(())
+    }
+
+    /// Makes the region `new_size` bytes long, without mapping anything, if it has the capacity.
+    pub(crate) fn extend_within_capacity(&mut self, new_size: usize) -> bool {
+        if new_size > self.capacity {
+            return false;
+        }
+        self.len = self.len.max(new_size);
+        true
+    }
+
+    /// Makes the region `new_size` bytes long, keeping what's in it, and moving it if it has to.
+    /// The new memory can be accessed the same way as the old.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn remap(&mut self, new_size: usize) -> crate::Result<()> {
+        let capacity = round_up_to_page(new_size)?;
+        let memory =
+            unsafe { libc::mremap(self.addr, self.capacity, capacity, libc::MREMAP_MAYMOVE) };
+        if memory == libc::MAP_FAILED {
+            return Err(errno().into());
+        }
+        self.addr = memory;
+        self.len = new_size;
+        self.capacity = capacity;
+        Ok(())
+    }
+
+    /// Makes sure that the processor runs what was written to the region, and not stale
+    /// instructions from its instruction cache. On AArch64, the instruction cache doesn't see
+    /// writes by itself.
+    pub(crate) fn flush_instruction_cache(&self) {
+        unsafe { flush_instruction_cache(self.addr, self.len) }
+    }
+
     /// Returns a pointer to mapped memory.
     pub fn addr(&self) -> *const u8 {
         self.addr as *const u8
@@ -56,11 +131,16 @@ impl MappedRegion {
         self.addr as *mut u8
     }
 
-    /// Return the length of region.
+    /// Return the length of region, as it was asked for.
     pub fn len(&self) -> usize {
         self.len
     }
 
+    /// Return how many bytes are actually mapped: the length, rounded up to whole pages.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
     /// Return whether the size of this region is empty.
     pub fn is_empty(&self) -> bool {
         self.len == 0
@@ -72,11 +152,16 @@ impl MappedRegion {
     }
 }
 
+// The region owns its memory outright, like a Box<[u8]>, so it can go to (and be shared with)
+// any thread. The regions that wrap it inherit this.
+unsafe impl Send for MappedRegion {}
+unsafe impl Sync for MappedRegion {}
+
 impl Drop for MappedRegion {
     fn drop(&mut self) {
         unsafe {
             // TODO: check return value
-            libc::munmap(self.addr, self.len);
+            unmap(self.addr, self.capacity);
         }
     }
 }
@@ -97,3 +182,140 @@ impl Borrow<[u8]> for MappedRegion {
         &self[..]
     }
 }
+
+/// The size rounded up to whole pages, unless that's too big to map.
+pub(crate) fn round_up_to_page(size: usize) -> crate::Result<usize> {
+    size.checked_next_multiple_of(page_size())
+        .ok_or(MappingError::InvalidSize(size))
+}
+
+// Each platform's calls ///////////////////////////////////////////////////////////////////////////
+
+#[cfg(unix)]
+fn map(size: usize) -> crate::Result<*mut c_void> {
+    use libc::{MAP_ANON, MAP_PRIVATE};
+
+    // The hardened runtime on macOS only lets memory become executable if it's mapped for it.
+    #[cfg(target_os = "macos")]
+    let flags = MAP_PRIVATE | MAP_ANON | libc::MAP_JIT;
+    #[cfg(not(target_os = "macos"))]
+    let flags = MAP_PRIVATE | MAP_ANON;
+
+    let memory;
+    unsafe {
+        memory = libc::mmap(ptr::null_mut(), size, 0, flags, -1, 0);
+    }
+
+    if memory == libc::MAP_FAILED {
+        return Err(errno().into());
+    }
+    Ok(memory)
+}
+
+#[cfg(unix)]
+unsafe fn protect(addr: *mut c_void, len: usize, protection: Protection) -> Result<(), Errno> {
+    use libc::{PROT_EXEC, PROT_NONE, PROT_READ, PROT_WRITE};
+
+    let flags = match protection {
+        Protection::None => PROT_NONE,
+        Protection::ReadWrite => PROT_READ | PROT_WRITE,
+        Protection::ReadExecute => PROT_READ | PROT_EXEC,
+    };
+    if libc::mprotect(addr, len, flags) < 0 {
+        return Err(errno());
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+unsafe fn unmap(addr: *mut c_void, len: usize) {
+    libc::munmap(addr, len);
+}
+
+#[cfg(all(target_arch = "aarch64", target_vendor = "apple"))]
+pub(crate) unsafe fn flush_instruction_cache(addr: *mut c_void, len: usize) {
+    extern "C" {
+        fn sys_icache_invalidate(start: *mut c_void, len: size_t);
+    }
+    sys_icache_invalidate(addr, len);
+}
+
+#[cfg(all(target_arch = "aarch64", unix, not(target_vendor = "apple")))]
+pub(crate) unsafe fn flush_instruction_cache(addr: *mut c_void, len: usize) {
+    // From libgcc (or compiler-rt), which is what GCC's __builtin___clear_cache() calls.
+    extern "C" {
+        fn __clear_cache(start: *mut libc::c_char, end: *mut libc::c_char);
+    }
+    let start = addr as *mut libc::c_char;
+    __clear_cache(start, start.add(len));
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+pub(crate) unsafe fn flush_instruction_cache(_addr: *mut c_void, _len: usize) {
+    // x86_64 keeps the instruction cache coherent with writes.
+}
+
+/// The size of a page, which is the smallest amount of memory that can be mapped or protected.
+#[cfg(unix)]
+pub fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+#[cfg(windows)]
+fn map(size: usize) -> crate::Result<*mut c_void> {
+    use windows_sys::Win32::System::Memory::{
+        VirtualAlloc, MEM_COMMIT, MEM_RESERVE, PAGE_NOACCESS,
+    };
+
+    let memory =
+        unsafe { VirtualAlloc(ptr::null(), size, MEM_COMMIT | MEM_RESERVE, PAGE_NOACCESS) };
+    if memory.is_null() {
+        // errno() is GetLastError() on Windows.
+        return Err(errno().into());
+    }
+    Ok(memory)
+}
+
+#[cfg(windows)]
+unsafe fn protect(addr: *mut c_void, len: usize, protection: Protection) -> Result<(), Errno> {
+    use windows_sys::Win32::System::Memory::{
+        VirtualProtect, PAGE_EXECUTE_READ, PAGE_NOACCESS, PAGE_PROTECTION_FLAGS, PAGE_READWRITE,
+    };
+
+    let flags = match protection {
+        Protection::None => PAGE_NOACCESS,
+        Protection::ReadWrite => PAGE_READWRITE,
+        Protection::ReadExecute => PAGE_EXECUTE_READ,
+    };
+    let mut previous: PAGE_PROTECTION_FLAGS = 0;
+    if VirtualProtect(addr as *const _, len, flags, &mut previous) == 0 {
+        return Err(errno());
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+unsafe fn unmap(addr: *mut c_void, _len: usize) {
+    use windows_sys::Win32::System::Memory::{VirtualFree, MEM_RELEASE};
+
+    // Releasing frees the whole allocation, so it has to be given a length of zero.
+    VirtualFree(addr as *mut _, 0, MEM_RELEASE);
+}
+
+#[cfg(all(target_arch = "aarch64", windows))]
+pub(crate) unsafe fn flush_instruction_cache(addr: *mut c_void, len: usize) {
+    use windows_sys::Win32::System::Diagnostics::Debug::FlushInstructionCache;
+    use windows_sys::Win32::System::Threading::GetCurrentProcess;
+
+    FlushInstructionCache(GetCurrentProcess(), addr as *const _, len);
+}
+
+/// The size of a page, which is the smallest amount of memory that can be mapped or protected.
+#[cfg(windows)]
+pub fn page_size() -> usize {
+    use windows_sys::Win32::System::SystemInformation::{GetSystemInfo, SYSTEM_INFO};
+
+    let mut info = SYSTEM_INFO::default();
+    unsafe { GetSystemInfo(&mut info) };
+    info.dwPageSize as usize
+}
@@ -2,8 +2,7 @@ use std::borrow::{Borrow, BorrowMut};
 use std::ops::{Deref, DerefMut, Index, IndexMut};
 use std::slice::SliceIndex;
 
-use errno::errno;
-
+use crate::mapped_region::Protection;
 use crate::ExecutableRegion;
 use crate::MappedRegion;
 
@@ -33,16 +32,8 @@ pub struct WritableRegion {
 
 impl WritableRegion {
     /// Consumes the existing [MappedRegion] and makes its memory writable.
-    pub fn from(region: MappedRegion) -> crate::Result<Self> {
-        use libc::{PROT_READ, PROT_WRITE};
-
-        unsafe {
-            let addr = region.addr_mut() as *mut libc::c_void;
-            if libc::mprotect(addr, region.len(), PROT_READ | PROT_WRITE) < 0 {
-                return Err(errno().into());
-            }
-        }
-
+    pub fn from(mut region: MappedRegion) -> crate::Result<Self> {
+        region.protect_all(Protection::ReadWrite)?;
         Ok(Self { region })
     }
 
@@ -52,7 +43,38 @@ impl WritableRegion {
         WritableRegion::from(region)
     }
 
-    /// Consumes the region and returns an read-only, [ExecutableRegion].
+    /// Makes the region at least `new_size` bytes long, keeping what's written to it. Any capacity
+    /// left in its last page is used first. Past that, the memory may move, so take its address
+    /// again afterwards.
+    ///
+    /// On Linux, this is `mremap(2)`, which moves the pages rather than the bytes. Elsewhere, the
+    /// bytes are copied to a new, bigger region.
+    pub fn grow(&mut self, new_size: usize) -> crate::Result<()> {
+        if self.region.extend_within_capacity(new_size) {
+            return Ok(());
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            self.region.remap(new_size)
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let mut bigger = WritableRegion::allocate(new_size)?;
+            bigger[..self.region.len()].copy_from_slice(self);
+            *self = bigger;
+            Ok(())
+        }
+    }
+
+    /// Returns how many bytes are actually mapped, which can be more than its length. See
+    /// [MappedRegion::capacity].
+    pub fn capacity(&self) -> usize {
+        self.region.capacity()
+    }
+
+    /// Consumes the region and returns an read-only, [ExecutableRegion]. What was written is
+    /// ready to run, even on processors whose instruction cache doesn't see writes (AArch64).
     pub fn into_executable(self) -> crate::Result<ExecutableRegion> {
         ExecutableRegion::from(self.region)
     }
@@ -1,35 +1,177 @@
 extern crate brainmuck_core;
 extern crate structopt;
 
+use std::env;
 use std::error::Error;
 use std::fs;
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
 use structopt::StructOpt;
 
-use brainmuck_core::BrainmuckProgram;
-
-const SIZE_OF_UNIVERSE: usize = 4096;
+use brainmuck_core::bytecode::InterpretedProgram;
+use brainmuck_core::errors::Location;
+use brainmuck_core::evaluator::EvaluatedProgram;
+use brainmuck_core::frontend::BrainfuckFrontend;
+use brainmuck_core::ir::{self, ControlFlowGraph};
+use brainmuck_core::lazy::LazyProgram;
+use brainmuck_core::parsing::AbstractSyntaxTree;
+use brainmuck_core::tiered::TieredProgram;
+use brainmuck_core::{
+    BrainmuckProgram, CodegenOptions, CompiledProgram, FrontendRegistry, GuardedTape,
+    NativeCodeCache,
+};
 
 /// Run the program
 pub fn run(opt: Opt) -> Result<(), Box<dyn Error>> {
-    let program = compile_program(&opt)?;
+    let source_text = fs::read(&opt.program)?;
+    let filename = from_path(&opt.program);
+    let registry = FrontendRegistry::new();
+    // Assume anything we don't recognize is brainfuck.
+    let brainfuck = BrainfuckFrontend::default();
+    let frontend = registry.for_path(&opt.program).unwrap_or(&brainfuck);
 
-    let mut universe = [0u8; SIZE_OF_UNIVERSE];
-    program.run(&mut universe);
+    let cache = if opt.cache {
+        Some(NativeCodeCache::in_user_cache_dir().ok_or("no cache directory (set $HOME)")?)
+    } else {
+        None
+    };
+    // Which frontend parses the source depends on the extension, so that's part of the key.
+    let extension = opt.program.extension().unwrap_or_default();
+    let cache_key = [extension.as_encoded_bytes(), b"\0", &source_text].concat();
+    if let Some(program) = cache.as_ref().and_then(|cache| cache.load(&cache_key)) {
+        inspect_native_code(&opt, &program)?;
+        return run_program(&program, program.tape_length(), filename);
+    }
 
-    Ok(())
+    let (ast, warnings) = frontend.parse_with_warnings(&filename, &source_text)?;
+    for warning in warnings {
+        eprintln!("{}", warning);
+    }
+
+    let (cfg, report) = brainmuck_core::ast_to_optimized_cfg_with_report(&ast);
+    for warning in report.warnings(&filename) {
+        eprintln!("{}", warning);
+    }
+    if opt.remarks {
+        eprint!("{}", report);
+    }
+    if opt.stats {
+        eprint!("{}", cfg.stats());
+    }
+    if opt.emit_c {
+        print!("{}", brainmuck_core::compile_cfg_to_c(&cfg, ast.config()));
+        return Ok(());
+    }
+    if opt.dump_asm {
+        print!(
+            "{}",
+            brainmuck_core::compile_to_annotated_aarch64_listing(&ast)
+        );
+        return Ok(());
+    }
+    if let Some(output) = &opt.compile_via_c {
+        return compile_via_c(
+            &brainmuck_core::compile_cfg_to_c(&cfg, ast.config()),
+            output,
+        );
+    }
+
+    let cache = cache.as_ref().map(|cache| (cache, &cache_key[..]));
+    let program = compile_program(&opt, &ast, cfg, cache)?;
+
+    run_program(program.as_ref(), ast.config().tape_length, filename)
 }
 
-fn compile_program(opt: &Opt) -> Result<Box<dyn BrainmuckProgram>, Box<dyn Error>> {
-    let source_text = fs::read(&opt.program)?;
-    let filename = from_path(&opt.program);
-    let ast = brainmuck_core::parse(&filename, &source_text)?;
+fn run_program(
+    program: &dyn BrainmuckProgram,
+    tape_length: usize,
+    filename: String,
+) -> Result<(), Box<dyn Error>> {
+    // Guarded, so that native code that moves off the tape is an error like any other.
+    let mut universe = GuardedTape::new(tape_length);
+    if let Err(error) = program.run(&mut universe) {
+        return Err(match program.source_position(error.instruction()) {
+            Some(position) => error.with_location(Location::new(filename, position.line)),
+            None => error,
+        }
+        .into());
+    }
 
-    if opt.should_use_jit() {
-        Ok(Box::new(brainmuck_core::compile_to_native_code(&ast)))
+    Ok(())
+}
+
+fn compile_program(
+    opt: &Opt,
+    ast: &AbstractSyntaxTree,
+    cfg: ControlFlowGraph,
+    cache: Option<(&NativeCodeCache, &[u8])>,
+) -> Result<Box<dyn BrainmuckProgram>, Box<dyn Error>> {
+    let config = ast.config();
+    Ok(if opt.evaluate_ir {
+        let cfg = if opt.no_optimize { ir::lower(ast) } else { cfg };
+        Box::new(EvaluatedProgram::new(cfg, config))
+    } else if opt.tiered {
+        Box::new(TieredProgram::new(&cfg, config))
+    } else if opt.lazy && brainmuck_core::can_compile_to_native_code(config) {
+        Box::new(LazyProgram::new(&cfg, config))
+    } else if opt.should_use_jit() && brainmuck_core::can_compile_to_native_code(config) {
+        let options = CodegenOptions {
+            outline_loops: opt.outline_loops,
+            ..CodegenOptions::from(config)
+        };
+        let program = brainmuck_core::compile_cfg_to_native_code_with_options(&cfg, &options);
+        if let Some((cache, key)) = cache {
+            if let Err(error) = cache.save(key, &program) {
+                eprintln!("warning: could not cache the machine code: {}", error);
+            }
+        }
+        inspect_native_code(opt, &program)?;
+        Box::new(program)
     } else {
-        Ok(Box::new(brainmuck_core::compile_to_bytecode(&ast)))
+        Box::new(InterpretedProgram::new(&cfg, config))
+    })
+}
+
+fn inspect_native_code(opt: &Opt, program: &CompiledProgram) -> Result<(), Box<dyn Error>> {
+    if opt.perf_map {
+        program.write_perf_map()?;
+    }
+    if opt.dump_code {
+        eprint!("{}", program.annotated_dump());
     }
+    Ok(())
+}
+
+/// Compiles the C to a standalone executable with the system's C compiler, which is `$CC`, or `cc`
+/// if that's not set. Whatever the compiler produces natively (e.g., Mach-O on macOS) is what you
+/// get. Without a C compiler, there's no way to build an executable at all.
+fn compile_via_c(c_source: &str, output: &Path) -> Result<(), Box<dyn Error>> {
+    let compiler = env::var("CC").unwrap_or_else(|_| String::from("cc"));
+    let mut child = Command::new(&compiler)
+        .args(["-O2", "-x", "c", "-", "-o"])
+        .arg(output)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|error| match error.kind() {
+            io::ErrorKind::NotFound => format!(
+                "no C compiler found: --compile-via-c needs `{}` (set $CC to use another one)",
+                compiler
+            ),
+            _ => format!("could not run C compiler `{}`: {}", compiler, error),
+        })?;
+
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(c_source.as_bytes())?;
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(format!("C compiler `{}` failed: {}", compiler, status).into());
+    }
+
+    Ok(())
 }
 
 fn from_path(path: &Path) -> String {
@@ -48,6 +190,80 @@ pub struct Opt {
     #[structopt(short = "-J", long = "--no-jit")]
     no_jit: bool,
 
+    /// Start in the interpreter, and compile loops to machine code once they get hot
+    #[structopt(long = "--tiered", conflicts_with_all = &["no-jit", "evaluate-ir"])]
+    tiered: bool,
+
+    /// Compile each top-level loop to machine code the first time it runs, instead of all up front
+    #[structopt(
+        long = "--lazy",
+        conflicts_with_all = &["no-jit", "tiered", "evaluate-ir", "dump-code", "perf-map", "cache"]
+    )]
+    lazy: bool,
+
+    /// Compile each top-level loop as a function of its own, which shows up separately in
+    /// --dump-code and --perf-map
+    #[structopt(
+        long = "--outline-loops",
+        conflicts_with_all = &["no-jit", "tiered", "lazy", "evaluate-ir", "cache"]
+    )]
+    outline_loops: bool,
+
+    /// Run the intermediate representation directly, instead of compiling it (slowest!)
+    #[structopt(long = "--eval-ir")]
+    evaluate_ir: bool,
+
+    /// Skip the optimization passes (only with --eval-ir)
+    #[structopt(long = "--no-optimize", requires = "evaluate-ir")]
+    no_optimize: bool,
+
+    /// Print statistics about the optimized program to stderr before running it
+    #[structopt(long = "--stats")]
+    stats: bool,
+
+    /// Print what the optimizer did to the program to stderr before running it
+    #[structopt(long = "--remarks")]
+    remarks: bool,
+
+    /// Print the program as C source code, instead of running it
+    #[structopt(long = "--emit-c")]
+    emit_c: bool,
+
+    /// Compile the program to a standalone executable by translating it to C and running the
+    /// system's C compiler ($CC, or cc) on it, instead of running it. Fails if there's no C
+    /// compiler
+    #[structopt(
+        long = "--compile-via-c",
+        name = "EXECUTABLE",
+        conflicts_with = "emit-c"
+    )]
+    compile_via_c: Option<PathBuf>,
+
+    /// Print the machine code that the JIT generated to stderr before running it
+    #[structopt(long = "--dump-code", conflicts_with_all = &["no-jit", "tiered", "evaluate-ir"])]
+    dump_code: bool,
+
+    /// Print the AArch64 assembly that the JIT generates for the program, with the offset and
+    /// encoding of each instruction, instead of running it (on any machine)
+    #[structopt(
+        long = "--dump-asm",
+        conflicts_with_all = &["no-jit", "tiered", "lazy", "evaluate-ir", "emit-c", "compile-via-c", "cache"]
+    )]
+    dump_asm: bool,
+
+    /// Write /tmp/perf-<pid>.map, so that `perf` can tell which part of the program the JIT'd
+    /// code came from
+    #[structopt(long = "--perf-map", conflicts_with_all = &["no-jit", "tiered", "evaluate-ir"])]
+    perf_map: bool,
+
+    /// Reuse the machine code from the last time the program ran, if its source hasn't changed.
+    /// The code is kept in $XDG_CACHE_HOME/brainmuck, or ~/.cache/brainmuck
+    #[structopt(
+        long = "--cache",
+        conflicts_with_all = &["no-jit", "tiered", "evaluate-ir", "emit-c", "compile-via-c", "stats", "remarks"]
+    )]
+    cache: bool,
+
     /// filename of the program to run
     #[structopt(name = "PROGRAM")]
     program: PathBuf,